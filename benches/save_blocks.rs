@@ -0,0 +1,179 @@
+// Throughput of Storage::save_blocks, the batched write used when syncing
+// and when copying between backends:
+//
+//     cargo bench --bench save_blocks
+//     cargo bench --bench save_blocks --features rocksdb
+//
+// Each backend gets a fresh database: a temporary SQLite file, a temporary
+// RocksDB directory when built with the feature, and the MySQL database in
+// BENCH_MYSQL_URL if set, which must be empty. BENCH_BLOCKS and
+// BENCH_TRANSACTIONS (per block) size the chain.
+//
+// The storage code lives in the node binary rather than the library, so
+// the modules it needs are compiled into the benchmark directly.
+#![allow(dead_code)]
+
+#[path = "../src/blockchain.rs"]
+mod blockchain;
+#[path = "../src/database.rs"]
+mod database;
+#[path = "../src/governance.rs"]
+mod governance;
+#[path = "../src/market.rs"]
+mod market;
+#[path = "../src/mdns.rs"]
+mod mdns;
+#[path = "../src/migrations.rs"]
+mod migrations;
+#[path = "../src/multisig.rs"]
+mod multisig;
+#[path = "../src/nat.rs"]
+mod nat;
+#[path = "../src/network.rs"]
+mod network;
+#[cfg(feature = "libp2p")]
+#[path = "../src/p2p.rs"]
+mod p2p;
+#[cfg(feature = "rocksdb")]
+#[path = "../src/rocksdb_storage.rs"]
+mod rocksdb_storage;
+#[path = "../src/secure.rs"]
+mod secure;
+#[path = "../src/security.rs"]
+mod security;
+#[path = "../src/signer.rs"]
+mod signer;
+#[path = "../src/sqlite_storage.rs"]
+mod sqlite_storage;
+#[path = "../src/storage.rs"]
+mod storage;
+#[path = "../src/wallet.rs"]
+mod wallet;
+#[path = "../src/wire.rs"]
+mod wire;
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+
+use blockchain::{Block, Transaction, TransactionKind};
+use database::{Database, DatabaseConfig};
+use storage::StorageBackend;
+
+const DEFAULT_BLOCKS: usize = 2_000;
+const DEFAULT_TRANSACTIONS: usize = 20;
+// Blocks per save_blocks call, as sync and copy write them
+const BATCH: usize = 500;
+
+fn env_size(name: &str, default: usize) -> usize {
+    std::env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+fn hash(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hex::encode(hasher.finalize())
+}
+
+// A linked chain of blocks full of transfers. Storage doesn't re-validate
+// blocks, so the transactions only need well-formed ids and signatures.
+fn chain(blocks: usize, transactions: usize) -> Vec<Block> {
+    let start = chrono::Utc::now();
+    let mut previous_hash = "0".repeat(64);
+    (0..blocks)
+        .map(|height| {
+            let timestamp = start + chrono::Duration::milliseconds(height as i64);
+            let transactions = (0..transactions)
+                .map(|i| Transaction {
+                    version: blockchain::TRANSACTION_VERSION,
+                    id: uuid::Uuid::new_v4().to_string(),
+                    from: format!("bench-sender-{}", i % 100),
+                    to: format!("bench-recipient-{}", (height + i) % 1000),
+                    amount: 1.0,
+                    fee: blockchain::MIN_TRANSACTION_FEE,
+                    timestamp,
+                    lock_time: None,
+                    kind: TransactionKind::Transfer,
+                    signature: vec![0; storage::SIGNATURE_BYTES],
+                    public_key: vec![0; 32],
+                    key_scheme: Default::default(),
+                    multisig: None,
+                })
+                .collect();
+            let block = Block {
+                version: blockchain::BLOCK_VERSION,
+                hash: hash(&[previous_hash.as_bytes(), &height.to_be_bytes()]),
+                previous_hash: previous_hash.clone(),
+                timestamp,
+                transactions,
+                poh_hash: hash(&[&height.to_be_bytes()]),
+                poh_count: height as u64,
+            };
+            previous_hash = block.hash.clone();
+            block
+        })
+        .collect()
+}
+
+fn run(name: &str, config: DatabaseConfig, blocks: &[Block]) -> Result<(), Box<dyn Error>> {
+    let db = Database::connect(config)?;
+    db.migrate()?;
+    if db.get_chain_state()?.best_hash.is_some() {
+        return Err(format!("{} database already holds blocks", name).into());
+    }
+
+    let mut elapsed = Duration::ZERO;
+    for batch in blocks.chunks(BATCH) {
+        let started = Instant::now();
+        db.save_blocks(batch)?;
+        elapsed += started.elapsed();
+    }
+
+    let transactions: usize = blocks.iter().map(|block| block.transactions.len()).sum();
+    let seconds = elapsed.as_secs_f64();
+    println!(
+        "{:<8} {} blocks, {} transactions in {:.2}s: {:.0} blocks/s, {:.0} transactions/s",
+        name,
+        blocks.len(),
+        transactions,
+        seconds,
+        blocks.len() as f64 / seconds,
+        transactions as f64 / seconds
+    );
+    Ok(())
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("save_blocks-{}-{}", name, uuid::Uuid::new_v4()))
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let blocks = chain(env_size("BENCH_BLOCKS", DEFAULT_BLOCKS), env_size("BENCH_TRANSACTIONS", DEFAULT_TRANSACTIONS));
+
+    let path = temp_path("sqlite");
+    let config = DatabaseConfig { backend: StorageBackend::Sqlite(path.clone()), ..DatabaseConfig::default() };
+    let result = run("sqlite", config, &blocks);
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+    result?;
+
+    #[cfg(feature = "rocksdb")]
+    {
+        let path = temp_path("rocksdb");
+        let config = DatabaseConfig { backend: StorageBackend::Rocksdb(path.clone()), ..DatabaseConfig::default() };
+        let result = run("rocksdb", config, &blocks);
+        let _ = std::fs::remove_dir_all(&path);
+        result?;
+    }
+
+    if let Ok(url) = std::env::var("BENCH_MYSQL_URL") {
+        let mut config = DatabaseConfig::default();
+        config.apply_url(&url)?;
+        run("mysql", config, &blocks)?;
+    }
+    Ok(())
+}
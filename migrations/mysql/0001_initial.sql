@@ -0,0 +1,29 @@
+-- Tables as created by init_database before migrations existed
+
+CREATE TABLE IF NOT EXISTS wallets (
+    id VARCHAR(36) PRIMARY KEY,
+    email VARCHAR(255) UNIQUE NOT NULL,
+    public_key BLOB NOT NULL,
+    hardware_id VARCHAR(64) NOT NULL,
+    balance DECIMAL(20,8) DEFAULT 0,
+    created_at DATETIME NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS blocks (
+    hash VARCHAR(64) PRIMARY KEY,
+    previous_hash VARCHAR(64) NOT NULL,
+    timestamp DATETIME NOT NULL,
+    poh_hash VARCHAR(64) NOT NULL,
+    poh_count BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS transactions (
+    id VARCHAR(36) PRIMARY KEY,
+    block_hash VARCHAR(64),
+    from_address VARCHAR(16) NOT NULL,
+    to_address VARCHAR(16) NOT NULL,
+    amount DECIMAL(20,8) NOT NULL,
+    timestamp DATETIME NOT NULL,
+    signature BLOB NOT NULL,
+    FOREIGN KEY (block_hash) REFERENCES blocks(hash)
+);
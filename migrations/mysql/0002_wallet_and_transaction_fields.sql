@@ -0,0 +1,61 @@
+-- Wallet keys, versioned and feeable transactions, derived addresses, the
+-- address book, bookkeeping metadata and known peers. Addresses grow to
+-- hold the checksummed encoding.
+
+ALTER TABLE wallets
+    ADD COLUMN address VARCHAR(64) NOT NULL,
+    ADD COLUMN key_scheme VARCHAR(16) NOT NULL DEFAULT 'ed25519',
+    ADD COLUMN encrypted_key TEXT NOT NULL,
+    ADD COLUMN pin_hash VARCHAR(255) NOT NULL,
+    ADD COLUMN portable BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN label VARCHAR(255) NULL,
+    ADD COLUMN category VARCHAR(64) NULL,
+    ADD COLUMN notes TEXT NULL;
+
+ALTER TABLE blocks ADD COLUMN version INT UNSIGNED NOT NULL DEFAULT 0;
+
+ALTER TABLE transactions
+    ADD COLUMN version INT UNSIGNED NOT NULL DEFAULT 0,
+    MODIFY from_address VARCHAR(64) NOT NULL,
+    MODIFY to_address VARCHAR(64) NOT NULL,
+    ADD COLUMN fee DECIMAL(20,8) NOT NULL DEFAULT 0,
+    ADD COLUMN lock_height BIGINT UNSIGNED NULL,
+    ADD COLUMN lock_timestamp DATETIME NULL,
+    ADD COLUMN kind TEXT NULL,
+    ADD COLUMN public_key BLOB NULL,
+    ADD COLUMN key_scheme VARCHAR(16) NOT NULL DEFAULT 'ed25519';
+
+CREATE TABLE IF NOT EXISTS wallet_addresses (
+    wallet_id VARCHAR(36) NOT NULL,
+    address_index INT UNSIGNED NOT NULL,
+    address VARCHAR(64) UNIQUE NOT NULL,
+    public_key BLOB NOT NULL,
+    created_at DATETIME NOT NULL,
+    PRIMARY KEY (wallet_id, address_index),
+    FOREIGN KEY (wallet_id) REFERENCES wallets(id)
+);
+
+CREATE TABLE IF NOT EXISTS address_book (
+    wallet_id VARCHAR(36) NOT NULL,
+    label VARCHAR(64) NOT NULL,
+    address VARCHAR(64) NOT NULL,
+    PRIMARY KEY (wallet_id, label),
+    FOREIGN KEY (wallet_id) REFERENCES wallets(id)
+);
+
+CREATE TABLE IF NOT EXISTS transaction_metadata (
+    wallet_id VARCHAR(36) NOT NULL,
+    transaction_id VARCHAR(36) NOT NULL,
+    label VARCHAR(255) NULL,
+    category VARCHAR(64) NULL,
+    notes TEXT NULL,
+    PRIMARY KEY (wallet_id, transaction_id),
+    FOREIGN KEY (wallet_id) REFERENCES wallets(id)
+);
+
+CREATE TABLE IF NOT EXISTS peer_addresses (
+    address VARCHAR(255) PRIMARY KEY,
+    score INT NOT NULL DEFAULT 0,
+    last_seen DATETIME NULL,
+    last_attempt DATETIME NULL
+);
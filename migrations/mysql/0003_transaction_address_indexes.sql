@@ -0,0 +1,4 @@
+-- Address history pages seek on (address, timestamp, id) instead of
+-- scanning the table
+CREATE INDEX transactions_from_address ON transactions (from_address, timestamp, id);
+CREATE INDEX transactions_to_address ON transactions (to_address, timestamp, id);
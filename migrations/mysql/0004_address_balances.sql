@@ -0,0 +1,7 @@
+-- Native balance per address from the stored transactions, updated as
+-- blocks are saved and reverted. Existing chains fill it with
+-- `db check-balances --repair`.
+CREATE TABLE address_balances (
+    address VARCHAR(64) PRIMARY KEY,
+    balance DECIMAL(20,8) NOT NULL DEFAULT 0
+);
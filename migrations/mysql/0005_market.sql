@@ -0,0 +1,38 @@
+-- Market state that used to live only in memory
+
+CREATE TABLE tokens (
+    symbol VARCHAR(16) PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    total_supply DECIMAL(20,8) NOT NULL,
+    current_price DECIMAL(20,8) NOT NULL,
+    last_updated DATETIME NOT NULL
+);
+
+-- Orders move from Pending to Filled, Cancelled or Failed and then stay put
+CREATE TABLE orders (
+    id VARCHAR(36) PRIMARY KEY,
+    user_id VARCHAR(255) NOT NULL,
+    token_symbol VARCHAR(16) NOT NULL,
+    order_type VARCHAR(8) NOT NULL,
+    amount DECIMAL(20,8) NOT NULL,
+    price DECIMAL(20,8) NOT NULL,
+    filled DECIMAL(20,8) NOT NULL DEFAULT 0,
+    status VARCHAR(16) NOT NULL,
+    timestamp DATETIME NOT NULL,
+    INDEX orders_open_by_user (status, user_id, timestamp)
+);
+
+CREATE TABLE trades (
+    id VARCHAR(36) PRIMARY KEY,
+    token_symbol VARCHAR(16) NOT NULL,
+    buy_order_id VARCHAR(36) NOT NULL,
+    sell_order_id VARCHAR(36) NOT NULL,
+    buyer VARCHAR(255) NOT NULL,
+    seller VARCHAR(255) NOT NULL,
+    amount DECIMAL(20,8) NOT NULL,
+    price DECIMAL(20,8) NOT NULL,
+    timestamp DATETIME NOT NULL,
+    INDEX trades_by_token (token_symbol, timestamp, id),
+    FOREIGN KEY (buy_order_id) REFERENCES orders(id),
+    FOREIGN KEY (sell_order_id) REFERENCES orders(id)
+);
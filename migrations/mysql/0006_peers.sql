@@ -0,0 +1,16 @@
+-- Peer addresses and bans share one table so both survive restarts. Bans
+-- are kept per IP on rows of their own, which the address book skips.
+
+CREATE TABLE peers (
+    address VARCHAR(255) PRIMARY KEY,
+    score INT NOT NULL DEFAULT 0,
+    last_seen DATETIME NULL,
+    last_attempt DATETIME NULL,
+    banned_until DATETIME NULL,
+    INDEX peers_banned_until (banned_until)
+);
+
+INSERT INTO peers (address, score, last_seen, last_attempt)
+SELECT address, score, last_seen, last_attempt FROM peer_addresses;
+
+DROP TABLE peer_addresses;
@@ -0,0 +1,19 @@
+-- Tip and sync state by name: best_hash, best_height, genesis_hash and
+-- last_snapshot_height. Existing chains start from the newest block, as
+-- the tip was found before.
+
+CREATE TABLE chain_metadata (
+    name VARCHAR(64) PRIMARY KEY,
+    value VARCHAR(255) NOT NULL
+);
+
+INSERT INTO chain_metadata (name, value)
+SELECT 'best_hash', hash FROM blocks ORDER BY timestamp DESC LIMIT 1;
+
+INSERT INTO chain_metadata (name, value)
+SELECT 'best_height', CAST(COUNT(*) - 1 AS CHAR) FROM blocks HAVING COUNT(*) > 0;
+
+INSERT INTO chain_metadata (name, value)
+SELECT 'genesis_hash', child.hash FROM blocks child
+WHERE NOT EXISTS (SELECT 1 FROM blocks parent WHERE parent.hash = child.previous_hash)
+ORDER BY child.timestamp LIMIT 1;
@@ -0,0 +1,16 @@
+-- Proposals as last saved, for search; votes stay in memory. Proposal
+-- text and transaction annotations get FULLTEXT indexes.
+
+CREATE TABLE proposals (
+    id VARCHAR(36) PRIMARY KEY,
+    title VARCHAR(255) NOT NULL,
+    description TEXT NOT NULL,
+    creator VARCHAR(64) NOT NULL,
+    status VARCHAR(16) NOT NULL,
+    budget_amount DOUBLE NOT NULL,
+    created_at DATETIME NOT NULL,
+    voting_end DATETIME NOT NULL,
+    FULLTEXT INDEX proposals_search (title, description)
+);
+
+ALTER TABLE transaction_metadata ADD FULLTEXT INDEX transaction_metadata_search (label, notes);
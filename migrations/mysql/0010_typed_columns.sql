@@ -0,0 +1,50 @@
+-- Hashes move from hex text to BINARY(32) and ids to fixed-width ASCII
+-- UUIDs. Foreign keys over the changed columns are dropped and put back
+-- around the conversion.
+
+ALTER TABLE transactions DROP FOREIGN KEY transactions_ibfk_1;
+ALTER TABLE wallet_addresses DROP FOREIGN KEY wallet_addresses_ibfk_1;
+ALTER TABLE address_book DROP FOREIGN KEY address_book_ibfk_1;
+ALTER TABLE transaction_metadata DROP FOREIGN KEY transaction_metadata_ibfk_1;
+ALTER TABLE trades DROP FOREIGN KEY trades_ibfk_1, DROP FOREIGN KEY trades_ibfk_2;
+
+ALTER TABLE blocks
+    ADD COLUMN hash_bin BINARY(32) NULL,
+    ADD COLUMN previous_hash_bin BINARY(32) NULL,
+    ADD COLUMN poh_hash_bin BINARY(32) NULL;
+UPDATE blocks SET hash_bin = UNHEX(hash), previous_hash_bin = UNHEX(previous_hash), poh_hash_bin = UNHEX(poh_hash);
+ALTER TABLE blocks DROP PRIMARY KEY, DROP COLUMN hash, DROP COLUMN previous_hash, DROP COLUMN poh_hash;
+ALTER TABLE blocks
+    CHANGE hash_bin hash BINARY(32) NOT NULL FIRST,
+    CHANGE previous_hash_bin previous_hash BINARY(32) NOT NULL AFTER version,
+    CHANGE poh_hash_bin poh_hash BINARY(32) NOT NULL AFTER timestamp,
+    ADD PRIMARY KEY (hash);
+
+ALTER TABLE transactions ADD COLUMN block_hash_bin BINARY(32) NULL;
+UPDATE transactions SET block_hash_bin = UNHEX(block_hash);
+ALTER TABLE transactions DROP COLUMN block_hash;
+ALTER TABLE transactions
+    CHANGE block_hash_bin block_hash BINARY(32) NULL AFTER version,
+    MODIFY id CHAR(36) CHARACTER SET ascii NOT NULL,
+    MODIFY signature VARBINARY(64) NOT NULL;
+
+ALTER TABLE wallets MODIFY id CHAR(36) CHARACTER SET ascii NOT NULL;
+ALTER TABLE wallet_addresses MODIFY wallet_id CHAR(36) CHARACTER SET ascii NOT NULL;
+ALTER TABLE address_book MODIFY wallet_id CHAR(36) CHARACTER SET ascii NOT NULL;
+ALTER TABLE transaction_metadata
+    MODIFY wallet_id CHAR(36) CHARACTER SET ascii NOT NULL,
+    MODIFY transaction_id CHAR(36) CHARACTER SET ascii NOT NULL;
+ALTER TABLE orders MODIFY id CHAR(36) CHARACTER SET ascii NOT NULL;
+ALTER TABLE trades
+    MODIFY id CHAR(36) CHARACTER SET ascii NOT NULL,
+    MODIFY buy_order_id CHAR(36) CHARACTER SET ascii NOT NULL,
+    MODIFY sell_order_id CHAR(36) CHARACTER SET ascii NOT NULL;
+ALTER TABLE proposals MODIFY id CHAR(36) CHARACTER SET ascii NOT NULL;
+
+ALTER TABLE transactions ADD FOREIGN KEY (block_hash) REFERENCES blocks(hash);
+ALTER TABLE wallet_addresses ADD FOREIGN KEY (wallet_id) REFERENCES wallets(id);
+ALTER TABLE address_book ADD FOREIGN KEY (wallet_id) REFERENCES wallets(id);
+ALTER TABLE transaction_metadata ADD FOREIGN KEY (wallet_id) REFERENCES wallets(id);
+ALTER TABLE trades
+    ADD FOREIGN KEY (buy_order_id) REFERENCES orders(id),
+    ADD FOREIGN KEY (sell_order_id) REFERENCES orders(id);
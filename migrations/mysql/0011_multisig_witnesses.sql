@@ -0,0 +1,3 @@
+-- Co-signer signatures of multisig spends, serialized like kind. Without
+-- them a stored block can't be re-verified.
+ALTER TABLE transactions ADD COLUMN multisig TEXT NULL;
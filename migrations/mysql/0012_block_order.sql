@@ -0,0 +1,3 @@
+-- Blocks are listed oldest first, a page at a time, when copying or
+-- comparing databases
+CREATE INDEX blocks_by_time ON blocks (timestamp, hash);
@@ -0,0 +1,28 @@
+-- Block and transaction times are hashed to the nanosecond, but DATETIME
+-- keeps whole seconds, so a reloaded block no longer matched its hash.
+-- They move to BIGINT nanoseconds since the epoch. Rows written before
+-- this were already truncated and keep their second precision.
+
+DROP INDEX blocks_by_time ON blocks;
+DROP INDEX transactions_from_address ON transactions;
+DROP INDEX transactions_to_address ON transactions;
+
+ALTER TABLE blocks ADD COLUMN timestamp_nanos BIGINT NULL;
+UPDATE blocks SET timestamp_nanos = TIMESTAMPDIFF(SECOND, '1970-01-01 00:00:00', timestamp) * 1000000000;
+ALTER TABLE blocks DROP COLUMN timestamp;
+ALTER TABLE blocks CHANGE timestamp_nanos timestamp BIGINT NOT NULL AFTER previous_hash;
+
+ALTER TABLE transactions
+    ADD COLUMN timestamp_nanos BIGINT NULL,
+    ADD COLUMN lock_timestamp_nanos BIGINT NULL;
+UPDATE transactions SET
+    timestamp_nanos = TIMESTAMPDIFF(SECOND, '1970-01-01 00:00:00', timestamp) * 1000000000,
+    lock_timestamp_nanos = TIMESTAMPDIFF(SECOND, '1970-01-01 00:00:00', lock_timestamp) * 1000000000;
+ALTER TABLE transactions DROP COLUMN timestamp, DROP COLUMN lock_timestamp;
+ALTER TABLE transactions
+    CHANGE timestamp_nanos timestamp BIGINT NOT NULL AFTER fee,
+    CHANGE lock_timestamp_nanos lock_timestamp BIGINT NULL AFTER lock_height;
+
+CREATE INDEX blocks_by_time ON blocks (timestamp, hash);
+CREATE INDEX transactions_from_address ON transactions (from_address, timestamp, id);
+CREATE INDEX transactions_to_address ON transactions (to_address, timestamp, id);
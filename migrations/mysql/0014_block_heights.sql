@@ -0,0 +1,23 @@
+-- Blocks are paged by height rather than timestamp, which doesn't order
+-- a parent before a child sharing its timestamp. Stored blocks count up
+-- from their roots: genesis, which names itself as its parent, and any
+-- block whose parent isn't stored.
+
+ALTER TABLE blocks ADD COLUMN height BIGINT UNSIGNED NOT NULL DEFAULT 0;
+
+SET SESSION cte_max_recursion_depth = 4294967295;
+CREATE TEMPORARY TABLE block_heights (hash BINARY(32) PRIMARY KEY, height BIGINT UNSIGNED NOT NULL);
+INSERT INTO block_heights
+WITH RECURSIVE chain (hash, height) AS (
+    SELECT hash, CAST(0 AS UNSIGNED) FROM blocks
+    WHERE previous_hash = hash OR previous_hash NOT IN (SELECT hash FROM blocks)
+    UNION ALL
+    SELECT child.hash, chain.height + 1 FROM blocks AS child
+    JOIN chain ON child.previous_hash = chain.hash AND child.hash <> child.previous_hash
+)
+SELECT hash, height FROM chain;
+UPDATE blocks JOIN block_heights USING (hash) SET blocks.height = block_heights.height;
+DROP TEMPORARY TABLE block_heights;
+
+DROP INDEX blocks_by_time ON blocks;
+CREATE INDEX blocks_by_height ON blocks (height, hash);
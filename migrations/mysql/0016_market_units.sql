@@ -0,0 +1,28 @@
+-- Order and trade amounts and prices become whole numbers of the smallest
+-- unit, 10^-8, the precision the DECIMAL columns already had, so matching
+-- never leaves a fraction of a unit open on an order.
+
+ALTER TABLE orders
+    ADD COLUMN amount_units BIGINT UNSIGNED NULL,
+    ADD COLUMN price_units BIGINT UNSIGNED NULL,
+    ADD COLUMN filled_units BIGINT UNSIGNED NULL;
+UPDATE orders SET
+    amount_units = ROUND(amount * 100000000),
+    price_units = ROUND(price * 100000000),
+    filled_units = ROUND(filled * 100000000);
+ALTER TABLE orders DROP COLUMN amount, DROP COLUMN price, DROP COLUMN filled;
+ALTER TABLE orders
+    CHANGE amount_units amount BIGINT UNSIGNED NOT NULL AFTER order_type,
+    CHANGE price_units price BIGINT UNSIGNED NOT NULL AFTER amount,
+    CHANGE filled_units filled BIGINT UNSIGNED NOT NULL DEFAULT 0 AFTER price;
+
+ALTER TABLE trades
+    ADD COLUMN amount_units BIGINT UNSIGNED NULL,
+    ADD COLUMN price_units BIGINT UNSIGNED NULL;
+UPDATE trades SET
+    amount_units = ROUND(amount * 100000000),
+    price_units = ROUND(price * 100000000);
+ALTER TABLE trades DROP COLUMN amount, DROP COLUMN price;
+ALTER TABLE trades
+    CHANGE amount_units amount BIGINT UNSIGNED NOT NULL AFTER seller,
+    CHANGE price_units price BIGINT UNSIGNED NOT NULL AFTER amount;
@@ -0,0 +1,28 @@
+-- Tables as created by init_database before migrations existed
+
+CREATE TABLE IF NOT EXISTS wallets (
+    id TEXT PRIMARY KEY,
+    email TEXT UNIQUE NOT NULL,
+    public_key BLOB NOT NULL,
+    hardware_id TEXT NOT NULL,
+    balance REAL DEFAULT 0,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS blocks (
+    hash TEXT PRIMARY KEY,
+    previous_hash TEXT NOT NULL,
+    timestamp TEXT NOT NULL,
+    poh_hash TEXT NOT NULL,
+    poh_count INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS transactions (
+    id TEXT PRIMARY KEY,
+    block_hash TEXT REFERENCES blocks(hash),
+    from_address TEXT NOT NULL,
+    to_address TEXT NOT NULL,
+    amount REAL NOT NULL,
+    timestamp TEXT NOT NULL,
+    signature BLOB NOT NULL
+);
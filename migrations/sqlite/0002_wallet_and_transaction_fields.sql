@@ -0,0 +1,54 @@
+-- Wallet keys, versioned and feeable transactions, derived addresses, the
+-- address book, bookkeeping metadata and known peers. SQLite can only add
+-- NOT NULL columns with a default.
+
+ALTER TABLE wallets ADD COLUMN address TEXT NOT NULL DEFAULT '';
+ALTER TABLE wallets ADD COLUMN key_scheme TEXT NOT NULL DEFAULT 'ed25519';
+ALTER TABLE wallets ADD COLUMN encrypted_key TEXT NOT NULL DEFAULT '';
+ALTER TABLE wallets ADD COLUMN pin_hash TEXT NOT NULL DEFAULT '';
+ALTER TABLE wallets ADD COLUMN portable INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE wallets ADD COLUMN label TEXT NULL;
+ALTER TABLE wallets ADD COLUMN category TEXT NULL;
+ALTER TABLE wallets ADD COLUMN notes TEXT NULL;
+
+ALTER TABLE blocks ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE transactions ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE transactions ADD COLUMN fee REAL NOT NULL DEFAULT 0;
+ALTER TABLE transactions ADD COLUMN lock_height INTEGER NULL;
+ALTER TABLE transactions ADD COLUMN lock_timestamp TEXT NULL;
+ALTER TABLE transactions ADD COLUMN kind TEXT NULL;
+ALTER TABLE transactions ADD COLUMN public_key BLOB NULL;
+ALTER TABLE transactions ADD COLUMN key_scheme TEXT NOT NULL DEFAULT 'ed25519';
+
+CREATE TABLE IF NOT EXISTS wallet_addresses (
+    wallet_id TEXT NOT NULL REFERENCES wallets(id),
+    address_index INTEGER NOT NULL,
+    address TEXT UNIQUE NOT NULL,
+    public_key BLOB NOT NULL,
+    created_at TEXT NOT NULL,
+    PRIMARY KEY (wallet_id, address_index)
+);
+
+CREATE TABLE IF NOT EXISTS address_book (
+    wallet_id TEXT NOT NULL REFERENCES wallets(id),
+    label TEXT NOT NULL,
+    address TEXT NOT NULL,
+    PRIMARY KEY (wallet_id, label)
+);
+
+CREATE TABLE IF NOT EXISTS transaction_metadata (
+    wallet_id TEXT NOT NULL REFERENCES wallets(id),
+    transaction_id TEXT NOT NULL,
+    label TEXT NULL,
+    category TEXT NULL,
+    notes TEXT NULL,
+    PRIMARY KEY (wallet_id, transaction_id)
+);
+
+CREATE TABLE IF NOT EXISTS peer_addresses (
+    address TEXT PRIMARY KEY,
+    score INTEGER NOT NULL DEFAULT 0,
+    last_seen TEXT NULL,
+    last_attempt TEXT NULL
+);
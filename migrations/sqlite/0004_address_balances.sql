@@ -0,0 +1,7 @@
+-- Native balance per address from the stored transactions, updated as
+-- blocks are saved and reverted. Existing chains fill it with
+-- `db check-balances --repair`.
+CREATE TABLE IF NOT EXISTS address_balances (
+    address TEXT PRIMARY KEY,
+    balance REAL NOT NULL DEFAULT 0
+);
@@ -0,0 +1,38 @@
+-- Market state that used to live only in memory
+
+CREATE TABLE IF NOT EXISTS tokens (
+    symbol TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    total_supply REAL NOT NULL,
+    current_price REAL NOT NULL,
+    last_updated TEXT NOT NULL
+);
+
+-- Orders move from Pending to Filled, Cancelled or Failed and then stay put
+CREATE TABLE IF NOT EXISTS orders (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    token_symbol TEXT NOT NULL,
+    order_type TEXT NOT NULL,
+    amount REAL NOT NULL,
+    price REAL NOT NULL,
+    filled REAL NOT NULL DEFAULT 0,
+    status TEXT NOT NULL,
+    timestamp TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS orders_open_by_user ON orders (status, user_id, timestamp);
+
+CREATE TABLE IF NOT EXISTS trades (
+    id TEXT PRIMARY KEY,
+    token_symbol TEXT NOT NULL,
+    buy_order_id TEXT NOT NULL REFERENCES orders(id),
+    sell_order_id TEXT NOT NULL REFERENCES orders(id),
+    buyer TEXT NOT NULL,
+    seller TEXT NOT NULL,
+    amount REAL NOT NULL,
+    price REAL NOT NULL,
+    timestamp TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS trades_by_token ON trades (token_symbol, timestamp, id);
@@ -0,0 +1,17 @@
+-- Peer addresses and bans share one table so both survive restarts. Bans
+-- are kept per IP on rows of their own, which the address book skips.
+
+CREATE TABLE peers (
+    address TEXT PRIMARY KEY,
+    score INTEGER NOT NULL DEFAULT 0,
+    last_seen TEXT NULL,
+    last_attempt TEXT NULL,
+    banned_until TEXT NULL
+);
+
+CREATE INDEX peers_banned_until ON peers (banned_until);
+
+INSERT INTO peers (address, score, last_seen, last_attempt)
+SELECT address, score, last_seen, last_attempt FROM peer_addresses;
+
+DROP TABLE peer_addresses;
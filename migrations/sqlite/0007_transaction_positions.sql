@@ -0,0 +1,5 @@
+-- Where each transaction sits in its block, which the block hash depends
+-- on. Rows stored before this have no position and are read back in
+-- timestamp order.
+
+ALTER TABLE transactions ADD COLUMN position INTEGER NULL;
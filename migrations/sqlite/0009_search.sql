@@ -0,0 +1,55 @@
+-- Proposals as last saved, for search; votes stay in memory. Proposal
+-- text and transaction annotations are indexed by FTS5 tables that
+-- triggers keep in step with their source tables.
+
+CREATE TABLE proposals (
+    id TEXT PRIMARY KEY,
+    title TEXT NOT NULL,
+    description TEXT NOT NULL,
+    creator TEXT NOT NULL,
+    status TEXT NOT NULL,
+    budget_amount REAL NOT NULL,
+    created_at TEXT NOT NULL,
+    voting_end TEXT NOT NULL
+);
+
+CREATE VIRTUAL TABLE proposals_search USING fts5(
+    title, description, content = 'proposals', content_rowid = 'rowid'
+);
+
+CREATE TRIGGER proposals_search_insert AFTER INSERT ON proposals BEGIN
+    INSERT INTO proposals_search (rowid, title, description) VALUES (new.rowid, new.title, new.description);
+END;
+
+CREATE TRIGGER proposals_search_delete AFTER DELETE ON proposals BEGIN
+    INSERT INTO proposals_search (proposals_search, rowid, title, description)
+    VALUES ('delete', old.rowid, old.title, old.description);
+END;
+
+CREATE TRIGGER proposals_search_update AFTER UPDATE ON proposals BEGIN
+    INSERT INTO proposals_search (proposals_search, rowid, title, description)
+    VALUES ('delete', old.rowid, old.title, old.description);
+    INSERT INTO proposals_search (rowid, title, description) VALUES (new.rowid, new.title, new.description);
+END;
+
+CREATE VIRTUAL TABLE transaction_metadata_search USING fts5(
+    label, notes, content = 'transaction_metadata', content_rowid = 'rowid'
+);
+
+CREATE TRIGGER transaction_metadata_search_insert AFTER INSERT ON transaction_metadata BEGIN
+    INSERT INTO transaction_metadata_search (rowid, label, notes) VALUES (new.rowid, new.label, new.notes);
+END;
+
+CREATE TRIGGER transaction_metadata_search_delete AFTER DELETE ON transaction_metadata BEGIN
+    INSERT INTO transaction_metadata_search (transaction_metadata_search, rowid, label, notes)
+    VALUES ('delete', old.rowid, old.label, old.notes);
+END;
+
+CREATE TRIGGER transaction_metadata_search_update AFTER UPDATE ON transaction_metadata BEGIN
+    INSERT INTO transaction_metadata_search (transaction_metadata_search, rowid, label, notes)
+    VALUES ('delete', old.rowid, old.label, old.notes);
+    INSERT INTO transaction_metadata_search (rowid, label, notes) VALUES (new.rowid, new.label, new.notes);
+END;
+
+-- Index the annotations saved before this migration
+INSERT INTO transaction_metadata_search (transaction_metadata_search) VALUES ('rebuild');
@@ -0,0 +1,48 @@
+-- Hashes move from hex text to 32-byte blobs and ids are checked to be
+-- hyphenated UUIDs. SQLite can't change a column's type in place, so
+-- blocks and transactions are rebuilt.
+
+PRAGMA defer_foreign_keys = ON;
+
+CREATE TABLE blocks_new (
+    hash BLOB PRIMARY KEY CHECK (length(hash) = 32),
+    version INTEGER NOT NULL DEFAULT 0,
+    previous_hash BLOB NOT NULL CHECK (length(previous_hash) = 32),
+    timestamp TEXT NOT NULL,
+    poh_hash BLOB NOT NULL CHECK (length(poh_hash) = 32),
+    poh_count INTEGER NOT NULL
+);
+
+INSERT INTO blocks_new (hash, version, previous_hash, timestamp, poh_hash, poh_count)
+SELECT unhex(hash), version, unhex(previous_hash), timestamp, unhex(poh_hash), poh_count FROM blocks;
+
+CREATE TABLE transactions_new (
+    id TEXT PRIMARY KEY CHECK (length(id) = 36),
+    version INTEGER NOT NULL DEFAULT 0,
+    block_hash BLOB REFERENCES blocks(hash) CHECK (length(block_hash) = 32),
+    from_address TEXT NOT NULL,
+    to_address TEXT NOT NULL,
+    amount REAL NOT NULL,
+    fee REAL NOT NULL DEFAULT 0,
+    timestamp TEXT NOT NULL,
+    lock_height INTEGER NULL,
+    lock_timestamp TEXT NULL,
+    kind TEXT NULL,
+    signature BLOB NOT NULL CHECK (length(signature) <= 64),
+    public_key BLOB NULL,
+    key_scheme TEXT NOT NULL DEFAULT 'ed25519',
+    position INTEGER NULL
+);
+
+INSERT INTO transactions_new (id, version, block_hash, from_address, to_address, amount, fee, timestamp,
+    lock_height, lock_timestamp, kind, signature, public_key, key_scheme, position)
+SELECT id, version, unhex(block_hash), from_address, to_address, amount, fee, timestamp,
+    lock_height, lock_timestamp, kind, signature, public_key, key_scheme, position FROM transactions;
+
+DROP TABLE transactions;
+DROP TABLE blocks;
+ALTER TABLE blocks_new RENAME TO blocks;
+ALTER TABLE transactions_new RENAME TO transactions;
+
+CREATE INDEX transactions_from_address ON transactions (from_address, timestamp, id);
+CREATE INDEX transactions_to_address ON transactions (to_address, timestamp, id);
@@ -0,0 +1,4 @@
+-- Blocks are listed oldest first, a page at a time, when copying or
+-- comparing databases, and each page's transactions are looked up by block
+CREATE INDEX blocks_by_time ON blocks (timestamp, hash);
+CREATE INDEX transactions_by_block ON transactions (block_hash, position);
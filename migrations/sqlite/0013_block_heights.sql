@@ -0,0 +1,22 @@
+-- Blocks are paged by height rather than timestamp, which doesn't order
+-- a parent before a child sharing its timestamp. Stored blocks count up
+-- from their roots: genesis, which names itself as its parent, and any
+-- block whose parent isn't stored.
+
+ALTER TABLE blocks ADD COLUMN height INTEGER NOT NULL DEFAULT 0;
+
+CREATE TEMP TABLE block_heights (hash BLOB PRIMARY KEY, height INTEGER NOT NULL);
+WITH RECURSIVE chain (hash, height) AS (
+    SELECT hash, 0 FROM blocks
+    WHERE previous_hash = hash OR previous_hash NOT IN (SELECT hash FROM blocks)
+    UNION ALL
+    SELECT child.hash, chain.height + 1 FROM blocks AS child
+    JOIN chain ON child.previous_hash = chain.hash AND child.hash <> child.previous_hash
+)
+INSERT INTO block_heights SELECT hash, height FROM chain;
+UPDATE blocks SET height = (SELECT height FROM block_heights WHERE block_heights.hash = blocks.hash)
+WHERE hash IN (SELECT hash FROM block_heights);
+DROP TABLE block_heights;
+
+DROP INDEX blocks_by_time;
+CREATE INDEX blocks_by_height ON blocks (height, hash);
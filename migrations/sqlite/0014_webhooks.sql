@@ -0,0 +1,11 @@
+-- Webhooks registered through the admin API, so deliveries resume after a
+-- restart. Event kinds and watched addresses are JSON arrays; the secret is
+-- sealed with the column keys like wallet keystores.
+
+CREATE TABLE webhooks (
+    id TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    events TEXT NOT NULL,
+    watch_addresses TEXT NOT NULL,
+    secret TEXT NOT NULL
+);
@@ -0,0 +1,28 @@
+-- Order and trade amounts and prices become whole numbers of the smallest
+-- unit, 10^-8, so matching never leaves a fraction of a unit open on an
+-- order. REAL columns would turn the integers back into floats, so new
+-- INTEGER columns replace them.
+
+ALTER TABLE orders ADD COLUMN amount_units INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN price_units INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE orders ADD COLUMN filled_units INTEGER NOT NULL DEFAULT 0;
+UPDATE orders SET
+    amount_units = CAST(ROUND(amount * 100000000) AS INTEGER),
+    price_units = CAST(ROUND(price * 100000000) AS INTEGER),
+    filled_units = CAST(ROUND(filled * 100000000) AS INTEGER);
+ALTER TABLE orders DROP COLUMN amount;
+ALTER TABLE orders DROP COLUMN price;
+ALTER TABLE orders DROP COLUMN filled;
+ALTER TABLE orders RENAME COLUMN amount_units TO amount;
+ALTER TABLE orders RENAME COLUMN price_units TO price;
+ALTER TABLE orders RENAME COLUMN filled_units TO filled;
+
+ALTER TABLE trades ADD COLUMN amount_units INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE trades ADD COLUMN price_units INTEGER NOT NULL DEFAULT 0;
+UPDATE trades SET
+    amount_units = CAST(ROUND(amount * 100000000) AS INTEGER),
+    price_units = CAST(ROUND(price * 100000000) AS INTEGER);
+ALTER TABLE trades DROP COLUMN amount;
+ALTER TABLE trades DROP COLUMN price;
+ALTER TABLE trades RENAME COLUMN amount_units TO amount;
+ALTER TABLE trades RENAME COLUMN price_units TO price;
@@ -0,0 +1,395 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use warp::Filter;
+
+use crate::api::{handle_rejection, respond, validated_json, FieldError, Unauthorized, Validate};
+use crate::blockchain::Blockchain;
+use crate::database::Database;
+use crate::market::{Market, Token};
+use crate::network::Network;
+use crate::security::{self, Security};
+use crate::storage::{self, IntegrityReport};
+use crate::webhook::{WebhookEventKind, WebhookRegistry};
+
+// Shortest admin token accepted, so a placeholder can't slip into production
+pub const MIN_ADMIN_TOKEN_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    // Loopback by default; the admin API should never face the internet
+    pub listen: SocketAddr,
+    // Sent as `Authorization: Bearer <token>`; unrelated to user JWTs
+    pub token: String,
+    pub snapshot_dir: PathBuf,
+    pub backup_dir: PathBuf,
+}
+
+impl AdminConfig {
+    pub fn new(token: String) -> Self {
+        AdminConfig {
+            listen: SocketAddr::from(([127, 0, 0, 1], 8081)),
+            token,
+            snapshot_dir: PathBuf::from("snapshots"),
+            backup_dir: PathBuf::from("backups"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PeerRequest {
+    pub address: String,
+}
+
+impl Validate for PeerRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if self.address.trim().is_empty() {
+            errors.push(FieldError {
+                field: "address".to_string(),
+                message: "is required".to_string(),
+            });
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterWebhookRequest {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<WebhookEventKind>,
+    #[serde(default)]
+    pub watch_addresses: Vec<String>,
+    pub secret: String,
+}
+
+impl Validate for RegisterWebhookRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        if !self.url.starts_with("https://") && !self.url.starts_with("http://") {
+            errors.push(FieldError {
+                field: "url".to_string(),
+                message: "must be an http or https URL".to_string(),
+            });
+        }
+        if self.secret.is_empty() {
+            errors.push(FieldError {
+                field: "secret".to_string(),
+                message: "is required".to_string(),
+            });
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterTokenRequest {
+    pub symbol: String,
+    pub name: String,
+    pub total_supply: f64,
+    // Starting price in coins; trades move it from there
+    pub price: f64,
+}
+
+impl Validate for RegisterTokenRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        let symbol_valid = !self.symbol.is_empty()
+            && self.symbol.len() <= 16
+            && self.symbol.chars().all(|c| c.is_ascii_alphanumeric());
+        if !symbol_valid {
+            errors.push(FieldError {
+                field: "symbol".to_string(),
+                message: "must be 1 to 16 letters or digits".to_string(),
+            });
+        }
+        if self.name.trim().is_empty() {
+            errors.push(FieldError {
+                field: "name".to_string(),
+                message: "is required".to_string(),
+            });
+        }
+        if !(self.total_supply.is_finite() && self.total_supply > 0.0) {
+            errors.push(FieldError {
+                field: "total_supply".to_string(),
+                message: "must be a positive number".to_string(),
+            });
+        }
+        if !(self.price.is_finite() && self.price >= 0.0) {
+            errors.push(FieldError {
+                field: "price".to_string(),
+                message: "must not be negative".to_string(),
+            });
+        }
+        errors
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SnapshotCreated {
+    pub path: String,
+    pub blocks: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupCreated {
+    pub path: String,
+}
+
+// Node operations for the operator, served apart from the public API
+pub struct AdminServer {
+    config: AdminConfig,
+    blockchain: Arc<Blockchain>,
+    market: Arc<Market>,
+    network: Arc<Network>,
+    database: Arc<Database>,
+    security: Arc<Security>,
+    webhooks: Arc<WebhookRegistry>,
+}
+
+impl AdminServer {
+    pub fn new(
+        config: AdminConfig,
+        blockchain: Arc<Blockchain>,
+        market: Arc<Market>,
+        network: Arc<Network>,
+        database: Arc<Database>,
+        security: Arc<Security>,
+        webhooks: Arc<WebhookRegistry>,
+    ) -> Self {
+        AdminServer {
+            config,
+            blockchain,
+            market,
+            network,
+            database,
+            security,
+            webhooks,
+        }
+    }
+
+    pub async fn start(&self) -> Result<(), Box<dyn Error>> {
+        if self.config.token.len() < MIN_ADMIN_TOKEN_LEN {
+            return Err(format!("Admin token must be at least {} characters", MIN_ADMIN_TOKEN_LEN).into());
+        }
+        if !self.config.listen.ip().is_loopback() {
+            eprintln!("Warning: admin API listening on non-loopback address {}", self.config.listen);
+        }
+
+        let network = self.network.clone();
+        let add_peer = warp::post()
+            .and(warp::path!("peers"))
+            .and(validated_json())
+            .and_then(move |req: PeerRequest| {
+                let network = network.clone();
+                async move {
+                    Ok::<_, warp::Rejection>(respond(network.add_peer(req.address).await))
+                }
+            });
+
+        let network = self.network.clone();
+        let database = self.database.clone();
+        let ban_peer = warp::post()
+            .and(warp::path!("peers" / "ban"))
+            .and(validated_json())
+            .and_then(move |req: PeerRequest| {
+                let network = network.clone();
+                let database = database.clone();
+                async move {
+                    // Save right away so the ban holds even if the node
+                    // stops before the next periodic save
+                    let result = async {
+                        network.ban(&req.address).await?;
+                        network.save_bans(database).await
+                    }.await;
+                    Ok::<_, warp::Rejection>(respond(result))
+                }
+            });
+
+        let blockchain = self.blockchain.clone();
+        let database = self.database.clone();
+        let snapshot_dir = self.config.snapshot_dir.clone();
+        let snapshot = warp::post()
+            .and(warp::path!("snapshot"))
+            .and_then(move || {
+                let blockchain = blockchain.clone();
+                let database = database.clone();
+                let snapshot_dir = snapshot_dir.clone();
+                async move {
+                    Ok::<_, warp::Rejection>(respond(take_snapshot(&blockchain, database, snapshot_dir).await))
+                }
+            });
+
+        let database = self.database.clone();
+        let backup_dir = self.config.backup_dir.clone();
+        let backup = warp::post()
+            .and(warp::path!("backup"))
+            .and_then(move || {
+                let database = database.clone();
+                let backup_dir = backup_dir.clone();
+                async move {
+                    Ok::<_, warp::Rejection>(respond(take_backup(database, backup_dir).await))
+                }
+            });
+
+        let database = self.database.clone();
+        let verify = warp::get()
+            .and(warp::path!("verify"))
+            .and_then(move || {
+                let database = database.clone();
+                async move {
+                    Ok::<_, warp::Rejection>(respond(verify_database(database).await))
+                }
+            });
+
+        let security = self.security.clone();
+        let rotate_jwt = warp::post()
+            .and(warp::path!("jwt" / "rotate"))
+            .map(move || {
+                security.rotate_jwt_secret();
+                respond(Ok(()))
+            });
+
+        let network = self.network.clone();
+        let intrusion = warp::get()
+            .and(warp::path!("intrusion"))
+            .map(move || respond(Ok(network.intrusion_records())));
+
+        // Tokens must be registered before orders for them are accepted
+        let market = self.market.clone();
+        let register_token = warp::post()
+            .and(warp::path!("tokens"))
+            .and(validated_json())
+            .and_then(move |req: RegisterTokenRequest| {
+                let market = market.clone();
+                async move {
+                    let token = Token {
+                        symbol: req.symbol,
+                        name: req.name,
+                        total_supply: req.total_supply,
+                        current_price: req.price,
+                        last_updated: chrono::Utc::now(),
+                    };
+                    let added = market.add_token(token.clone()).await.map(|_| token);
+                    Ok::<_, warp::Rejection>(respond(added))
+                }
+            });
+
+        let webhooks = self.webhooks.clone();
+        let register_webhook = warp::post()
+            .and(warp::path!("webhooks"))
+            .and(validated_json())
+            .and_then(move |req: RegisterWebhookRequest| {
+                let webhooks = webhooks.clone();
+                async move {
+                    let hook = webhooks.register(req.url, req.events, req.watch_addresses, req.secret).await;
+                    Ok::<_, warp::Rejection>(respond(hook))
+                }
+            });
+
+        let webhooks = self.webhooks.clone();
+        let list_webhooks = warp::get()
+            .and(warp::path!("webhooks"))
+            .and_then(move || {
+                let webhooks = webhooks.clone();
+                async move {
+                    Ok::<_, warp::Rejection>(respond(Ok(webhooks.list().await)))
+                }
+            });
+
+        let webhooks = self.webhooks.clone();
+        let remove_webhook = warp::delete()
+            .and(warp::path!("webhooks" / String))
+            .and_then(move |id: String| {
+                let webhooks = webhooks.clone();
+                async move {
+                    let removed = match webhooks.remove(&id).await {
+                        Ok(true) => Ok(()),
+                        Ok(false) => Err(format!("No webhook {}", id).into()),
+                        Err(e) => Err(e),
+                    };
+                    Ok::<_, warp::Rejection>(respond(removed))
+                }
+            });
+
+        let routes = warp::path("admin")
+            .and(authorize(self.config.token.clone()))
+            .and(
+                add_peer.or(ban_peer)
+                    .or(snapshot)
+                    .or(backup)
+                    .or(verify)
+                    .or(rotate_jwt)
+                    .or(intrusion)
+                    .or(register_token)
+                    .or(register_webhook)
+                    .or(list_webhooks)
+                    .or(remove_webhook)
+            )
+            .recover(handle_rejection);
+
+        warp::serve(routes).run(self.config.listen).await;
+        Ok(())
+    }
+}
+
+// Reject requests that don't carry the admin token
+fn authorize(token: String) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let authorized = header
+                .as_deref()
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .map_or(false, |presented| security::constant_time_eq(presented.as_bytes(), token.as_bytes()));
+            async move {
+                if authorized {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// Export the chain to a timestamped file in `dir`
+async fn take_snapshot(
+    blockchain: &Blockchain,
+    database: Arc<Database>,
+    dir: PathBuf,
+) -> Result<SnapshotCreated, Box<dyn Error>> {
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("chain-{}.bin", chrono::Utc::now().format("%Y%m%dT%H%M%SZ")));
+    let blocks = blockchain.export(&path).await?;
+    // The export starts at genesis, so its last block is at height blocks - 1
+    let height = blocks.saturating_sub(1);
+    tokio::task::spawn_blocking(move || database.set_last_snapshot_height(height).map_err(|e| e.to_string()))
+        .await??;
+    Ok(SnapshotCreated {
+        path: path.display().to_string(),
+        blocks,
+    })
+}
+
+// Reads the whole chain, so it runs off the async workers like backups
+async fn verify_database(database: Arc<Database>) -> Result<IntegrityReport, Box<dyn Error>> {
+    let report = tokio::task::spawn_blocking(move || storage::verify(&**database).map_err(|e| e.to_string()))
+        .await??;
+    Ok(report)
+}
+
+// Back the database up to a timestamped path in `dir`. Backups block on
+// database I/O, so they run off the async workers.
+async fn take_backup(database: Arc<Database>, dir: PathBuf) -> Result<BackupCreated, Box<dyn Error>> {
+    tokio::fs::create_dir_all(&dir).await?;
+    let path = dir.join(format!("database-{}", chrono::Utc::now().format("%Y%m%dT%H%M%SZ")));
+    let target = path.clone();
+    tokio::task::spawn_blocking(move || database.backup(&target).map_err(|e| e.to_string()))
+        .await??;
+    Ok(BackupCreated {
+        path: path.display().to_string(),
+    })
+}
@@ -1,12 +1,34 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
 use std::error::Error;
+use std::future::Future;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use warp::http::{header, HeaderValue, Method, StatusCode};
+use warp::hyper::body::{Body, HttpBody};
+use warp::hyper::service::Service;
 use warp::{Filter, Reply};
-use serde::{Serialize, Deserialize};
+use serde::{de::DeserializeOwned, Serialize, Deserialize};
 use std::sync::Arc;
-use tokio::sync::broadcast;
-use futures_util::{SinkExt, StreamExt};
+use tokio::sync::{broadcast, watch};
+use futures_util::{FutureExt, SinkExt, Stream, StreamExt};
 use tokio_tungstenite::{WebSocketStream, accept_async};
 use tokio::net::TcpStream;
 
+use crate::blockchain::{ChainEvent, Transaction, MIN_TRANSACTION_FEE};
+use crate::database::Database;
+use crate::governance::{Proposal, Vote, VoteChoice};
+use crate::market::{to_units, DecentralizedExchange, MarketEvent, Order, OrderStatus, OrderType, Trade};
+use crate::network::NetworkMessage;
+use crate::security::Security;
+use crate::signer::KeyScheme;
+use crate::storage::{MemoMatch, PageRequest, ProposalMatch, DEFAULT_SEARCH_LIMIT, MAX_SEARCH_LIMIT};
+use crate::sync::SyncProgress;
+use crate::wallet::{self, validate_address, validate_email, validate_pin, FeePriority};
+
 // API Response types
 #[derive(Debug, Serialize)]
 pub struct ApiResponse<T> {
@@ -15,11 +37,181 @@ pub struct ApiResponse<T> {
     pub error: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// Body of a 400 response to a request that failed validation
+#[derive(Debug, Serialize)]
+pub struct ValidationErrorResponse {
+    pub success: bool,
+    pub error: String,
+    pub fields: Vec<FieldError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WalletCreatedResponse {
+    pub id: String,
+    pub address: String,
+    // Shown once; the node does not keep a readable copy
+    pub mnemonic: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    // Identity the token carries
+    pub address: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RawTransactionSubmitted {
+    pub id: String,
+    pub hash: String,
+    // Always "pending" on acceptance; the transaction is in the mempool
+    pub status: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrderPlaced {
+    pub order: Order,
+    // Trades the order matched straight away
+    pub trades: Vec<Trade>,
+}
+
+// Memos are only searched for an authenticated caller, and only their own
+#[derive(Debug, Serialize)]
+pub struct SearchResponse {
+    pub query: String,
+    pub proposals: Vec<ProposalMatch>,
+    pub memos: Vec<MemoMatch>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VotingPowerResponse {
+    pub address: String,
+    pub voting_power: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransactionSubmitted {
+    pub id: String,
+    pub hash: String,
+    pub fee: f64,
+}
+
+// One page of a list endpoint; `total` counts every match, not just this page
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    pub page: usize,
+    pub limit: usize,
+}
+
+// One page of a cursor-paged list. Pass `next_cursor` back as `cursor` to
+// continue; it is None on the last page.
+#[derive(Debug, Serialize)]
+pub struct CursorPage<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlockSummary {
+    pub height: u64,
+    pub hash: String,
+    pub previous_hash: String,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub transaction_count: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TransactionRecord {
+    // None while the transaction is still in the mempool
+    pub block_height: Option<u64>,
+    #[serde(flatten)]
+    pub transaction: Transaction,
+}
+
+// Everything a dashboard or load balancer needs in one call
+#[derive(Debug, Serialize)]
+pub struct NodeInfo {
+    pub version: String,
+    pub chain_id: String,
+    pub node_id: String,
+    pub best_height: u64,
+    pub best_hash: String,
+    // None when this node isn't running a sync manager
+    pub sync: Option<SyncProgress>,
+    pub peer_count: usize,
+    pub uptime_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BalanceResponse {
+    pub address: String,
+    pub balance: f64,
+    pub tokens: std::collections::HashMap<String, f64>,
+}
+
 // API Request types
+pub const DEFAULT_PAGE_LIMIT: usize = 20;
+pub const MAX_PAGE_LIMIT: usize = 100;
+// Transactions one /transactions request looks at before handing back a
+// cursor, so a filter that rarely matches can't walk the whole chain
+pub const MAX_SCANNED_TRANSACTIONS: usize = 10_000;
+
+// Query parameters shared by every list endpoint. Pages start at 1 and
+// `sort` names a field, prefixed with `-` for descending order.
+#[derive(Debug, Default, Deserialize)]
+pub struct PageParams {
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+    pub sort: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BlockFilter {
+    pub min_height: Option<u64>,
+    pub max_height: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct TransactionFilter {
+    pub address: Option<String>,
+    pub kind: Option<String>,
+    // "pending" or "confirmed"
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct OrderFilter {
+    pub token: Option<String>,
+    pub user: Option<String>,
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ProposalFilter {
+    pub creator: Option<String>,
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchParams {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct CreateWalletRequest {
     pub email: String,
     pub pin: String,
+    #[serde(default)]
+    pub key_scheme: KeyScheme,
 }
 
 #[derive(Debug, Deserialize)]
@@ -27,275 +219,2019 @@ pub struct TransferRequest {
     pub from: String,
     pub to: String,
     pub amount: f64,
+    // Credentials of the wallet that owns `from`, used to sign
+    pub email: String,
+    pub pin: String,
+    // Estimated from recent blocks when omitted
+    pub fee: Option<f64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreateProposalRequest {
+    pub creator: String,
     pub title: String,
     pub description: String,
     pub budget_amount: f64,
 }
 
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+// One API call inside a batch, e.g. {"method": "GET", "path": "/api/node"}
+#[derive(Debug, Deserialize)]
+pub struct SubRequest {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub path: String,
+    pub body: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub requests: Vec<SubRequest>,
+    // Run the sub-requests at the same time instead of in order
+    #[serde(default)]
+    pub concurrent: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubResponse {
+    pub status: u16,
+    pub body: serde_json::Value,
+}
+
+// Exchanged for a JWT identifying the wallet's address
+#[derive(Debug, Deserialize)]
+pub struct TokenRequest {
+    pub email: String,
+    pub pin: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RawEncoding {
+    #[default]
+    Hex,
+    Base64,
+}
+
+// A transaction signed elsewhere, e.g. by the offline signing menu
+#[derive(Debug, Deserialize)]
+pub struct RawTransactionRequest {
+    pub raw: String,
+    #[serde(default)]
+    pub encoding: RawEncoding,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PlaceOrderRequest {
+    pub token_symbol: String,
+    pub order_type: OrderType,
+    // Smallest token units, and smallest coin units per whole token
+    pub amount: u64,
+    pub price: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VoteRequest {
+    pub choice: VoteChoice,
+}
+
+// Field checks a request body must pass before its handler runs
+pub trait Validate {
+    fn validate(&self) -> Vec<FieldError>;
+}
+
+// Record `message` against `field` unless `valid`
+fn check(errors: &mut Vec<FieldError>, valid: bool, field: &str, message: &str) {
+    if !valid {
+        errors.push(FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        });
+    }
+}
+
+impl Validate for CreateWalletRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check(&mut errors, validate_email(&self.email), "email", "must be a valid email address");
+        check(&mut errors, validate_pin(&self.pin), "pin", "must be 7 digits");
+        errors
+    }
+}
+
+impl Validate for TransferRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check(&mut errors, validate_address(&self.from), "from", "invalid address or checksum");
+        check(&mut errors, validate_address(&self.to), "to", "invalid address or checksum");
+        check(&mut errors, self.amount.is_finite() && self.amount > 0.0, "amount", "must be a positive number");
+        check(&mut errors, validate_email(&self.email), "email", "must be a valid email address");
+        check(&mut errors, validate_pin(&self.pin), "pin", "must be 7 digits");
+        if let Some(fee) = self.fee {
+            check(
+                &mut errors,
+                fee.is_finite() && fee >= MIN_TRANSACTION_FEE,
+                "fee",
+                &format!("must be at least {}", MIN_TRANSACTION_FEE),
+            );
+        }
+        errors
+    }
+}
+
+impl Validate for BatchRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check(
+            &mut errors,
+            !self.requests.is_empty() && self.requests.len() <= MAX_BATCH_REQUESTS,
+            "requests",
+            &format!("must contain between 1 and {} requests", MAX_BATCH_REQUESTS),
+        );
+        for (i, sub) in self.requests.iter().enumerate() {
+            // Batches don't nest, and event streams never finish
+            check(
+                &mut errors,
+                sub.path.starts_with("/api/") && !sub.path.starts_with("/api/batch") && !sub.path.starts_with("/api/events"),
+                &format!("requests[{}].path", i),
+                "must be an /api/ path other than /api/batch or /api/events",
+            );
+            check(
+                &mut errors,
+                Method::from_bytes(sub.method.as_bytes()).is_ok(),
+                &format!("requests[{}].method", i),
+                "is not an HTTP method",
+            );
+        }
+        errors
+    }
+}
+
+impl Validate for TokenRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check(&mut errors, validate_email(&self.email), "email", "must be a valid email address");
+        check(&mut errors, validate_pin(&self.pin), "pin", "must be 7 digits");
+        errors
+    }
+}
+
+impl Validate for RawTransactionRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check(&mut errors, !self.raw.trim().is_empty(), "raw", "is required");
+        errors
+    }
+}
+
+impl Validate for PlaceOrderRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check(&mut errors, !self.token_symbol.trim().is_empty(), "token_symbol", "is required");
+        check(&mut errors, self.amount > 0, "amount", "must be a positive number");
+        check(&mut errors, self.price > 0, "price", "must be a positive number");
+        errors
+    }
+}
+
+// The choice is checked by deserialization
+impl Validate for VoteRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        Vec::new()
+    }
+}
+
+impl Validate for CreateProposalRequest {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check(&mut errors, validate_address(&self.creator), "creator", "invalid address or checksum");
+        check(&mut errors, !self.title.trim().is_empty(), "title", "is required");
+        check(
+            &mut errors,
+            self.budget_amount.is_finite() && self.budget_amount >= 0.0,
+            "budget_amount",
+            "must be zero or more",
+        );
+        errors
+    }
+}
+
+#[derive(Debug)]
+struct InvalidRequest(Vec<FieldError>);
+
+// Missing or invalid credentials
+#[derive(Debug)]
+pub(crate) struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+impl warp::reject::Reject for InvalidRequest {}
+
+// Deserialize a JSON body, rejecting it with field-level errors if it
+// doesn't validate
+pub(crate) fn validated_json<T>() -> impl Filter<Extract = (T,), Error = warp::Rejection> + Clone
+where
+    T: Validate + DeserializeOwned + Send,
+{
+    warp::body::json().and_then(|req: T| async move {
+        let errors = req.validate();
+        if errors.is_empty() {
+            Ok(req)
+        } else {
+            Err(warp::reject::custom(InvalidRequest(errors)))
+        }
+    })
+}
+
+// Header identifying the client for rate limiting; requests without it are
+// limited by source IP
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+// How long a completed response is replayed for
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How long shutdown waits for in-flight requests before dropping them
+const API_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub const MAX_BATCH_REQUESTS: usize = 20;
+// Sub-requests still running after this are answered with 504
+const BATCH_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Above this many tracked clients, buckets that have refilled are dropped.
+// If every one is still in use, new clients share a single overflow bucket.
+const MAX_RATE_LIMIT_CLIENTS: usize = 10_000;
+const OVERFLOW_CLIENT: &str = "overflow";
+
+// Token bucket for one class of routes: up to `burst` requests at once,
+// refilled at `per_second`
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiRateLimits {
+    // GET, HEAD and OPTIONS requests
+    pub reads: RateLimit,
+    // Everything else
+    pub writes: RateLimit,
+    // Keys that get a bucket of their own; requests with any other key are
+    // charged to their source IP, so made-up keys can't mint fresh buckets
+    pub api_keys: HashSet<String>,
+}
+
+impl Default for ApiRateLimits {
+    fn default() -> Self {
+        ApiRateLimits {
+            reads: RateLimit { burst: 60.0, per_second: 10.0 },
+            writes: RateLimit { burst: 10.0, per_second: 1.0 },
+            api_keys: HashSet::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// Per-client request buckets, one each for reads and writes
+pub struct ApiRateLimiter {
+    limits: ApiRateLimits,
+    buckets: std::sync::Mutex<HashMap<(String, bool), TokenBucket>>,
+}
+
+impl ApiRateLimiter {
+    pub fn new(limits: ApiRateLimits) -> Self {
+        ApiRateLimiter {
+            limits,
+            buckets: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Whether the key is one of the configured API keys
+    pub fn is_registered(&self, api_key: &str) -> bool {
+        self.limits.api_keys.contains(api_key)
+    }
+
+    // The bucket a request is charged to: its API key when that key is
+    // registered, its source IP otherwise
+    pub fn client(&self, api_key: Option<&str>, remote: Option<SocketAddr>) -> String {
+        match (api_key, remote) {
+            (Some(key), _) if self.is_registered(key) => format!("key:{}", key),
+            (_, Some(remote)) => format!("ip:{}", remote.ip()),
+            (_, None) => "unknown".to_string(),
+        }
+    }
+
+    // Take a token from the client's bucket; on failure returns how long
+    // until the next one is available
+    pub fn check(&self, client: &str, write: bool) -> Result<(), Duration> {
+        let limit = if write { self.limits.writes } else { self.limits.reads };
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut client = client;
+        if buckets.len() >= MAX_RATE_LIMIT_CLIENTS && !buckets.contains_key(&(client.to_string(), write)) {
+            buckets.retain(|(_, write), bucket| {
+                let limit = if *write { self.limits.writes } else { self.limits.reads };
+                bucket.tokens + bucket.last_refill.elapsed().as_secs_f64() * limit.per_second < limit.burst
+            });
+            if buckets.len() >= MAX_RATE_LIMIT_CLIENTS {
+                client = OVERFLOW_CLIENT;
+            }
+        }
+
+        let bucket = buckets.entry((client.to_string(), write)).or_insert(TokenBucket {
+            tokens: limit.burst,
+            last_refill: Instant::now(),
+        });
+        let elapsed = bucket.last_refill.elapsed().as_secs_f64();
+        bucket.last_refill = Instant::now();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(limit.burst);
+
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.per_second));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum IdempotencyState {
+    InProgress,
+    Completed(serde_json::Value),
+}
+
+// Responses to write requests, keyed by (Idempotency-Key, principal). Only
+// successful responses are kept, so a failed request can be retried with
+// the same key.
+pub struct IdempotencyCache {
+    entries: std::sync::Mutex<HashMap<(String, String), (Instant, IdempotencyState)>>,
+}
+
+impl IdempotencyCache {
+    pub fn new() -> Self {
+        IdempotencyCache {
+            entries: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The existing state for `key`, or None after claiming it for this request
+    fn begin(&self, key: &(String, String)) -> Option<IdempotencyState> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (created, _)| created.elapsed() < IDEMPOTENCY_TTL);
+        match entries.get(key) {
+            Some((_, state)) => Some(state.clone()),
+            None => {
+                entries.insert(key.clone(), (Instant::now(), IdempotencyState::InProgress));
+                None
+            }
+        }
+    }
+
+    fn complete(&self, key: &(String, String), response: serde_json::Value) {
+        self.entries.lock().unwrap().insert(key.clone(), (Instant::now(), IdempotencyState::Completed(response)));
+    }
+
+    fn release(&self, key: &(String, String)) {
+        self.entries.lock().unwrap().remove(key);
+    }
+}
+
+// Releases a claimed key if its request never completes, e.g. because the
+// client disconnected and the handler was dropped
+struct IdempotencyClaim<'a> {
+    cache: &'a IdempotencyCache,
+    key: (String, String),
+    completed: bool,
+}
+
+impl Drop for IdempotencyClaim<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.cache.release(&self.key);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimited {
+    retry_after: Duration,
+}
+
+impl warp::reject::Reject for RateLimited {}
+
+// Which browser origins may call the API. Origins are full scheme://host[:port]
+// strings, e.g. "https://wallet.example.com".
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    // Accept any origin; for local development only
+    pub permissive: bool,
+}
+
+impl CorsConfig {
+    pub fn permissive() -> Self {
+        CorsConfig {
+            permissive: true,
+            ..CorsConfig::default()
+        }
+    }
+
+    fn build(&self) -> warp::cors::Builder {
+        let cors = warp::cors()
+            .allow_methods(self.allowed_methods.iter().map(String::as_str))
+            .allow_headers(self.allowed_headers.iter().map(String::as_str))
+            .allow_credentials(self.allow_credentials);
+        if self.permissive {
+            cors.allow_any_origin()
+        } else {
+            cors.allow_origins(self.allowed_origins.iter().map(String::as_str))
+        }
+    }
+}
+
+// No cross-origin access until origins are configured
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: vec![],
+            allowed_methods: ["GET", "POST", "PUT", "DELETE", "OPTIONS"].iter().map(|m| m.to_string()).collect(),
+            allowed_headers: ["content-type", "authorization", API_KEY_HEADER, IDEMPOTENCY_KEY_HEADER].iter().map(|h| h.to_string()).collect(),
+            allow_credentials: false,
+            permissive: false,
+        }
+    }
+}
+
+// Response compression, negotiated from Accept-Encoding. Streaming
+// responses (SSE, WebSocket upgrades) are never buffered to compress them.
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // Bodies smaller than this are sent as they are
+    pub min_size: usize,
+    pub gzip: bool,
+    pub brotli: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            min_size: 1024,
+            gzip: true,
+            brotli: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentEncoding {
+    fn name(&self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+}
+
+impl CompressionConfig {
+    // The enabled encoding the client ranks highest, brotli winning ties
+    fn negotiate(&self, accept_encoding: &str) -> Option<ContentEncoding> {
+        let mut best: Option<(ContentEncoding, f32)> = None;
+        for part in accept_encoding.split(',') {
+            let mut params = part.split(';');
+            let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let encoding = match coding.as_str() {
+                "br" if self.brotli => ContentEncoding::Brotli,
+                "gzip" if self.gzip => ContentEncoding::Gzip,
+                _ => continue,
+            };
+            if quality <= 0.0 {
+                continue;
+            }
+            match best {
+                Some((current, q)) if q > quality || (q == quality && current == ContentEncoding::Brotli) => {}
+                _ => best = Some((encoding, quality)),
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    async fn compress(&self, accept_encoding: Option<String>, reply: impl Reply) -> warp::reply::Response {
+        let response = reply.into_response();
+        let encoding = match accept_encoding.as_deref().and_then(|accept| self.negotiate(accept)) {
+            Some(encoding) if self.enabled => encoding,
+            _ => return response,
+        };
+        // Only bodies already held in memory have an exact size
+        let size = HttpBody::size_hint(response.body()).exact();
+        if response.headers().contains_key(header::CONTENT_ENCODING)
+            || !matches!(size, Some(size) if size as usize >= self.min_size)
+        {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let bytes = match warp::hyper::body::to_bytes(body).await {
+            Ok(bytes) => bytes,
+            Err(_) => return warp::reply::Response::from_parts(parts, Body::empty()),
+        };
+        let compressed = match encode(encoding, &bytes) {
+            Ok(compressed) if compressed.len() < bytes.len() => compressed,
+            _ => return warp::reply::Response::from_parts(parts, Body::from(bytes)),
+        };
+        parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+        parts.headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        parts.headers.remove(header::CONTENT_LENGTH);
+        warp::reply::Response::from_parts(parts, Body::from(compressed))
+    }
+}
+
+fn encode(encoding: ContentEncoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        ContentEncoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        ContentEncoding::Brotli => {
+            let mut output = Vec::new();
+            {
+                // Quality 5 keeps compression fast enough to do per request
+                let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                encoder.write_all(data)?;
+            }
+            Ok(output)
+        }
+    }
+}
+
+// PEM certificate chain and private key for serving the API over HTTPS.
+// Certificates from an ACME client such as certbot can be used directly;
+// restart the server after they are renewed.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    // warp panics on unreadable key material, so check it up front
+    fn check(&self) -> Result<(), Box<dyn Error>> {
+        for (label, path) in [("certificate", &self.cert_path), ("private key", &self.key_path)] {
+            let pem = std::fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read TLS {} {}: {}", label, path.display(), e))?;
+            if !pem.contains("-----BEGIN") {
+                return Err(format!("TLS {} {} is not PEM encoded", label, path.display()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+// Everything request handlers share. Every field is cheap to clone, so each
+// route gets its own copy through `with_state`.
+#[derive(Clone)]
+pub struct AppState {
+    pub blockchain: Arc<crate::blockchain::Blockchain>,
+    pub market: Arc<crate::market::Market>,
+    pub exchange: Arc<DecentralizedExchange>,
+    pub governance: Arc<crate::governance::Governance>,
+    pub network: Arc<crate::network::Network>,
+    pub database: Arc<Database>,
+    pub security: Arc<Security>,
+    pub rate_limiter: Arc<ApiRateLimiter>,
+    pub idempotency: Arc<IdempotencyCache>,
+    pub sync_progress: Option<watch::Receiver<SyncProgress>>,
+    pub started_at: Instant,
+    pub notification_tx: broadcast::Sender<serde_json::Value>,
+    // Set by `ApiServer::shutdown`, cleared again when the server is restarted
+    pub shutdown: Arc<watch::Sender<bool>>,
+}
+
+// Hand a copy of the shared state to a route's handler
+fn with_state(state: AppState) -> impl Filter<Extract = (AppState,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
 pub struct ApiServer {
-    blockchain: Arc<crate::blockchain::Blockchain>,
-    wallet: Arc<crate::wallet::Wallet>,
-    market: Arc<crate::market::Market>,
-    governance: Arc<crate::governance::Governance>,
-    notification_tx: broadcast::Sender<serde_json::Value>,
+    state: AppState,
+    cors: CorsConfig,
+    compression: CompressionConfig,
+    tls: Option<TlsConfig>,
 }
 
 impl ApiServer {
     pub fn new(
         blockchain: Arc<crate::blockchain::Blockchain>,
-        wallet: Arc<crate::wallet::Wallet>,
         market: Arc<crate::market::Market>,
         governance: Arc<crate::governance::Governance>,
+        network: Arc<crate::network::Network>,
+        database: Arc<Database>,
+        security: Arc<Security>,
     ) -> Self {
         let (notification_tx, _) = broadcast::channel(100);
         ApiServer {
-            blockchain,
-            wallet,
-            market,
-            governance,
-            notification_tx,
+            state: AppState {
+                blockchain,
+                exchange: Arc::new(DecentralizedExchange::new(market.clone())),
+                market,
+                governance,
+                network,
+                database,
+                security,
+                rate_limiter: Arc::new(ApiRateLimiter::new(ApiRateLimits::default())),
+                idempotency: Arc::new(IdempotencyCache::new()),
+                sync_progress: None,
+                started_at: Instant::now(),
+                notification_tx,
+                shutdown: Arc::new(watch::channel(false).0),
+            },
+            cors: CorsConfig::default(),
+            compression: CompressionConfig::default(),
+            tls: None,
         }
     }
 
+    pub fn with_rate_limits(mut self, limits: ApiRateLimits) -> Self {
+        self.state.rate_limiter = Arc::new(ApiRateLimiter::new(limits));
+        self
+    }
+
+    pub fn with_cors(mut self, cors: CorsConfig) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    pub fn with_compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    // Report this sync manager's progress from /api/node
+    pub fn with_sync_progress(mut self, progress: watch::Receiver<SyncProgress>) -> Self {
+        self.state.sync_progress = Some(progress);
+        self
+    }
+
+    pub fn state(&self) -> &AppState {
+        &self.state
+    }
+
+    // Serve the API until `shutdown` is called. Once this returns the server
+    // can be reconfigured with the `with_*` methods and started again.
     pub async fn start(&self, port: u16) -> Result<(), Box<dyn Error>> {
-        // REST API routes
+        let state = &self.state;
+        state.shutdown.send_replace(false);
+
+        // REST API routes, served under /api
+        let routes = wallet_routes(state)
+            .or(transaction_routes(state))
+            .or(market_routes(state))
+            .or(governance_routes(state))
+            .or(chain_routes(state))
+            .or(metrics_routes(state))
+            .or(events_route(state))
+            .or(node_route(state))
+            .or(search_route(state));
         let api = warp::path("api")
-            .and(
-                // Wallet routes
-                self.wallet_routes()
-                    .or(self.transaction_routes())
-                    .or(self.market_routes())
-                    .or(self.governance_routes())
-            );
+            .and(rate_limit(state.rate_limiter.clone()))
+            .and(routes.clone());
+
+        // Batches are dispatched through the same routes and charge each
+        // sub-request to the caller themselves
+        let batch = batch_route(
+            state.rate_limiter.clone(),
+            warp::path("api").and(routes).recover(handle_rejection),
+        );
+
+        // Prometheus scrape route
+        let prometheus = prometheus_route(state);
+
+        forward_network_events(state);
+        forward_market_ticker(state);
 
         // WebSocket route
         let ws = warp::path("ws")
             .and(warp::ws())
-            .and_then(self.handle_websocket);
+            .and(with_state(state.clone()))
+            .map(|ws: warp::ws::Ws, state: AppState| {
+                ws.on_upgrade(move |socket| handle_websocket_connection(state, socket))
+            });
 
         // Combine routes
-        let routes = api.or(ws).with(warp::cors().allow_any_origin());
+        let routes = batch.or(api).or(prometheus).or(ws)
+            .recover(handle_rejection);
+
+        let compression = self.compression.clone();
+        let routes = warp::header::optional::<String>("accept-encoding")
+            .and(routes)
+            .and_then(move |accept_encoding: Option<String>, reply| {
+                let compression = compression.clone();
+                async move { Ok::<_, Infallible>(compression.compress(accept_encoding, reply).await) }
+            })
+            .with(self.cors.build());
 
         // Start server
-        warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+        let server = warp::serve(routes);
+        let stopped = shutdown_signal(&state.shutdown);
+        let (addr, serving) = match &self.tls {
+            Some(tls) => {
+                tls.check()?;
+                let (addr, serving) = server.tls()
+                    .cert_path(&tls.cert_path)
+                    .key_path(&tls.key_path)
+                    .try_bind_with_graceful_shutdown(([0, 0, 0, 0], port), stopped)?;
+                (addr, serving.boxed())
+            }
+            None => {
+                let (addr, serving) = server.try_bind_with_graceful_shutdown(([0, 0, 0, 0], port), stopped)?;
+                (addr, serving.boxed())
+            }
+        };
+        println!("API listening on {}", addr);
 
+        // After the signal, hyper stops accepting and waits for in-flight
+        // requests; event streams and WebSockets end on the same signal
+        let serving = tokio::spawn(serving);
+        shutdown_signal(&state.shutdown).await;
+        if tokio::time::timeout(API_SHUTDOWN_TIMEOUT, serving).await.is_err() {
+            eprintln!("API requests still running after {:?} were dropped", API_SHUTDOWN_TIMEOUT);
+        }
+        println!("API server stopped");
         Ok(())
     }
 
-    fn wallet_routes(&self) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
-        let blockchain = self.blockchain.clone();
-        let wallet = self.wallet.clone();
-
-        // Create wallet
-        let create_wallet = warp::post()
-            .and(warp::path("wallet"))
-            .and(warp::body::json())
-            .and_then(move |req: CreateWalletRequest| {
-                let blockchain = blockchain.clone();
-                let wallet = wallet.clone();
-                async move {
-                    // TODO: Implement wallet creation
-                    Ok(warp::reply::json(&ApiResponse {
-                        success: true,
-                        data: Some("Wallet created successfully"),
-                        error: None,
-                    }))
-                }
-            });
+    // Stop accepting requests, close event streams and WebSocket clients, and
+    // let `start` return once in-flight requests have finished
+    pub fn shutdown(&self) {
+        self.state.shutdown.send_replace(true);
+    }
+}
 
-        // Get wallet balance
-        let get_balance = warp::get()
-            .and(warp::path("wallet"))
-            .and(warp::path("balance"))
-            .and_then(move || {
-                let wallet = wallet.clone();
-                async move {
-                    // TODO: Implement balance retrieval
-                    Ok(warp::reply::json(&ApiResponse {
-                        success: true,
-                        data: Some(0.0),
-                        error: None,
-                    }))
+// Pass peer and sync events on to WebSocket clients. Per-message events
+// are too frequent to be useful as notifications.
+fn forward_network_events(state: &AppState) {
+    let mut events = state.network.subscribe_events();
+    let notification_tx = state.notification_tx.clone();
+    let stopped = shutdown_signal(&state.shutdown);
+    tokio::spawn(async move {
+        tokio::pin!(stopped);
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = &mut stopped => break,
+            };
+            match event {
+                Ok(crate::network::NetworkEvent::MessageReceived { .. }) => {}
+                Ok(event) => {
+                    if let Ok(value) = serde_json::to_value(&event) {
+                        let _ = notification_tx.send(value);
+                    }
                 }
-            });
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
 
-        create_wallet.or(get_balance)
-    }
-
-    fn transaction_routes(&self) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
-        let blockchain = self.blockchain.clone();
-
-        // Create transaction
-        let create_transaction = warp::post()
-            .and(warp::path("transaction"))
-            .and(warp::body::json())
-            .and_then(move |req: TransferRequest| {
-                let blockchain = blockchain.clone();
-                async move {
-                    // TODO: Implement transaction creation
-                    Ok(warp::reply::json(&ApiResponse {
-                        success: true,
-                        data: Some("Transaction created successfully"),
-                        error: None,
-                    }))
+// Send price changes to WebSocket clients as "ticker" notifications
+fn forward_market_ticker(state: &AppState) {
+    let mut events = state.market.subscribe();
+    let notification_tx = state.notification_tx.clone();
+    let stopped = shutdown_signal(&state.shutdown);
+    tokio::spawn(async move {
+        tokio::pin!(stopped);
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => event,
+                _ = &mut stopped => break,
+            };
+            match event {
+                Ok(MarketEvent::PriceUpdated(token)) => {
+                    let _ = notification_tx.send(serde_json::json!({
+                        "type": "ticker",
+                        "symbol": token.symbol,
+                        "price": token.current_price,
+                        "last_updated": token.last_updated,
+                    }));
                 }
-            });
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    });
+}
 
-        create_transaction
-    }
-
-    fn market_routes(&self) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
-        let market = self.market.clone();
-
-        // Get token price
-        let get_price = warp::get()
-            .and(warp::path("market"))
-            .and(warp::path("price"))
-            .and(warp::path::param::<String>())
-            .and_then(move |symbol: String| {
-                let market = market.clone();
-                async move {
-                    // TODO: Implement price retrieval
-                    Ok(warp::reply::json(&ApiResponse {
-                        success: true,
-                        data: Some(1.5), // Default price
-                        error: None,
-                    }))
-                }
-            });
+fn wallet_routes(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    // Create wallet
+    let create_wallet = warp::post()
+        .and(warp::path("wallet"))
+        .and(validated_json())
+        .and(with_state(state.clone()))
+        .and_then(|req: CreateWalletRequest, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(create_wallet(state.database, req).await))
+        });
 
-        get_price
-    }
-
-    fn governance_routes(&self) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
-        let governance = self.governance.clone();
-
-        // Create proposal
-        let create_proposal = warp::post()
-            .and(warp::path("governance"))
-            .and(warp::path("proposal"))
-            .and(warp::body::json())
-            .and_then(move |req: CreateProposalRequest| {
-                let governance = governance.clone();
-                async move {
-                    // TODO: Implement proposal creation
-                    Ok(warp::reply::json(&ApiResponse {
-                        success: true,
-                        data: Some("Proposal created successfully"),
-                        error: None,
-                    }))
-                }
-            });
+    // Get address balance including token holdings
+    let get_balance = warp::get()
+        .and(warp::path("wallet"))
+        .and(warp::path("balance"))
+        .and(warp::path::param::<String>())
+        .and(with_state(state.clone()))
+        .and_then(|address: String, state: AppState| async move {
+            Ok::<_, warp::Rejection>(warp::reply::json(&ApiResponse {
+                success: true,
+                data: Some(BalanceResponse {
+                    balance: state.blockchain.get_balance(&address).await,
+                    tokens: state.blockchain.token_balances(&address).await,
+                    address,
+                }),
+                error: None,
+            }))
+        });
 
-        create_proposal
-    }
+    // Exchange wallet credentials for a JWT
+    let issue_token = warp::post()
+        .and(warp::path!("auth" / "token"))
+        .and(validated_json())
+        .and(with_state(state.clone()))
+        .and_then(|req: TokenRequest, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(issue_token(state.database, &state.security, req).await))
+        });
 
-    async fn handle_websocket(
-        &self,
-        ws: warp::ws::Ws,
-    ) -> Result<impl warp::Reply, warp::Rejection> {
-        Ok(ws.on_upgrade(|socket| self.handle_websocket_connection(socket)))
-    }
+    create_wallet.or(get_balance).or(issue_token)
+}
 
-    async fn handle_websocket_connection(&self, ws: warp::ws::WebSocket) {
-        let (ws_sender, mut ws_receiver) = ws.split();
-        let mut notification_rx = self.notification_tx.subscribe();
+fn transaction_routes(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    // Create transaction; the sending address is the idempotency principal
+    let create_transaction = warp::post()
+        .and(warp::path("transaction"))
+        .and(idempotency_key())
+        .and(validated_json())
+        .and(with_state(state.clone()))
+        .and_then(|key: Option<String>, req: TransferRequest, state: AppState| async move {
+            let principal = req.from.clone();
+            let transfer = submit_transfer(state.blockchain, state.network, state.database, req);
+            Ok::<_, warp::Rejection>(idempotent(&state.idempotency, key, principal, transfer).await)
+        });
 
-        // Handle incoming WebSocket messages
-        tokio::task::spawn(async move {
-            while let Some(result) = ws_receiver.next().await {
-                match result {
-                    Ok(msg) => {
-                        // TODO: Handle incoming WebSocket messages
-                    }
-                    Err(e) => {
-                        eprintln!("WebSocket error: {}", e);
-                        break;
-                    }
+    // List pending and confirmed transactions, newest first, a cursor page at a time
+    let list_transactions = warp::get()
+        .and(warp::path!("transactions"))
+        .and(warp::query::<PageRequest>())
+        .and(warp::query::<TransactionFilter>())
+        .and(with_state(state.clone()))
+        .and_then(|page: PageRequest, filter: TransactionFilter, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(list_transactions(&state.blockchain, &page, &filter).await))
+        });
+
+    // Submit a pre-signed transaction
+    let submit_raw = warp::post()
+        .and(warp::path!("tx" / "raw"))
+        .and(validated_json())
+        .and(with_state(state.clone()))
+        .and_then(|req: RawTransactionRequest, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(submit_raw_transaction(&state.blockchain, &state.network, req).await))
+        });
+
+    // Confirmed history of one address, newest first, a cursor page at a time
+    let address_history = warp::get()
+        .and(warp::path!("address" / String / "transactions"))
+        .and(warp::query::<PageRequest>())
+        .and(with_state(state.clone()))
+        .and_then(|address: String, page: PageRequest, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(state.database.get_transactions_for_address(&address, &page)))
+        });
+
+    create_transaction.or(list_transactions).or(submit_raw).or(address_history)
+}
+
+fn market_routes(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    // Get token price
+    let get_price = warp::get()
+        .and(warp::path("market"))
+        .and(warp::path("price"))
+        .and(warp::path::param::<String>())
+        .and(with_state(state.clone()))
+        .and_then(|symbol: String, state: AppState| async move {
+            let price = state.market.get_token(&symbol).await
+                .map(|token| token.current_price)
+                .ok_or_else(|| format!("Unknown token {}", symbol).into());
+            Ok::<_, warp::Rejection>(respond(price))
+        });
+
+    // List orders
+    let list_orders = warp::get()
+        .and(warp::path!("market" / "orders"))
+        .and(warp::query::<PageParams>())
+        .and(warp::query::<OrderFilter>())
+        .and(with_state(state.clone()))
+        .and_then(|params: PageParams, filter: OrderFilter, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(list_orders(&state.market, &params, &filter).await))
+        });
+
+    // Place an order as the caller and match it
+    let place_order = warp::post()
+        .and(warp::path!("market" / "orders"))
+        .and(authenticated(state.security.clone()))
+        .and(idempotency_key())
+        .and(validated_json())
+        .and(with_state(state.clone()))
+        .and_then(|identity: String, key: Option<String>, req: PlaceOrderRequest, state: AppState| async move {
+            let order = place_order(&state.blockchain, &state.market, &state.exchange, identity.clone(), req);
+            Ok::<_, warp::Rejection>(idempotent(&state.idempotency, key, identity, order).await)
+        });
+
+    // Cancel one of the caller's open orders
+    let cancel_order = warp::delete()
+        .and(warp::path!("market" / "orders" / String))
+        .and(authenticated(state.security.clone()))
+        .and(with_state(state.clone()))
+        .and_then(|id: String, identity: String, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(state.market.cancel_order(&id, &identity).await))
+        });
+
+    // The caller's open orders
+    let open_orders = warp::get()
+        .and(warp::path!("market" / "orders" / "open"))
+        .and(authenticated(state.security.clone()))
+        .and(with_state(state.clone()))
+        .and_then(|identity: String, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(Ok(state.market.open_orders(&identity).await)))
+        });
+
+    // The caller's trade history
+    let trades = warp::get()
+        .and(warp::path!("market" / "trades"))
+        .and(authenticated(state.security.clone()))
+        .and(with_state(state.clone()))
+        .and_then(|identity: String, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(state.market.trades_for(&identity).await))
+        });
+
+    // A token's trades, newest first, a cursor page at a time
+    let market_trades = warp::get()
+        .and(warp::path!("market" / "trades" / String))
+        .and(warp::query::<PageRequest>())
+        .and(with_state(state.clone()))
+        .and_then(|symbol: String, page: PageRequest, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(state.market.trade_history(&symbol, &page).await))
+        });
+
+    // Aggregated order book for a token
+    let order_book = warp::get()
+        .and(warp::path!("market" / "orderbook" / String))
+        .and(with_state(state.clone()))
+        .and_then(|symbol: String, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(Ok(state.market.order_book(&symbol).await)))
+        });
+
+    get_price
+        .or(list_orders)
+        .or(place_order)
+        .or(cancel_order)
+        .or(open_orders)
+        .or(trades)
+        .or(market_trades)
+        .or(order_book)
+}
+
+fn governance_routes(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    // Create proposal. Nothing funds the treasury yet, so the requested
+    // budget isn't checked against it.
+    let create_proposal = warp::post()
+        .and(warp::path!("governance" / "proposal"))
+        .and(authenticated(state.security.clone()))
+        .and(idempotency_key())
+        .and(validated_json())
+        .and(with_state(state.clone()))
+        .and_then(|identity: String, key: Option<String>, req: CreateProposalRequest, state: AppState| async move {
+            let proposal = create_proposal(&state.governance, &identity, req);
+            Ok::<_, warp::Rejection>(idempotent(&state.idempotency, key, identity.clone(), proposal).await)
+        });
+
+    // Get a single proposal
+    let get_proposal = warp::get()
+        .and(warp::path!("governance" / "proposal" / String))
+        .and(with_state(state.clone()))
+        .and_then(|id: String, state: AppState| async move {
+            let proposal = state.governance.get_proposal(&id).await
+                .ok_or_else(|| format!("Unknown proposal {}", id).into());
+            Ok::<_, warp::Rejection>(respond(proposal))
+        });
+
+    // Vote with the caller's voting power
+    let vote = warp::post()
+        .and(warp::path!("governance" / "proposal" / String / "vote"))
+        .and(authenticated(state.security.clone()))
+        .and(validated_json())
+        .and(with_state(state.clone()))
+        .and_then(|id: String, identity: String, req: VoteRequest, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(cast_vote(&state.blockchain, &state.governance, id, identity, req).await))
+        });
+
+    // Community budget status
+    let get_budget = warp::get()
+        .and(warp::path!("governance" / "budget"))
+        .and(with_state(state.clone()))
+        .and_then(|state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(Ok(state.governance.budget().await)))
+        });
+
+    // Voting power of an address
+    let get_voting_power = warp::get()
+        .and(warp::path!("governance" / "voting-power" / String))
+        .and(with_state(state.clone()))
+        .and_then(|address: String, state: AppState| async move {
+            let voting_power = voting_power(&state.blockchain, &address).await;
+            Ok::<_, warp::Rejection>(respond(Ok(VotingPowerResponse { address, voting_power })))
+        });
+
+    // List proposals
+    let list_proposals = warp::get()
+        .and(warp::path!("governance" / "proposals"))
+        .and(warp::query::<PageParams>())
+        .and(warp::query::<ProposalFilter>())
+        .and(with_state(state.clone()))
+        .and_then(|params: PageParams, filter: ProposalFilter, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(list_proposals(&state.governance, &params, &filter).await))
+        });
+
+    create_proposal
+        .or(list_proposals)
+        .or(get_proposal)
+        .or(vote)
+        .or(get_budget)
+        .or(get_voting_power)
+}
+
+fn chain_routes(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    // Get vested/unvested split for an address
+    let get_vesting = warp::get()
+        .and(warp::path("vesting"))
+        .and(warp::path::param::<String>())
+        .and(with_state(state.clone()))
+        .and_then(|address: String, state: AppState| async move {
+            Ok::<_, warp::Rejection>(warp::reply::json(&ApiResponse {
+                success: true,
+                data: Some(state.blockchain.vesting_status(&address).await),
+                error: None,
+            }))
+        });
+
+    // Get supply statistics
+    let get_supply = warp::get()
+        .and(warp::path("supply"))
+        .and(with_state(state.clone()))
+        .and_then(|state: AppState| async move {
+            Ok::<_, warp::Rejection>(warp::reply::json(&ApiResponse {
+                success: true,
+                data: Some(state.blockchain.supply_stats().await),
+                error: None,
+            }))
+        });
+
+    // List blocks
+    let list_blocks = warp::get()
+        .and(warp::path!("blocks"))
+        .and(warp::query::<PageParams>())
+        .and(warp::query::<BlockFilter>())
+        .and(with_state(state.clone()))
+        .and_then(|params: PageParams, filter: BlockFilter, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(list_blocks(&state.blockchain, &params, &filter).await))
+        });
+
+    get_vesting.or(get_supply).or(list_blocks)
+}
+
+fn metrics_routes(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    // Get per-peer traffic metrics
+    let get_network = warp::get()
+        .and(warp::path!("metrics" / "network"))
+        .and(with_state(state.clone()))
+        .and_then(|state: AppState| async move {
+            Ok::<_, warp::Rejection>(warp::reply::json(&ApiResponse {
+                success: true,
+                data: Some(state.network.metrics().await),
+                error: None,
+            }))
+        });
+
+    // Get chain metrics
+    let get_chain = warp::get()
+        .and(warp::path("metrics"))
+        .and(with_state(state.clone()))
+        .and_then(|state: AppState| async move {
+            Ok::<_, warp::Rejection>(warp::reply::json(&ApiResponse {
+                success: true,
+                data: Some(state.blockchain.get_metrics().await),
+                error: None,
+            }))
+        });
+
+    get_network.or(get_chain)
+}
+
+// Chain, market and network events as server-sent events, for clients
+// that can't hold a WebSocket open
+fn events_route(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("events"))
+        .and(with_state(state.clone()))
+        .map(|state: AppState| {
+            // Ended on shutdown so graceful shutdown isn't held up by open streams
+            let events = futures_util::stream::select_all(vec![
+                sse_stream(state.blockchain.subscribe(), chain_sse_event).boxed(),
+                sse_stream(state.market.subscribe(), market_sse_event).boxed(),
+                sse_stream(state.notification_tx.subscribe(), notification_sse_event).boxed(),
+            ])
+            .take_until(shutdown_signal(&state.shutdown));
+            warp::sse::reply(warp::sse::keep_alive().stream(events))
+        })
+}
+
+// Version, chain tip, sync state, peers and uptime
+fn node_route(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("node"))
+        .and(with_state(state.clone()))
+        .and_then(|state: AppState| async move {
+            let sync = state.sync_progress.as_ref().map(|progress| progress.borrow().clone());
+            let tip = state.blockchain.latest_block().await;
+            let info = NodeInfo {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                chain_id: state.network.chain_id().to_string(),
+                node_id: state.network.node_id(),
+                best_height: state.blockchain.height().await,
+                best_hash: tip.hash,
+                sync,
+                peer_count: state.network.peer_count().await,
+                uptime_secs: state.started_at.elapsed().as_secs(),
+            };
+            Ok::<_, warp::Rejection>(respond(Ok(info)))
+        })
+}
+
+// Full-text search over proposals and the caller's transaction memos
+fn search_route(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("search"))
+        .and(warp::query::<SearchParams>())
+        .and(optional_identity(state.security.clone()))
+        .and(with_state(state.clone()))
+        .and_then(|params: SearchParams, identity: Option<String>, state: AppState| async move {
+            Ok::<_, warp::Rejection>(respond(search(state.database, identity, params).await))
+        })
+}
+
+fn prometheus_route(state: &AppState) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    warp::get()
+        .and(warp::path("metrics"))
+        .and(with_state(state.clone()))
+        .and_then(|state: AppState| async move {
+            let mut body = state.blockchain.get_metrics().await.to_prometheus();
+            body.push_str(&state.network.metrics().await.to_prometheus());
+            Ok::<_, warp::Rejection>(warp::reply::with_header(
+                body,
+                "content-type",
+                "text/plain; version=0.0.4",
+            ))
+        })
+}
+
+async fn handle_websocket_connection(state: AppState, ws: warp::ws::WebSocket) {
+    let (mut ws_sender, mut ws_receiver) = ws.split();
+    let mut notification_rx = state.notification_tx.subscribe();
+    let stopped = shutdown_signal(&state.shutdown);
+
+    // Handle incoming WebSocket messages
+    tokio::task::spawn(async move {
+        while let Some(result) = ws_receiver.next().await {
+            match result {
+                Ok(msg) => {
+                    // TODO: Handle incoming WebSocket messages
+                }
+                Err(e) => {
+                    eprintln!("WebSocket error: {}", e);
+                    break;
                 }
             }
-        });
+        }
+    });
 
-        // Send notifications to WebSocket client
-        tokio::task::spawn(async move {
-            while let Ok(notification) = notification_rx.recv().await {
-                if let Err(e) = ws_sender.send(notification.into()).await {
-                    eprintln!("Error sending notification: {}", e);
+    // Send notifications to WebSocket client
+    tokio::task::spawn(async move {
+        tokio::pin!(stopped);
+        loop {
+            let notification = tokio::select! {
+                notification = notification_rx.recv() => notification,
+                _ = &mut stopped => {
+                    // Close frame first so the client knows to reconnect
+                    let _ = ws_sender.send(warp::ws::Message::close_with(1001u16, "server shutting down")).await;
+                    let _ = ws_sender.close().await;
                     break;
                 }
+            };
+            let notification = match notification {
+                Ok(notification) => notification,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            if let Err(e) = ws_sender.send(warp::ws::Message::text(notification.to_string())).await {
+                eprintln!("Error sending notification: {}", e);
+                break;
             }
-        });
-    }
+        }
+    });
 }
 
-// Client-side library for Python
-#[cfg(feature = "python")]
-pub mod python {
-    use pyo3::prelude::*;
+// POST /api/batch: run several API calls in one round trip. Sub-requests
+// carry the caller's credentials, so each is authenticated as if sent on its
+// own, and each is charged to the caller's rate limit bucket.
+fn batch_route<F>(limiter: Arc<ApiRateLimiter>, api: F) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply + Send,
+{
+    warp::post()
+        .and(warp::path!("api" / "batch"))
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>(API_KEY_HEADER))
+        .and(validated_json())
+        .and_then(move |remote: Option<SocketAddr>, authorization: Option<String>, api_key: Option<String>, req: BatchRequest| {
+            let (api, limiter) = (api.clone(), limiter.clone());
+            async move {
+                let client = limiter.client(api_key.as_deref(), remote);
+                let forwarded: Vec<(&str, String)> = [("authorization", authorization), (API_KEY_HEADER, api_key)]
+                    .into_iter()
+                    .filter_map(|(name, value)| value.map(|value| (name, value)))
+                    .collect();
+                let calls = req.requests.iter().map(|sub| run_sub_request(&api, &limiter, &client, sub, &forwarded));
+                let responses = if req.concurrent {
+                    futures_util::future::join_all(calls).await
+                } else {
+                    let mut responses = Vec::new();
+                    for call in calls {
+                        responses.push(call.await);
+                    }
+                    responses
+                };
+                Ok::<_, warp::Rejection>(respond(Ok(responses)))
+            }
+        })
+}
 
-    #[pyclass]
-    pub struct BlockchainClient {
-        api_url: String,
+// Sub-requests go through the route tree as a service, which has no socket
+// address to rate limit by, so the caller's bucket is charged here instead
+async fn run_sub_request<F>(
+    api: &F,
+    limiter: &ApiRateLimiter,
+    client: &str,
+    sub: &SubRequest,
+    forwarded: &[(&str, String)],
+) -> SubResponse
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply + Send,
+{
+    // The batch was validated, so the method parses
+    let method = Method::from_bytes(sub.method.as_bytes()).unwrap_or(Method::GET);
+    if let Err(retry_after) = limiter.check(client, is_write(&method)) {
+        return sub_response(rate_limited(retry_after)).await;
     }
 
-    #[pymethods]
-    impl BlockchainClient {
-        #[new]
-        pub fn new(api_url: String) -> Self {
-            BlockchainClient { api_url }
+    let mut request = warp::http::Request::builder().method(method).uri(&sub.path);
+    for (name, value) in forwarded {
+        request = request.header(*name, value);
+    }
+    let body = match &sub.body {
+        Some(body) => {
+            request = request.header(header::CONTENT_TYPE, "application/json");
+            Body::from(body.to_string())
+        }
+        None => Body::empty(),
+    };
+    let request = match request.body(body) {
+        Ok(request) => request,
+        Err(e) => {
+            return SubResponse {
+                status: StatusCode::BAD_REQUEST.as_u16(),
+                body: serde_json::Value::String(e.to_string()),
+            }
         }
+    };
+
+    let mut service = warp::service(api.clone());
+    let call = async move {
+        let response = service.call(request).await.unwrap_or_else(|never| match never {});
+        sub_response(response).await
+    };
+    match tokio::time::timeout(BATCH_REQUEST_TIMEOUT, call).await {
+        Ok(response) => response,
+        Err(_) => SubResponse {
+            status: StatusCode::GATEWAY_TIMEOUT.as_u16(),
+            body: serde_json::Value::String("Request timed out".to_string()),
+        },
+    }
+}
+
+// A sub-request's reply, with its body as JSON when it parses
+async fn sub_response(response: warp::reply::Response) -> SubResponse {
+    let status = response.status().as_u16();
+    let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap_or_default();
+    SubResponse {
+        status,
+        body: serde_json::from_slice(&body)
+            .unwrap_or_else(|_| serde_json::Value::String(String::from_utf8_lossy(&body).into_owned())),
+    }
+}
+
+// Reads are limited separately from everything else
+fn is_write(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// Charge each API request to its client's read or write bucket, keyed by
+// API key when a registered one is sent and by source IP otherwise
+fn rate_limit(limiter: Arc<ApiRateLimiter>) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::method()
+        .and(warp::header::optional::<String>(API_KEY_HEADER))
+        .and(warp::addr::remote())
+        .and_then(move |method: Method, api_key: Option<String>, remote: Option<SocketAddr>| {
+            let limiter = limiter.clone();
+            async move {
+                let client = limiter.client(api_key.as_deref(), remote);
+                limiter.check(&client, is_write(&method))
+                    .map_err(|retry_after| warp::reject::custom(RateLimited { retry_after }))
+            }
+        })
+        .untuple_one()
+}
 
-        pub fn create_wallet(&self, email: String, pin: String) -> PyResult<String> {
-            // TODO: Implement Python client methods
-            Ok("Wallet created".to_string())
+// Turn validation, authentication and rate limit rejections into 400, 401
+// and 429 responses; anything else falls through to warp's default handling
+pub(crate) async fn handle_rejection(rejection: warp::Rejection) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Some(InvalidRequest(errors)) = rejection.find::<InvalidRequest>() {
+        return Ok(validation_failed(errors.clone()));
+    }
+    if rejection.find::<Unauthorized>().is_some() {
+        let body = warp::reply::json(&ApiResponse::<()> {
+            success: false,
+            data: None,
+            error: Some("Unauthorized".to_string()),
+        });
+        return Ok(warp::reply::with_status(body, StatusCode::UNAUTHORIZED).into_response());
+    }
+    if let Some(e) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        return Ok(validation_failed(vec![FieldError {
+            field: "body".to_string(),
+            message: e.to_string(),
+        }]));
+    }
+
+    match rejection.find::<RateLimited>() {
+        Some(limited) => Ok(rate_limited(limited.retry_after)),
+        None => Err(rejection),
+    }
+}
+
+fn rate_limited(retry_after: Duration) -> warp::reply::Response {
+    let body = warp::reply::json(&ApiResponse::<()> {
+        success: false,
+        data: None,
+        error: Some("Rate limit exceeded".to_string()),
+    });
+    let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    warp::reply::with_header(
+        warp::reply::with_status(body, StatusCode::TOO_MANY_REQUESTS),
+        "retry-after",
+        retry_after.to_string(),
+    ).into_response()
+}
+
+fn validation_failed(fields: Vec<FieldError>) -> warp::reply::Response {
+    let body = warp::reply::json(&ValidationErrorResponse {
+        success: false,
+        error: "Invalid request".to_string(),
+        fields,
+    });
+    warp::reply::with_status(body, StatusCode::BAD_REQUEST).into_response()
+}
+
+// Resolves once the server is told to shut down
+fn shutdown_signal(shutdown: &watch::Sender<bool>) -> impl Future<Output = ()> + Send + 'static {
+    let mut shutdown = shutdown.subscribe();
+    async move {
+        while !*shutdown.borrow_and_update() {
+            if shutdown.changed().await.is_err() {
+                break;
+            }
         }
+    }
+}
 
-        pub fn get_balance(&self) -> PyResult<f64> {
-            // TODO: Implement Python client methods
-            Ok(0.0)
+// Turn a broadcast subscription into an SSE stream. Events missed because
+// the client fell behind are skipped.
+fn sse_stream<T: Clone + Send + 'static>(
+    events: broadcast::Receiver<T>,
+    to_sse: fn(T) -> Option<warp::sse::Event>,
+) -> impl Stream<Item = Result<warp::sse::Event, Infallible>> + Send {
+    futures_util::stream::unfold(events, move |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => match to_sse(event) {
+                    Some(sse) => return Some((Ok(sse), events)),
+                    None => continue,
+                },
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
         }
+    })
+}
+
+fn sse_event(name: &str, data: &impl Serialize) -> warp::sse::Event {
+    warp::sse::Event::default()
+        .event(name)
+        .data(serde_json::to_string(data).unwrap_or_default())
+}
+
+fn chain_sse_event(event: ChainEvent) -> Option<warp::sse::Event> {
+    Some(match event {
+        ChainEvent::TransactionAccepted(transaction) => sse_event("transaction_accepted", &transaction),
+        ChainEvent::BlockAdded(block) => sse_event("block_added", &block),
+    })
+}
+
+// Price updates reach SSE clients as "ticker" notifications instead
+fn market_sse_event(event: MarketEvent) -> Option<warp::sse::Event> {
+    Some(match event {
+        MarketEvent::OrderPlaced(order) => sse_event("order_placed", &order),
+        MarketEvent::OrderFilled(order) => sse_event("order_filled", &order),
+        MarketEvent::OrderCancelled(order) => sse_event("order_cancelled", &order),
+        MarketEvent::TradeExecuted(trade) => sse_event("trade", &trade),
+        MarketEvent::PriceUpdated(_) => return None,
+    })
+}
+
+// Notifications already carry their name in "type"
+fn notification_sse_event(notification: serde_json::Value) -> Option<warp::sse::Event> {
+    let name = notification["type"].as_str().unwrap_or("network").to_string();
+    Some(sse_event(&name, &notification))
+}
+
+// Address of the caller, taken from a bearer JWT issued by /api/auth/token
+fn authenticated(security: Arc<Security>) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let identity = header
+                .as_deref()
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .and_then(|token| security.verify_token(token).ok())
+                .map(|claims| claims.sub);
+            async move { identity.ok_or_else(|| warp::reject::custom(Unauthorized)) }
+        })
+}
+
+// Like `authenticated`, but requests without a valid token still pass
+fn optional_identity(security: Arc<Security>) -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .map(move |header: Option<String>| {
+            header
+                .as_deref()
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .and_then(|token| security.verify_token(token).ok())
+                .map(|claims| claims.sub)
+        })
+}
+
+async fn search(database: Arc<Database>, identity: Option<String>, params: SearchParams) -> Result<SearchResponse, Box<dyn Error>> {
+    let query = params.q.trim().to_string();
+    if query.is_empty() {
+        return Err("Search query is empty".into());
+    }
+    let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).clamp(1, MAX_SEARCH_LIMIT);
 
-        pub fn transfer(&self, to: String, amount: f64) -> PyResult<String> {
-            // TODO: Implement Python client methods
-            Ok("Transfer successful".to_string())
+    let (proposals, memos) = tokio::task::spawn_blocking({
+        let query = query.clone();
+        move || -> Result<_, String> {
+            let proposals = database.search_proposals(&query, limit).map_err(|e| e.to_string())?;
+            let memos = match identity {
+                Some(address) => database.search_memos(&address, &query, limit).map_err(|e| e.to_string())?,
+                None => vec![],
+            };
+            Ok((proposals, memos))
         }
+    }).await??;
+
+    Ok(SearchResponse { query, proposals, memos })
+}
+
+async fn issue_token(database: Arc<Database>, security: &Security, req: TokenRequest) -> Result<TokenResponse, Box<dyn Error>> {
+    let wallet = tokio::task::spawn_blocking(move || {
+        wallet::access_wallet(&database, req.email, req.pin, None).map_err(|e| e.to_string())
+    }).await??;
+    wallet.lock();
+
+    Ok(TokenResponse {
+        token: security.generate_token(&wallet.address)?,
+        address: wallet.address,
+    })
+}
+
+async fn place_order(
+    blockchain: &crate::blockchain::Blockchain,
+    market: &crate::market::Market,
+    exchange: &DecentralizedExchange,
+    identity: String,
+    req: PlaceOrderRequest,
+) -> Result<OrderPlaced, Box<dyn Error>> {
+    if market.get_token(&req.token_symbol).await.is_none() {
+        return Err(format!("Unknown token {}", req.token_symbol).into());
     }
+    // Buys spend coins, sells the token itself
+    let balance = match req.order_type {
+        OrderType::Buy => blockchain.available_balance(&identity).await,
+        OrderType::Sell => blockchain.token_balances(&identity).await.get(&req.token_symbol).copied().unwrap_or(0.0),
+    };
+
+    let order = Order {
+        id: uuid::Uuid::new_v4().to_string(),
+        user_id: identity,
+        token_symbol: req.token_symbol,
+        order_type: req.order_type,
+        amount: req.amount,
+        price: req.price,
+        timestamp: chrono::Utc::now(),
+        status: OrderStatus::Pending,
+        filled: 0,
+    };
+    let id = order.id.clone();
+    let trades = exchange.add_to_order_book(order, to_units(balance)).await?;
+    let order = market.get_order(&id).await.ok_or("Order disappeared")?;
+    Ok(OrderPlaced { order, trades })
 }
 
-// Client-side library for JavaScript
-#[cfg(feature = "javascript")]
-pub mod javascript {
-    use wasm_bindgen::prelude::*;
+// Vote as `identity`, weighted by its current voting power
+// One vote per whole coin the address holds on chain right now
+async fn voting_power(blockchain: &crate::blockchain::Blockchain, address: &str) -> u64 {
+    blockchain.get_balance(address).await.max(0.0).floor() as u64
+}
 
-    #[wasm_bindgen]
-    pub struct BlockchainClient {
-        api_url: String,
+async fn cast_vote(
+    blockchain: &crate::blockchain::Blockchain,
+    governance: &crate::governance::Governance,
+    proposal_id: String,
+    identity: String,
+    req: VoteRequest,
+) -> Result<Proposal, Box<dyn Error>> {
+    let weight = voting_power(blockchain, &identity).await;
+    if weight == 0 {
+        return Err("No voting power".into());
     }
 
-    #[wasm_bindgen]
-    impl BlockchainClient {
-        #[wasm_bindgen(constructor)]
-        pub fn new(api_url: String) -> Self {
-            BlockchainClient { api_url }
+    governance.cast_vote(Vote {
+        voter: identity,
+        proposal_id: proposal_id.clone(),
+        choice: req.choice,
+        timestamp: chrono::Utc::now(),
+        weight,
+    }).await?;
+    governance.get_proposal(&proposal_id).await.ok_or_else(|| "Proposal disappeared".into())
+}
+
+// Wrap a handler result in the standard response envelope
+pub(crate) fn respond<T: Serialize>(result: Result<T, Box<dyn Error>>) -> warp::reply::Json {
+    warp::reply::json(&envelope(result))
+}
+
+fn envelope<T: Serialize>(result: Result<T, Box<dyn Error>>) -> ApiResponse<T> {
+    match result {
+        Ok(data) => ApiResponse {
+            success: true,
+            data: Some(data),
+            error: None,
+        },
+        Err(e) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+// Optional Idempotency-Key header of a write request
+fn idempotency_key() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>(IDEMPOTENCY_KEY_HEADER).and_then(|key: Option<String>| async move {
+        match key {
+            Some(key) if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN => {
+                Err(warp::reject::custom(InvalidRequest(vec![FieldError {
+                    field: IDEMPOTENCY_KEY_HEADER.to_string(),
+                    message: format!("must be 1 to {} characters", MAX_IDEMPOTENCY_KEY_LEN),
+                }])))
+            }
+            key => Ok(key),
         }
+    })
+}
 
-        pub async fn create_wallet(&self, email: String, pin: String) -> Result<String, JsValue> {
-            // TODO: Implement JavaScript client methods
-            Ok("Wallet created".to_string())
+// Run a write handler at most once per idempotency key and principal.
+// Retries of a completed request get the stored response back; retries
+// while it is still running get 409.
+async fn idempotent<T: Serialize>(
+    cache: &IdempotencyCache,
+    key: Option<String>,
+    principal: String,
+    handler: impl Future<Output = Result<T, Box<dyn Error>>>,
+) -> warp::reply::Response {
+    let key = match key {
+        Some(key) => (key, principal),
+        None => return respond(handler.await).into_response(),
+    };
+    match cache.begin(&key) {
+        Some(IdempotencyState::Completed(response)) => {
+            return warp::reply::with_header(warp::reply::json(&response), "idempotent-replayed", "true").into_response();
+        }
+        Some(IdempotencyState::InProgress) => {
+            let body = warp::reply::json(&ApiResponse::<()> {
+                success: false,
+                data: None,
+                error: Some("A request with this idempotency key is still in progress".to_string()),
+            });
+            return warp::reply::with_status(body, StatusCode::CONFLICT).into_response();
         }
+        None => {}
+    }
 
-        pub async fn get_balance(&self) -> Result<f64, JsValue> {
-            // TODO: Implement JavaScript client methods
-            Ok(0.0)
+    let mut claim = IdempotencyClaim { cache, key, completed: false };
+    let response = envelope(handler.await);
+    if response.success {
+        if let Ok(value) = serde_json::to_value(&response) {
+            cache.complete(&claim.key, value);
+            claim.completed = true;
         }
+    }
+    warp::reply::json(&response).into_response()
+}
+
+async fn create_wallet(database: Arc<Database>, req: CreateWalletRequest) -> Result<WalletCreatedResponse, Box<dyn Error>> {
+    // The database client blocks, so keep it off the async workers
+    let wallet = tokio::task::spawn_blocking(move || {
+        let create = || -> Result<wallet::Wallet, Box<dyn Error>> {
+            if database.get_wallet(&req.email)?.is_some() {
+                return Err("A wallet already exists for this email".into());
+            }
+            let wallet = wallet::create_wallet(req.email, req.pin, req.key_scheme)?;
+            database.save_wallet(&wallet)?;
+            Ok(wallet)
+        };
+        create().map_err(|e| e.to_string())
+    }).await??;
+
+    Ok(WalletCreatedResponse {
+        mnemonic: wallet.mnemonic()?,
+        id: wallet.id,
+        address: wallet.address,
+    })
+}
+
+// Unlock the sender's wallet, sign the transfer, add it to the mempool and
+// announce it to peers
+async fn submit_transfer(
+    blockchain: Arc<crate::blockchain::Blockchain>,
+    network: Arc<crate::network::Network>,
+    database: Arc<Database>,
+    req: TransferRequest,
+) -> Result<TransactionSubmitted, Box<dyn Error>> {
+    let (email, pin) = (req.email.clone(), req.pin.clone());
+    let wallet = tokio::task::spawn_blocking(move || {
+        wallet::access_wallet(&database, email, pin, None).map_err(|e| e.to_string())
+    }).await??;
+    if !wallet.all_addresses().contains(&req.from) {
+        return Err("Sender address does not belong to this wallet".into());
+    }
+
+    let fee = match req.fee {
+        Some(fee) => fee,
+        None => wallet.estimate_fee(&blockchain, req.amount, FeePriority::Medium).await.fee,
+    };
+    let mut transaction = wallet.build_transfer_from(&req.from, &req.to, req.amount, fee, None)?;
+    wallet.sign_transaction(&mut transaction).await?;
+    wallet.lock();
+
+    let submitted = TransactionSubmitted {
+        id: transaction.id.clone(),
+        hash: transaction.hash(),
+        fee,
+    };
+    blockchain.add_transaction(transaction.clone()).await?;
+    network.broadcast_message(NetworkMessage::NewTransaction(transaction)).await?;
+    Ok(submitted)
+}
+
+// Decode, check and queue a transaction signed elsewhere, then announce it
+async fn submit_raw_transaction(
+    blockchain: &crate::blockchain::Blockchain,
+    network: &crate::network::Network,
+    req: RawTransactionRequest,
+) -> Result<RawTransactionSubmitted, Box<dyn Error>> {
+    let bytes = match req.encoding {
+        RawEncoding::Hex => hex::decode(req.raw.trim())?,
+        RawEncoding::Base64 => base64::Engine::decode(&base64::engine::general_purpose::STANDARD, req.raw.trim())?,
+    };
+    let transaction = Transaction::decode(&bytes)?;
+    if !transaction.verify_signature() {
+        return Err("Transaction signature is invalid".into());
+    }
+
+    let submitted = RawTransactionSubmitted {
+        id: transaction.id.clone(),
+        hash: transaction.hash(),
+        status: "pending".to_string(),
+    };
+    blockchain.add_transaction(transaction.clone()).await?;
+    network.broadcast_message(NetworkMessage::NewTransaction(transaction)).await?;
+    Ok(submitted)
+}
+
+async fn create_proposal(
+    governance: &crate::governance::Governance,
+    identity: &str,
+    req: CreateProposalRequest,
+) -> Result<Proposal, Box<dyn Error>> {
+    if req.creator != identity {
+        return Err("Proposals can only be created from your own address".into());
+    }
+    let proposal = Proposal::new(req.title, req.description, req.creator, req.budget_amount);
+    governance.create_proposal(proposal.clone()).await?;
+    Ok(proposal)
+}
+
+// Sort `items` by the requested field and cut out the requested page.
+// `order` maps a sortable field name to its comparison.
+fn paginate<T>(
+    mut items: Vec<T>,
+    params: &PageParams,
+    default_sort: &str,
+    order: fn(&str) -> Option<fn(&T, &T) -> Ordering>,
+) -> Result<Page<T>, Box<dyn Error>> {
+    let (page, limit) = page_bounds(params)?;
+    let sort = params.sort.as_deref().unwrap_or(default_sort);
+    let (field, descending) = match sort.strip_prefix('-') {
+        Some(field) => (field, true),
+        None => (sort, false),
+    };
+    let compare = order(field).ok_or_else(|| format!("Cannot sort by '{}'", field))?;
+    items.sort_by(|a, b| if descending { compare(b, a) } else { compare(a, b) });
+
+    let total = items.len();
+    let items = items.into_iter()
+        .skip((page - 1).saturating_mul(limit))
+        .take(limit)
+        .collect();
+    Ok(Page { items, total, page, limit })
+}
+
+// The requested page number and page size, checked against the limits
+fn page_bounds(params: &PageParams) -> Result<(usize, usize), Box<dyn Error>> {
+    let page = params.page.unwrap_or(1);
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+    if page == 0 {
+        return Err("Pages start at 1".into());
+    }
+    if limit == 0 || limit > MAX_PAGE_LIMIT {
+        return Err(format!("Limit must be between 1 and {}", MAX_PAGE_LIMIT).into());
+    }
+    Ok((page, limit))
+}
+
+// Case-insensitive match of an enum value against a filter like "pending"
+fn status_matches(status: &impl std::fmt::Debug, filter: &Option<String>) -> bool {
+    filter.as_ref().map_or(true, |wanted| format!("{:?}", status).eq_ignore_ascii_case(wanted))
+}
 
-        pub async fn transfer(&self, to: String, amount: f64) -> Result<String, JsValue> {
-            // TODO: Implement JavaScript client methods
-            Ok("Transfer successful".to_string())
+// Blocks are stored by height, so a page is cut straight out of the chain
+// and only its own blocks are read. They sort by height alone.
+async fn list_blocks(
+    blockchain: &crate::blockchain::Blockchain,
+    params: &PageParams,
+    filter: &BlockFilter,
+) -> Result<Page<BlockSummary>, Box<dyn Error>> {
+    let (page, limit) = page_bounds(params)?;
+    let descending = match params.sort.as_deref().unwrap_or("-height") {
+        "height" => false,
+        "-height" => true,
+        sort => return Err(format!("Cannot sort by '{}'", sort.trim_start_matches('-')).into()),
+    };
+
+    let state = blockchain.read().await;
+    let low = filter.min_height.unwrap_or(0);
+    let high = match (state.blocks.len() as u64).checked_sub(1) {
+        Some(tip) => filter.max_height.map_or(tip, |max| max.min(tip)),
+        None => return Ok(Page { items: vec![], total: 0, page, limit }),
+    };
+    let heights = low..=high;
+    let total = if low > high { 0 } else { (high - low + 1) as usize };
+    let skip = (page - 1).saturating_mul(limit);
+    let heights: Vec<u64> = if descending {
+        heights.rev().skip(skip).take(limit).collect()
+    } else {
+        heights.skip(skip).take(limit).collect()
+    };
+
+    let items = heights.into_iter()
+        .map(|height| {
+            let block = &state.blocks[height as usize];
+            BlockSummary {
+                height,
+                hash: block.hash.clone(),
+                previous_hash: block.previous_hash.clone(),
+                timestamp: block.timestamp,
+                transaction_count: block.transactions.len(),
+            }
+        })
+        .collect();
+    Ok(Page { items, total, page, limit })
+}
+
+// Pending transactions, newest first, then confirmed ones from the tip down.
+// A cursor is "height.index": the page resumes just before transaction
+// `index` of the block at `height`, where a height one past the tip means
+// the mempool. Mempool positions shift as transactions are mined, so a
+// cursor into it is only a best effort.
+async fn list_transactions(
+    blockchain: &crate::blockchain::Blockchain,
+    page: &PageRequest,
+    filter: &TransactionFilter,
+) -> Result<CursorPage<TransactionRecord>, Box<dyn Error>> {
+    let (confirmed, pending) = match filter.status.as_deref() {
+        None => (true, true),
+        Some(status) if status.eq_ignore_ascii_case("confirmed") => (true, false),
+        Some(status) if status.eq_ignore_ascii_case("pending") => (false, true),
+        Some(status) => return Err(format!("Unknown transaction status '{}'", status).into()),
+    };
+    let matches = |tx: &Transaction| {
+        filter.address.as_ref().map_or(true, |address| tx.involves(address))
+            && filter.kind.as_ref().map_or(true, |kind| tx.kind.name().eq_ignore_ascii_case(kind))
+    };
+    let limit = page.limit().min(MAX_PAGE_LIMIT);
+
+    let state = blockchain.read().await;
+    let tip = state.blocks.len();
+    let (height, index) = match page.cursor.as_deref() {
+        Some(cursor) => cursor.split_once('.')
+            .and_then(|(height, index)| Some((height.parse::<usize>().ok()?, index.parse::<usize>().ok()?)))
+            .ok_or("Malformed cursor")?,
+        None => (tip, usize::MAX),
+    };
+    let mut height = height.min(tip);
+    let mut index = if height == tip {
+        if pending { index.min(state.pending_transactions.len()) } else { 0 }
+    } else if confirmed {
+        index.min(state.blocks[height].transactions.len())
+    } else {
+        0
+    };
+
+    let mut items = Vec::new();
+    let mut scanned = 0;
+    while items.len() < limit && scanned < MAX_SCANNED_TRANSACTIONS {
+        if index == 0 {
+            if height == 0 || !confirmed {
+                return Ok(CursorPage { items, next_cursor: None });
+            }
+            height -= 1;
+            index = state.blocks[height].transactions.len();
+        } else {
+            index -= 1;
+            let (transaction, block_height) = if height == tip {
+                (&state.pending_transactions[index], None)
+            } else {
+                (&state.blocks[height].transactions[index], Some(height as u64))
+            };
+            if matches(transaction) {
+                items.push(TransactionRecord { block_height, transaction: transaction.clone() });
+            }
         }
+        scanned += 1;
+    }
+    Ok(CursorPage { items, next_cursor: Some(format!("{}.{}", height, index)) })
+}
+
+async fn list_orders(
+    market: &crate::market::Market,
+    params: &PageParams,
+    filter: &OrderFilter,
+) -> Result<Page<Order>, Box<dyn Error>> {
+    let orders: Vec<Order> = market.list_orders().await.into_iter()
+        .filter(|order| filter.token.as_ref().map_or(true, |token| order.token_symbol.eq_ignore_ascii_case(token)))
+        .filter(|order| filter.user.as_ref().map_or(true, |user| &order.user_id == user))
+        .filter(|order| status_matches(&order.status, &filter.status))
+        .collect();
+
+    paginate(orders, params, "-timestamp", |field| Some(match field {
+        "timestamp" => |a, b| a.timestamp.cmp(&b.timestamp),
+        "price" => |a, b| a.price.cmp(&b.price),
+        "amount" => |a, b| a.amount.cmp(&b.amount),
+        _ => return None,
+    }))
+}
+
+async fn list_proposals(
+    governance: &crate::governance::Governance,
+    params: &PageParams,
+    filter: &ProposalFilter,
+) -> Result<Page<Proposal>, Box<dyn Error>> {
+    let proposals: Vec<Proposal> = governance.list_proposals().await.into_iter()
+        .filter(|proposal| filter.creator.as_ref().map_or(true, |creator| &proposal.creator == creator))
+        .filter(|proposal| status_matches(&proposal.status, &filter.status))
+        .collect();
+
+    paginate(proposals, params, "-created_at", |field| Some(match field {
+        "created_at" => |a, b| a.created_at.cmp(&b.created_at),
+        "voting_end" => |a, b| a.voting_end.cmp(&b.voting_end),
+        "budget_amount" => |a, b| a.budget_amount.total_cmp(&b.budget_amount),
+        "votes" => |a, b| a.votes.len().cmp(&b.votes.len()),
+        _ => return None,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::funded_chain;
+    use crate::database::tests::{test_databases, TestDatabase};
+    use crate::signer::Signer;
+
+    // Server state around `blockchain` and `governance`. The database it
+    // returns must outlive the state.
+    fn test_state(
+        blockchain: crate::blockchain::Blockchain,
+        governance: Arc<crate::governance::Governance>,
+    ) -> (AppState, TestDatabase) {
+        let database = test_databases().remove(0);
+        let server = ApiServer::new(
+            Arc::new(blockchain),
+            Arc::new(crate::market::Market::new()),
+            governance,
+            Arc::new(crate::network::Network::new(crate::blockchain::Blockchain::new())),
+            database.shared(),
+            Arc::new(Security::new(vec![7; 32]).unwrap()),
+        );
+        (server.state, database)
     }
-} 
\ No newline at end of file
+
+    #[tokio::test]
+    async fn vote_is_weighted_by_the_voters_balance() {
+        let (blockchain, signer) = funded_chain(250.5);
+        let voter = signer.address();
+        let governance = Arc::new(crate::governance::Governance::new());
+        let proposal = Proposal::new("Title".to_string(), "Description".to_string(), voter.clone(), 0.0);
+        governance.create_proposal(proposal.clone()).await.unwrap();
+        let (state, _database) = test_state(blockchain, governance.clone());
+
+        let token = state.security.generate_token(&voter).unwrap();
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/governance/proposal/{}/vote", proposal.id))
+            .header("authorization", format!("Bearer {}", token))
+            .json(&serde_json::json!({ "choice": "Yes" }))
+            .reply(&governance_routes(&state))
+            .await;
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["success"], true, "{}", body);
+
+        let proposal = governance.get_proposal(&proposal.id).await.unwrap();
+        assert_eq!(proposal.votes[&voter].weight, 250);
+    }
+}
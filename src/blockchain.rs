@@ -2,11 +2,30 @@ use std::error::Error;
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 use sha2::{Sha256, Digest};
-use tokio::sync::mpsc;
-use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc};
+use crate::multisig::MultisigWitness;
+use crate::signer::{self, KeyScheme, Signer};
+use crate::wallet::validate_address;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use std::sync::Arc;
+use tokio::sync::{RwLock, RwLockReadGuard};
+
+// Current wire format versions. Payloads from before versioning carry no
+// version field and decode as version 0.
+pub const BLOCK_VERSION: u32 = 1;
+pub const TRANSACTION_VERSION: u32 = 6;
+
+fn legacy_version() -> u32 {
+    0
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
     pub hash: String,
     pub previous_hash: String,
     pub timestamp: DateTime<Utc>,
@@ -17,23 +36,676 @@ pub struct Block {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
+    #[serde(default = "legacy_version")]
+    pub version: u32,
     pub id: String,
     pub from: String,
     pub to: String,
     pub amount: f64,
+    pub fee: f64,
     pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub lock_time: Option<LockTime>,
+    #[serde(default)]
+    pub kind: TransactionKind,
     pub signature: Vec<u8>,
+    #[serde(default)]
+    pub public_key: Vec<u8>,
+    // Witness data like the signature, so not part of the canonical bytes
+    #[serde(default)]
+    pub key_scheme: KeyScheme,
+    #[serde(default)]
+    pub multisig: Option<MultisigWitness>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TransactionKind {
+    Transfer,
+    // Locks `amount` for the recipient, released according to the schedule
+    Vesting(VestingSchedule),
+    // Destroys `amount`; the recipient must be `BURN_ADDRESS`
+    Burn,
+    // Creates `amount` units of a token for the recipient. The first issuer
+    // of a symbol becomes its only permitted issuer.
+    TokenIssue { symbol: String },
+    // Moves `amount` units of a token; the fee is still paid natively
+    TokenTransfer { symbol: String },
+    // Pays every output atomically; `amount` is their total and the
+    // recipient must be `BATCH_ADDRESS`
+    Batch { outputs: Vec<Payout> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Payout {
+    pub address: String,
+    pub amount: f64,
+}
+
+// Unspendable address that burn transactions are sent to
+pub const BURN_ADDRESS: &str = "burn";
+
+// Placeholder recipient of batch transactions, which pay their outputs instead
+pub const BATCH_ADDRESS: &str = "batch";
+pub const MAX_BATCH_OUTPUTS: usize = 1000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SupplyStats {
+    pub total_issued: f64,
+    pub burned: f64,
+    pub locked_in_vesting: f64,
+    // Fees are destroyed rather than paid to anyone, since blocks have no
+    // producer to credit, so they leave circulation like burns do
+    pub fees_destroyed: f64,
+    pub circulating: f64,
+}
+
+impl Default for TransactionKind {
+    fn default() -> Self {
+        TransactionKind::Transfer
+    }
+}
+
+impl TransactionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionKind::Transfer => "transfer",
+            TransactionKind::Vesting(_) => "vesting",
+            TransactionKind::Burn => "burn",
+            TransactionKind::TokenIssue { .. } => "token_issue",
+            TransactionKind::TokenTransfer { .. } => "token_transfer",
+            TransactionKind::Batch { .. } => "batch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VestingSchedule {
+    pub start: DateTime<Utc>,
+    pub cliff: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub release: VestingRelease,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum VestingRelease {
+    // Vests pro rata between start and end, nothing before the cliff
+    Linear,
+    // Vests in full at the cliff
+    Cliff,
+}
+
+impl VestingSchedule {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.cliff < self.start || self.end < self.cliff {
+            return Err("Vesting schedule must satisfy start <= cliff <= end".into());
+        }
+        if matches!(self.release, VestingRelease::Linear) && self.end == self.start {
+            return Err("Linear vesting requires end after start".into());
+        }
+        Ok(())
+    }
+
+    pub fn vested_amount(&self, total: f64, time: DateTime<Utc>) -> f64 {
+        if time < self.cliff {
+            return 0.0;
+        }
+        match self.release {
+            VestingRelease::Cliff => total,
+            VestingRelease::Linear if time >= self.end => total,
+            VestingRelease::Linear => {
+                let elapsed = (time - self.start).num_seconds() as f64;
+                let duration = (self.end - self.start).num_seconds() as f64;
+                total * elapsed / duration
+            }
+        }
+    }
+}
+
+// An allocation locked by a vesting transaction, tracked in chain state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VestingGrant {
+    pub transaction_id: String,
+    pub grantor: String,
+    pub total: f64,
+    pub released: f64,
+    pub schedule: VestingSchedule,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VestingStatus {
+    pub vested: f64,
+    pub unvested: f64,
+    pub released: f64,
+    pub grants: Vec<VestingGrant>,
+}
+
+// Earliest point at which a transaction may be included in a block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LockTime {
+    Height(u64),
+    Timestamp(DateTime<Utc>),
+}
+
+impl LockTime {
+    pub fn is_final(&self, height: u64, time: DateTime<Utc>) -> bool {
+        match self {
+            LockTime::Height(lock_height) => height >= *lock_height,
+            LockTime::Timestamp(lock_time) => time >= *lock_time,
+        }
+    }
+}
+
+// The fields of a block that are committed to by its hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub previous_hash: String,
+    pub timestamp: DateTime<Utc>,
+    pub transactions_root: String,
+    pub poh_hash: String,
+    pub poh_count: u64,
+}
+
+impl BlockHeader {
+    // Fixed-order, length-prefixed encoding so the hash never depends on
+    // serde field order or formatting
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if self.version >= 1 {
+            buf.extend_from_slice(&self.version.to_be_bytes());
+        }
+        put_str(&mut buf, &self.previous_hash);
+        put_timestamp(&mut buf, &self.timestamp);
+        put_str(&mut buf, &self.transactions_root);
+        put_str(&mut buf, &self.poh_hash);
+        buf.extend_from_slice(&self.poh_count.to_be_bytes());
+        buf
+    }
+
+    pub fn hash(&self) -> String {
+        sha256_hex(&self.canonical_bytes())
+    }
+}
+
+impl Block {
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            version: self.version,
+            previous_hash: self.previous_hash.clone(),
+            timestamp: self.timestamp,
+            transactions_root: transactions_root(&self.transactions),
+            poh_hash: self.poh_hash.clone(),
+            poh_count: self.poh_count,
+        }
+    }
+
+    pub fn compute_hash(&self) -> String {
+        self.header().hash()
+    }
+
+    pub fn verify_hash(&self) -> bool {
+        self.hash == self.compute_hash()
+    }
+
+    // Decode a JSON block of any supported version
+    pub fn decode(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let block: Block = serde_json::from_slice(data)?;
+        block.check_version()?;
+        Ok(block)
+    }
+
+    pub fn check_version(&self) -> Result<(), Box<dyn Error>> {
+        if self.version > BLOCK_VERSION {
+            return Err(format!("Unsupported block version {}", self.version).into());
+        }
+        for tx in &self.transactions {
+            tx.check_version()?;
+        }
+        Ok(())
+    }
+
+    // What can be checked without chain state: versions, the hash and every
+    // transaction's own rules and signatures. A block failing these is
+    // invalid on any chain.
+    pub fn check_standalone(&self) -> Result<(), Box<dyn Error>> {
+        self.check_version()?;
+        if !self.verify_hash() {
+            return Err(format!("Block {} has an invalid hash", self.hash).into());
+        }
+        for tx in &self.transactions {
+            tx.check_standalone().map_err(|e| format!("Transaction {}: {}", tx.id, e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Transaction {
+    // Signed fields only; the signature itself is not part of the encoding
+    pub fn canonical_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        if self.version >= 1 {
+            buf.extend_from_slice(&self.version.to_be_bytes());
+        }
+        put_str(&mut buf, &self.id);
+        put_str(&mut buf, &self.from);
+        put_str(&mut buf, &self.to);
+        buf.extend_from_slice(&self.amount.to_bits().to_be_bytes());
+        buf.extend_from_slice(&self.fee.to_bits().to_be_bytes());
+        put_timestamp(&mut buf, &self.timestamp);
+        if self.version >= 2 {
+            match &self.lock_time {
+                None => buf.push(0),
+                Some(LockTime::Height(height)) => {
+                    buf.push(1);
+                    buf.extend_from_slice(&height.to_be_bytes());
+                }
+                Some(LockTime::Timestamp(time)) => {
+                    buf.push(2);
+                    put_timestamp(&mut buf, time);
+                }
+            }
+        }
+        if self.version >= 3 {
+            match &self.kind {
+                TransactionKind::Transfer => buf.push(0),
+                TransactionKind::Vesting(schedule) => {
+                    buf.push(1);
+                    put_timestamp(&mut buf, &schedule.start);
+                    put_timestamp(&mut buf, &schedule.cliff);
+                    put_timestamp(&mut buf, &schedule.end);
+                    buf.push(match schedule.release {
+                        VestingRelease::Linear => 0,
+                        VestingRelease::Cliff => 1,
+                    });
+                }
+                TransactionKind::Burn => buf.push(2),
+                TransactionKind::TokenIssue { symbol } => {
+                    buf.push(3);
+                    put_str(&mut buf, symbol);
+                }
+                TransactionKind::TokenTransfer { symbol } => {
+                    buf.push(4);
+                    put_str(&mut buf, symbol);
+                }
+                TransactionKind::Batch { outputs } => {
+                    buf.push(5);
+                    buf.extend_from_slice(&(outputs.len() as u32).to_be_bytes());
+                    for output in outputs {
+                        put_str(&mut buf, &output.address);
+                        buf.extend_from_slice(&output.amount.to_bits().to_be_bytes());
+                    }
+                }
+            }
+        }
+        buf
+    }
+
+    pub fn hash(&self) -> String {
+        sha256_hex(&self.canonical_bytes())
+    }
+
+    // Attach the signer's public key and a signature over the canonical bytes
+    pub async fn sign(&mut self, signer: &dyn Signer) -> Result<(), Box<dyn Error>> {
+        if signer.address() != self.from {
+            return Err("Signer does not control the sending address".into());
+        }
+        self.signature = signer.sign(&self.canonical_bytes()).await?;
+        self.public_key = signer.public_key();
+        self.key_scheme = signer.scheme();
+        Ok(())
+    }
+
+    // The signature, or enough co-signer signatures for a multisig spend
+    pub fn verify_witnesses(&self) -> bool {
+        match &self.multisig {
+            Some(witness) => witness.verify(self),
+            None => self.verify_signature(),
+        }
+    }
+
+    // Rules and signatures, which hold or fail regardless of chain state
+    pub fn check_standalone(&self) -> Result<(), Box<dyn Error>> {
+        check_rules(self)?;
+        if !self.verify_witnesses() {
+            return Err("Invalid transaction signature".into());
+        }
+        Ok(())
+    }
+
+    pub fn verify_signature(&self) -> bool {
+        crate::wallet::encode_address(&self.public_key) == self.from
+            && signer::verify_signature(self.key_scheme, &self.canonical_bytes(), &self.public_key, &self.signature)
+    }
+
+    // Native balance this transaction spends, including the fee
+    pub fn native_cost(&self) -> f64 {
+        match self.kind {
+            TransactionKind::TokenIssue { .. } | TransactionKind::TokenTransfer { .. } => self.fee,
+            _ => self.amount + self.fee,
+        }
+    }
+
+    // Native amounts this transaction credits to spendable balances on inclusion
+    pub fn credits(&self) -> Vec<(&str, f64)> {
+        match &self.kind {
+            TransactionKind::Transfer => vec![(self.to.as_str(), self.amount)],
+            TransactionKind::Batch { outputs } => outputs.iter()
+                .map(|output| (output.address.as_str(), output.amount))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    // Whether `address` sends, receives or is paid by this transaction
+    pub fn involves(&self, address: &str) -> bool {
+        self.from == address
+            || self.to == address
+            || matches!(&self.kind, TransactionKind::Batch { outputs } if outputs.iter().any(|o| o.address == address))
+    }
+
+    pub fn token_symbol(&self) -> Option<&str> {
+        match &self.kind {
+            TransactionKind::TokenIssue { symbol } | TransactionKind::TokenTransfer { symbol } => Some(symbol),
+            _ => None,
+        }
+    }
+
+    pub fn is_final(&self, height: u64, time: DateTime<Utc>) -> bool {
+        self.lock_time.as_ref().map_or(true, |lock| lock.is_final(height, time))
+    }
+
+    // Hex-encoded blob for moving transactions between online and offline machines
+    pub fn to_raw(&self) -> Result<String, Box<dyn Error>> {
+        Ok(hex::encode(serde_json::to_vec(self)?))
+    }
+
+    pub fn from_raw(raw: &str) -> Result<Self, Box<dyn Error>> {
+        Self::decode(&hex::decode(raw.trim())?)
+    }
+
+    // Decode a JSON transaction of any supported version
+    pub fn decode(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let transaction: Transaction = serde_json::from_slice(data)?;
+        transaction.check_version()?;
+        Ok(transaction)
+    }
+
+    pub fn check_version(&self) -> Result<(), Box<dyn Error>> {
+        if self.version > TRANSACTION_VERSION {
+            return Err(format!("Unsupported transaction version {}", self.version).into());
+        }
+        if self.version < 2 && self.lock_time.is_some() {
+            return Err("Lock time requires transaction version 2".into());
+        }
+        if self.version < 3 && !matches!(self.kind, TransactionKind::Transfer) {
+            return Err("Transaction kind requires transaction version 3".into());
+        }
+        if self.version < 4 && matches!(self.kind, TransactionKind::Burn) {
+            return Err("Burn transactions require transaction version 4".into());
+        }
+        if self.version < 5 && matches!(self.kind, TransactionKind::TokenIssue { .. } | TransactionKind::TokenTransfer { .. }) {
+            return Err("Token transactions require transaction version 5".into());
+        }
+        if self.version < 6 && matches!(self.kind, TransactionKind::Batch { .. }) {
+            return Err("Batch transactions require transaction version 6".into());
+        }
+        Ok(())
+    }
+}
+
+pub fn transactions_root(transactions: &[Transaction]) -> String {
+    let mut hasher = Sha256::new();
+    for tx in transactions {
+        hasher.update(tx.hash().as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+// Rules a transaction must pass on its own, whether it arrives for the
+// mempool or inside a block. Balances are checked separately.
+fn check_rules(transaction: &Transaction) -> Result<(), Box<dyn Error>> {
+    transaction.check_version()?;
+    // Ids and signatures must fit the storage columns, or a block could be
+    // accepted that no node is able to persist
+    crate::storage::check_id(&transaction.id)?;
+    crate::storage::check_signature(&transaction.signature)?;
+
+    // NaN compares false both ways, so finiteness is checked first
+    if !transaction.amount.is_finite() || !transaction.fee.is_finite() || transaction.amount <= 0.0 || transaction.fee < 0.0 {
+        return Err("Invalid transaction amount".into());
+    }
+    if transaction.fee < MIN_TRANSACTION_FEE {
+        return Err(format!("Fee must be at least {}", MIN_TRANSACTION_FEE).into());
+    }
+
+    if let TransactionKind::Vesting(schedule) = &transaction.kind {
+        schedule.validate()?;
+    }
+
+    if transaction.from == BURN_ADDRESS || transaction.from == BATCH_ADDRESS {
+        return Err("Burn and batch addresses cannot spend".into());
+    }
+    if !validate_address(&transaction.from)
+        || (transaction.to != BURN_ADDRESS && transaction.to != BATCH_ADDRESS && !validate_address(&transaction.to))
+    {
+        return Err("Invalid address".into());
+    }
+    if matches!(transaction.kind, TransactionKind::Burn) != (transaction.to == BURN_ADDRESS) {
+        return Err("Burns must be sent to the burn address".into());
+    }
+    if let TransactionKind::Batch { outputs } = &transaction.kind {
+        check_batch_outputs(transaction, outputs)?;
+    } else if transaction.to == BATCH_ADDRESS {
+        return Err("Only batch transactions may use the batch address".into());
+    }
+    Ok(())
+}
+
+// Balances as a block's transactions are applied in order on top of
+// confirmed state, so each is checked against everything before it
+struct BlockLedger<'a> {
+    state: &'a ChainState,
+    balances: HashMap<String, f64>,
+    token_balances: HashMap<(String, String), f64>,
+    token_issuers: HashMap<String, String>,
+}
+
+impl<'a> BlockLedger<'a> {
+    fn new(state: &'a ChainState) -> Self {
+        BlockLedger {
+            state,
+            balances: HashMap::new(),
+            token_balances: HashMap::new(),
+            token_issuers: HashMap::new(),
+        }
+    }
+
+    fn balance(&self, address: &str) -> f64 {
+        self.balances.get(address).copied().unwrap_or_else(|| self.state.get_balance(address))
+    }
+
+    fn credit(&mut self, address: &str, amount: f64) {
+        let balance = self.balance(address) + amount;
+        self.balances.insert(address.to_string(), balance);
+    }
+
+    fn token_balance(&self, address: &str, symbol: &str) -> f64 {
+        self.token_balances.get(&(address.to_string(), symbol.to_string()))
+            .copied()
+            .unwrap_or_else(|| self.state.token_balance(address, symbol))
+    }
+
+    fn credit_token(&mut self, address: &str, symbol: &str, amount: f64) {
+        let balance = self.token_balance(address, symbol) + amount;
+        self.token_balances.insert((address.to_string(), symbol.to_string()), balance);
+    }
+
+    fn apply(&mut self, tx: &Transaction) -> Result<(), Box<dyn Error>> {
+        let cost = tx.native_cost();
+        if cost > self.balance(&tx.from) {
+            return Err(format!("{} cannot cover {}", tx.from, cost).into());
+        }
+        match &tx.kind {
+            TransactionKind::TokenIssue { symbol } => {
+                let issuer = self.token_issuers.get(symbol).or_else(|| self.state.token_issuers.get(symbol));
+                if let Some(issuer) = issuer {
+                    if issuer != &tx.from {
+                        return Err(format!("Only {} may issue {}", issuer, symbol).into());
+                    }
+                }
+            }
+            TransactionKind::TokenTransfer { symbol } => {
+                if tx.amount > self.token_balance(&tx.from, symbol) {
+                    return Err(format!("Insufficient {} balance", symbol).into());
+                }
+            }
+            _ => {}
+        }
+
+        // Everything is checked above, so a failing transaction leaves the
+        // ledger as it was and block building can skip it
+        self.credit(&tx.from, -cost);
+        match &tx.kind {
+            TransactionKind::TokenIssue { symbol } => {
+                self.token_issuers.insert(symbol.clone(), tx.from.clone());
+                self.credit_token(&tx.to, symbol, tx.amount);
+            }
+            TransactionKind::TokenTransfer { symbol } => {
+                self.credit_token(&tx.from, symbol, -tx.amount);
+                self.credit_token(&tx.to, symbol, tx.amount);
+            }
+            _ => {
+                for (address, amount) in tx.credits() {
+                    self.credit(address, amount);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Outputs must be valid, positive and add up to the transaction amount
+fn check_batch_outputs(transaction: &Transaction, outputs: &[Payout]) -> Result<(), Box<dyn Error>> {
+    if transaction.to != BATCH_ADDRESS {
+        return Err("Batch transactions must be sent to the batch address".into());
+    }
+    if outputs.is_empty() || outputs.len() > MAX_BATCH_OUTPUTS {
+        return Err(format!("Batch must have between 1 and {} outputs", MAX_BATCH_OUTPUTS).into());
+    }
+    if let Some(output) = outputs.iter().find(|o| !validate_address(&o.address) || !o.amount.is_finite() || o.amount <= 0.0) {
+        return Err(format!("Invalid batch output to {}", output.address).into());
+    }
+    // Outputs may never pay out more than the sender is charged; rounding
+    // may only leave them a hair short
+    let total: f64 = outputs.iter().map(|o| o.amount).sum();
+    if total > transaction.amount || transaction.amount - total > 1e-8 {
+        return Err("Batch outputs do not add up to the transaction amount".into());
+    }
+    Ok(())
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+// Full nanosecond precision, so every storage backend has to keep it too
+fn put_timestamp(buf: &mut Vec<u8>, value: &DateTime<Utc>) {
+    buf.extend_from_slice(&value.timestamp().to_be_bytes());
+    buf.extend_from_slice(&value.timestamp_subsec_nanos().to_be_bytes());
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
+// Handle to the chain that can be cloned into the API server, network and
+// miner. Readers share the lock; mutations take it exclusively.
+#[derive(Debug, Clone)]
 pub struct Blockchain {
+    state: Arc<RwLock<ChainState>>,
+    events: broadcast::Sender<ChainEvent>,
+}
+
+// Published on the chain event bus after state changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChainEvent {
+    TransactionAccepted(Transaction),
+    BlockAdded(Block),
+}
+
+#[derive(Debug)]
+pub struct ChainState {
     pub blocks: Vec<Block>,
     pub pending_transactions: Vec<Transaction>,
     pub transaction_pool: HashMap<String, Transaction>,
+    // Ids of every transaction in `blocks`, so a confirmed transaction
+    // can't be submitted or mined again
+    pub confirmed_ids: HashSet<String>,
     pub poh_verifier: PoHVerifier,
+    pub balances: HashMap<String, f64>,
+    pub pending_spent: HashMap<String, f64>,
+    pub metrics: ChainMetrics,
+    pub genesis_allocations: HashMap<String, f64>,
+    pub vesting: HashMap<String, Vec<VestingGrant>>,
+    pub total_burned: f64,
+    // Every fee paid in a confirmed block; see SupplyStats::fees_destroyed
+    pub total_fees: f64,
+    pub token_balances: HashMap<String, HashMap<String, f64>>,
+    pub token_issuers: HashMap<String, String>,
 }
 
-#[derive(Debug)]
+// File signature and per-record limit for chain export files
+const EXPORT_MAGIC: &[u8; 8] = b"CHAINEXP";
+const MAX_EXPORT_RECORD: usize = 64 * 1024 * 1024;
+
+// Number of most recent blocks used for rate and interval metrics
+const METRICS_WINDOW: usize = 10;
+
+// Smallest fee the network accepts on a transaction. There is no upper
+// bound, so senders can always outbid a congested mempool.
+pub const MIN_TRANSACTION_FEE: f64 = 0.0001;
+
+// Fee levels observed in the mempool and recent blocks, each sorted ascending
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FeeStats {
+    pub mempool_size: usize,
+    pub mempool_fees: Vec<f64>,
+    pub recent_block_fees: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChainMetrics {
+    pub block_height: u64,
+    pub total_transactions: u64,
+    pub transactions_per_second: f64,
+    pub average_block_interval: f64,
+    pub mempool_size: usize,
+    pub reorg_count: u64,
+}
+
+impl ChainMetrics {
+    // Render in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let gauges = [
+            ("chain_block_height", "Height of the best block", self.block_height as f64),
+            ("chain_transactions_total", "Transactions included in blocks", self.total_transactions as f64),
+            ("chain_transactions_per_second", "Transaction throughput over recent blocks", self.transactions_per_second),
+            ("chain_block_interval_seconds", "Average interval between recent blocks", self.average_block_interval),
+            ("chain_mempool_size", "Transactions waiting in the mempool", self.mempool_size as f64),
+            ("chain_reorgs_total", "Chain reorganizations observed", self.reorg_count as f64),
+        ];
+        for (name, help, value) in gauges {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, value));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct PoHVerifier {
     pub current_hash: String,
     pub count: u64,
@@ -42,7 +714,263 @@ pub struct PoHVerifier {
 
 impl Blockchain {
     pub fn new() -> Self {
+        Self::with_allocations(HashMap::new())
+    }
+
+    pub fn with_allocations(allocations: HashMap<String, f64>) -> Self {
+        let (events, _) = broadcast::channel(1000);
+        Blockchain {
+            state: Arc::new(RwLock::new(ChainState::new(allocations))),
+            events,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn add_transaction(&self, transaction: Transaction) -> Result<(), Box<dyn Error>> {
+        self.state.write().await.add_transaction(transaction.clone())?;
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(ChainEvent::TransactionAccepted(transaction));
+        Ok(())
+    }
+
+    // Submit a signed transaction produced by an offline signer
+    pub async fn submit_raw(&self, raw: &str) -> Result<String, Box<dyn Error>> {
+        let transaction = Transaction::from_raw(raw)?;
+        let id = transaction.id.clone();
+        self.add_transaction(transaction).await?;
+        Ok(id)
+    }
+
+    // Validate a transaction against current state without submitting it
+    pub async fn dry_run(&self, transaction: &Transaction, check_signature: bool) -> Result<Vec<String>, Box<dyn Error>> {
+        self.state.read().await.check_transaction(transaction, check_signature)
+    }
+
+    pub async fn mine_block(&self) -> Result<Block, Box<dyn Error>> {
+        let block = self.state.write().await.mine_block()?;
+        let _ = self.events.send(ChainEvent::BlockAdded(block.clone()));
+        Ok(block)
+    }
+
+    // Apply a block received from a peer
+    pub async fn add_block(&self, block: Block) -> Result<(), Box<dyn Error>> {
+        self.state.write().await.add_block(block.clone())?;
+        let _ = self.events.send(ChainEvent::BlockAdded(block));
+        Ok(())
+    }
+
+    // Apply a block from a peer if it builds on our tip. Returns false
+    // without checking it when it doesn't, as it can't be validated yet.
+    pub async fn offer_block(&self, block: Block) -> Result<bool, Box<dyn Error>> {
+        {
+            let mut state = self.state.write().await;
+            if state.blocks.last().unwrap().hash != block.previous_hash {
+                return Ok(false);
+            }
+            state.add_block(block.clone())?;
+        }
+        let _ = self.events.send(ChainEvent::BlockAdded(block));
+        Ok(true)
+    }
+
+    // Hashes of our chain at exponentially spaced heights back from the tip,
+    // always ending at genesis, so a peer can find where our chains diverge
+    pub async fn block_locator(&self) -> Vec<String> {
+        let state = self.state.read().await;
+        let mut locator = vec![];
+        let mut height = state.blocks.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(state.blocks[height].hash.clone());
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    // Headers following the most recent locator hash found on our chain
+    pub async fn headers_after(&self, locator: &[String], limit: usize) -> Vec<BlockHeader> {
+        let state = self.state.read().await;
+        let start = locator.iter()
+            .find_map(|hash| state.blocks.iter().position(|block| &block.hash == hash))
+            .unwrap_or(0);
+        state.blocks.iter()
+            .skip(start + 1)
+            .take(limit)
+            .map(|block| block.header())
+            .collect()
+    }
+
+    pub async fn get_blocks(&self, hashes: &[String]) -> Vec<Block> {
+        let state = self.state.read().await;
+        hashes.iter()
+            .filter_map(|hash| state.blocks.iter().find(|block| &block.hash == hash).cloned())
+            .collect()
+    }
+
+    pub async fn get_balance(&self, address: &str) -> f64 {
+        self.state.read().await.get_balance(address)
+    }
+
+    pub async fn available_balance(&self, address: &str) -> f64 {
+        self.state.read().await.available_balance(address)
+    }
+
+    pub async fn token_balances(&self, address: &str) -> HashMap<String, f64> {
+        self.state.read().await.token_balances(address)
+    }
+
+    pub async fn fee_stats(&self) -> FeeStats {
+        self.state.read().await.fee_stats()
+    }
+
+    pub async fn supply_stats(&self) -> SupplyStats {
+        self.state.read().await.supply_stats()
+    }
+
+    pub async fn vesting_status(&self, address: &str) -> VestingStatus {
+        self.state.read().await.vesting_status(address, Utc::now())
+    }
+
+    pub async fn get_metrics(&self) -> ChainMetrics {
+        self.state.read().await.metrics.clone()
+    }
+
+    pub async fn height(&self) -> u64 {
+        (self.state.read().await.blocks.len() - 1) as u64
+    }
+
+    pub async fn genesis_hash(&self) -> String {
+        self.state.read().await.blocks[0].hash.clone()
+    }
+
+    pub async fn latest_block(&self) -> Block {
+        self.state.read().await.blocks.last().unwrap().clone()
+    }
+
+    pub async fn get_block(&self, hash: &str) -> Option<Block> {
+        let state = self.state.read().await;
+        state.blocks.iter().find(|block| block.hash == hash).cloned()
+    }
+
+    // Confirmed transactions sent from or to any of the given addresses
+    pub async fn transactions_for(&self, addresses: &[String]) -> Vec<Transaction> {
+        let state = self.state.read().await;
+        state.blocks.iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|tx| addresses.iter().any(|address| tx.involves(address)))
+            .cloned()
+            .collect()
+    }
+
+    pub async fn pending_transactions(&self) -> Vec<Transaction> {
+        self.state.read().await.pending_transactions.clone()
+    }
+
+    pub async fn get_pending_transaction(&self, hash: &str) -> Option<Transaction> {
+        let state = self.state.read().await;
+        state.pending_transactions.iter().find(|tx| tx.hash() == hash).cloned()
+    }
+
+    // Write every block to a file as length-prefixed binary records
+    pub async fn export(&self, path: impl AsRef<Path>) -> Result<u64, Box<dyn Error>> {
+        let state = self.state.read().await;
+        let mut writer = BufWriter::new(File::create(path).await?);
+
+        writer.write_all(EXPORT_MAGIC).await?;
+        for block in &state.blocks {
+            let bytes = bincode::serialize(block)?;
+            writer.write_u32(bytes.len() as u32).await?;
+            writer.write_all(&bytes).await?;
+        }
+        writer.flush().await?;
+
+        Ok(state.blocks.len() as u64)
+    }
+
+    // Replace the chain with the blocks from an export file. Every block after
+    // genesis is re-validated, balances included, before the existing chain
+    // is swapped out.
+    pub async fn import(&self, path: impl AsRef<Path>) -> Result<u64, Box<dyn Error>> {
+        let mut reader = BufReader::new(File::open(path).await?);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic).await?;
+        if &magic != EXPORT_MAGIC {
+            return Err("Not a chain export file".into());
+        }
+
+        let mut imported: Option<ChainState> = None;
+        loop {
+            let len = match reader.read_u32().await {
+                Ok(len) => len as usize,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            if len > MAX_EXPORT_RECORD {
+                return Err("Export record exceeds maximum block size".into());
+            }
+            let mut bytes = vec![0u8; len];
+            reader.read_exact(&mut bytes).await?;
+            let block: Block = bincode::deserialize(&bytes)?;
+
+            match imported.as_mut() {
+                Some(chain) => chain.apply_block(block)?,
+                None => {
+                    let allocations = self.state.read().await.genesis_allocations.clone();
+                    let mut chain = ChainState::new(allocations);
+                    chain.blocks[0] = block;
+                    imported = Some(chain);
+                }
+            }
+        }
+
+        let mut imported = imported.ok_or("Export file contains no blocks")?;
+        let mut state = self.state.write().await;
+        imported.metrics.reorg_count = state.metrics.reorg_count;
+        if state.blocks.len() > 1 {
+            imported.metrics.reorg_count += 1;
+        }
+        let height = imported.blocks.len() as u64;
+        *state = imported;
+
+        Ok(height)
+    }
+
+    // Rebuild a chain from stored blocks, genesis first. Every block after
+    // genesis is re-validated as it is applied, which also rebuilds the
+    // index of confirmed transaction ids.
+    pub fn from_blocks(blocks: Vec<Block>) -> Result<Self, Box<dyn Error>> {
+        let blockchain = Self::new();
+        let mut blocks = blocks.into_iter();
+        {
+            let mut state = blockchain.state.try_write()?;
+            state.blocks[0] = blocks.next().ok_or("No blocks to load")?;
+            for block in blocks {
+                state.apply_block(block)?;
+            }
+        }
+        Ok(blockchain)
+    }
+
+    // Direct read access for callers that need several values under one lock
+    pub async fn read(&self) -> RwLockReadGuard<'_, ChainState> {
+        self.state.read().await
+    }
+}
+
+impl ChainState {
+    pub fn new(allocations: HashMap<String, f64>) -> Self {
         let genesis_block = Block {
+            version: BLOCK_VERSION,
             hash: "0".repeat(64),
             previous_hash: "0".repeat(64),
             timestamp: Utc::now(),
@@ -51,35 +979,289 @@ impl Blockchain {
             poh_count: 0,
         };
 
-        Blockchain {
+        ChainState {
             blocks: vec![genesis_block],
             pending_transactions: vec![],
             transaction_pool: HashMap::new(),
+            confirmed_ids: HashSet::new(),
             poh_verifier: PoHVerifier::new(),
+            balances: allocations.clone(),
+            pending_spent: HashMap::new(),
+            metrics: ChainMetrics::default(),
+            genesis_allocations: allocations,
+            vesting: HashMap::new(),
+            total_burned: 0.0,
+            total_fees: 0.0,
+            token_balances: HashMap::new(),
+            token_issuers: HashMap::new(),
         }
     }
 
-    pub async fn add_transaction(&mut self, transaction: Transaction) -> Result<(), Box<dyn Error>> {
-        // Verify transaction signature
-        if !self.verify_transaction(&transaction) {
-            return Err("Invalid transaction signature".into());
+    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), Box<dyn Error>> {
+        let replaced = self.check_transaction(&transaction, true)?;
+        for id in replaced {
+            self.remove_pending(&id);
         }
+        self.insert_pending(transaction);
+        Ok(())
+    }
 
-        // Add to transaction pool
+    fn insert_pending(&mut self, transaction: Transaction) {
+        *self.pending_spent.entry(transaction.from.clone()).or_insert(0.0) += transaction.native_cost();
         self.transaction_pool.insert(transaction.id.clone(), transaction.clone());
         self.pending_transactions.push(transaction);
+        self.metrics.mempool_size = self.pending_transactions.len();
+    }
+
+    // Re-admit the mempool, in order, against the chain after a new block.
+    // Transactions the block confirmed or made unaffordable are dropped, so
+    // they can't make every later block fail to apply. Signatures were
+    // checked on admission and aren't checked again.
+    fn revalidate_pending(&mut self) {
+        let pending = std::mem::take(&mut self.pending_transactions);
+        self.transaction_pool.clear();
+        self.pending_spent.clear();
+        for transaction in pending {
+            match self.check_transaction(&transaction, false) {
+                Ok(replaced) if replaced.is_empty() => self.insert_pending(transaction),
+                _ => {}
+            }
+        }
+        self.metrics.mempool_size = self.pending_transactions.len();
+    }
+
+    // Run every mempool admission rule without changing state. Returns the
+    // ids of pending transactions that admission would replace.
+    pub fn check_transaction(&self, transaction: &Transaction, check_signature: bool) -> Result<Vec<String>, Box<dyn Error>> {
+        check_rules(transaction)?;
+
+        // Verify transaction signature
+        if check_signature && !transaction.verify_witnesses() {
+            return Err("Invalid transaction signature".into());
+        }
+
+        if self.transaction_pool.contains_key(&transaction.id) {
+            return Err("Transaction already in pool".into());
+        }
+        if self.confirmed_ids.contains(&transaction.id) {
+            return Err("Transaction already confirmed".into());
+        }
+
+        self.check_token_transaction(transaction)?;
+
+        // Timelocked transactions must be includable in the next block
+        if !transaction.is_final(self.blocks.len() as u64, Utc::now()) {
+            return Err("Transaction is timelocked".into());
+        }
 
+        // Check the sender can cover this transaction on top of everything
+        // it already has pending, replacing lower-fee spends if needed
+        let cost = transaction.native_cost();
+        let available = self.available_balance(&transaction.from);
+        if cost > available {
+            return self.find_replaceable(transaction, cost - available)
+                .ok_or_else(|| "Conflicting transaction: insufficient unspent balance".into());
+        }
+
+        Ok(vec![])
+    }
+
+    fn update_metrics(&mut self) {
+        let window_start = self.blocks.len().saturating_sub(METRICS_WINDOW + 1);
+        let window = &self.blocks[window_start..];
+
+        if let (Some(first), Some(last)) = (window.first(), window.last()) {
+            let elapsed = (last.timestamp - first.timestamp).num_milliseconds() as f64 / 1000.0;
+            let intervals = (window.len() - 1) as f64;
+            let transactions: usize = window[1..].iter().map(|b| b.transactions.len()).sum();
+
+            if intervals > 0.0 {
+                self.metrics.average_block_interval = elapsed / intervals;
+            }
+            if elapsed > 0.0 {
+                self.metrics.transactions_per_second = transactions as f64 / elapsed;
+            }
+        }
+
+        self.metrics.block_height = (self.blocks.len() - 1) as u64;
+        self.metrics.mempool_size = self.pending_transactions.len();
+    }
+
+    pub fn get_balance(&self, address: &str) -> f64 {
+        *self.balances.get(address).unwrap_or(&0.0)
+    }
+
+    pub fn token_balance(&self, address: &str, symbol: &str) -> f64 {
+        self.token_balances.get(address)
+            .and_then(|tokens| tokens.get(symbol))
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    pub fn token_balances(&self, address: &str) -> HashMap<String, f64> {
+        self.token_balances.get(address).cloned().unwrap_or_default()
+    }
+
+    fn token_balance_mut(&mut self, address: &str, symbol: &str) -> &mut f64 {
+        self.token_balances.entry(address.to_string())
+            .or_insert_with(HashMap::new)
+            .entry(symbol.to_string())
+            .or_insert(0.0)
+    }
+
+    // Issuance is restricted to the symbol's issuer, or for a new symbol to
+    // whoever first has an issue pending; transfers must be covered by the
+    // confirmed token balance less pending token spends
+    fn check_token_transaction(&self, transaction: &Transaction) -> Result<(), Box<dyn Error>> {
+        match &transaction.kind {
+            TransactionKind::TokenIssue { symbol } => {
+                let issuer = self.token_issuers.get(symbol).or_else(|| {
+                    self.pending_transactions.iter()
+                        .find(|tx| matches!(&tx.kind, TransactionKind::TokenIssue { symbol: s } if s == symbol))
+                        .map(|tx| &tx.from)
+                });
+                if let Some(issuer) = issuer {
+                    if issuer != &transaction.from {
+                        return Err(format!("Only {} may issue {}", issuer, symbol).into());
+                    }
+                }
+            }
+            TransactionKind::TokenTransfer { symbol } => {
+                let pending: f64 = self.pending_transactions.iter()
+                    .filter(|tx| tx.from == transaction.from)
+                    .filter(|tx| matches!(&tx.kind, TransactionKind::TokenTransfer { symbol: s } if s == symbol))
+                    .map(|tx| tx.amount)
+                    .sum();
+                if transaction.amount > self.token_balance(&transaction.from, symbol) - pending {
+                    return Err(format!("Insufficient {} balance", symbol).into());
+                }
+            }
+            _ => {}
+        }
         Ok(())
     }
 
-    pub async fn mine_block(&mut self) -> Result<Block, Box<dyn Error>> {
+    // Move everything that has vested by `time` into spendable balances
+    fn release_vested(&mut self, time: DateTime<Utc>) {
+        for (beneficiary, grants) in self.vesting.iter_mut() {
+            for grant in grants.iter_mut() {
+                let vested = grant.schedule.vested_amount(grant.total, time);
+                if vested > grant.released {
+                    *self.balances.entry(beneficiary.clone()).or_insert(0.0) += vested - grant.released;
+                    grant.released = vested;
+                }
+            }
+        }
+    }
+
+    pub fn fee_stats(&self) -> FeeStats {
+        let window_start = self.blocks.len().saturating_sub(METRICS_WINDOW);
+        let mut recent_block_fees: Vec<f64> = self.blocks[window_start..].iter()
+            .flat_map(|block| block.transactions.iter().map(|tx| tx.fee))
+            .collect();
+        let mut mempool_fees: Vec<f64> = self.pending_transactions.iter().map(|tx| tx.fee).collect();
+        recent_block_fees.sort_by(f64::total_cmp);
+        mempool_fees.sort_by(f64::total_cmp);
+
+        FeeStats {
+            mempool_size: self.pending_transactions.len(),
+            mempool_fees,
+            recent_block_fees,
+        }
+    }
+
+    pub fn supply_stats(&self) -> SupplyStats {
+        let total_issued: f64 = self.genesis_allocations.values().sum();
+        let locked_in_vesting: f64 = self.vesting.values()
+            .flatten()
+            .map(|grant| grant.total - grant.released)
+            .sum();
+
+        SupplyStats {
+            total_issued,
+            burned: self.total_burned,
+            locked_in_vesting,
+            fees_destroyed: self.total_fees,
+            circulating: total_issued - self.total_burned - locked_in_vesting - self.total_fees,
+        }
+    }
+
+    pub fn vesting_status(&self, address: &str, time: DateTime<Utc>) -> VestingStatus {
+        let mut status = VestingStatus::default();
+        if let Some(grants) = self.vesting.get(address) {
+            for grant in grants {
+                let vested = grant.schedule.vested_amount(grant.total, time);
+                status.vested += vested;
+                status.unvested += grant.total - vested;
+                status.released += grant.released;
+            }
+            status.grants = grants.clone();
+        }
+        status
+    }
+
+    // Confirmed balance minus what pending transactions already spend
+    pub fn available_balance(&self, address: &str) -> f64 {
+        self.get_balance(address) - *self.pending_spent.get(address).unwrap_or(&0.0)
+    }
+
+    // Replace-by-fee: pick the cheapest pending spends from the same sender
+    // until enough balance is freed. The new transaction must pay a higher
+    // fee than everything it evicts combined.
+    fn find_replaceable(&self, transaction: &Transaction, shortfall: f64) -> Option<Vec<String>> {
+        let mut candidates: Vec<&Transaction> = self.pending_transactions.iter()
+            .filter(|tx| tx.from == transaction.from && tx.fee < transaction.fee)
+            .collect();
+        candidates.sort_by(|a, b| a.fee.total_cmp(&b.fee));
+
+        let mut freed = 0.0;
+        let mut evicted_fees = 0.0;
+        let mut replaced = vec![];
+        for tx in candidates {
+            if freed >= shortfall {
+                break;
+            }
+            freed += tx.native_cost();
+            evicted_fees += tx.fee;
+            replaced.push(tx.id.clone());
+        }
+
+        if freed >= shortfall && transaction.fee > evicted_fees {
+            Some(replaced)
+        } else {
+            None
+        }
+    }
+
+    fn remove_pending(&mut self, id: &str) {
+        if let Some(tx) = self.transaction_pool.remove(id) {
+            self.pending_transactions.retain(|pending| pending.id != id);
+            self.metrics.mempool_size = self.pending_transactions.len();
+            if let Some(spent) = self.pending_spent.get_mut(&tx.from) {
+                *spent -= tx.native_cost();
+                if *spent <= 0.0 {
+                    self.pending_spent.remove(&tx.from);
+                }
+            }
+        }
+    }
+
+    // Build a block from the mempool entries that still apply, in order,
+    // and apply it. The block is built from copies, so the mempool and PoH
+    // state only change once it applies.
+    pub fn mine_block(&mut self) -> Result<Block, Box<dyn Error>> {
         let previous_block = self.blocks.last().unwrap();
-        let transactions = self.pending_transactions.drain(..).collect();
-        
-        // Generate PoH hash
-        let (poh_hash, poh_count) = self.poh_verifier.generate_hash();
-        
+        let mut ledger = BlockLedger::new(self);
+        let transactions: Vec<Transaction> = self.pending_transactions.iter()
+            .filter(|tx| !self.confirmed_ids.contains(&tx.id) && check_rules(tx).and_then(|()| ledger.apply(tx)).is_ok())
+            .cloned()
+            .collect();
+
+        // Generate PoH hash; apply_block advances the verifier to it
+        let (poh_hash, poh_count) = self.poh_verifier.clone().generate_hash();
+
         let mut block = Block {
+            version: BLOCK_VERSION,
             hash: "".to_string(),
             previous_hash: previous_block.hash.clone(),
             timestamp: Utc::now(),
@@ -89,24 +1271,101 @@ impl Blockchain {
         };
 
         // Calculate block hash
-        block.hash = self.calculate_block_hash(&block);
-        
-        // Add block to chain
-        self.blocks.push(block.clone());
-        
+        block.hash = block.compute_hash();
+
+        self.add_block(block.clone())?;
         Ok(block)
     }
 
-    fn verify_transaction(&self, transaction: &Transaction) -> bool {
-        // TODO: Implement transaction signature verification
-        true
+    // Apply a block produced elsewhere and drop its transactions, and any
+    // it invalidated, from the mempool
+    pub fn add_block(&mut self, block: Block) -> Result<(), Box<dyn Error>> {
+        self.apply_block(block)?;
+        self.revalidate_pending();
+        Ok(())
     }
 
-    fn calculate_block_hash(&self, block: &Block) -> String {
-        let mut hasher = Sha256::new();
-        let block_data = serde_json::to_string(block).unwrap();
-        hasher.update(block_data.as_bytes());
-        format!("{:x}", hasher.finalize())
+    // Validate a block against the current tip and apply it to chain state
+    pub fn apply_block(&mut self, block: Block) -> Result<(), Box<dyn Error>> {
+        let tip = self.blocks.last().unwrap();
+        if block.previous_hash != tip.hash {
+            return Err(format!("Block {} does not extend the current tip", block.hash).into());
+        }
+        block.check_version()?;
+        if !block.verify_hash() {
+            return Err(format!("Block {} has an invalid hash", block.hash).into());
+        }
+        if let Some(tx) = block.transactions.iter().find(|tx| !tx.verify_witnesses()) {
+            return Err(format!("Block {} includes transaction {} with invalid signatures", block.hash, tx.id).into());
+        }
+        let height = self.blocks.len() as u64;
+        if let Some(tx) = block.transactions.iter().find(|tx| !tx.is_final(height, block.timestamp)) {
+            return Err(format!("Block {} includes timelocked transaction {}", block.hash, tx.id).into());
+        }
+
+        // Check every transaction against the balances left by those before
+        // it, so nothing below can overspend or mint
+        let mut ledger = BlockLedger::new(self);
+        let mut ids = HashSet::new();
+        for tx in &block.transactions {
+            if !ids.insert(&tx.id) {
+                return Err(format!("Block {} includes transaction {} twice", block.hash, tx.id).into());
+            }
+            if self.confirmed_ids.contains(&tx.id) {
+                return Err(format!("Block {} includes already confirmed transaction {}", block.hash, tx.id).into());
+            }
+            check_rules(tx)
+                .and_then(|()| ledger.apply(tx))
+                .map_err(|e| format!("Block {} includes invalid transaction {}: {}", block.hash, tx.id, e))?;
+        }
+
+        // Apply transfers to account balances
+        for tx in &block.transactions {
+            *self.balances.entry(tx.from.clone()).or_insert(0.0) -= tx.native_cost();
+            match &tx.kind {
+                TransactionKind::Transfer => {
+                    *self.balances.entry(tx.to.clone()).or_insert(0.0) += tx.amount;
+                }
+                TransactionKind::Vesting(schedule) => {
+                    self.vesting.entry(tx.to.clone()).or_insert_with(Vec::new).push(VestingGrant {
+                        transaction_id: tx.id.clone(),
+                        grantor: tx.from.clone(),
+                        total: tx.amount,
+                        released: 0.0,
+                        schedule: schedule.clone(),
+                    });
+                }
+                TransactionKind::Burn => {
+                    self.total_burned += tx.amount;
+                }
+                TransactionKind::TokenIssue { symbol } => {
+                    self.token_issuers.entry(symbol.clone()).or_insert_with(|| tx.from.clone());
+                    *self.token_balance_mut(&tx.to, symbol) += tx.amount;
+                }
+                TransactionKind::TokenTransfer { symbol } => {
+                    *self.token_balance_mut(&tx.from, symbol) -= tx.amount;
+                    *self.token_balance_mut(&tx.to, symbol) += tx.amount;
+                }
+                TransactionKind::Batch { outputs } => {
+                    for output in outputs {
+                        *self.balances.entry(output.address.clone()).or_insert(0.0) += output.amount;
+                    }
+                }
+            }
+            // The fee was debited with the sender's cost and goes to no one
+            self.total_fees += tx.fee;
+        }
+        self.release_vested(block.timestamp);
+
+        // Add block to chain
+        self.metrics.total_transactions += block.transactions.len() as u64;
+        self.confirmed_ids.extend(block.transactions.iter().map(|tx| tx.id.clone()));
+        self.poh_verifier.current_hash = block.poh_hash.clone();
+        self.poh_verifier.count = block.poh_count;
+        self.blocks.push(block);
+        self.update_metrics();
+
+        Ok(())
     }
 }
 
@@ -154,4 +1413,139 @@ impl TransactionPipeline {
         // 4. Broadcast to network
         Ok(())
     }
-} 
\ No newline at end of file
+} 
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::signer::KeypairSigner;
+
+    pub(crate) fn new_signer() -> KeypairSigner {
+        KeypairSigner::from_secret(KeyScheme::Ed25519, &KeyScheme::Ed25519.generate_secret()).unwrap()
+    }
+
+    // A chain whose genesis allocates `balance` to the returned key
+    pub(crate) fn funded_chain(balance: f64) -> (Blockchain, KeypairSigner) {
+        let signer = new_signer();
+        let blockchain = Blockchain::with_allocations(HashMap::from([(signer.address(), balance)]));
+        (blockchain, signer)
+    }
+
+    // A signed transfer of `amount` to a fresh address
+    pub(crate) async fn transfer(signer: &KeypairSigner, amount: f64) -> Transaction {
+        let mut transaction = Transaction {
+            version: TRANSACTION_VERSION,
+            id: uuid::Uuid::new_v4().to_string(),
+            from: signer.address(),
+            to: new_signer().address(),
+            amount,
+            fee: MIN_TRANSACTION_FEE,
+            timestamp: Utc::now(),
+            lock_time: None,
+            kind: TransactionKind::Transfer,
+            signature: vec![],
+            public_key: vec![],
+            key_scheme: KeyScheme::Ed25519,
+            multisig: None,
+        };
+        transaction.sign(signer).await.unwrap();
+        transaction
+    }
+
+    #[tokio::test]
+    async fn rejected_block_leaves_the_mempool_untouched() {
+        let (blockchain, signer) = funded_chain(100.0);
+        blockchain.add_transaction(transfer(&signer, 1.0).await).await.unwrap();
+
+        // A pool entry whose signature no longer matches, so the block
+        // built from the mempool fails to apply
+        let mut invalid = transfer(&signer, 1.0).await;
+        invalid.amount = 2.0;
+        let mut state = blockchain.state.write().await;
+        *state.pending_spent.get_mut(&signer.address()).unwrap() += invalid.native_cost();
+        state.transaction_pool.insert(invalid.id.clone(), invalid.clone());
+        state.pending_transactions.push(invalid.clone());
+
+        let pending: Vec<String> = state.pending_transactions.iter().map(|tx| tx.id.clone()).collect();
+        let spent = state.pending_spent.clone();
+        let poh = (state.poh_verifier.current_hash.clone(), state.poh_verifier.count);
+        assert!(state.mine_block().is_err());
+        assert_eq!(state.pending_transactions.iter().map(|tx| tx.id.clone()).collect::<Vec<_>>(), pending);
+        assert_eq!(state.transaction_pool.len(), pending.len());
+        assert_eq!(state.pending_spent, spent);
+        assert_eq!((state.poh_verifier.current_hash.clone(), state.poh_verifier.count), poh);
+        assert_eq!(state.blocks.len(), 1);
+
+        // Without the bad entry the rest of the mempool mines as usual
+        state.remove_pending(&invalid.id);
+        let block = state.mine_block().unwrap();
+        assert_eq!(block.transactions.len(), 1);
+        assert!(state.pending_transactions.is_empty());
+        assert_eq!(state.poh_verifier.count, block.poh_count);
+    }
+
+    #[tokio::test]
+    async fn peer_block_evicts_pending_spends_it_made_unaffordable() {
+        let (blockchain, signer) = funded_chain(100.0);
+        let peer = Blockchain::with_allocations(HashMap::from([(signer.address(), 100.0)]));
+        let ours = transfer(&signer, 60.0).await;
+        let theirs = transfer(&signer, 60.0).await;
+        blockchain.add_transaction(ours.clone()).await.unwrap();
+        peer.add_transaction(theirs).await.unwrap();
+
+        // The peer's block spends the balance our pending transfer relied on
+        let block = peer.mine_block().await.unwrap();
+        blockchain.add_block(block).await.unwrap();
+        assert!(blockchain.get_pending_transaction(&ours.hash()).await.is_none());
+        assert_eq!(blockchain.available_balance(&signer.address()).await, blockchain.get_balance(&signer.address()).await);
+
+        // Block production carries on with whatever is admitted next
+        blockchain.add_transaction(transfer(&signer, 1.0).await).await.unwrap();
+        let next = blockchain.mine_block().await.unwrap();
+        assert_eq!(next.transactions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn mined_transaction_cannot_be_replayed() {
+        let (blockchain, signer) = funded_chain(100.0);
+        let transaction = transfer(&signer, 10.0).await;
+        blockchain.add_transaction(transaction.clone()).await.unwrap();
+        blockchain.mine_block().await.unwrap();
+        let balance = blockchain.get_balance(&signer.address()).await;
+
+        // Neither the mempool nor a block may take it a second time
+        assert!(blockchain.add_transaction(transaction.clone()).await.is_err());
+        let mut state = blockchain.state.write().await;
+        let (poh_hash, poh_count) = state.poh_verifier.clone().generate_hash();
+        let mut replay = Block {
+            version: BLOCK_VERSION,
+            hash: String::new(),
+            previous_hash: state.blocks.last().unwrap().hash.clone(),
+            timestamp: Utc::now(),
+            transactions: vec![transaction],
+            poh_hash,
+            poh_count,
+        };
+        replay.hash = replay.compute_hash();
+        assert!(state.apply_block(replay).is_err());
+        assert_eq!(state.get_balance(&signer.address()), balance);
+    }
+
+    #[tokio::test]
+    async fn kinds_are_rejected_below_the_version_that_introduced_them() {
+        let (_, signer) = funded_chain(100.0);
+        let cases = [
+            (TransactionKind::Burn, 4),
+            (TransactionKind::TokenIssue { symbol: "GOLD".to_string() }, 5),
+            (TransactionKind::TokenTransfer { symbol: "GOLD".to_string() }, 5),
+            (TransactionKind::Batch { outputs: vec![] }, 6),
+        ];
+        for (kind, introduced) in cases {
+            let mut transaction = transfer(&signer, 1.0).await;
+            transaction.kind = kind;
+            transaction.version = introduced;
+            assert!(transaction.check_version().is_ok(), "{} at version {}", transaction.kind.name(), introduced);
+            transaction.version = introduced - 1;
+            assert!(transaction.check_version().is_err(), "{} at version {}", transaction.kind.name(), introduced - 1);
+        }
+    }
+}
@@ -0,0 +1,557 @@
+use std::error::Error;
+use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+
+use crate::blockchain::MIN_TRANSACTION_FEE;
+use crate::database::{Database, DatabaseConfig};
+use crate::node_client::NodeClient;
+use crate::signer::KeyScheme;
+use crate::storage::{self, BalanceMismatch, CopyReport, IntegrityReport, StorageBackend};
+use crate::wallet::{self, HistoryEntry, Metadata, Wallet};
+
+/// Command-line interface; running without a subcommand opens the interactive menu
+#[derive(Debug, Parser)]
+#[command(version, about = "Chinese Blockchain Network")]
+pub struct Cli {
+    /// Print machine-readable JSON instead of text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// Apply pending database migrations before starting
+    #[arg(long)]
+    pub migrate: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run a full node: P2P networking, chain sync and the REST API
+    Node,
+    #[command(subcommand)]
+    Wallet(WalletCommand),
+    #[command(subcommand)]
+    Db(DbCommand),
+    #[command(subcommand)]
+    Storage(StorageCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum WalletCommand {
+    /// Create a new wallet and print its recovery phrase
+    Create {
+        #[arg(long)]
+        email: String,
+        /// Prompted for when omitted, to keep it out of shell history
+        #[arg(long)]
+        pin: Option<String>,
+        #[arg(long, default_value = "ed25519")]
+        scheme: KeyScheme,
+    },
+    /// Restore a wallet from a recovery phrase or a keystore file
+    Restore {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        pin: Option<String>,
+        #[arg(long, conflicts_with = "keystore")]
+        mnemonic: Option<String>,
+        #[arg(long, requires = "password")]
+        keystore: Option<PathBuf>,
+        #[arg(long)]
+        password: Option<String>,
+        #[arg(long, default_value = "ed25519")]
+        scheme: KeyScheme,
+    },
+    /// Sync the wallet's balance across all its addresses from the node and show it
+    Balance {
+        #[arg(long)]
+        email: String,
+    },
+    /// Sign a transfer to an address or address book contact and submit it to the node
+    Send {
+        #[arg(long)]
+        email: String,
+        #[arg(long)]
+        pin: Option<String>,
+        /// Primary or receive address to spend from; defaults to the primary
+        #[arg(long)]
+        from: Option<String>,
+        #[arg(long)]
+        to: String,
+        #[arg(long)]
+        amount: f64,
+        #[arg(long, default_value_t = MIN_TRANSACTION_FEE)]
+        fee: f64,
+    },
+    /// List confirmed transactions with their labels
+    History {
+        #[arg(long)]
+        email: String,
+    },
+    /// Set the label, category or notes of the wallet, or of one of its transactions
+    Label {
+        #[arg(long)]
+        email: String,
+        /// Annotate this transaction instead of the wallet itself
+        #[arg(long)]
+        transaction: Option<String>,
+        /// Fields left out keep their current value; an empty value clears one
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        category: Option<String>,
+        #[arg(long)]
+        notes: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DbCommand {
+    /// Compare stored address balances against a recomputation from every transaction
+    CheckBalances {
+        /// Overwrite the stored balances with the recomputed ones
+        #[arg(long)]
+        repair: bool,
+    },
+    /// Write a consistent copy of the database while the node keeps running
+    Backup {
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Replace the database's contents with a backup
+    Restore {
+        #[arg(long)]
+        input: PathBuf,
+    },
+    /// Check block hashes, chain linkage and heights, and look for orphaned transactions
+    Verify,
+    /// Move wallet keystores onto the active column key after a rotation
+    ReencryptWallets {
+        /// Wallets rewritten per transaction
+        #[arg(long, default_value_t = 500)]
+        batch: usize,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum StorageCommand {
+    /// Copy everything from one storage backend into a new, empty one and compare the two
+    Migrate {
+        /// `mysql` for the server the environment configures, or a database URL
+        /// such as `sqlite:data/chain.db`
+        #[arg(long)]
+        from: String,
+        /// Same forms as --from; migrated first, and must hold no data
+        #[arg(long)]
+        to: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct WalletInfo {
+    id: String,
+    email: String,
+    address: String,
+    key_scheme: KeyScheme,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mnemonic: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BalanceInfo {
+    address: String,
+    addresses: Vec<String>,
+    balance: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct SendInfo {
+    id: String,
+    from: String,
+    to: String,
+    amount: f64,
+    fee: f64,
+    raw: String,
+}
+
+#[derive(Debug, Serialize)]
+struct MigrateInfo {
+    applied: Vec<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct BalanceCheckInfo {
+    mismatches: Vec<BalanceMismatch>,
+    repaired: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct BackupInfo {
+    path: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+struct ReencryptInfo {
+    reencrypted: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorInfo {
+    error: String,
+}
+
+// Run a subcommand, printing its result (or error) in the requested format.
+// Returns false on failure so the caller can set the exit code.
+pub async fn run(command: Command, json: bool) -> bool {
+    let result = match command {
+        Command::Node => crate::node::run().await,
+        Command::Wallet(command) => run_wallet(command, json).await,
+        Command::Db(command) => run_db(command, json),
+        Command::Storage(command) => run_storage(command, json),
+    };
+    report(result, json)
+}
+
+// Apply pending schema migrations, for the --migrate startup step
+pub fn migrate(json: bool) -> bool {
+    report(run_migrate(json), json)
+}
+
+fn report(result: Result<(), Box<dyn Error>>, json: bool) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(e) => {
+            if json {
+                println!("{}", serde_json::to_string(&ErrorInfo { error: e.to_string() }).unwrap());
+            } else {
+                eprintln!("Error: {}", e);
+            }
+            false
+        }
+    }
+}
+
+fn run_migrate(json: bool) -> Result<(), Box<dyn Error>> {
+    // Not Database::new, which refuses to open an outdated schema
+    let db = Database::connect(DatabaseConfig::from_env()?)?;
+    let applied = db.migrate()?;
+
+    print_output(json, &MigrateInfo { applied: applied.clone() }, || {
+        if applied.is_empty() {
+            "Database schema is up to date".to_string()
+        } else {
+            format!("Applied {} migration(s)", applied.len())
+        }
+    })
+}
+
+async fn run_wallet(command: WalletCommand, json: bool) -> Result<(), Box<dyn Error>> {
+    let db = Database::new(DatabaseConfig::from_env()?)?;
+
+    match command {
+        WalletCommand::Create { email, pin, scheme } => {
+            let pin = pin.unwrap_or_else(read_pin);
+            let wallet = wallet::create_wallet(email, pin, scheme)?;
+            db.save_wallet(&wallet)?;
+
+            let info = wallet_info(&wallet, Some(wallet.mnemonic()?));
+            print_output(json, &info, || {
+                format!(
+                    "Created wallet {}\nRecovery phrase (write it down):\n{}",
+                    info.address,
+                    info.mnemonic.as_deref().unwrap_or_default()
+                )
+            })
+        }
+        WalletCommand::Restore { email, pin, mnemonic, keystore, password, scheme } => {
+            let pin = pin.unwrap_or_else(read_pin);
+            let wallet = match (mnemonic, keystore) {
+                (Some(mnemonic), _) => wallet::restore_wallet(email, pin, &mnemonic, scheme)?,
+                (None, Some(path)) => {
+                    let mut wallet = Wallet::import_keystore(
+                        &std::fs::read_to_string(path)?,
+                        password.as_deref().unwrap_or_default(),
+                        &pin,
+                    )?;
+                    wallet.email = email;
+                    wallet
+                }
+                (None, None) => return Err("Either --mnemonic or --keystore is required".into()),
+            };
+            db.save_wallet(&wallet)?;
+
+            let info = wallet_info(&wallet, None);
+            print_output(json, &info, || format!("Restored wallet {}", info.address))
+        }
+        WalletCommand::Balance { email } => {
+            let wallet = db.get_wallet(&email)?.ok_or("Wallet not found")?;
+            let node = NodeClient::from_env();
+            let mut balance = 0.0;
+            for address in wallet.all_addresses() {
+                balance += node.balance(&address).await?;
+            }
+            db.update_cached_balance(&wallet.id, balance)?;
+
+            let info = BalanceInfo {
+                address: wallet.address.clone(),
+                addresses: wallet.all_addresses(),
+                balance,
+            };
+            print_output(json, &info, || format!("{}: {:.8}", info.address, info.balance))
+        }
+        WalletCommand::Send { email, pin, from, to, amount, fee } => {
+            let pin = pin.unwrap_or_else(read_pin);
+            let wallet = wallet::access_wallet(&db, email, pin, None)?;
+            let book = db.get_address_book(&wallet.id)?;
+
+            let from = from.unwrap_or_else(|| wallet.address.clone());
+            let mut transaction = wallet.build_transfer_from(&from, &to, amount, fee, Some(&book))?;
+            wallet.sign_transaction(&mut transaction).await?;
+            NodeClient::from_env().submit(&transaction).await?;
+            let info = SendInfo {
+                raw: transaction.to_raw()?,
+                id: transaction.id,
+                from: transaction.from,
+                to: transaction.to,
+                amount: transaction.amount,
+                fee: transaction.fee,
+            };
+            print_output(json, &info, || {
+                format!("Submitted transaction {} sending {} to {}", info.id, info.amount, info.to)
+            })
+        }
+        WalletCommand::History { email } => {
+            let wallet = db.get_wallet(&email)?.ok_or("Wallet not found")?;
+            let entries = wallet.annotate(db.get_transactions_for(&wallet.all_addresses())?);
+            print_output(json, &entries, || format_history(&entries))
+        }
+        WalletCommand::Label { email, transaction, label, category, notes } => {
+            let mut wallet = db.get_wallet(&email)?.ok_or("Wallet not found")?;
+            let metadata = match &transaction {
+                Some(id) => {
+                    let history = db.get_transactions_for(&wallet.all_addresses())?;
+                    if !history.iter().any(|tx| &tx.id == id) {
+                        return Err(format!("Transaction {} is not in this wallet's history", id).into());
+                    }
+                    let metadata = update_metadata(
+                        wallet.transaction_metadata.get(id).cloned().unwrap_or_default(),
+                        label,
+                        category,
+                        notes,
+                    );
+                    db.save_transaction_metadata(&wallet.id, id, &metadata)?;
+                    metadata
+                }
+                None => {
+                    wallet.metadata = update_metadata(wallet.metadata.clone(), label, category, notes);
+                    db.save_wallet_metadata(&wallet)?;
+                    wallet.metadata
+                }
+            };
+            print_output(json, &metadata, || match &transaction {
+                Some(id) => format!("Updated transaction {}", id),
+                None => format!("Updated wallet {}", wallet.address),
+            })
+        }
+    }
+}
+
+// Apply the fields given on the command line over the stored ones
+fn update_metadata(mut metadata: Metadata, label: Option<String>, category: Option<String>, notes: Option<String>) -> Metadata {
+    let set = |field: &mut Option<String>, value: Option<String>| {
+        if let Some(value) = value {
+            *field = Some(value.trim().to_string()).filter(|value| !value.is_empty());
+        }
+    };
+    set(&mut metadata.label, label);
+    set(&mut metadata.category, category);
+    set(&mut metadata.notes, notes);
+    metadata
+}
+
+fn run_db(command: DbCommand, json: bool) -> Result<(), Box<dyn Error>> {
+    let db = Database::new(DatabaseConfig::from_env()?)?;
+
+    match command {
+        DbCommand::CheckBalances { repair } => {
+            let mismatches = storage::check_balances(&*db, repair)?;
+            let info = BalanceCheckInfo { repaired: repair && !mismatches.is_empty(), mismatches };
+            print_output(json, &info, || {
+                if info.mismatches.is_empty() {
+                    return "Address balances match the stored transactions".to_string();
+                }
+                let mut lines: Vec<String> = info.mismatches.iter()
+                    .map(|m| format!("{}  stored {:.8}  recomputed {:.8}", m.address, m.stored, m.recomputed))
+                    .collect();
+                lines.push(if info.repaired {
+                    format!("Repaired {} balance(s)", info.mismatches.len())
+                } else {
+                    format!("{} balance(s) differ; run with --repair to fix them", info.mismatches.len())
+                });
+                lines.join("\n")
+            })
+        }
+        DbCommand::Backup { output } => {
+            db.backup(&output)?;
+            let info = BackupInfo { path: output };
+            print_output(json, &info, || format!("Backed up to {}", info.path.display()))
+        }
+        DbCommand::Restore { input } => {
+            db.restore(&input)?;
+            let info = BackupInfo { path: input };
+            print_output(json, &info, || format!("Restored from {}", info.path.display()))
+        }
+        DbCommand::Verify => {
+            let report = storage::verify(&*db)?;
+            print_output(json, &report, || format_integrity_report(&report))?;
+            if !report.is_ok() {
+                return Err("Database integrity check failed".into());
+            }
+            Ok(())
+        }
+        DbCommand::ReencryptWallets { batch } => {
+            if batch == 0 {
+                return Err("--batch must be at least 1".into());
+            }
+            // Small batches keep each transaction short while the node runs
+            let mut info = ReencryptInfo { reencrypted: 0 };
+            loop {
+                let count = db.reencrypt_wallets(batch)?;
+                info.reencrypted += count;
+                if count < batch {
+                    break;
+                }
+            }
+            print_output(json, &info, || format!("Re-encrypted {} wallet(s)", info.reencrypted))
+        }
+    }
+}
+
+fn run_storage(command: StorageCommand, json: bool) -> Result<(), Box<dyn Error>> {
+    match command {
+        StorageCommand::Migrate { from, to } => {
+            let source = Database::new(storage_config(&from)?)?;
+            let target = Database::connect(storage_config(&to)?)?;
+            target.migrate()?;
+
+            // Progress goes to stderr so --json output stays parseable
+            let report = storage::copy(&*source, &*target, &mut |stage, done, total| {
+                eprint!("\rCopying {}: {}/{}", stage, done, total);
+                if done == total {
+                    eprintln!();
+                }
+            })?;
+            print_output(json, &report, || format_copy_report(&report))?;
+            if !report.mismatches.is_empty() {
+                return Err("Target doesn't match the source".into());
+            }
+            Ok(())
+        }
+    }
+}
+
+// The environment's database settings, pointed at `spec`
+fn storage_config(spec: &str) -> Result<DatabaseConfig, Box<dyn Error>> {
+    let mut config = DatabaseConfig::from_env()?;
+    if spec == "mysql" {
+        config.backend = StorageBackend::Mysql;
+    } else {
+        config.apply_url(spec)?;
+    }
+    Ok(config)
+}
+
+fn format_copy_report(report: &CopyReport) -> String {
+    let mut lines = vec![format!(
+        "Copied {} wallet(s), {} peer(s), {} block(s) with {} transaction(s), {} token(s), {} order(s), \
+         {} trade(s), {} proposal(s) and {} webhook(s)",
+        report.wallets,
+        report.peers,
+        report.blocks,
+        report.transactions,
+        report.tokens,
+        report.orders,
+        report.trades,
+        report.proposals,
+        report.webhooks
+    )];
+    lines.extend(report.mismatches.iter().map(|mismatch| format!("Mismatch: {}", mismatch)));
+    if report.mismatches.is_empty() {
+        lines.push("Target matches the source".to_string());
+    }
+    lines.join("\n")
+}
+
+fn format_integrity_report(report: &IntegrityReport) -> String {
+    let mut lines = vec![format!(
+        "{} block(s), {} transaction(s), height {}",
+        report.blocks,
+        report.transactions,
+        report.height.map_or("-".to_string(), |height| height.to_string())
+    )];
+    let problems = [
+        ("Hash mismatch", &report.hash_mismatches),
+        ("Missing parent", &report.missing_parents),
+        ("Fork at", &report.forks),
+        ("Not on the main chain", &report.unreachable),
+        ("Orphaned transaction", &report.orphaned_transactions),
+    ];
+    for (label, items) in problems {
+        lines.extend(items.iter().map(|item| format!("{}: {}", label, item)));
+    }
+    if report.is_ok() {
+        lines.push("No problems found".to_string());
+    }
+    lines.join("\n")
+}
+
+fn wallet_info(wallet: &Wallet, mnemonic: Option<String>) -> WalletInfo {
+    WalletInfo {
+        id: wallet.id.clone(),
+        email: wallet.email.clone(),
+        address: wallet.address.clone(),
+        key_scheme: wallet.key_scheme,
+        mnemonic,
+    }
+}
+
+fn format_history(entries: &[HistoryEntry]) -> String {
+    if entries.is_empty() {
+        return "No transactions.".to_string();
+    }
+    entries.iter()
+        .map(|entry| {
+            let tx = &entry.transaction;
+            let mut line = format!(
+                "{}  {}  {} -> {}  {:.8} (fee {:.8})",
+                tx.timestamp.format("%Y-%m-%d %H:%M"),
+                tx.id,
+                tx.from,
+                tx.to,
+                tx.amount,
+                tx.fee
+            );
+            if let Some(label) = &entry.metadata.label {
+                line.push_str(&format!("  [{}]", label));
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn print_output<T: Serialize>(json: bool, value: &T, text: impl FnOnce() -> String) -> Result<(), Box<dyn Error>> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        println!("{}", text());
+    }
+    Ok(())
+}
+
+fn read_pin() -> String {
+    crate::prompt("PIN")
+}
@@ -2,9 +2,12 @@ use std::error::Error;
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 use std::collections::HashMap;
-use tokio::sync::RwLock;
+use std::str::FromStr;
+use tokio::sync::{broadcast, RwLock};
 use std::sync::Arc;
 
+use crate::database::Database;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Proposal {
     pub id: String,
@@ -20,7 +23,32 @@ pub struct Proposal {
     pub budget_amount: f64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+// How long a new proposal stays open for voting
+pub const VOTING_PERIOD_DAYS: i64 = 7;
+// Total vote weight a proposal needs before it can pass
+pub const DEFAULT_REQUIRED_VOTES: u64 = 100;
+
+impl Proposal {
+    // A proposal open for voting from now
+    pub fn new(title: String, description: String, creator: String, budget_amount: f64) -> Self {
+        let now = Utc::now();
+        Proposal {
+            id: uuid::Uuid::new_v4().to_string(),
+            title,
+            description,
+            creator,
+            created_at: now,
+            voting_start: now,
+            voting_end: now + chrono::Duration::days(VOTING_PERIOD_DAYS),
+            status: ProposalStatus::Active,
+            votes: HashMap::new(),
+            required_votes: DEFAULT_REQUIRED_VOTES,
+            budget_amount,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ProposalStatus {
     Draft,
     Active,
@@ -29,6 +57,33 @@ pub enum ProposalStatus {
     Executed,
 }
 
+impl ProposalStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProposalStatus::Draft => "Draft",
+            ProposalStatus::Active => "Active",
+            ProposalStatus::Passed => "Passed",
+            ProposalStatus::Failed => "Failed",
+            ProposalStatus::Executed => "Executed",
+        }
+    }
+}
+
+impl FromStr for ProposalStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Draft" => Ok(ProposalStatus::Draft),
+            "Active" => Ok(ProposalStatus::Active),
+            "Passed" => Ok(ProposalStatus::Passed),
+            "Failed" => Ok(ProposalStatus::Failed),
+            "Executed" => Ok(ProposalStatus::Executed),
+            _ => Err(format!("Unknown proposal status: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Vote {
     pub voter: String,
@@ -52,10 +107,21 @@ pub struct CommunityBudget {
     pub proposals: Vec<String>,
 }
 
+// Published when a proposal is created or its status changes; carries the
+// proposal as it is after the change
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GovernanceEvent {
+    ProposalStatusChanged(Proposal),
+}
+
 pub struct Governance {
     proposals: Arc<RwLock<HashMap<String, Proposal>>>,
     community_budget: Arc<RwLock<CommunityBudget>>,
     voting_power: Arc<RwLock<HashMap<String, u64>>>,
+    events: broadcast::Sender<GovernanceEvent>,
+    // Proposals are saved here, for search, whenever they are created or
+    // change status
+    database: Option<Arc<Database>>,
 }
 
 impl Governance {
@@ -68,24 +134,83 @@ impl Governance {
                 proposals: vec![],
             })),
             voting_power: Arc::new(RwLock::new(HashMap::new())),
+            events: broadcast::channel(1000).0,
+            database: None,
         }
     }
 
+    pub fn with_database(mut self, database: Arc<Database>) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    // Save a proposal off the async workers; nothing to do without a database
+    async fn persist(&self, proposal: &Proposal) -> Result<(), Box<dyn Error>> {
+        let database = match &self.database {
+            Some(database) => database.clone(),
+            None => return Ok(()),
+        };
+        let proposal = proposal.clone();
+        tokio::task::spawn_blocking(move || database.save_proposal(&proposal).map_err(|e| e.to_string())).await??;
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<GovernanceEvent> {
+        self.events.subscribe()
+    }
+
     pub async fn create_proposal(&self, proposal: Proposal) -> Result<(), Box<dyn Error>> {
+        self.persist(&proposal).await?;
         let mut proposals = self.proposals.write().await;
-        proposals.insert(proposal.id.clone(), proposal);
+        proposals.insert(proposal.id.clone(), proposal.clone());
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(GovernanceEvent::ProposalStatusChanged(proposal));
         Ok(())
     }
 
+    // Treasury funds not yet allocated to an executed proposal
+    pub async fn available_budget(&self) -> f64 {
+        let budget = self.community_budget.read().await;
+        budget.total_amount - budget.allocated_amount
+    }
+
+    pub async fn list_proposals(&self) -> Vec<Proposal> {
+        self.proposals.read().await.values().cloned().collect()
+    }
+
+    pub async fn get_proposal(&self, proposal_id: &str) -> Option<Proposal> {
+        self.proposals.read().await.get(proposal_id).cloned()
+    }
+
+    pub async fn budget(&self) -> CommunityBudget {
+        self.community_budget.read().await.clone()
+    }
+
+    // Record a vote on an open proposal; each voter votes once
     pub async fn cast_vote(&self, vote: Vote) -> Result<(), Box<dyn Error>> {
         let mut proposals = self.proposals.write().await;
         if let Some(proposal) = proposals.get_mut(&vote.proposal_id) {
+            if proposal.status != ProposalStatus::Active
+                || vote.timestamp < proposal.voting_start
+                || vote.timestamp > proposal.voting_end
+            {
+                return Err("Proposal is not open for voting".into());
+            }
+            if proposal.votes.contains_key(&vote.voter) {
+                return Err("Already voted on this proposal".into());
+            }
             proposal.votes.insert(vote.voter.clone(), vote);
             
             // Check if proposal has passed
             if self.check_proposal_status(proposal).await? {
+                let mut passed = proposal.clone();
+                passed.status = ProposalStatus::Passed;
+                self.persist(&passed).await?;
                 proposal.status = ProposalStatus::Passed;
+                let _ = self.events.send(GovernanceEvent::ProposalStatusChanged(proposal.clone()));
             }
+        } else {
+            return Err(format!("Unknown proposal {}", vote.proposal_id).into());
         }
         Ok(())
     }
@@ -110,11 +235,16 @@ impl Governance {
         
         if let Some(proposal) = proposals.get_mut(proposal_id) {
             if proposal.status == ProposalStatus::Passed {
+                let mut executed = proposal.clone();
+                executed.status = ProposalStatus::Executed;
+                self.persist(&executed).await?;
+
                 // Update community budget
                 budget.allocated_amount += proposal.budget_amount;
                 budget.proposals.push(proposal_id.to_string());
                 
                 proposal.status = ProposalStatus::Executed;
+                let _ = self.events.send(GovernanceEvent::ProposalStatusChanged(proposal.clone()));
             }
         }
         
@@ -0,0 +1,133 @@
+// JavaScript bindings for the REST API, built for wasm32 with wasm-pack.
+// Kept free of node internals so the library target can build it alone.
+use std::cell::RefCell;
+use js_sys::{Function, Reflect, JSON};
+use serde_json::{json, Value};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, MessageEvent, Request, RequestInit, Response, WebSocket};
+
+#[wasm_bindgen]
+pub struct BlockchainClient {
+    api_url: String,
+    // JWT from `login`, sent on authenticated requests
+    token: RefCell<Option<String>>,
+}
+
+impl BlockchainClient {
+    fn url(&self, path: &str) -> String {
+        format!("{}/api/{}", self.api_url.trim_end_matches('/'), path)
+    }
+
+    // Fetch `path` and unwrap the response envelope's data. Failures reject
+    // with an Error carrying the HTTP `status` and any validation `fields`.
+    async fn call(&self, method: &str, path: &str, body: Option<Value>) -> Result<JsValue, JsValue> {
+        let headers = Headers::new()?;
+        headers.set("content-type", "application/json")?;
+        if let Some(token) = self.token.borrow().as_ref() {
+            headers.set("authorization", &format!("Bearer {}", token))?;
+        }
+        let init = RequestInit::new();
+        init.set_method(method);
+        init.set_headers(&headers);
+        if let Some(body) = body {
+            init.set_body(&JsValue::from_str(&body.to_string()));
+        }
+
+        let request = Request::new_with_str_and_init(&self.url(path), &init)?;
+        let window = web_sys::window().ok_or_else(|| JsValue::from_str("No window available"))?;
+        let response: Response = JsFuture::from(window.fetch_with_request(&request)).await?.dyn_into()?;
+        let envelope = JsFuture::from(response.json()?).await?;
+
+        let success = Reflect::get(&envelope, &"success".into())?.as_bool().unwrap_or(false);
+        if !response.ok() || !success {
+            let message = Reflect::get(&envelope, &"error".into())?
+                .as_string()
+                .unwrap_or_else(|| format!("Request failed with status {}", response.status()));
+            let error = js_sys::Error::new(&message);
+            Reflect::set(&error, &"status".into(), &response.status().into())?;
+            Reflect::set(&error, &"fields".into(), &Reflect::get(&envelope, &"fields".into())?)?;
+            return Err(error.into());
+        }
+        Reflect::get(&envelope, &"data".into())
+    }
+}
+
+#[wasm_bindgen]
+impl BlockchainClient {
+    #[wasm_bindgen(constructor)]
+    pub fn new(api_url: String) -> Self {
+        BlockchainClient {
+            api_url,
+            token: RefCell::new(None),
+        }
+    }
+
+    #[wasm_bindgen(js_name = setToken)]
+    pub fn set_token(&self, token: Option<String>) {
+        *self.token.borrow_mut() = token;
+    }
+
+    // Resolves to {id, address, mnemonic}; the mnemonic is only shown once
+    #[wasm_bindgen(js_name = createWallet)]
+    pub async fn create_wallet(&self, email: String, pin: String) -> Result<JsValue, JsValue> {
+        self.call("POST", "wallet", Some(json!({ "email": email, "pin": pin }))).await
+    }
+
+    // Exchange wallet credentials for a token used by later calls
+    pub async fn login(&self, email: String, pin: String) -> Result<String, JsValue> {
+        let data = self.call("POST", "auth/token", Some(json!({ "email": email, "pin": pin }))).await?;
+        let token = Reflect::get(&data, &"token".into())?
+            .as_string()
+            .ok_or_else(|| JsValue::from_str("Response has no token"))?;
+        *self.token.borrow_mut() = Some(token.clone());
+        Ok(token)
+    }
+
+    #[wasm_bindgen(js_name = getBalance)]
+    pub async fn get_balance(&self, address: String) -> Result<f64, JsValue> {
+        let data = self.call("GET", &format!("wallet/balance/{}", address), None).await?;
+        Reflect::get(&data, &"balance".into())?
+            .as_f64()
+            .ok_or_else(|| JsValue::from_str("Response has no balance"))
+    }
+
+    // Resolves to {id, hash, fee}; the fee is estimated when omitted
+    pub async fn transfer(
+        &self,
+        from: String,
+        to: String,
+        amount: f64,
+        email: String,
+        pin: String,
+        fee: Option<f64>,
+    ) -> Result<JsValue, JsValue> {
+        let body = json!({ "from": from, "to": to, "amount": amount, "email": email, "pin": pin, "fee": fee });
+        self.call("POST", "transaction", Some(body)).await
+    }
+
+    // Open the notification WebSocket and call `callback` with each parsed
+    // notification. Close the returned socket to unsubscribe.
+    pub fn subscribe(&self, callback: Function) -> Result<WebSocket, JsValue> {
+        let base = self.api_url.trim_end_matches('/');
+        let url = match base.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}/ws", rest),
+            Some((_, rest)) => format!("ws://{}/ws", rest),
+            None => format!("ws://{}/ws", base),
+        };
+        let socket = WebSocket::new(&url)?;
+
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            if let Some(text) = event.data().as_string() {
+                if let Ok(notification) = JSON::parse(&text) {
+                    let _ = callback.call1(&JsValue::NULL, &notification);
+                }
+            }
+        });
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        // The socket owns the handler from here on
+        on_message.forget();
+        Ok(socket)
+    }
+}
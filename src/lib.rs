@@ -0,0 +1,9 @@
+// Library target for the client bindings, built as a Python extension
+// module or a WASM package. The node itself is the binary in main.rs.
+#[cfg(feature = "python")]
+#[path = "python_client.rs"]
+pub mod python;
+
+#[cfg(all(feature = "javascript", target_arch = "wasm32"))]
+#[path = "js_client.rs"]
+pub mod javascript;
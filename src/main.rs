@@ -2,16 +2,54 @@ mod blockchain;
 mod wallet;
 mod network;
 mod api;
+mod admin;
+mod webhook;
 mod database;
+mod storage;
+mod migrations;
+mod sqlite_storage;
+#[cfg(feature = "rocksdb")]
+mod rocksdb_storage;
 mod security;
 mod consensus;
 mod market;
 mod governance;
+mod multisig;
+mod signer;
+mod cli;
+mod node;
+mod node_client;
+mod sync;
+mod nat;
+mod mdns;
+mod wire;
+mod secure;
+#[cfg(feature = "libp2p")]
+mod p2p;
+#[cfg(any(test, feature = "simulation"))]
+mod simulation;
 
+use std::error::Error;
 use std::io::{self, Write};
+use clap::Parser;
+
+use database::{Database, DatabaseConfig};
 
 #[tokio::main]
 async fn main() {
+    // Settings in .env fill in for variables the environment doesn't set
+    dotenv::dotenv().ok();
+    let cli = cli::Cli::parse();
+    if cli.migrate && !cli::migrate(cli.json) {
+        std::process::exit(1);
+    }
+    if let Some(command) = cli.command {
+        if !cli::run(command, cli.json).await {
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("Welcome to Chinese Blockchain Network");
     println!("=====================================");
     
@@ -24,9 +62,12 @@ async fn main() {
         println!("5. Market Operations");
         println!("6. Governance");
         println!("7. Network Settings");
-        println!("8. Exit");
+        println!("8. Address Book");
+        println!("9. Offline Signing");
+        println!("10. Batch Payout");
+        println!("11. Exit");
         
-        print!("\nPlease select an option (1-8): ");
+        print!("\nPlease select an option (1-11): ");
         io::stdout().flush().unwrap();
         
         let mut input = String::new();
@@ -35,15 +76,22 @@ async fn main() {
         match input.trim() {
             "1" => {
                 println!("\nCreating new wallet...");
-                // TODO: Implement wallet creation
+                if let Err(e) = create_wallet_menu() {
+                    println!("Error: {}", e);
+                }
             }
             "2" => {
                 println!("\nAccessing existing wallet...");
-                // TODO: Implement wallet access
+                match access_wallet_menu() {
+                    Ok(wallet) => println!("Wallet {} unlocked.", wallet.address),
+                    Err(e) => println!("Error: {}", e),
+                }
             }
             "3" => {
                 println!("\nTransfer balance...");
-                // TODO: Implement balance transfer
+                if let Err(e) = transfer_menu().await {
+                    println!("Error: {}", e);
+                }
             }
             "4" => {
                 println!("\nNetwork status...");
@@ -62,6 +110,24 @@ async fn main() {
                 // TODO: Implement network settings
             }
             "8" => {
+                println!("\nAddress book...");
+                if let Err(e) = address_book_menu() {
+                    println!("Error: {}", e);
+                }
+            }
+            "9" => {
+                println!("\nOffline signing...");
+                if let Err(e) = offline_signing_menu().await {
+                    println!("Error: {}", e);
+                }
+            }
+            "10" => {
+                println!("\nBatch payout...");
+                if let Err(e) = batch_payout_menu().await {
+                    println!("Error: {}", e);
+                }
+            }
+            "11" => {
                 println!("\nExiting...");
                 break;
             }
@@ -69,3 +135,193 @@ async fn main() {
         }
     }
 }
+
+fn prompt(label: &str) -> String {
+    print!("{}: ", label);
+    io::stdout().flush().unwrap();
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).unwrap();
+    input.trim().to_string()
+}
+
+fn load_wallet(db: &Database) -> Result<wallet::Wallet, Box<dyn Error>> {
+    let email = prompt("Wallet email");
+    db.get_wallet(&email)?.ok_or_else(|| "Wallet not found".into())
+}
+
+fn create_wallet_menu() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(DatabaseConfig::from_env()?)?;
+    let email = prompt("Email");
+    let pin = prompt("PIN (7 digits)");
+    let scheme: signer::KeyScheme = match prompt("Key scheme [ed25519]").as_str() {
+        "" => signer::KeyScheme::default(),
+        scheme => scheme.parse()?,
+    };
+
+    let wallet = wallet::create_wallet(email, pin, scheme)?;
+    db.save_wallet(&wallet)?;
+    println!("Wallet created: {}", wallet.address);
+    println!("Recovery phrase (write it down):\n{}", wallet.mnemonic()?);
+    Ok(())
+}
+
+fn access_wallet_menu() -> Result<wallet::Wallet, Box<dyn Error>> {
+    let db = Database::new(DatabaseConfig::from_env()?)?;
+    let email = prompt("Wallet email");
+    let pin = prompt("PIN");
+
+    match wallet::access_wallet(&db, email.clone(), pin.clone(), None) {
+        Err(e) if e.downcast_ref::<wallet::HardwareMismatch>().is_some() => {
+            println!("This wallet is bound to another machine.");
+            println!("Enter your recovery phrase to move it to this machine.");
+            let mnemonic = prompt("Recovery phrase");
+            wallet::access_wallet(&db, email, pin, Some(&mnemonic))
+        }
+        result => result,
+    }
+}
+
+fn address_book_menu() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(DatabaseConfig::from_env()?)?;
+    let wallet = load_wallet(&db)?;
+    let mut book = db.get_address_book(&wallet.id)?;
+
+    loop {
+        println!("\n1. List Contacts");
+        println!("2. Add Contact");
+        println!("3. Remove Contact");
+        println!("4. Back");
+
+        match prompt("Select an option").as_str() {
+            "1" => {
+                if book.contacts.is_empty() {
+                    println!("No contacts saved.");
+                }
+                for (label, address) in &book.contacts {
+                    println!("{:<20} {}", label, address);
+                }
+            }
+            "2" => {
+                let label = prompt("Label");
+                let address = prompt("Address");
+                match book.add_contact(&label, &address) {
+                    Ok(()) => {
+                        db.save_contact(&wallet.id, label.trim(), &address)?;
+                        println!("Contact saved.");
+                    }
+                    Err(e) => println!("Error: {}", e),
+                }
+            }
+            "3" => {
+                let label = prompt("Label");
+                if book.remove_contact(&label).is_some() {
+                    db.delete_contact(&wallet.id, &label)?;
+                    println!("Contact removed.");
+                } else {
+                    println!("No contact named '{}'.", label);
+                }
+            }
+            "4" => return Ok(()),
+            _ => println!("Invalid option. Please try again."),
+        }
+    }
+}
+
+async fn transfer_menu() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(DatabaseConfig::from_env()?)?;
+    let email = prompt("Wallet email");
+    let pin = prompt("PIN");
+    let wallet = wallet::access_wallet(&db, email, pin, None)?;
+    let book = db.get_address_book(&wallet.id)?;
+
+    let from = match prompt("Send from [primary address]") {
+        from if from.is_empty() => wallet.address.clone(),
+        from => from,
+    };
+    let recipient = prompt("Recipient (address or contact label)");
+    let amount: f64 = prompt("Amount").parse()?;
+    let fee: f64 = prompt("Fee").parse()?;
+    let mut transaction = wallet.build_transfer_from(&from, &recipient, amount, fee, Some(&book))?;
+    wallet.sign_transaction(&mut transaction).await?;
+
+    println!("Sending {} to {}", transaction.amount, transaction.to);
+    let id = node_client::NodeClient::from_env().submit(&transaction).await?;
+    println!("Submitted transaction {}", id);
+    Ok(())
+}
+
+// Pay out a CSV of `address,amount` lines, as one batch or one
+// transaction per recipient
+async fn batch_payout_menu() -> Result<(), Box<dyn Error>> {
+    let db = Database::new(DatabaseConfig::from_env()?)?;
+    let email = prompt("Wallet email");
+    let pin = prompt("PIN");
+    let wallet = wallet::access_wallet(&db, email, pin, None)?;
+    let payouts = wallet::parse_payouts_csv(&std::fs::read_to_string(prompt("CSV file"))?)?;
+
+    println!("1. Atomic batch (one transaction)");
+    println!("2. Sequence (one transaction per recipient)");
+    let mode = match prompt("Select a mode").as_str() {
+        "1" => wallet::PayoutMode::Atomic,
+        "2" => wallet::PayoutMode::Sequence,
+        _ => return Err("Invalid mode".into()),
+    };
+    let fee: f64 = prompt("Fee per transaction").parse()?;
+
+    let transactions = wallet.build_payouts(&payouts, mode, fee)?;
+    println!("\n{}", wallet::PayoutSummary::new(mode, &transactions));
+    if !prompt("Send? [y/N]").eq_ignore_ascii_case("y") {
+        return Ok(());
+    }
+
+    // A sequence stops at the first rejection; earlier payouts stay sent
+    let node = node_client::NodeClient::from_env();
+    for mut transaction in transactions {
+        transaction.sign(&wallet).await?;
+        let id = node.submit(&transaction).await?;
+        println!("Submitted transaction {} paying {}", id, transaction.amount);
+    }
+    Ok(())
+}
+
+// Cold-wallet workflow: build on an online node, sign on an air-gapped
+// machine from a keystore file, then broadcast the signed blob
+async fn offline_signing_menu() -> Result<(), Box<dyn Error>> {
+    println!("1. Build Unsigned Transaction");
+    println!("2. Sign Transaction (offline)");
+    println!("3. Broadcast Signed Transaction");
+
+    match prompt("Select an option").as_str() {
+        "1" => {
+            let db = Database::new(DatabaseConfig::from_env()?)?;
+            let wallet = load_wallet(&db)?;
+            let book = db.get_address_book(&wallet.id)?;
+            let recipient = prompt("Recipient (address or contact label)");
+            let amount: f64 = prompt("Amount").parse()?;
+            let fee: f64 = prompt("Fee").parse()?;
+
+            let transaction = wallet.build_transfer(&recipient, amount, fee, Some(&book))?;
+            println!("\nUnsigned transaction:\n{}", transaction.to_raw()?);
+        }
+        "2" => {
+            let keystore = std::fs::read_to_string(prompt("Keystore file"))?;
+            let password = prompt("Keystore password");
+            let pin = prompt("PIN");
+            let wallet = wallet::Wallet::import_keystore(&keystore, &password, &pin)?;
+
+            let signed = wallet.sign_raw(&prompt("Unsigned transaction")).await?;
+            println!("\nSigned transaction:\n{}", signed);
+        }
+        "3" => {
+            let transaction = blockchain::Transaction::from_raw(&prompt("Signed transaction"))?;
+            if !transaction.verify_signature() {
+                return Err("Transaction signature is invalid".into());
+            }
+            let id = node_client::NodeClient::from_env().submit(&transaction).await?;
+            println!("Broadcast transaction {}", id);
+        }
+        _ => println!("Invalid option. Please try again."),
+    }
+    Ok(())
+}
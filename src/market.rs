@@ -1,10 +1,30 @@
 use std::error::Error;
+use std::str::FromStr;
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 use std::collections::HashMap;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, RwLock};
 use std::sync::Arc;
 
+use crate::database::Database;
+use crate::storage::{PageRequest, TradePage};
+
+// Order and trade amounts are whole numbers of a token's smallest unit and
+// prices whole numbers of the coin's smallest unit per token, so fills add
+// up exactly. A whole coin or token is this many units.
+pub const UNITS_PER_COIN: u64 = 100_000_000;
+
+// Coin units a buy of `amount` token units at `price` costs, rounded up
+pub fn order_cost(amount: u64, price: u64) -> u64 {
+    let units = UNITS_PER_COIN as u128;
+    u64::try_from((amount as u128 * price as u128 + units - 1) / units).unwrap_or(u64::MAX)
+}
+
+// A chain balance in whole units, rounded down
+pub fn to_units(balance: f64) -> u64 {
+    (balance * UNITS_PER_COIN as f64).floor() as u64
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Token {
     pub symbol: String,
@@ -20,19 +40,95 @@ pub struct Order {
     pub user_id: String,
     pub token_symbol: String,
     pub order_type: OrderType,
-    pub amount: f64,
-    pub price: f64,
+    pub amount: u64,
+    pub price: u64,
     pub timestamp: DateTime<Utc>,
     pub status: OrderStatus,
+    // Amount matched so far; the order is Filled once this reaches `amount`
+    #[serde(default)]
+    pub filled: u64,
+}
+
+impl Order {
+    pub fn remaining(&self) -> u64 {
+        self.amount.saturating_sub(self.filled)
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(self.status, OrderStatus::Pending)
+    }
+
+    // What the rest of the order would spend: coin units for a buy, token
+    // units for a sell
+    pub fn committed(&self) -> u64 {
+        match self.order_type {
+            OrderType::Buy => order_cost(self.remaining(), self.price),
+            OrderType::Sell => self.remaining(),
+        }
+    }
 }
 
+// A match between a buy and a sell order, at the resting order's price
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trade {
+    pub id: String,
+    pub token_symbol: String,
+    pub buy_order_id: String,
+    pub sell_order_id: String,
+    pub buyer: String,
+    pub seller: String,
+    pub amount: u64,
+    pub price: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+// Open amount at one price
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceLevel {
+    pub price: u64,
+    pub amount: u64,
+    pub orders: usize,
+}
+
+// Open orders for a token aggregated by price; bids highest first, asks
+// lowest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderBook {
+    pub token_symbol: String,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum OrderType {
     Buy,
     Sell,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl OrderType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderType::Buy => "Buy",
+            OrderType::Sell => "Sell",
+        }
+    }
+}
+
+impl FromStr for OrderType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Buy" => Ok(OrderType::Buy),
+            "Sell" => Ok(OrderType::Sell),
+            _ => Err(format!("Unknown order type: {}", s)),
+        }
+    }
+}
+
+// Orders start Pending and move to exactly one of the other states, after
+// which they never change again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum OrderStatus {
     Pending,
     Filled,
@@ -40,6 +136,31 @@ pub enum OrderStatus {
     Failed,
 }
 
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "Pending",
+            OrderStatus::Filled => "Filled",
+            OrderStatus::Cancelled => "Cancelled",
+            OrderStatus::Failed => "Failed",
+        }
+    }
+}
+
+impl FromStr for OrderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Pending" => Ok(OrderStatus::Pending),
+            "Filled" => Ok(OrderStatus::Filled),
+            "Cancelled" => Ok(OrderStatus::Cancelled),
+            "Failed" => Ok(OrderStatus::Failed),
+            _ => Err(format!("Unknown order status: {}", s)),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SmartContract {
     pub id: String,
@@ -50,10 +171,26 @@ pub struct SmartContract {
     pub last_executed: DateTime<Utc>,
 }
 
+// Published after order book changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MarketEvent {
+    OrderPlaced(Order),
+    OrderFilled(Order),
+    OrderCancelled(Order),
+    TradeExecuted(Trade),
+    // A token's price moved; carries the token after the change
+    PriceUpdated(Token),
+}
+
 pub struct Market {
     tokens: Arc<RwLock<HashMap<String, Token>>>,
     orders: Arc<RwLock<HashMap<String, Order>>>,
+    trades: Arc<RwLock<Vec<Trade>>>,
     contracts: Arc<RwLock<HashMap<String, SmartContract>>>,
+    events: broadcast::Sender<MarketEvent>,
+    // Tokens, orders and trades are written here before they change in
+    // memory, so a failed write leaves the market as it was
+    database: Option<Arc<Database>>,
 }
 
 impl Market {
@@ -61,35 +198,301 @@ impl Market {
         Market {
             tokens: Arc::new(RwLock::new(HashMap::new())),
             orders: Arc::new(RwLock::new(HashMap::new())),
+            trades: Arc::new(RwLock::new(Vec::new())),
             contracts: Arc::new(RwLock::new(HashMap::new())),
+            events: broadcast::channel(1000).0,
+            database: None,
         }
     }
 
+    pub fn with_database(mut self, database: Arc<Database>) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    // Load the tokens and open orders saved by a previous run from the
+    // market's database. Returns how many orders were restored.
+    pub async fn restore(&self) -> Result<usize, Box<dyn Error>> {
+        let database = self.database.clone().ok_or("Market has no database to restore from")?;
+        let (tokens, orders) = tokio::task::spawn_blocking(move || {
+            let load = || -> Result<_, Box<dyn Error>> { Ok((database.get_tokens()?, database.get_open_orders(None)?)) };
+            load().map_err(|e| e.to_string())
+        }).await??;
+        let count = orders.len();
+        self.tokens.write().await.extend(tokens.into_iter().map(|token| (token.symbol.clone(), token)));
+        self.orders.write().await.extend(orders.into_iter().map(|order| (order.id.clone(), order)));
+        Ok(count)
+    }
+
+    // Run a storage write off the async workers; nothing to do without a database
+    async fn persist(
+        &self,
+        write: impl FnOnce(&Database) -> Result<(), Box<dyn Error>> + Send + 'static,
+    ) -> Result<(), Box<dyn Error>> {
+        let database = match &self.database {
+            Some(database) => database.clone(),
+            None => return Ok(()),
+        };
+        tokio::task::spawn_blocking(move || write(&database).map_err(|e| e.to_string())).await??;
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MarketEvent> {
+        self.events.subscribe()
+    }
+
+    // Register a new token; a symbol can only be registered once
     pub async fn add_token(&self, token: Token) -> Result<(), Box<dyn Error>> {
         let mut tokens = self.tokens.write().await;
+        if tokens.contains_key(&token.symbol) {
+            return Err(format!("Token {} is already registered", token.symbol).into());
+        }
+        let saved = token.clone();
+        self.persist(move |database| database.save_token(&saved)).await?;
         tokens.insert(token.symbol.clone(), token);
         Ok(())
     }
 
+    pub async fn get_token(&self, symbol: &str) -> Option<Token> {
+        self.tokens.read().await.get(symbol).cloned()
+    }
+
     pub async fn update_token_price(&self, symbol: &str, new_price: f64) -> Result<(), Box<dyn Error>> {
         let mut tokens = self.tokens.write().await;
         if let Some(token) = tokens.get_mut(symbol) {
-            token.current_price = new_price;
-            token.last_updated = Utc::now();
+            let mut updated = token.clone();
+            updated.current_price = new_price;
+            updated.last_updated = Utc::now();
+            let saved = updated.clone();
+            self.persist(move |database| database.save_token(&saved)).await?;
+            *token = updated;
+            let _ = self.events.send(MarketEvent::PriceUpdated(token.clone()));
         }
         Ok(())
     }
 
-    pub async fn place_order(&self, order: Order) -> Result<(), Box<dyn Error>> {
+    // Accept an order if, with the user's other open orders, it commits no
+    // more than `available`: coin units for a buy, or units of the token
+    // for a sell. Orders don't lock anything on chain, so this is checked
+    // against the balance when the order is placed.
+    pub async fn place_order(&self, order: Order, available: u64) -> Result<(), Box<dyn Error>> {
         let mut orders = self.orders.write().await;
-        orders.insert(order.id.clone(), order);
+        let committed: u64 = orders.values()
+            .filter(|open| open.is_open() && open.user_id == order.user_id && open.order_type == order.order_type)
+            .filter(|open| order.order_type == OrderType::Buy || open.token_symbol == order.token_symbol)
+            .map(Order::committed)
+            .sum();
+        if committed.saturating_add(order.committed()) > available {
+            return Err(format!(
+                "Insufficient balance: order needs {} units with {} already committed, {} available",
+                order.committed(), committed, available
+            ).into());
+        }
+        let saved = order.clone();
+        self.persist(move |database| database.save_order(&saved)).await?;
+        orders.insert(order.id.clone(), order.clone());
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(MarketEvent::OrderPlaced(order));
         Ok(())
     }
 
+    pub async fn list_orders(&self) -> Vec<Order> {
+        self.orders.read().await.values().cloned().collect()
+    }
+
+    pub async fn get_order(&self, order_id: &str) -> Option<Order> {
+        self.orders.read().await.get(order_id).cloned()
+    }
+
+    pub async fn open_orders(&self, user_id: &str) -> Vec<Order> {
+        self.orders.read().await.values()
+            .filter(|order| order.user_id == user_id && order.is_open())
+            .cloned()
+            .collect()
+    }
+
+    // Trades the user took either side of, oldest first. Without a
+    // database only this run's trades are known.
+    pub async fn trades_for(&self, user_id: &str) -> Result<Vec<Trade>, Box<dyn Error>> {
+        if let Some(database) = self.database.clone() {
+            let user_id = user_id.to_string();
+            return Ok(tokio::task::spawn_blocking(move || {
+                database.get_user_trades(&user_id).map_err(|e| e.to_string())
+            }).await??);
+        }
+
+        Ok(self.trades.read().await.iter()
+            .filter(|trade| trade.buyer == user_id || trade.seller == user_id)
+            .cloned()
+            .collect())
+    }
+
+    // A token's trades, newest first. Without a database only this run's
+    // trades are known.
+    pub async fn trade_history(&self, symbol: &str, page: &PageRequest) -> Result<TradePage, Box<dyn Error>> {
+        if let Some(database) = self.database.clone() {
+            let (symbol, page) = (symbol.to_string(), page.clone());
+            return Ok(tokio::task::spawn_blocking(move || {
+                database.get_trades(&symbol, &page).map_err(|e| e.to_string())
+            }).await??);
+        }
+
+        let cursor = page.cursor()?;
+        let mut trades: Vec<Trade> = self.trades.read().await.iter()
+            .filter(|trade| trade.token_symbol == symbol)
+            .filter(|trade| cursor.as_ref().map_or(true, |cursor| (trade.timestamp, &trade.id) < (cursor.timestamp, &cursor.id)))
+            .cloned()
+            .collect();
+        trades.sort_by(|a, b| (b.timestamp, &b.id).cmp(&(a.timestamp, &a.id)));
+        trades.truncate(page.limit() + 1);
+        Ok(TradePage::from_rows(trades, page.limit()))
+    }
+
+    // Cancel an open order on behalf of its owner
+    pub async fn cancel_order(&self, order_id: &str, user_id: &str) -> Result<Order, Box<dyn Error>> {
+        let mut orders = self.orders.write().await;
+        let order = orders.get_mut(order_id)
+            .filter(|order| order.user_id == user_id)
+            .ok_or_else(|| format!("Unknown order {}", order_id))?;
+        if !order.is_open() {
+            return Err(format!("Order {} is no longer open", order_id).into());
+        }
+        let mut cancelled = order.clone();
+        cancelled.status = OrderStatus::Cancelled;
+        let saved = cancelled.clone();
+        self.persist(move |database| database.update_order(&saved)).await?;
+        *order = cancelled;
+        let _ = self.events.send(MarketEvent::OrderCancelled(order.clone()));
+        Ok(order.clone())
+    }
+
+    pub async fn order_book(&self, symbol: &str) -> OrderBook {
+        let orders = self.orders.read().await;
+        let levels = |order_type: OrderType| {
+            let mut levels: Vec<PriceLevel> = Vec::new();
+            for order in orders.values().filter(|o| o.token_symbol == symbol && o.order_type == order_type && o.is_open()) {
+                match levels.iter_mut().find(|level| level.price == order.price) {
+                    Some(level) => {
+                        level.amount += order.remaining();
+                        level.orders += 1;
+                    }
+                    None => levels.push(PriceLevel { price: order.price, amount: order.remaining(), orders: 1 }),
+                }
+            }
+            levels
+        };
+
+        let mut bids = levels(OrderType::Buy);
+        let mut asks = levels(OrderType::Sell);
+        bids.sort_by(|a, b| b.price.cmp(&a.price));
+        asks.sort_by(|a, b| a.price.cmp(&b.price));
+        OrderBook { token_symbol: symbol.to_string(), bids, asks }
+    }
+
+    // Match crossing buy and sell orders for a token by price, then time.
+    // Each trade executes at the price of the order that was resting first,
+    // and the last trade sets the token's price. A user's orders never
+    // trade with each other; the newer of the two is cancelled instead.
+    pub async fn match_orders(&self, symbol: &str) -> Result<Vec<Trade>, Box<dyn Error>> {
+        let mut orders = self.orders.write().await;
+        // Matching works on a copy of the token's book, which replaces the
+        // live orders once the trades are stored
+        let mut book: HashMap<String, Order> = orders.values()
+            .filter(|order| order.token_symbol == symbol && order.is_open())
+            .map(|order| (order.id.clone(), order.clone()))
+            .collect();
+        let mut trades = Vec::new();
+        let mut cancelled = Vec::new();
+        let mut events = Vec::new();
+
+        loop {
+            let open = |order_type: OrderType| {
+                book.values()
+                    .filter(move |o| o.order_type == order_type && o.is_open())
+            };
+            let bid = open(OrderType::Buy)
+                .max_by(|a, b| a.price.cmp(&b.price).then(b.timestamp.cmp(&a.timestamp)))
+                .cloned();
+            let ask = open(OrderType::Sell)
+                .min_by(|a, b| a.price.cmp(&b.price).then(a.timestamp.cmp(&b.timestamp)))
+                .cloned();
+            let (bid, ask) = match (bid, ask) {
+                (Some(bid), Some(ask)) if bid.price >= ask.price => (bid, ask),
+                _ => break,
+            };
+            if bid.user_id == ask.user_id {
+                let newer = if (bid.timestamp, &bid.id) > (ask.timestamp, &ask.id) { &bid.id } else { &ask.id };
+                if let Some(order) = book.get_mut(newer) {
+                    order.status = OrderStatus::Cancelled;
+                    events.push(MarketEvent::OrderCancelled(order.clone()));
+                    cancelled.push(order.id.clone());
+                }
+                continue;
+            }
+
+            let trade = Trade {
+                id: uuid::Uuid::new_v4().to_string(),
+                token_symbol: symbol.to_string(),
+                buy_order_id: bid.id.clone(),
+                sell_order_id: ask.id.clone(),
+                buyer: bid.user_id.clone(),
+                seller: ask.user_id.clone(),
+                amount: bid.remaining().min(ask.remaining()),
+                price: if bid.timestamp <= ask.timestamp { bid.price } else { ask.price },
+                timestamp: Utc::now(),
+            };
+            for id in [&bid.id, &ask.id] {
+                if let Some(order) = book.get_mut(id) {
+                    order.filled += trade.amount;
+                    if order.remaining() == 0 {
+                        order.status = OrderStatus::Filled;
+                        events.push(MarketEvent::OrderFilled(order.clone()));
+                    }
+                }
+            }
+            events.push(MarketEvent::TradeExecuted(trade.clone()));
+            trades.push(trade);
+        }
+        if trades.is_empty() && cancelled.is_empty() {
+            return Ok(trades);
+        }
+
+        let changed: Vec<Order> = book.into_values()
+            .filter(|order| {
+                cancelled.contains(&order.id)
+                    || trades.iter().any(|trade| trade.buy_order_id == order.id || trade.sell_order_id == order.id)
+            })
+            .collect();
+        let (saved_trades, saved_orders) = (trades.clone(), changed.clone());
+        self.persist(move |database| database.save_trades(&saved_trades, &saved_orders)).await?;
+        for order in changed {
+            orders.insert(order.id.clone(), order);
+        }
+        drop(orders);
+        for event in events {
+            let _ = self.events.send(event);
+        }
+
+        if let Some(last) = trades.last() {
+            self.update_token_price(symbol, last.price as f64 / UNITS_PER_COIN as f64).await?;
+        }
+        self.trades.write().await.extend(trades.iter().cloned());
+        Ok(trades)
+    }
+
     pub async fn execute_order(&self, order_id: &str) -> Result<(), Box<dyn Error>> {
         let mut orders = self.orders.write().await;
         if let Some(order) = orders.get_mut(order_id) {
-            order.status = OrderStatus::Filled;
+            if !order.is_open() {
+                return Err(format!("Order {} is no longer open", order_id).into());
+            }
+            let mut filled = order.clone();
+            filled.status = OrderStatus::Filled;
+            filled.filled = filled.amount;
+            let saved = filled.clone();
+            self.persist(move |database| database.update_order(&saved)).await?;
+            *order = filled;
+            let _ = self.events.send(MarketEvent::OrderFilled(order.clone()));
         }
         Ok(())
     }
@@ -151,19 +554,28 @@ impl DecentralizedExchange {
         }
     }
 
-    pub async fn add_to_order_book(&self, order: Order) -> Result<(), Box<dyn Error>> {
-        let mut order_book = self.order_book.write().await;
-        let orders = order_book.entry(order.token_symbol.clone()).or_insert_with(Vec::new);
-        orders.push(order);
-        Ok(())
+    // Place an order and match it against the book straight away.
+    // `available` is as for `Market::place_order`.
+    pub async fn add_to_order_book(&self, order: Order, available: u64) -> Result<Vec<Trade>, Box<dyn Error>> {
+        let symbol = order.token_symbol.clone();
+        self.market.place_order(order.clone(), available).await?;
+        self.order_book.write().await.entry(symbol.clone()).or_insert_with(Vec::new).push(order);
+        self.match_orders(&symbol).await
     }
 
-    pub async fn match_orders(&self, token_symbol: &str) -> Result<(), Box<dyn Error>> {
-        let mut order_book = self.order_book.write().await;
-        if let Some(orders) = order_book.get_mut(token_symbol) {
-            // TODO: Implement order matching algorithm
+    pub async fn match_orders(&self, token_symbol: &str) -> Result<Vec<Trade>, Box<dyn Error>> {
+        let trades = self.market.match_orders(token_symbol).await?;
+        // Keep only orders that can still trade
+        if let Some(orders) = self.order_book.write().await.get_mut(token_symbol) {
+            let mut open = Vec::new();
+            for order in orders.drain(..) {
+                if let Some(order) = self.market.get_order(&order.id).await.filter(Order::is_open) {
+                    open.push(order);
+                }
+            }
+            *orders = open;
         }
-        Ok(())
+        Ok(trades)
     }
 }
 
@@ -186,4 +598,46 @@ impl ContractVM {
         // TODO: Implement contract execution
         Ok(())
     }
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::test_databases;
+
+    fn order(user_id: &str, order_type: OrderType) -> Order {
+        Order {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            token_symbol: "TKN".to_string(),
+            order_type,
+            amount: 10,
+            price: UNITS_PER_COIN,
+            timestamp: Utc::now(),
+            status: OrderStatus::Pending,
+            filled: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn user_trades_are_listed_after_a_restart() {
+        for database in test_databases() {
+            let market = Market::new().with_database(database.shared());
+            market.place_order(order("alice", OrderType::Sell), u64::MAX).await.unwrap();
+            market.place_order(order("bob", OrderType::Buy), u64::MAX).await.unwrap();
+            let trades = market.match_orders("TKN").await.unwrap();
+            assert_eq!(trades.len(), 1);
+
+            let restarted = Market::new().with_database(database.shared());
+            restarted.restore().await.unwrap();
+            for user in ["alice", "bob"] {
+                let listed: Vec<String> = restarted.trades_for(user).await.unwrap()
+                    .into_iter()
+                    .map(|trade| trade.id)
+                    .collect();
+                assert_eq!(listed, vec![trades[0].id.clone()]);
+            }
+            assert!(restarted.trades_for("carol").await.unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+// DNS-SD service type nodes advertise on the local network
+const SERVICE_TYPE: &str = "_rbn._tcp.local.";
+
+// Advertises this node over multicast DNS and reports other nodes on the
+// same LAN. Only peers announcing the same chain id are reported.
+pub struct LocalDiscovery {
+    daemon: ServiceDaemon,
+    fullname: String,
+    chain_id: String,
+}
+
+impl LocalDiscovery {
+    pub fn start(chain_id: &str, listen_port: u16) -> Result<Self, Box<dyn Error>> {
+        let daemon = ServiceDaemon::new()?;
+        let instance = uuid::Uuid::new_v4().simple().to_string();
+        let host_name = format!("{}.local.", instance);
+        let properties = [("chain_id", chain_id)];
+        let service = ServiceInfo::new(SERVICE_TYPE, &instance, &host_name, "", listen_port, &properties[..])?
+            .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service)?;
+
+        Ok(LocalDiscovery {
+            daemon,
+            fullname,
+            chain_id: chain_id.to_string(),
+        })
+    }
+
+    // Browse for other nodes, calling `found` with the addresses of each one
+    // as it is resolved. Runs until the daemon shuts down.
+    pub async fn run<F>(&self, mut found: F) -> Result<(), Box<dyn Error>>
+    where
+        F: FnMut(Vec<SocketAddr>),
+    {
+        let events = self.daemon.browse(SERVICE_TYPE)?;
+        while let Ok(event) = events.recv_async().await {
+            if let ServiceEvent::ServiceResolved(info) = event {
+                if info.get_fullname() == self.fullname {
+                    continue;
+                }
+                if info.get_property_val_str("chain_id") != Some(self.chain_id.as_str()) {
+                    continue;
+                }
+                let port = info.get_port();
+                let addresses = info.get_addresses()
+                    .iter()
+                    .map(|ip| SocketAddr::new((*ip).into(), port))
+                    .collect();
+                found(addresses);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LocalDiscovery {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
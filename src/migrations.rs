@@ -0,0 +1,260 @@
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use chrono::{DateTime, Utc};
+
+use crate::storage::Storage;
+
+// One step of a backend's schema. Applied steps are recorded with their
+// checksum, so editing a migration after release is caught at startup;
+// schema changes go in a new migration instead.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    // SQL for the SQL backends. RocksDB steps are code in its
+    // `apply_migration`, so this only describes them.
+    pub script: &'static str,
+}
+
+impl Migration {
+    pub fn checksum(&self) -> String {
+        hex::encode(Sha256::digest(self.script.as_bytes()))
+    }
+}
+
+// A row of the schema_version table
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub name: String,
+    pub checksum: String,
+    pub applied_at: DateTime<Utc>,
+}
+
+impl AppliedMigration {
+    pub fn new(migration: &Migration) -> Self {
+        AppliedMigration {
+            version: migration.version,
+            name: migration.name.to_string(),
+            checksum: migration.checksum(),
+            applied_at: Utc::now(),
+        }
+    }
+}
+
+pub const MYSQL_MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", script: include_str!("../migrations/mysql/0001_initial.sql") },
+    Migration {
+        version: 2,
+        name: "wallet_and_transaction_fields",
+        script: include_str!("../migrations/mysql/0002_wallet_and_transaction_fields.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "transaction_address_indexes",
+        script: include_str!("../migrations/mysql/0003_transaction_address_indexes.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "address_balances",
+        script: include_str!("../migrations/mysql/0004_address_balances.sql"),
+    },
+    Migration { version: 5, name: "market", script: include_str!("../migrations/mysql/0005_market.sql") },
+    Migration { version: 6, name: "peers", script: include_str!("../migrations/mysql/0006_peers.sql") },
+    Migration {
+        version: 7,
+        name: "transaction_positions",
+        script: include_str!("../migrations/mysql/0007_transaction_positions.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "chain_metadata",
+        script: include_str!("../migrations/mysql/0008_chain_metadata.sql"),
+    },
+    Migration { version: 9, name: "search", script: include_str!("../migrations/mysql/0009_search.sql") },
+    Migration {
+        version: 10,
+        name: "typed_columns",
+        script: include_str!("../migrations/mysql/0010_typed_columns.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "multisig_witnesses",
+        script: include_str!("../migrations/mysql/0011_multisig_witnesses.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "block_order",
+        script: include_str!("../migrations/mysql/0012_block_order.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "timestamp_nanos",
+        script: include_str!("../migrations/mysql/0013_timestamp_nanos.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "block_heights",
+        script: include_str!("../migrations/mysql/0014_block_heights.sql"),
+    },
+    Migration { version: 15, name: "webhooks", script: include_str!("../migrations/mysql/0015_webhooks.sql") },
+    Migration {
+        version: 16,
+        name: "market_units",
+        script: include_str!("../migrations/mysql/0016_market_units.sql"),
+    },
+];
+
+pub const SQLITE_MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", script: include_str!("../migrations/sqlite/0001_initial.sql") },
+    Migration {
+        version: 2,
+        name: "wallet_and_transaction_fields",
+        script: include_str!("../migrations/sqlite/0002_wallet_and_transaction_fields.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "transaction_address_indexes",
+        script: include_str!("../migrations/sqlite/0003_transaction_address_indexes.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "address_balances",
+        script: include_str!("../migrations/sqlite/0004_address_balances.sql"),
+    },
+    Migration { version: 5, name: "market", script: include_str!("../migrations/sqlite/0005_market.sql") },
+    Migration { version: 6, name: "peers", script: include_str!("../migrations/sqlite/0006_peers.sql") },
+    Migration {
+        version: 7,
+        name: "transaction_positions",
+        script: include_str!("../migrations/sqlite/0007_transaction_positions.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "chain_metadata",
+        script: include_str!("../migrations/sqlite/0008_chain_metadata.sql"),
+    },
+    Migration { version: 9, name: "search", script: include_str!("../migrations/sqlite/0009_search.sql") },
+    Migration {
+        version: 10,
+        name: "typed_columns",
+        script: include_str!("../migrations/sqlite/0010_typed_columns.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "multisig_witnesses",
+        script: include_str!("../migrations/sqlite/0011_multisig_witnesses.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "block_order",
+        script: include_str!("../migrations/sqlite/0012_block_order.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "block_heights",
+        script: include_str!("../migrations/sqlite/0013_block_heights.sql"),
+    },
+    Migration { version: 14, name: "webhooks", script: include_str!("../migrations/sqlite/0014_webhooks.sql") },
+    Migration {
+        version: 15,
+        name: "market_units",
+        script: include_str!("../migrations/sqlite/0015_market_units.sql"),
+    },
+];
+
+pub const ROCKSDB_MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", script: "create column families" },
+    Migration { version: 2, name: "address_balances", script: "compute address balances from stored transactions" },
+    Migration { version: 3, name: "market", script: "create tokens, orders and trades column families" },
+    Migration { version: 4, name: "peers", script: "create peer_bans column family" },
+    Migration {
+        version: 5,
+        name: "transaction_positions",
+        script: "record transaction positions in block_transactions values",
+    },
+    Migration {
+        version: 6,
+        name: "chain_metadata",
+        script: "replace the latest block pointer with the chain state",
+    },
+    Migration { version: 7, name: "search", script: "create proposals column family" },
+    Migration { version: 8, name: "block_order", script: "index blocks by timestamp in block_order" },
+    Migration {
+        version: 9,
+        name: "block_heights",
+        script: "record block heights in block_heights and index blocks by height in block_order",
+    },
+    Migration { version: 10, name: "webhooks", script: "create webhooks column family" },
+    Migration {
+        version: 11,
+        name: "market_units",
+        script: "store order and trade amounts and prices as whole 10^-8 units",
+    },
+];
+
+// Check what has been applied against what this build knows about and
+// return the migrations still to run, oldest first
+pub fn pending<'a>(
+    available: &'a [Migration],
+    applied: &[AppliedMigration],
+) -> Result<Vec<&'a Migration>, Box<dyn Error>> {
+    for record in applied {
+        let migration = available.iter()
+            .find(|migration| migration.version == record.version)
+            .ok_or_else(|| format!(
+                "Database has migration {} ({}) which this node doesn't know; it was written by a newer version",
+                record.version, record.name
+            ))?;
+        if migration.checksum() != record.checksum {
+            return Err(format!(
+                "Migration {} ({}) was changed after it was applied",
+                record.version, record.name
+            ).into());
+        }
+    }
+
+    let latest = applied.iter().map(|record| record.version).max().unwrap_or(0);
+    let mut pending = vec![];
+    for migration in available {
+        if applied.iter().any(|record| record.version == migration.version) {
+            continue;
+        }
+        if migration.version < latest {
+            return Err(format!(
+                "Migration {} ({}) is missing but later migrations were applied",
+                migration.version, migration.name
+            ).into());
+        }
+        pending.push(migration);
+    }
+    pending.sort_by_key(|migration| migration.version);
+    Ok(pending)
+}
+
+// Apply every pending migration in order, stopping at the first failure.
+// Returns the versions applied.
+pub fn migrate(storage: &dyn Storage) -> Result<Vec<u32>, Box<dyn Error>> {
+    let applied = storage.applied_migrations()?;
+    let mut versions = vec![];
+    for migration in pending(storage.migrations(), &applied)? {
+        storage.apply_migration(migration)
+            .map_err(|e| format!("Migration {} ({}) failed: {}", migration.version, migration.name, e))?;
+        versions.push(migration.version);
+    }
+    Ok(versions)
+}
+
+// A backup only restores onto a database with exactly the migrations it was
+// taken at, since its rows follow that schema
+pub fn check_backup_schema(backup: &[u32], current: &[u32]) -> Result<(), Box<dyn Error>> {
+    if backup != current {
+        return Err(format!(
+            "Backup was taken at schema version {} but the database is at {}",
+            backup.last().unwrap_or(&0),
+            current.last().unwrap_or(&0)
+        ).into());
+    }
+    Ok(())
+}
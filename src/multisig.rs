@@ -0,0 +1,152 @@
+use std::error::Error;
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+
+use crate::blockchain::Transaction;
+use crate::signer::{KeyScheme, Signer};
+
+// m-of-n spending policy shared by the co-signers of a multisig wallet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultisigPolicy {
+    pub threshold: u8,
+    pub public_keys: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultisigSignature {
+    #[serde(default)]
+    pub scheme: KeyScheme,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+// Signatures attached to a transaction spending from a multisig address.
+// Not part of the transaction's canonical bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MultisigWitness {
+    pub policy: MultisigPolicy,
+    pub signatures: Vec<MultisigSignature>,
+}
+
+// A transaction that is collecting co-signer signatures before broadcast
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartiallySignedTransaction {
+    pub transaction: Transaction,
+    pub policy: MultisigPolicy,
+    pub signatures: Vec<MultisigSignature>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: u8, mut public_keys: Vec<Vec<u8>>) -> Result<Self, Box<dyn Error>> {
+        // Sort so every co-signer derives the same address regardless of key order
+        public_keys.sort();
+        public_keys.dedup();
+
+        if threshold == 0 || threshold as usize > public_keys.len() {
+            return Err("Threshold must be between 1 and the number of keys".into());
+        }
+        // Co-signers may use different key schemes
+        for key in &public_keys {
+            if !KeyScheme::ALL.iter().any(|scheme| scheme.is_valid_public_key(key)) {
+                return Err("Invalid co-signer public key".into());
+            }
+        }
+
+        Ok(MultisigPolicy { threshold, public_keys })
+    }
+
+    pub fn address(&self) -> String {
+        let mut material = vec![self.threshold];
+        for key in &self.public_keys {
+            material.extend_from_slice(key);
+        }
+        crate::wallet::encode_address(&material)
+    }
+
+    pub fn contains(&self, public_key: &[u8]) -> bool {
+        self.public_keys.iter().any(|key| key == public_key)
+    }
+}
+
+impl MultisigWitness {
+    // Count distinct policy members with a valid signature over the transaction
+    pub fn valid_signatures(&self, transaction: &Transaction) -> usize {
+        let message = transaction.canonical_bytes();
+        let mut signers = HashSet::new();
+
+        for entry in &self.signatures {
+            if !self.policy.contains(&entry.public_key) || signers.contains(&entry.public_key) {
+                continue;
+            }
+            if entry.scheme.verify(&message, &entry.public_key, &entry.signature) {
+                signers.insert(entry.public_key.clone());
+            }
+        }
+
+        signers.len()
+    }
+
+    pub fn verify(&self, transaction: &Transaction) -> bool {
+        self.policy.address() == transaction.from
+            && self.valid_signatures(transaction) >= self.policy.threshold as usize
+    }
+}
+
+impl PartiallySignedTransaction {
+    pub fn new(transaction: Transaction, policy: MultisigPolicy) -> Result<Self, Box<dyn Error>> {
+        if transaction.from != policy.address() {
+            return Err("Transaction does not spend from the multisig address".into());
+        }
+        Ok(PartiallySignedTransaction {
+            transaction,
+            policy,
+            signatures: vec![],
+        })
+    }
+
+    pub fn add_signature(
+        &mut self,
+        scheme: KeyScheme,
+        public_key: Vec<u8>,
+        signature: Vec<u8>,
+    ) -> Result<(), Box<dyn Error>> {
+        if !self.policy.contains(&public_key) {
+            return Err("Signer is not part of the multisig policy".into());
+        }
+        if !scheme.verify(&self.transaction.canonical_bytes(), &public_key, &signature) {
+            return Err("Invalid signature".into());
+        }
+
+        self.signatures.retain(|entry| entry.public_key != public_key);
+        self.signatures.push(MultisigSignature { scheme, public_key, signature });
+        Ok(())
+    }
+
+    // Sign with one co-signer's key, wherever that key lives
+    pub async fn sign_with(&mut self, signer: &dyn Signer) -> Result<(), Box<dyn Error>> {
+        let signature = signer.sign(&self.transaction.canonical_bytes()).await?;
+        self.add_signature(signer.scheme(), signer.public_key(), signature)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.signatures.len() >= self.policy.threshold as usize
+    }
+
+    // Attach the collected signatures and return a transaction ready to submit
+    pub fn finalize(self) -> Result<Transaction, Box<dyn Error>> {
+        if !self.is_complete() {
+            return Err(format!(
+                "Only {} of {} required signatures collected",
+                self.signatures.len(),
+                self.policy.threshold
+            ).into());
+        }
+
+        let mut transaction = self.transaction;
+        transaction.multisig = Some(MultisigWitness {
+            policy: self.policy,
+            signatures: self.signatures,
+        });
+        Ok(transaction)
+    }
+}
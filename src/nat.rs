@@ -0,0 +1,66 @@
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use igd_next::aio::tokio::{search_gateway, Tokio};
+use igd_next::aio::Gateway;
+use igd_next::{PortMappingProtocol, SearchOptions};
+use tokio::net::UdpSocket;
+
+// Lease requested from the router; mappings are renewed well before expiry
+const MAPPING_LEASE: Duration = Duration::from_secs(60 * 60);
+pub const MAPPING_RENEW_INTERVAL: Duration = Duration::from_secs(30 * 60);
+const MAPPING_DESCRIPTION: &str = "sample-blockchain-rust p2p";
+
+// A TCP port forwarded on the local router via UPnP IGD
+pub struct PortMapping {
+    gateway: Gateway<Tokio>,
+    local: SocketAddr,
+    external: SocketAddr,
+}
+
+impl PortMapping {
+    // Find the router and forward the same port number to `listen_port` on
+    // this host's LAN address
+    pub async fn create(listen_port: u16) -> Result<Self, Box<dyn Error>> {
+        let gateway = search_gateway(SearchOptions::default()).await?;
+        let local = SocketAddr::new(local_ip_towards(gateway.addr).await?, listen_port);
+        let external_ip = gateway.get_external_ip().await?;
+
+        let mapping = PortMapping {
+            external: SocketAddr::new(external_ip, listen_port),
+            gateway,
+            local,
+        };
+        mapping.renew().await?;
+        Ok(mapping)
+    }
+
+    pub fn external_address(&self) -> SocketAddr {
+        self.external
+    }
+
+    pub async fn renew(&self) -> Result<(), Box<dyn Error>> {
+        self.gateway.add_port(
+            PortMappingProtocol::TCP,
+            self.external.port(),
+            self.local,
+            MAPPING_LEASE.as_secs() as u32,
+            MAPPING_DESCRIPTION,
+        ).await?;
+        Ok(())
+    }
+
+    pub async fn remove(&self) -> Result<(), Box<dyn Error>> {
+        self.gateway.remove_port(PortMappingProtocol::TCP, self.external.port()).await?;
+        Ok(())
+    }
+}
+
+// The interface address the OS would use to reach `target`. Connecting a UDP
+// socket sends nothing but selects a route.
+async fn local_ip_towards(target: SocketAddr) -> Result<IpAddr, Box<dyn Error>> {
+    let bind = if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(target).await?;
+    Ok(socket.local_addr()?.ip())
+}
@@ -1,134 +1,2724 @@
 use std::error::Error;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{accept_async, WebSocketStream};
+use tokio_socks::tcp::Socks5Stream;
+use tokio_tungstenite::{accept_async, accept_async_with_config, client_async_with_config, connect_async_with_config, WebSocketStream};
+use tokio_tungstenite::tungstenite::{protocol::WebSocketConfig, Message};
 use futures_util::{SinkExt, StreamExt};
+use async_trait::async_trait;
 use serde::{Serialize, Deserialize};
 use std::sync::Arc;
-use tokio::sync::broadcast;
-use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex, OwnedSemaphorePermit, RwLock, Semaphore};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::blockchain::{Block, BlockHeader, Blockchain, Transaction};
+use crate::database::Database;
+use crate::mdns::LocalDiscovery;
+use crate::nat::{PortMapping, MAPPING_RENEW_INTERVAL};
+use crate::secure::{self, NodeIdentity, SecureReceiver};
+use crate::security::{IntrusionDetection, IntrusionRecord};
+use crate::wire::{decode_frame, encode_frame, frame_type, Compression, CompressionMetrics, CompressionStats, SUPPORTED_COMPRESSION};
+
+// Bumped whenever the wire protocol changes incompatibly
+pub const PROTOCOL_VERSION: u32 = 4;
+// Oldest protocol version this node still talks to. Version 1 used JSON
+// text frames, version 2 was unencrypted and version 3 sent capabilities as
+// strings; this node speaks none of them.
+pub const MIN_PROTOCOL_VERSION: u32 = 4;
+pub const DEFAULT_CHAIN_ID: &str = "rbn-mainnet";
+// Optional features this node supports, advertised in the handshake
+pub const CAPABILITIES: Capabilities = Capabilities::PEER_EXCHANGE
+    .union(Capabilities::HEADERS_SYNC)
+    .union(Capabilities::GOSSIP)
+    .union(Capabilities::REQUEST_IDS)
+    .union(Capabilities::INVENTORY);
+// A peer must complete the handshake within this time
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Hops a gossiped block or transaction may travel from its origin
+pub const DEFAULT_GOSSIP_TTL: u8 = 8;
+// Gossip ids remembered, and for how long, to suppress duplicates
+const SEEN_CACHE_SIZE: usize = 20_000;
+const SEEN_CACHE_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+// Peers that must report the same public IP before we advertise it
+const OBSERVED_ADDRESS_QUORUM: usize = 2;
+
+// Most headers and blocks served per request
+pub const MAX_HEADERS_PER_REQUEST: usize = 2000;
+pub const MAX_BLOCKS_PER_REQUEST: usize = 100;
+
+// Messages queued for a single peer before further sends are dropped
+const PEER_QUEUE_SIZE: usize = 256;
+// Events buffered for each slow event subscriber before it starts missing them
+const EVENT_QUEUE_SIZE: usize = 1024;
+
+// How long shutdown waits for connections to close after saying goodbye
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Longest wait for one DNS seed to resolve
+const DNS_SEED_TIMEOUT: Duration = Duration::from_secs(10);
+// Port assumed for DNS seed results when the seed doesn't name one
+pub const DEFAULT_P2P_PORT: u16 = 7000;
+
+// How often discovery tops up outbound connections and asks for more addresses
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+// Most addresses sent in a single `Peers` reply
+const MAX_PEERS_PER_REPLY: usize = 100;
+// Addresses scored this low are never dialled again
+const MIN_ADDRESS_SCORE: i32 = -10;
+// How often a connected peer's address book entry is marked as seen
+const LAST_SEEN_UPDATE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Default time a peer has to answer a request
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Delay before the first reconnect to a dropped outbound peer, doubling on
+// each failure up to the maximum; the peer is dropped after the last attempt
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+const RECONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// With no peers for this long, the node goes back to its bootstrap nodes
+const ISOLATION_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+// Window for the per-IP connection rate limit
+const CONNECTION_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// Most items in one Inventory or GetData message
+const MAX_INVENTORY_PER_MESSAGE: usize = 1000;
+
+// How often the address book is written to the database
+const ADDRESS_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Upper bound on a connected peer's score
+const MAX_PEER_SCORE: i32 = 1000;
+// A peer whose score falls to this is disconnected
+const MIN_PEER_SCORE: i32 = -100;
+// Score changes for gossiped blocks and transactions: a little for each one
+// that checks out, a lot more for each one that doesn't
+const VALID_ITEM_REWARD: i32 = 1;
+const INVALID_ITEM_PENALTY: i32 = 20;
+
+// Peers may exceed their sustained rate for this many seconds' worth of traffic
+const RATE_LIMIT_BURST_SECONDS: f64 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransportKind {
+    // JSON messages over WebSocket connections (the default)
+    WebSocket,
+    // Gossipsub and Kademlia over libp2p; needs the `libp2p` feature
+    Libp2p,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub transport: TransportKind,
+    pub chain_id: String,
+    pub bootstrap_nodes: Vec<String>,
+    // Hostnames whose A/AAAA records list nodes to bootstrap from, as
+    // "host" or "host:port"; `default_port` is used when none is given
+    pub dns_seeds: Vec<String>,
+    pub default_port: u16,
+    // Outbound connections discovery tries to keep open
+    pub target_outbound: usize,
+    // Ask the router to forward the listen port via UPnP at startup
+    pub upnp: bool,
+    // Publicly reachable address to advertise, if known in advance
+    pub external_address: Option<SocketAddr>,
+    // Find other nodes on the LAN via multicast DNS; meant for local testnets
+    pub mdns: bool,
+    // File holding the node's identity key; a fresh key is used each run if unset
+    pub identity_path: Option<PathBuf>,
+    pub limits: PeerLimits,
+    // Connection slots. When inbound slots run out, the worst-scored
+    // inbound peer is evicted to make room; outbound dials are refused.
+    pub max_peers: usize,
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+    // Inbound connections allowed to be mid-handshake at once
+    pub max_pending_inbound: usize,
+    // New inbound connections accepted from one IP per minute
+    pub max_connections_per_ip: usize,
+    // Peers kept connected at all times: redialled forever and exempt from
+    // the outbound quota
+    pub static_peers: Vec<String>,
+    // Only talk to static peers, bootstrap nodes and `whitelist` entries
+    // (IPs or socket addresses); for private and consortium networks
+    pub whitelist_only: bool,
+    pub whitelist: Vec<String>,
+    // Route every outbound connection through this SOCKS5 proxy, e.g. a
+    // local Tor daemon
+    pub proxy: Option<ProxyConfig>,
+    pub relay: RelayConfig,
+    // Addresses not seen for this long are forgotten and no longer shared
+    pub address_expiry: Duration,
+    // Node ids or addresses of validator peers, for RelayPolicy::ValidatorsFirst
+    pub validators: Vec<String>,
+}
+
+// How far a block or transaction is pushed when we first see it. Peers left
+// out still get it, a hop later, from the peers that were chosen.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RelayPolicy {
+    // Every peer that doesn't have it yet
+    Flood,
+    // At most this many peers picked at random
+    Fanout(usize),
+    // Validator peers first, then up to `fanout` others at random (0 for all)
+    ValidatorsFirst { fanout: usize },
+}
+
+impl RelayPolicy {
+    // Pick and order recipients from the peers still missing the item
+    fn select(&self, mut candidates: Vec<PeerId>, is_validator: impl Fn(&str) -> bool) -> Vec<PeerId> {
+        let mut rng = rand::thread_rng();
+        match self {
+            RelayPolicy::Flood => candidates,
+            RelayPolicy::Fanout(count) => {
+                candidates.shuffle(&mut rng);
+                candidates.truncate(*count);
+                candidates
+            }
+            RelayPolicy::ValidatorsFirst { fanout } => {
+                let (mut selected, mut others): (Vec<PeerId>, Vec<PeerId>) =
+                    candidates.into_iter().partition(|peer| is_validator(peer));
+                others.shuffle(&mut rng);
+                if *fanout > 0 {
+                    others.truncate(*fanout);
+                }
+                selected.extend(others);
+                selected
+            }
+        }
+    }
+}
+
+// Relay policy per message type
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    pub blocks: RelayPolicy,
+    pub transactions: RelayPolicy,
+}
+
+impl RelayConfig {
+    pub fn policy(&self, kind: InventoryKind) -> &RelayPolicy {
+        match kind {
+            InventoryKind::Block => &self.blocks,
+            InventoryKind::Transaction => &self.transactions,
+        }
+    }
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            blocks: RelayPolicy::Flood,
+            transactions: RelayPolicy::Flood,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    pub address: SocketAddr,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+// Inbound limits applied to each peer; a peer exceeding any is disconnected
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerLimits {
+    // Largest single message, in bytes on the wire
+    pub max_message_size: usize,
+    pub max_messages_per_sec: f64,
+    pub max_bytes_per_sec: f64,
+}
+
+impl Default for PeerLimits {
+    fn default() -> Self {
+        PeerLimits {
+            max_message_size: 16 * 1024 * 1024,
+            max_messages_per_sec: 200.0,
+            max_bytes_per_sec: 8.0 * 1024.0 * 1024.0,
+        }
+    }
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            transport: TransportKind::WebSocket,
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            bootstrap_nodes: vec![],
+            dns_seeds: vec![],
+            default_port: DEFAULT_P2P_PORT,
+            target_outbound: 8,
+            upnp: false,
+            external_address: None,
+            mdns: false,
+            identity_path: None,
+            limits: PeerLimits::default(),
+            max_peers: 64,
+            max_inbound: 48,
+            max_outbound: 16,
+            max_pending_inbound: 32,
+            max_connections_per_ip: 10,
+            static_peers: vec![],
+            whitelist_only: false,
+            whitelist: vec![],
+            proxy: None,
+            relay: RelayConfig::default(),
+            address_expiry: Duration::from_secs(3 * 24 * 60 * 60),
+            validators: vec![],
+        }
+    }
+}
+
+// Optional protocol features as bit flags. New features get a new bit
+// rather than a protocol version bump; bits a node doesn't recognise are
+// ignored, and messages needing a feature are never sent to a peer without it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    // GetPeers / Peers
+    pub const PEER_EXCHANGE: Capabilities = Capabilities(1 << 0);
+    // GetHeaders / Headers / GetBlocks / Blocks
+    pub const HEADERS_SYNC: Capabilities = Capabilities(1 << 1);
+    // Gossip envelopes; peers without it get blocks and transactions unwrapped
+    pub const GOSSIP: Capabilities = Capabilities(1 << 2);
+    // Request / Response envelopes carrying a correlation id
+    pub const REQUEST_IDS: Capabilities = Capabilities(1 << 3);
+    // Inventory / GetData; peers without it are sent full gossip envelopes
+    pub const INVENTORY: Capabilities = Capabilities(1 << 4);
+
+    const NAMES: &'static [(Capabilities, &'static str)] = &[
+        (Capabilities::PEER_EXCHANGE, "peer-exchange"),
+        (Capabilities::HEADERS_SYNC, "headers-sync"),
+        (Capabilities::GOSSIP, "gossip"),
+        (Capabilities::REQUEST_IDS, "request-ids"),
+        (Capabilities::INVENTORY, "inventory"),
+    ];
+
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        Self::NAMES.iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.names().join(","))
+    }
+}
+
+// A dialable address learned from configuration or peer exchange
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownAddress {
+    pub address: String,
+    pub score: i32,
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_attempt: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// An IP locked out until `until`, by an operator or after repeated failures
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerBan {
+    pub address: String,
+    pub until: chrono::DateTime<chrono::Utc>,
+}
+
+// Addresses the node could connect to, scored by connection history
+#[derive(Debug, Default)]
+pub struct PeerAddressBook {
+    addresses: HashMap<String, KnownAddress>,
+}
+
+impl PeerAddressBook {
+    pub fn add(&mut self, address: &str) {
+        self.addresses.entry(address.to_string()).or_insert_with(|| KnownAddress {
+            address: address.to_string(),
+            score: 0,
+            last_seen: None,
+            last_attempt: None,
+        });
+    }
+
+    pub fn mark_attempt(&mut self, address: &str) {
+        if let Some(entry) = self.addresses.get_mut(address) {
+            entry.last_attempt = Some(chrono::Utc::now());
+        }
+    }
+
+    pub fn mark_success(&mut self, address: &str) {
+        self.add(address);
+        let entry = self.addresses.get_mut(address).unwrap();
+        entry.score = (entry.score + 1).min(100);
+        entry.last_seen = Some(chrono::Utc::now());
+    }
+
+    // Record that `address` was seen alive at `when`, by us or by the peer
+    // that told us about it. Times in the future are clamped to now.
+    pub fn mark_seen(&mut self, address: &str, when: chrono::DateTime<chrono::Utc>) {
+        self.add(address);
+        let entry = self.addresses.get_mut(address).unwrap();
+        let when = when.min(chrono::Utc::now());
+        if entry.last_seen.map_or(true, |last_seen| when > last_seen) {
+            entry.last_seen = Some(when);
+        }
+    }
+
+    pub fn mark_failure(&mut self, address: &str) {
+        if let Some(entry) = self.addresses.get_mut(address) {
+            entry.score -= 2;
+        }
+    }
+
+    // Highest-scored dialable addresses not in `exclude`, best first
+    pub fn candidates(&self, exclude: &[String], limit: usize) -> Vec<String> {
+        let mut candidates: Vec<&KnownAddress> = self.addresses.values()
+            .filter(|entry| entry.score > MIN_ADDRESS_SCORE && !exclude.contains(&entry.address))
+            .collect();
+        candidates.sort_by(|a, b| b.score.cmp(&a.score).then(a.last_attempt.cmp(&b.last_attempt)));
+        candidates.into_iter().take(limit).map(|entry| entry.address.clone()).collect()
+    }
+
+    // Addresses worth sharing with other peers: well behaved and seen
+    // within `max_age`
+    pub fn good_addresses(&self, limit: usize, max_age: Duration) -> Vec<KnownAddress> {
+        let mut good: Vec<KnownAddress> = self.addresses.values()
+            .filter(|entry| entry.score >= 0 && !is_stale(entry.last_seen, max_age))
+            .cloned()
+            .collect();
+        good.sort_by(|a, b| b.score.cmp(&a.score));
+        good.truncate(limit);
+        good
+    }
+
+    // Merge addresses loaded from storage, keeping what we learned this run
+    pub fn restore(&mut self, entries: Vec<KnownAddress>) {
+        for entry in entries {
+            self.addresses.entry(entry.address.clone()).or_insert(entry);
+        }
+    }
+
+    // Everything worth remembering across restarts
+    pub fn persistable(&self) -> Vec<KnownAddress> {
+        self.addresses.values()
+            .filter(|entry| entry.score > MIN_ADDRESS_SCORE)
+            .cloned()
+            .collect()
+    }
+
+    // Give an address a clean slate so it is dialled again
+    pub fn reset(&mut self, address: &str) {
+        self.add(address);
+        let entry = self.addresses.get_mut(address).unwrap();
+        entry.score = entry.score.max(0);
+        entry.last_attempt = None;
+    }
+
+    // Forget addresses not seen within `max_age`. Addresses never seen are
+    // kept until they have been tried and failed for that long. `keep`
+    // lists configured addresses, which are never dropped.
+    pub fn expire(&mut self, max_age: Duration, keep: &[String]) -> usize {
+        let before = self.addresses.len();
+        self.addresses.retain(|address, entry| {
+            if keep.contains(address) {
+                return true;
+            }
+            match entry.last_seen {
+                Some(_) => !is_stale(entry.last_seen, max_age),
+                None => !is_stale(entry.last_attempt, max_age),
+            }
+        });
+        before - self.addresses.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+}
+
+fn is_stale(time: Option<chrono::DateTime<chrono::Utc>>, max_age: Duration) -> bool {
+    match (time, chrono::Duration::from_std(max_age)) {
+        (Some(time), Ok(max_age)) => chrono::Utc::now() - time > max_age,
+        (Some(_), Err(_)) => false,
+        (None, _) => true,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InventoryKind {
+    Block,
+    Transaction,
+}
+
+// A block or transaction named by hash, announced instead of sent in full
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InventoryItem {
+    pub kind: InventoryKind,
+    pub hash: String,
+}
+
+impl InventoryItem {
+    // Key shared with the seen cache
+    pub fn id(&self) -> String {
+        match self.kind {
+            InventoryKind::Block => format!("block:{}", self.hash),
+            InventoryKind::Transaction => format!("tx:{}", self.hash),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum NetworkMessage {
+    // Must be the first message each side sends
+    Handshake(Handshake),
     NewBlock(Block),
     NewTransaction(Transaction),
+    // Headers after the first locator hash the peer recognises
+    GetHeaders { locator: Vec<String>, limit: u32 },
+    Headers(Vec<BlockHeader>),
     GetBlocks(Vec<String>),
     Blocks(Vec<Block>),
     GetPeers,
     Peers(Vec<PeerInfo>),
+    // Envelope for blocks and transactions relayed across the network
+    Gossip { ttl: u8, message: Box<NetworkMessage> },
+    // A query whose answer is sent back as a Response with the same id
+    Request { id: u64, message: Box<NetworkMessage> },
+    Response { id: u64, message: Box<NetworkMessage> },
+    // Hashes of blocks and transactions the sender has
+    Inventory(Vec<InventoryItem>),
+    // Ask for the full blocks and transactions behind announced hashes
+    GetData(Vec<InventoryItem>),
+    // Last message before the sender closes the connection on purpose
+    Disconnect { reason: String },
+}
+
+impl NetworkMessage {
+    // Type byte written in the wire frame header
+    pub fn message_type(&self) -> u8 {
+        match self {
+            NetworkMessage::Handshake(_) => 0,
+            NetworkMessage::NewBlock(_) => 1,
+            NetworkMessage::NewTransaction(_) => 2,
+            NetworkMessage::GetHeaders { .. } => 3,
+            NetworkMessage::Headers(_) => 4,
+            NetworkMessage::GetBlocks(_) => 5,
+            NetworkMessage::Blocks(_) => 6,
+            NetworkMessage::GetPeers => 7,
+            NetworkMessage::Peers(_) => 8,
+            NetworkMessage::Gossip { .. } => 9,
+            NetworkMessage::Request { .. } => 10,
+            NetworkMessage::Response { .. } => 11,
+            NetworkMessage::Inventory(_) => 12,
+            NetworkMessage::GetData(_) => 13,
+            NetworkMessage::Disconnect { .. } => 14,
+        }
+    }
+
+    // Label used in traffic metrics
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            NetworkMessage::Handshake(_) => "handshake",
+            NetworkMessage::NewBlock(_) => "new_block",
+            NetworkMessage::NewTransaction(_) => "new_transaction",
+            NetworkMessage::GetHeaders { .. } => "get_headers",
+            NetworkMessage::Headers(_) => "headers",
+            NetworkMessage::GetBlocks(_) => "get_blocks",
+            NetworkMessage::Blocks(_) => "blocks",
+            NetworkMessage::GetPeers => "get_peers",
+            NetworkMessage::Peers(_) => "peers",
+            NetworkMessage::Gossip { .. } => "gossip",
+            NetworkMessage::Request { .. } => "request",
+            NetworkMessage::Response { .. } => "response",
+            NetworkMessage::Inventory(_) => "inventory",
+            NetworkMessage::GetData(_) => "get_data",
+            NetworkMessage::Disconnect { .. } => "disconnect",
+        }
+    }
+
+    // Types a newer node may send that this one can't decode
+    pub fn is_known_type(message_type: u8) -> bool {
+        message_type <= 14
+    }
+
+    // Capability the receiving peer must have for this message
+    pub fn required_capability(&self) -> Capabilities {
+        match self {
+            NetworkMessage::GetPeers | NetworkMessage::Peers(_) => Capabilities::PEER_EXCHANGE,
+            NetworkMessage::GetHeaders { .. }
+            | NetworkMessage::Headers(_)
+            | NetworkMessage::GetBlocks(_)
+            | NetworkMessage::Blocks(_) => Capabilities::HEADERS_SYNC,
+            NetworkMessage::Gossip { .. } => Capabilities::GOSSIP,
+            NetworkMessage::Request { .. } | NetworkMessage::Response { .. } => Capabilities::REQUEST_IDS,
+            NetworkMessage::Inventory(_) | NetworkMessage::GetData(_) => Capabilities::INVENTORY,
+            _ => Capabilities::NONE,
+        }
+    }
+
+    // The form of this message a peer with `capabilities` understands, or
+    // None if it can't be expressed for that peer at all
+    pub fn adapt_for(&self, capabilities: Capabilities) -> Option<NetworkMessage> {
+        if capabilities.contains(self.required_capability()) {
+            return Some(self.clone());
+        }
+        // A request can't be unwrapped: the reply would never be matched
+        match self {
+            NetworkMessage::Gossip { message, .. } => message.adapt_for(capabilities),
+            NetworkMessage::Response { message, .. } => message.adapt_for(capabilities),
+            _ => None,
+        }
+    }
+
+    // Content-derived id for messages that are gossiped; None for
+    // point-to-point messages
+    pub fn gossip_id(&self) -> Option<String> {
+        self.inventory_item().map(|item| item.id())
+    }
+
+    // How a block or transaction is announced
+    pub fn inventory_item(&self) -> Option<InventoryItem> {
+        match self {
+            NetworkMessage::NewBlock(block) => Some(InventoryItem {
+                kind: InventoryKind::Block,
+                hash: block.hash.clone(),
+            }),
+            NetworkMessage::NewTransaction(transaction) => Some(InventoryItem {
+                kind: InventoryKind::Transaction,
+                hash: transaction.hash(),
+            }),
+            NetworkMessage::Gossip { message, .. } => message.inventory_item(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrafficCounts {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+// Wire traffic exchanged with one peer, in total and per message type
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PeerTraffic {
+    pub total: TrafficCounts,
+    pub by_type: HashMap<String, TrafficCounts>,
+}
+
+impl PeerTraffic {
+    pub fn record_sent(&mut self, message_type: &str, bytes: usize) {
+        for counts in [&mut self.total, self.by_type.entry(message_type.to_string()).or_default()] {
+            counts.messages_sent += 1;
+            counts.bytes_sent += bytes as u64;
+        }
+    }
+
+    pub fn record_received(&mut self, message_type: &str, bytes: usize) {
+        for counts in [&mut self.total, self.by_type.entry(message_type.to_string()).or_default()] {
+            counts.messages_received += 1;
+            counts.bytes_received += bytes as u64;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerMetrics {
+    pub peer: PeerId,
+    pub outbound: bool,
+    pub score: i32,
+    pub connected_seconds: u64,
+    pub traffic: PeerTraffic,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkMetrics {
+    pub peer_count: usize,
+    pub known_addresses: usize,
+    pub peers: Vec<PeerMetrics>,
+    pub compression: CompressionMetrics,
+}
+
+impl NetworkMetrics {
+    // Render in the Prometheus text exposition format, labelled by peer and
+    // message type
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!(
+            "# HELP p2p_peers Connected peers\n# TYPE p2p_peers gauge\np2p_peers {}\n",
+            self.peer_count
+        ));
+        out.push_str(&format!(
+            "# HELP p2p_known_addresses Addresses in the peer address book\n# TYPE p2p_known_addresses gauge\np2p_known_addresses {}\n",
+            self.known_addresses
+        ));
+
+        let counters: [(&str, &str, fn(&TrafficCounts) -> u64); 4] = [
+            ("p2p_messages_sent_total", "Messages sent to a peer", |c| c.messages_sent),
+            ("p2p_messages_received_total", "Messages received from a peer", |c| c.messages_received),
+            ("p2p_bytes_sent_total", "Bytes sent to a peer", |c| c.bytes_sent),
+            ("p2p_bytes_received_total", "Bytes received from a peer", |c| c.bytes_received),
+        ];
+        for (name, help, value) in counters {
+            out.push_str(&format!("# HELP {} {}\n# TYPE {} counter\n", name, help, name));
+            for peer in &self.peers {
+                let mut types: Vec<_> = peer.traffic.by_type.iter().collect();
+                types.sort_by(|a, b| a.0.cmp(b.0));
+                for (message_type, counts) in types {
+                    out.push_str(&format!(
+                        "{}{{peer=\"{}\",type=\"{}\"}} {}\n",
+                        name, peer.peer, message_type, value(counts)
+                    ));
+                }
+            }
+        }
+        out
+    }
+}
+
+// Token buckets for one peer's message and byte rates
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: PeerLimits,
+    messages: f64,
+    bytes: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limits: PeerLimits) -> Self {
+        RateLimiter {
+            messages: limits.max_messages_per_sec * RATE_LIMIT_BURST_SECONDS,
+            bytes: limits.max_bytes_per_sec * RATE_LIMIT_BURST_SECONDS,
+            limits,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // Account for a received message of `size` bytes
+    pub fn check(&mut self, size: usize) -> Result<(), String> {
+        if size > self.limits.max_message_size {
+            return Err(format!("message of {} bytes exceeds the {} byte limit", size, self.limits.max_message_size));
+        }
+
+        let elapsed = self.last_refill.elapsed().as_secs_f64();
+        self.last_refill = Instant::now();
+        self.messages = (self.messages + elapsed * self.limits.max_messages_per_sec)
+            .min(self.limits.max_messages_per_sec * RATE_LIMIT_BURST_SECONDS);
+        self.bytes = (self.bytes + elapsed * self.limits.max_bytes_per_sec)
+            .min(self.limits.max_bytes_per_sec * RATE_LIMIT_BURST_SECONDS);
+
+        self.messages -= 1.0;
+        self.bytes -= size as f64;
+        if self.messages < 0.0 {
+            return Err(format!("more than {} messages per second", self.limits.max_messages_per_sec));
+        }
+        if self.bytes < 0.0 {
+            return Err(format!("more than {} bytes per second", self.limits.max_bytes_per_sec));
+        }
+        Ok(())
+    }
+}
+
+// Reconnect schedule for one dropped outbound peer
+#[derive(Debug, Clone)]
+struct Backoff {
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+impl Backoff {
+    // Exponential delay for the given attempt with +/-50% jitter, so peers
+    // dropped together don't all redial at once
+    fn delay(attempts: u32) -> Duration {
+        let exponential = RECONNECT_BASE_DELAY.saturating_mul(1u32 << attempts.min(16));
+        let capped = exponential.min(RECONNECT_MAX_DELAY);
+        capped.mul_f64(rand::thread_rng().gen_range(0.5..1.5))
+    }
+}
+
+// Recent inbound connection times per source IP
+#[derive(Debug, Default)]
+pub struct ConnectionRateLimiter {
+    attempts: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl ConnectionRateLimiter {
+    // Record a connection from `ip`; false if it already made `limit` in
+    // the current window
+    pub fn allow(&mut self, ip: IpAddr, limit: usize) -> bool {
+        self.attempts.retain(|_, times| {
+            while times.front().map_or(false, |time| time.elapsed() > CONNECTION_RATE_WINDOW) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = self.attempts.entry(ip).or_default();
+        if times.len() >= limit {
+            return false;
+        }
+        times.push_back(Instant::now());
+        true
+    }
+}
+
+// What became of a gossiped block or transaction
+enum Admission {
+    // Valid and now held by the chain or mempool, where GetData is served from
+    Accepted,
+    // Already held, or well-formed but in conflict with our state, like a
+    // spend a block we applied first has made unaffordable. Honest peers
+    // relay these in races, so they are dropped without a penalty.
+    Known,
+    // Can't be checked yet, like a block that doesn't extend our tip
+    Deferred,
+    // Invalid on its own, whatever the chain state; costs the sender score
+    Rejected(String),
+}
+
+// Recently seen gossip ids and the peers known to already have each one
+#[derive(Debug, Default)]
+pub struct SeenCache {
+    entries: HashMap<String, (Instant, HashSet<PeerId>)>,
+    order: VecDeque<String>,
+}
+
+impl SeenCache {
+    // Record that `peer` (if any) has the message; returns true the first
+    // time the id is seen
+    pub fn insert(&mut self, id: &str, peer: Option<&str>) -> bool {
+        self.expire();
+        let first = !self.entries.contains_key(id);
+        if first {
+            self.order.push_back(id.to_string());
+        }
+        let entry = self.entries.entry(id.to_string()).or_insert_with(|| (Instant::now(), HashSet::new()));
+        if let Some(peer) = peer {
+            entry.1.insert(peer.to_string());
+        }
+        first
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    // Forget an id so the next announcement of it is fetched again
+    pub fn remove(&mut self, id: &str) {
+        if self.entries.remove(id).is_some() {
+            self.order.retain(|entry| entry != id);
+        }
+    }
+
+    // Peers from `candidates` not known to have the message
+    pub fn missing(&self, id: &str, candidates: Vec<PeerId>) -> Vec<PeerId> {
+        match self.entries.get(id) {
+            Some((_, known)) => candidates.into_iter().filter(|peer| !known.contains(peer)).collect(),
+            None => candidates,
+        }
+    }
+
+    // Peers from `candidates` that don't have the message yet, marking them as having it
+    pub fn take_recipients(&mut self, id: &str, candidates: Vec<PeerId>) -> Vec<PeerId> {
+        let known = match self.entries.get_mut(id) {
+            Some((_, known)) => known,
+            None => return candidates,
+        };
+        candidates.into_iter().filter(|peer| known.insert(peer.clone())).collect()
+    }
+
+    fn expire(&mut self) {
+        while let Some(id) = self.order.front() {
+            let expired = self.entries.get(id).map_or(true, |(seen, _)| seen.elapsed() > SEEN_CACHE_EXPIRY);
+            if !expired && self.order.len() <= SEEN_CACHE_SIZE {
+                break;
+            }
+            let id = self.order.pop_front().unwrap();
+            self.entries.remove(&id);
+        }
+    }
+}
+
+// Changes in the network's state, for observers such as the API. Tagged by
+// "type" so they can be forwarded as notifications.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NetworkEvent {
+    PeerConnected { peer: PeerId, outbound: bool },
+    PeerDisconnected { peer: PeerId },
+    // An address locked out after repeated failed or abusive connections
+    PeerBanned { address: String },
+    SyncStarted { peer: PeerId, target_height: u64 },
+    SyncFinished { peer: PeerId, height: u64 },
+    SyncFailed { peer: PeerId, error: String },
+    MessageReceived { peer: PeerId, message_type: String, size: usize },
+}
+
+// Serialized through `HandshakeWire`, so fields added later travel in the
+// extension blob (see `wire::extensions`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "HandshakeWire", from = "HandshakeWire")]
+pub struct Handshake {
+    pub protocol_version: u32,
+    pub chain_id: String,
+    pub genesis_hash: String,
+    pub best_height: u64,
+    // Software version, e.g. "sample-blockchain-rust/0.1.0"
+    pub user_agent: String,
+    // Port the sender accepts connections on, if any; the first of
+    // `listen_addresses` for peers that predate it
+    pub listen_port: Option<u16>,
+    // Every address the sender accepts connections on that others can
+    // reach. Wildcard binds are sent as 0.0.0.0 or [::] with the port, for
+    // the receiver to fill in with the address it sees.
+    pub listen_addresses: Vec<String>,
+    // Where the sender can be reached from the internet, if it knows
+    pub external_address: Option<String>,
+    // The receiver's address as seen by the sender, for NAT detection
+    pub observed_address: Option<String>,
+    pub capabilities: Capabilities,
+    // Compression algorithms the sender accepts, most preferred first
+    pub compression: Vec<Compression>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HandshakeWire {
+    protocol_version: u32,
+    chain_id: String,
+    genesis_hash: String,
+    best_height: u64,
+    user_agent: String,
+    listen_port: Option<u16>,
+    capabilities: Capabilities,
+    #[serde(with = "crate::wire::extensions")]
+    extensions: HandshakeExtensions,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct HandshakeExtensions {
+    listen_addresses: Vec<String>,
+    external_address: Option<String>,
+    observed_address: Option<String>,
+    compression: Vec<Compression>,
+}
+
+impl From<Handshake> for HandshakeWire {
+    fn from(handshake: Handshake) -> Self {
+        HandshakeWire {
+            protocol_version: handshake.protocol_version,
+            chain_id: handshake.chain_id,
+            genesis_hash: handshake.genesis_hash,
+            best_height: handshake.best_height,
+            user_agent: handshake.user_agent,
+            listen_port: handshake.listen_port,
+            capabilities: handshake.capabilities,
+            extensions: HandshakeExtensions {
+                listen_addresses: handshake.listen_addresses,
+                external_address: handshake.external_address,
+                observed_address: handshake.observed_address,
+                compression: handshake.compression,
+            },
+        }
+    }
+}
+
+impl From<HandshakeWire> for Handshake {
+    fn from(wire: HandshakeWire) -> Self {
+        Handshake {
+            protocol_version: wire.protocol_version,
+            chain_id: wire.chain_id,
+            genesis_hash: wire.genesis_hash,
+            best_height: wire.best_height,
+            user_agent: wire.user_agent,
+            listen_port: wire.listen_port,
+            listen_addresses: wire.extensions.listen_addresses,
+            external_address: wire.extensions.external_address,
+            observed_address: wire.extensions.observed_address,
+            capabilities: wire.capabilities,
+            compression: wire.extensions.compression,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct PeerInfo {
-    pub address: String,
-    pub version: String,
-    pub last_seen: chrono::DateTime<chrono::Utc>,
-}
+// Serialized through `PeerInfoWire`; only the address, version and last
+// seen time were in the first wire format
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(into = "PeerInfoWire", from = "PeerInfoWire")]
+pub struct PeerInfo {
+    pub address: String,
+    // Hex public key the peer proved ownership of in the Noise handshake
+    pub node_id: String,
+    pub version: String,
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+    // Filled in from the peer's handshake
+    pub protocol_version: u32,
+    pub best_height: u64,
+    // Capabilities both sides support
+    pub capabilities: Capabilities,
+    // Raised for each valid block or transaction the peer relays and
+    // lowered for each invalid one; the lowest-scored peer is evicted first,
+    // and one reaching MIN_PEER_SCORE is dropped
+    pub score: i32,
+    // Negotiated in the handshake and applied to every later message
+    pub compression: Compression,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeerInfoWire {
+    address: String,
+    version: String,
+    last_seen: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "crate::wire::extensions")]
+    extensions: PeerInfoExtensions,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct PeerInfoExtensions {
+    node_id: String,
+    protocol_version: u32,
+    best_height: u64,
+    capabilities: Capabilities,
+    score: i32,
+    compression: Compression,
+}
+
+impl From<PeerInfo> for PeerInfoWire {
+    fn from(peer: PeerInfo) -> Self {
+        PeerInfoWire {
+            address: peer.address,
+            version: peer.version,
+            last_seen: peer.last_seen,
+            extensions: PeerInfoExtensions {
+                node_id: peer.node_id,
+                protocol_version: peer.protocol_version,
+                best_height: peer.best_height,
+                capabilities: peer.capabilities,
+                score: peer.score,
+                compression: peer.compression,
+            },
+        }
+    }
+}
+
+impl From<PeerInfoWire> for PeerInfo {
+    fn from(wire: PeerInfoWire) -> Self {
+        PeerInfo {
+            address: wire.address,
+            node_id: wire.extensions.node_id,
+            version: wire.version,
+            last_seen: wire.last_seen,
+            protocol_version: wire.extensions.protocol_version,
+            best_height: wire.extensions.best_height,
+            capabilities: wire.extensions.capabilities,
+            score: wire.extensions.score,
+            compression: wire.extensions.compression,
+        }
+    }
+}
+
+// Peers are identified by their socket address, or by their libp2p peer id
+pub type PeerId = String;
+
+// What the rest of the node needs from a P2P backend
+#[async_trait]
+pub trait NetworkTransport: Send + Sync {
+    // Accept connections on `addr`; runs until the listener fails
+    async fn listen(&self, addr: SocketAddr) -> Result<(), Box<dyn Error>>;
+
+    async fn connect(&self, addr: String) -> Result<(), Box<dyn Error>>;
+
+    // Deliver to every peer; blocks and transactions are gossiped
+    async fn broadcast(&self, message: NetworkMessage) -> Result<(), Box<dyn Error>>;
+
+    async fn send_to(&self, peer: &str, message: NetworkMessage) -> Result<(), Box<dyn Error>>;
+
+    // Messages received from peers, tagged with the sender
+    fn subscribe(&self) -> broadcast::Receiver<(PeerId, NetworkMessage)>;
+
+    async fn peers(&self) -> Vec<PeerInfo>;
+}
+
+// Build the backend selected in `config`
+pub fn build_transport(config: NetworkConfig, blockchain: Blockchain) -> Result<Arc<dyn NetworkTransport>, Box<dyn Error>> {
+    match config.transport {
+        TransportKind::WebSocket => Ok(Arc::new(Network::with_config(config, blockchain))),
+        #[cfg(feature = "libp2p")]
+        TransportKind::Libp2p => Ok(Arc::new(crate::p2p::Libp2pNetwork::new(config, blockchain)?)),
+        #[cfg(not(feature = "libp2p"))]
+        TransportKind::Libp2p => Err("This build does not include the libp2p transport".into()),
+    }
+}
+
+// A connected peer. Messages sent to `sender` are written to the socket by
+// the peer's writer task, so any number of tasks can send concurrently.
+pub struct PeerHandle {
+    pub info: PeerInfo,
+    pub outbound: bool,
+    pub connected_at: Instant,
+    // Shared with the peer's writer task, which records what it sends
+    pub traffic: Arc<std::sync::Mutex<PeerTraffic>>,
+    sender: mpsc::Sender<NetworkMessage>,
+}
+
+type PeerMap = Arc<RwLock<HashMap<PeerId, PeerHandle>>>;
+
+// A request waiting for its response. Only the peer it was sent to may answer.
+struct PendingRequest {
+    peer: PeerId,
+    reply: oneshot::Sender<NetworkMessage>,
+}
+
+#[derive(Clone)]
+pub struct Network {
+    config: Arc<NetworkConfig>,
+    blockchain: Blockchain,
+    // Where blocks accepted from peers are saved, if anywhere
+    database: Option<Arc<Database>>,
+    // Addresses we accept connections on, in the order they were bound
+    listen_addrs: Arc<RwLock<Vec<SocketAddr>>>,
+    external_address: Arc<RwLock<Option<SocketAddr>>>,
+    // Votes from peers on our public IP, used when UPnP is unavailable
+    observed_ips: Arc<RwLock<HashMap<IpAddr, HashSet<PeerId>>>>,
+    peers: PeerMap,
+    address_book: Arc<RwLock<PeerAddressBook>>,
+    seen: Arc<RwLock<SeenCache>>,
+    compression_stats: Arc<CompressionStats>,
+    identity: Arc<NodeIdentity>,
+    pending_requests: Arc<Mutex<HashMap<u64, PendingRequest>>>,
+    intrusion_detection: Arc<IntrusionDetection>,
+    connection_attempts: Arc<Mutex<ConnectionRateLimiter>>,
+    // One permit per inbound connection that hasn't finished its handshakes
+    pending_inbound: Arc<Semaphore>,
+    // Outbound peers that dropped and are waiting to be redialled
+    reconnects: Arc<Mutex<HashMap<String, Backoff>>>,
+    whitelist: Arc<HashSet<IpAddr>>,
+    // Peers we disconnected on purpose, which must not be redialled
+    suppressed_reconnects: Arc<Mutex<HashSet<String>>>,
+    // Announced items we've asked a peer for; another peer is asked if the
+    // first doesn't deliver within REQUEST_TIMEOUT
+    requested_inventory: Arc<Mutex<HashMap<String, Instant>>>,
+    next_request_id: Arc<AtomicU64>,
+    // Every message received from any peer, tagged with its sender
+    message_tx: broadcast::Sender<(PeerId, NetworkMessage)>,
+    event_tx: broadcast::Sender<NetworkEvent>,
+    // Set once by `shutdown`; accept loops and new connections watch it
+    shutdown: Arc<watch::Sender<bool>>,
+    // Background tasks to abort on shutdown
+    tasks: Arc<std::sync::Mutex<Vec<tokio::task::AbortHandle>>>,
+    // Connections being handshaken or served, so shutdown can wait for them
+    active_connections: Arc<watch::Sender<usize>>,
+}
+
+// Counts a connection in `active_connections` for as long as it lives
+struct ConnectionGuard(Arc<watch::Sender<usize>>);
+
+impl ConnectionGuard {
+    fn new(active: Arc<watch::Sender<usize>>) -> Self {
+        active.send_modify(|count| *count += 1);
+        ConnectionGuard(active)
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+impl Network {
+    pub fn new(blockchain: Blockchain) -> Self {
+        Self::with_config(NetworkConfig::default(), blockchain)
+    }
+
+    pub fn with_config(config: NetworkConfig, blockchain: Blockchain) -> Self {
+        let (message_tx, _) = broadcast::channel(100);
+        let (event_tx, _) = broadcast::channel(EVENT_QUEUE_SIZE);
+        let identity = match &config.identity_path {
+            Some(path) => NodeIdentity::load_or_generate(path),
+            None => NodeIdentity::generate(),
+        };
+        let identity = identity.unwrap_or_else(|e| {
+            eprintln!("Failed to load node identity, using a temporary one: {}", e);
+            NodeIdentity::generate().expect("Failed to generate node identity")
+        });
+        let mut address_book = PeerAddressBook::default();
+        for address in config.bootstrap_nodes.iter().chain(&config.static_peers) {
+            address_book.add(address);
+        }
+        let whitelist = config.bootstrap_nodes.iter()
+            .chain(&config.static_peers)
+            .chain(&config.whitelist)
+            .filter_map(|entry| {
+                let ip = entry.parse::<SocketAddr>().map(|addr| addr.ip()).or_else(|_| entry.parse::<IpAddr>());
+                if ip.is_err() {
+                    eprintln!("Ignoring whitelist entry {}: not an IP or socket address", entry);
+                }
+                ip.ok()
+            })
+            .collect();
+
+        Network {
+            external_address: Arc::new(RwLock::new(config.external_address)),
+            pending_inbound: Arc::new(Semaphore::new(config.max_pending_inbound)),
+            config: Arc::new(config),
+            blockchain,
+            database: None,
+            listen_addrs: Arc::new(RwLock::new(vec![])),
+            observed_ips: Arc::new(RwLock::new(HashMap::new())),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            address_book: Arc::new(RwLock::new(address_book)),
+            seen: Arc::new(RwLock::new(SeenCache::default())),
+            compression_stats: Arc::new(CompressionStats::default()),
+            identity: Arc::new(identity),
+            pending_requests: Arc::new(Mutex::new(HashMap::new())),
+            intrusion_detection: Arc::new(IntrusionDetection::new()),
+            connection_attempts: Arc::new(Mutex::new(ConnectionRateLimiter::default())),
+            reconnects: Arc::new(Mutex::new(HashMap::new())),
+            whitelist: Arc::new(whitelist),
+            suppressed_reconnects: Arc::new(Mutex::new(HashSet::new())),
+            requested_inventory: Arc::new(Mutex::new(HashMap::new())),
+            next_request_id: Arc::new(AtomicU64::new(1)),
+            message_tx,
+            event_tx,
+            shutdown: Arc::new(watch::channel(false).0),
+            tasks: Arc::new(std::sync::Mutex::new(vec![])),
+            active_connections: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    // Save blocks accepted from peers, so the chain survives a restart
+    pub fn with_database(mut self, database: Arc<Database>) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<(PeerId, NetworkMessage)> {
+        self.message_tx.subscribe()
+    }
+
+    pub fn subscribe_events(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.event_tx.subscribe()
+    }
+
+    pub fn emit(&self, event: NetworkEvent) {
+        // Nobody may be listening; that is not an error
+        let _ = self.event_tx.send(event);
+    }
+
+    // Count a failed or abusive connection from `ip`, announcing a ban when
+    // it tips the address into lockout
+    fn record_failure(&self, ip: &str) {
+        let was_locked_out = self.intrusion_detection.is_locked_out(ip);
+        if !self.intrusion_detection.record_failed_attempt(ip) && !was_locked_out {
+            println!("Locking out {} after repeated failures", ip);
+            self.emit(NetworkEvent::PeerBanned { address: ip.to_string() });
+        }
+    }
+
+    // Lock out the IP behind `address` (an IP or host:port) and drop every
+    // peer connected from it
+    pub async fn ban(&self, address: &str) -> Result<(), Box<dyn Error>> {
+        let ip: IpAddr = match address.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip(),
+            Err(_) => address.parse().map_err(|_| format!("Invalid address {}", address))?,
+        };
+        self.intrusion_detection.lock_out(&ip.to_string());
+
+        let banned: Vec<PeerId> = self.peers.read().await.iter()
+            .filter(|(_, handle)| {
+                handle.info.address.parse::<SocketAddr>().map_or(false, |addr| addr.ip() == ip)
+            })
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        for peer in &banned {
+            self.disconnect(peer).await;
+        }
+        self.emit(NetworkEvent::PeerBanned { address: ip.to_string() });
+        Ok(())
+    }
+
+    pub fn intrusion_records(&self) -> Vec<IntrusionRecord> {
+        self.intrusion_detection.records()
+    }
+
+    // Remember a background task so shutdown can stop it
+    fn track(&self, task: tokio::task::JoinHandle<()>) -> tokio::task::JoinHandle<()> {
+        self.tasks.lock().unwrap().push(task.abort_handle());
+        task
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    // Stop accepting connections and background work, say goodbye to every
+    // peer, and wait for the connections to flush their queues and close.
+    // Connections still open after SHUTDOWN_TIMEOUT are abandoned.
+    pub async fn shutdown(&self) {
+        if self.shutdown.send_replace(true) {
+            return;
+        }
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+
+        // Dropping the handles closes each peer's queue; its writer sends
+        // what is left, ending with the goodbye, then closes the socket
+        let peers: Vec<PeerHandle> = self.peers.write().await.drain().map(|(_, peer)| peer).collect();
+        println!("Shutting down network, disconnecting {} peers", peers.len());
+        for peer in &peers {
+            let _ = peer.sender.try_send(NetworkMessage::Disconnect { reason: "Node shutting down".to_string() });
+        }
+        drop(peers);
+
+        let mut active = self.active_connections.subscribe();
+        let closed = async {
+            while *active.borrow_and_update() > 0 {
+                if active.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, closed).await.is_err() {
+            eprintln!("{} connections did not close in time", *self.active_connections.borrow());
+        }
+        // Wake anyone still waiting on a reply
+        self.pending_requests.lock().await.clear();
+    }
+
+    pub async fn start(&self, addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+        self.start_all(&[addr]).await
+    }
+
+    // Accept connections on every address in `addrs`, e.g. an IPv4 and an
+    // IPv6 wildcard on the same port. Fails if any address can't be bound.
+    pub async fn start_all(&self, addrs: &[SocketAddr]) -> Result<(), Box<dyn Error>> {
+        let mut listeners = vec![];
+        for addr in addrs {
+            let listener = bind_listener(*addr).map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+            println!("Network listening on {}", addr);
+            listeners.push(listener);
+        }
+        let port = addrs.first().map(|addr| addr.port()).ok_or("No listen addresses given")?;
+        self.listen_addrs.write().await.extend_from_slice(addrs);
+        if self.config.upnp {
+            self.start_port_mapping(port).await;
+        }
+        if self.config.mdns {
+            self.start_local_discovery(port);
+        }
+
+        let accepts = listeners.into_iter().map(|listener| {
+            let network = self.clone();
+            async move { network.accept_loop(listener).await }
+        });
+        futures_util::future::join_all(accepts).await;
+
+        Ok(())
+    }
+
+    // Addresses we listen on, first bound first
+    pub async fn listen_addresses(&self) -> Vec<SocketAddr> {
+        self.listen_addrs.read().await.clone()
+    }
+
+    // Addresses to advertise: the external address if we know one, then
+    // every bound address other peers could plausibly reach
+    async fn advertised_addresses(&self) -> Vec<String> {
+        let mut addresses = vec![];
+        if let Some(external) = *self.external_address.read().await {
+            addresses.push(external.to_string());
+        }
+        for addr in self.listen_addrs.read().await.iter() {
+            if !addr.ip().is_loopback() && !addresses.contains(&addr.to_string()) {
+                addresses.push(addr.to_string());
+            }
+        }
+        addresses
+    }
+
+    async fn accept_loop(&self, listener: TcpListener) {
+        let mut shutdown = self.shutdown.subscribe();
+        loop {
+            if *shutdown.borrow_and_update() {
+                break;
+            }
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                },
+                _ = shutdown.changed() => break,
+            };
+            // Dropping the stream closes the connection
+            let ip = addr.ip().to_string();
+            if !self.is_allowed(addr.ip()) {
+                println!("Refusing connection from {}: not whitelisted", addr);
+                continue;
+            }
+            if self.intrusion_detection.is_locked_out(&ip) {
+                continue;
+            }
+            if !self.connection_attempts.lock().await.allow(addr.ip(), self.config.max_connections_per_ip) {
+                eprintln!("Connection rate limit exceeded by {}", ip);
+                self.record_failure(&ip);
+                continue;
+            }
+            let permit = match self.pending_inbound.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    eprintln!("Too many pending connections, dropping {}", addr);
+                    continue;
+                }
+            };
+
+            println!("New connection from {}", addr);
+            let network = self.clone();
+            
+            tokio::spawn(async move {
+                let upgrade = accept_async_with_config(stream, Some(network.websocket_config()));
+                let result = match tokio::time::timeout(HANDSHAKE_TIMEOUT, upgrade).await {
+                    Ok(Ok(ws_stream)) => network.run_peer(ws_stream, addr.to_string(), false, Some(permit)).await,
+                    Ok(Err(e)) => Err(e.into()),
+                    Err(_) => Err("WebSocket upgrade timed out".into()),
+                };
+                if let Err(e) = result {
+                    eprintln!("Error handling connection: {}", e);
+                    network.record_failure(&ip);
+                }
+            });
+        }
+    }
+
+    // Forward the listen port on the router and keep the lease alive. Failure
+    // is not fatal; the node just stays outbound-only unless peers observe
+    // a reachable address for it.
+    async fn start_port_mapping(&self, port: u16) {
+        let mapping = match PortMapping::create(port).await {
+            Ok(mapping) => mapping,
+            Err(e) => {
+                eprintln!("UPnP port mapping failed: {}", e);
+                return;
+            }
+        };
+        println!("UPnP mapped external address {}", mapping.external_address());
+        *self.external_address.write().await = Some(mapping.external_address());
+
+        self.track(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MAPPING_RENEW_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = mapping.renew().await {
+                    eprintln!("Failed to renew UPnP mapping: {}", e);
+                }
+            }
+        }));
+    }
+
+    // Advertise on the LAN and dial any node found there that we aren't
+    // already connected to
+    fn start_local_discovery(&self, port: u16) {
+        let discovery = match LocalDiscovery::start(&self.config.chain_id, port) {
+            Ok(discovery) => discovery,
+            Err(e) => {
+                eprintln!("mDNS discovery failed to start: {}", e);
+                return;
+            }
+        };
+
+        let network = self.clone();
+        self.track(tokio::spawn(async move {
+            let result = discovery.run(|addresses| {
+                let network = network.clone();
+                tokio::spawn(async move {
+                    network.connect_local(addresses).await;
+                });
+            }).await;
+            if let Err(e) = result {
+                eprintln!("mDNS discovery stopped: {}", e);
+            }
+        }));
+    }
+
+    async fn connect_local(&self, addresses: Vec<SocketAddr>) {
+        let connected: HashSet<String> = self.peers.read().await
+            .values()
+            .map(|peer| peer.info.address.clone())
+            .collect();
+        if addresses.iter().any(|addr| connected.contains(&addr.to_string())) {
+            return;
+        }
+
+        for addr in addresses {
+            let addr = addr.to_string();
+            self.address_book.write().await.add(&addr);
+            match self.connect_to_peer(addr.clone()).await {
+                Ok(()) => {
+                    println!("Connected to local peer {}", addr);
+                    return;
+                }
+                Err(e) => eprintln!("Failed to connect to local peer {}: {}", addr, e),
+            }
+        }
+    }
+
+    pub async fn external_address(&self) -> Option<SocketAddr> {
+        *self.external_address.read().await
+    }
+
+    // Adopt an address reported by enough distinct peers, unless one is
+    // already configured or mapped
+    async fn record_observed_address(&self, peer: &str, observed: &str) {
+        let ip = match observed.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return,
+        };
+        // Prefer a listener of the same family as the observed address
+        let port = {
+            let listen_addrs = self.listen_addrs.read().await;
+            match listen_addrs.iter().find(|addr| addr.is_ipv4() == ip.is_ipv4()).or(listen_addrs.first()) {
+                Some(addr) => addr.port(),
+                None => return,
+            }
+        };
+        if self.external_address.read().await.is_some() {
+            return;
+        }
+
+        let mut observed_ips = self.observed_ips.write().await;
+        let votes = observed_ips.entry(ip).or_insert_with(HashSet::new);
+        votes.insert(peer.to_string());
+        if votes.len() >= OBSERVED_ADDRESS_QUORUM {
+            *self.external_address.write().await = Some(SocketAddr::new(ip, port));
+        }
+    }
+
+    // Remember an operator-supplied address and dial it straight away
+    pub async fn add_peer(&self, addr: String) -> Result<(), Box<dyn Error>> {
+        self.address_book.write().await.add(&addr);
+        self.connect_to_peer(addr).await
+    }
+
+    pub async fn connect_to_peer(&self, addr: String) -> Result<(), Box<dyn Error>> {
+        if self.is_shutting_down() {
+            return Err("Network is shutting down".into());
+        }
+        if self.config.whitelist_only && !self.is_whitelisted_address(&addr) {
+            return Err(format!("{} is not whitelisted", addr).into());
+        }
+        let outbound = self.peers.read().await.values().filter(|peer| peer.outbound).count();
+        if outbound >= self.config.max_outbound && !self.is_static(&addr) {
+            return Err("No outbound slots available".into());
+        }
+        self.address_book.write().await.mark_attempt(&addr);
+        // Errors are kept as strings so the future stays Send across the
+        // address book update
+        let result = match &self.config.proxy {
+            Some(proxy) => match self.connect_via_proxy(proxy, &addr).await {
+                Ok(ws_stream) => {
+                    self.spawn_outbound(ws_stream, addr.clone());
+                    Ok(())
+                }
+                Err(e) => Err(e.to_string()),
+            },
+            None => match connect_async_with_config(format!("ws://{}", addr), Some(self.websocket_config()), false).await {
+                Ok((ws_stream, _)) => {
+                    self.spawn_outbound(ws_stream, addr.clone());
+                    Ok(())
+                }
+                Err(e) => Err(e.to_string()),
+            },
+        };
+
+        match &result {
+            Ok(()) => self.address_book.write().await.mark_success(&addr),
+            Err(_) => self.address_book.write().await.mark_failure(&addr),
+        }
+        result.map_err(Into::into)
+    }
+
+    // Open a WebSocket to `addr` through a SOCKS5 proxy. The proxy resolves
+    // host names itself, so .onion addresses work when it is a Tor daemon.
+    async fn connect_via_proxy(
+        &self,
+        proxy: &ProxyConfig,
+        addr: &str,
+    ) -> Result<WebSocketStream<Socks5Stream<TcpStream>>, Box<dyn Error>> {
+        let stream = match (&proxy.username, &proxy.password) {
+            (Some(username), Some(password)) => {
+                Socks5Stream::connect_with_password(proxy.address, addr, username, password).await?
+            }
+            _ => Socks5Stream::connect(proxy.address, addr).await?,
+        };
+        let (ws_stream, _) = client_async_with_config(format!("ws://{}", addr), stream, Some(self.websocket_config())).await?;
+        Ok(ws_stream)
+    }
+
+    fn spawn_outbound<S>(&self, ws_stream: WebSocketStream<S>, addr: String)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let network = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = network.run_peer(ws_stream, addr.clone(), true, None).await {
+                eprintln!("Error handling connection: {}", e);
+            }
+            network.schedule_reconnect(&addr).await;
+        });
+    }
+
+    pub fn is_static(&self, addr: &str) -> bool {
+        self.config.static_peers.iter().any(|peer| peer == addr)
+    }
+
+    // Whether a connection from `ip` may be accepted
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        !self.config.whitelist_only || self.whitelist.contains(&ip)
+    }
+
+    fn is_whitelisted_address(&self, addr: &str) -> bool {
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => self.whitelist.contains(&addr.ip()),
+            Err(_) => self.is_static(addr) || self.config.bootstrap_nodes.iter().any(|node| node == addr),
+        }
+    }
+
+    // Queue a redial of an outbound peer whose connection ended, backing off
+    // further after each failed attempt. Static peers are never given up on.
+    async fn schedule_reconnect(&self, addr: &str) {
+        if self.is_shutting_down() {
+            return;
+        }
+        if self.suppressed_reconnects.lock().await.remove(addr) {
+            return;
+        }
+        let mut reconnects = self.reconnects.lock().await;
+        let attempts = reconnects.get(addr).map_or(0, |backoff| backoff.attempts);
+        if attempts >= MAX_RECONNECT_ATTEMPTS && !self.is_static(addr) {
+            eprintln!("Giving up on peer {} after {} reconnect attempts", addr, attempts);
+            reconnects.remove(addr);
+            return;
+        }
+        reconnects.insert(addr.to_string(), Backoff {
+            attempts: attempts + 1,
+            next_attempt: Instant::now() + Backoff::delay(attempts),
+        });
+    }
+
+    // Redial dropped outbound peers when their backoff expires, and fall
+    // back to the bootstrap nodes if the node is left with no peers
+    pub fn start_reconnect_scheduler(&self) -> tokio::task::JoinHandle<()> {
+        let network = self.clone();
+        self.track(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECONNECT_CHECK_INTERVAL);
+            let mut isolated_since: Option<Instant> = None;
+            loop {
+                interval.tick().await;
+                network.redial_due().await;
+
+                if network.peer_count().await > 0 {
+                    isolated_since = None;
+                    continue;
+                }
+                let since = *isolated_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= ISOLATION_TIMEOUT {
+                    println!("No peers for {:?}, re-bootstrapping", ISOLATION_TIMEOUT);
+                    network.rebootstrap().await;
+                    isolated_since = Some(Instant::now());
+                }
+            }
+        }))
+    }
+
+    async fn redial_due(&self) {
+        let due: Vec<String> = self.reconnects.lock().await.iter()
+            .filter(|(_, backoff)| backoff.next_attempt <= Instant::now())
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        for addr in due {
+            // Push the entry out so a slow dial isn't retried concurrently;
+            // the connection task reschedules it properly when it ends
+            if let Some(backoff) = self.reconnects.lock().await.get_mut(&addr) {
+                backoff.next_attempt = Instant::now() + RECONNECT_MAX_DELAY;
+            }
+            if let Err(e) = self.connect_to_peer(addr.clone()).await.map_err(|e| e.to_string()) {
+                eprintln!("Reconnect to {} failed: {}", addr, e);
+                self.schedule_reconnect(&addr).await;
+            }
+        }
+    }
+
+    async fn rebootstrap(&self) {
+        {
+            let mut address_book = self.address_book.write().await;
+            for address in &self.config.bootstrap_nodes {
+                address_book.reset(address);
+            }
+        }
+        self.query_dns_seeds().await;
+        self.discover().await;
+    }
+
+    // Add the nodes listed by each DNS seed to the address book. A seed
+    // that fails to resolve is skipped; the others still count.
+    pub async fn query_dns_seeds(&self) -> usize {
+        let mut found = 0;
+        for seed in &self.config.dns_seeds {
+            let host = if seed.contains(':') {
+                seed.clone()
+            } else {
+                format!("{}:{}", seed, self.config.default_port)
+            };
+            let addrs = match tokio::time::timeout(DNS_SEED_TIMEOUT, tokio::net::lookup_host(host)).await {
+                Ok(Ok(addrs)) => addrs,
+                Ok(Err(e)) => {
+                    eprintln!("Failed to resolve DNS seed {}: {}", seed, e);
+                    continue;
+                }
+                Err(_) => {
+                    eprintln!("Resolving DNS seed {} timed out", seed);
+                    continue;
+                }
+            };
+            let mut address_book = self.address_book.write().await;
+            for addr in addrs.filter(|addr| self.is_allowed(addr.ip())).take(MAX_PEERS_PER_REPLY) {
+                address_book.reset(&addr.to_string());
+                found += 1;
+            }
+        }
+        if found > 0 {
+            println!("DNS seeds returned {} addresses", found);
+        }
+        found
+    }
+
+    // Queue a message for every connected peer. Blocks and transactions are
+    // gossiped so they reach each peer once. A peer whose queue is full is
+    // skipped rather than allowed to stall delivery to everyone else.
+    pub async fn broadcast_message(&self, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        if let Some(id) = message.gossip_id() {
+            self.seen.write().await.insert(&id, None);
+            self.relay(&id, &message, DEFAULT_GOSSIP_TTL).await;
+            return Ok(());
+        }
+
+        let peers = self.peers.read().await;
+        
+        for (id, peer) in peers.iter() {
+            let message = match message.adapt_for(peer.info.capabilities) {
+                Some(message) => message,
+                None => continue,
+            };
+            if let Err(e) = peer.sender.try_send(message) {
+                eprintln!("Error broadcasting message to {}: {}", id, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Pass a block or transaction on to the peers that haven't seen `id`,
+    // as chosen by the relay policy for its type. Peers supporting inventory
+    // get its hash and fetch it if they need it; others get the full message
+    // in a gossip envelope while `ttl` remains.
+    async fn relay(&self, id: &str, message: &NetworkMessage, ttl: u8) {
+        let item = match message.inventory_item() {
+            Some(item) => item,
+            None => return,
+        };
+        let policy = self.config.relay.policy(item.kind);
+        let announcement = NetworkMessage::Inventory(vec![item]);
+        let envelope = NetworkMessage::Gossip { ttl, message: Box::new(message.clone()) };
+
+        let peers = self.peers.read().await;
+        let recipients = {
+            let mut seen = self.seen.write().await;
+            let missing = seen.missing(id, peers.keys().cloned().collect());
+            let selected = policy.select(missing, |peer| {
+                peers.get(peer).map_or(false, |handle| {
+                    self.config.validators.iter().any(|v| *v == handle.info.node_id || *v == handle.info.address)
+                })
+            });
+            seen.take_recipients(id, selected)
+        };
+        for peer_id in recipients {
+            if let Some(peer) = peers.get(&peer_id) {
+                let message = if peer.info.capabilities.contains(Capabilities::INVENTORY) {
+                    announcement.clone()
+                } else if ttl > 0 {
+                    match envelope.adapt_for(peer.info.capabilities) {
+                        Some(message) => message,
+                        None => continue,
+                    }
+                } else {
+                    continue;
+                };
+                if let Err(e) = peer.sender.try_send(message) {
+                    eprintln!("Error relaying message to {}: {}", peer_id, e);
+                }
+            }
+        }
+    }
+
+    // Unwrap a gossip envelope and drop duplicates. A new block or
+    // transaction is applied to the chain or mempool, and only once it is
+    // accepted there is it relayed onward while hops remain and delivered
+    // locally; one that fails validation costs the sender score. Blocks
+    // and transactions sent bare (answers to GetData) are handled the same
+    // way. Returns the message to deliver locally.
+    async fn handle_gossip(&self, from: &str, message: NetworkMessage) -> Option<NetworkMessage> {
+        let (ttl, inner) = match message {
+            NetworkMessage::Gossip { ttl, message } => (ttl, *message),
+            other if other.gossip_id().is_some() => (DEFAULT_GOSSIP_TTL, other),
+            other => return Some(other),
+        };
+        let id = inner.gossip_id()?;
+        self.requested_inventory.lock().await.remove(&id);
+
+        if !self.seen.write().await.insert(&id, Some(from)) {
+            return None;
+        }
+        match self.admit(&inner).await {
+            Admission::Accepted => {
+                if let NetworkMessage::NewBlock(block) = &inner {
+                    self.store_block(block).await;
+                }
+                self.adjust_score(from, VALID_ITEM_REWARD).await;
+                self.relay(&id, &inner, ttl.saturating_sub(1)).await;
+                Some(inner)
+            }
+            Admission::Known => None,
+            Admission::Deferred => {
+                // Let a later announcement fetch it again once we can check it
+                self.seen.write().await.remove(&id);
+                None
+            }
+            Admission::Rejected(reason) => {
+                println!("Rejected {} from {}: {}", id, from, reason);
+                self.adjust_score(from, -INVALID_ITEM_PENALTY).await;
+                None
+            }
+        }
+    }
+
+    async fn store_block(&self, block: &Block) {
+        let database = match &self.database {
+            Some(database) => database.clone(),
+            None => return,
+        };
+        let stored = block.clone();
+        let saved = tokio::task::spawn_blocking(move || database.save_block(&stored).map_err(|e| e.to_string()))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result);
+        if let Err(e) = saved {
+            eprintln!("Failed to store block {}: {}", block.hash, e);
+        }
+    }
+
+    // Validate a gossiped block or transaction by applying it. Only checks
+    // that need no chain state can reject it outright.
+    async fn admit(&self, message: &NetworkMessage) -> Admission {
+        match message {
+            NetworkMessage::NewBlock(block) => {
+                if self.blockchain.get_block(&block.hash).await.is_some() {
+                    return Admission::Known;
+                }
+                if let Err(e) = block.check_standalone() {
+                    return Admission::Rejected(e.to_string());
+                }
+                match self.blockchain.offer_block(block.clone()).await {
+                    Ok(true) => Admission::Accepted,
+                    Ok(false) => Admission::Deferred,
+                    Err(_) => Admission::Known,
+                }
+            }
+            NetworkMessage::NewTransaction(transaction) => {
+                if self.blockchain.get_pending_transaction(&transaction.hash()).await.is_some() {
+                    return Admission::Known;
+                }
+                if let Err(e) = transaction.check_standalone() {
+                    return Admission::Rejected(e.to_string());
+                }
+                match self.blockchain.add_transaction(transaction.clone()).await {
+                    Ok(()) => Admission::Accepted,
+                    Err(_) => Admission::Known,
+                }
+            }
+            _ => Admission::Rejected("Not a block or transaction".to_string()),
+        }
+    }
+
+    // Everything done with a message from a registered peer, whichever
+    // connection it arrived on
+    async fn handle_peer_message(&self, from: &PeerId, message: NetworkMessage) {
+        let message = match self.handle_gossip(from, message).await {
+            Some(message) => message,
+            None => return,
+        };
+        let (request_id, message) = match message {
+            NetworkMessage::Response { id: request_id, message } => {
+                self.complete_request(from, request_id, *message).await;
+                return;
+            }
+            NetworkMessage::Request { id: request_id, message } => (Some(request_id), *message),
+            message => (None, message),
+        };
+        self.handle_inventory_message(from, &message).await;
+        self.handle_discovery_message(from, request_id, &message).await;
+        self.handle_sync_request(from, request_id, &message).await;
+        // Nobody may be listening yet; that is not an error
+        let _ = self.message_tx.send((from.clone(), message));
+    }
 
-pub struct Network {
-    peers: Arc<HashMap<String, WebSocketStream<TcpStream>>>,
-    message_tx: broadcast::Sender<NetworkMessage>,
-    message_rx: broadcast::Receiver<NetworkMessage>,
-}
+    // Fetch announced items we don't have, and serve items peers ask for
+    async fn handle_inventory_message(&self, from: &str, message: &NetworkMessage) {
+        match message {
+            NetworkMessage::Inventory(items) => {
+                let mut wanted = vec![];
+                {
+                    let mut seen = self.seen.write().await;
+                    let mut requested = self.requested_inventory.lock().await;
+                    requested.retain(|_, asked| asked.elapsed() < REQUEST_TIMEOUT);
+                    for item in items.iter().take(MAX_INVENTORY_PER_MESSAGE) {
+                        let id = item.id();
+                        if seen.contains(&id) {
+                            // Don't announce it back to the peer that told us
+                            seen.insert(&id, Some(from));
+                        } else if !requested.contains_key(&id) {
+                            requested.insert(id, Instant::now());
+                            wanted.push(item.clone());
+                        }
+                    }
+                }
+                if !wanted.is_empty() {
+                    let _ = self.send_to(from, NetworkMessage::GetData(wanted)).await;
+                }
+            }
+            NetworkMessage::GetData(items) => {
+                for item in items.iter().take(MAX_INVENTORY_PER_MESSAGE) {
+                    let reply = match item.kind {
+                        InventoryKind::Block => self.blockchain.get_block(&item.hash).await
+                            .map(NetworkMessage::NewBlock),
+                        InventoryKind::Transaction => self.blockchain.get_pending_transaction(&item.hash).await
+                            .map(NetworkMessage::NewTransaction),
+                    };
+                    if let Some(reply) = reply {
+                        let _ = self.send_to(from, reply).await;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
 
-impl Network {
-    pub fn new() -> Self {
-        let (message_tx, message_rx) = broadcast::channel(100);
-        Network {
-            peers: Arc::new(HashMap::new()),
-            message_tx,
-            message_rx,
+    pub async fn send_to(&self, peer: &str, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        let (sender, capabilities) = self.peers.read().await
+            .get(peer)
+            .map(|handle| (handle.sender.clone(), handle.info.capabilities))
+            .ok_or_else(|| format!("Unknown peer {}", peer))?;
+        let message = message.adapt_for(capabilities).ok_or_else(|| {
+            format!("Peer {} does not support {}", peer, message.required_capability())
+        })?;
+        sender.send(message).await.map_err(|_| format!("Peer {} disconnected", peer))?;
+        Ok(())
+    }
+
+    pub async fn adjust_score(&self, peer: &str, delta: i32) {
+        let address = {
+            let mut peers = self.peers.write().await;
+            let handle = match peers.get_mut(peer) {
+                Some(handle) => handle,
+                None => return,
+            };
+            handle.info.score = (handle.info.score + delta).clamp(MIN_PEER_SCORE, MAX_PEER_SCORE);
+            if handle.info.score > MIN_PEER_SCORE {
+                return;
+            }
+            handle.info.address.clone()
+        };
+        println!("Disconnecting {}: score fell to {}", peer, MIN_PEER_SCORE);
+        self.disconnect(peer).await;
+        if !self.is_whitelisted_address(&address) {
+            if let Ok(addr) = address.parse::<SocketAddr>() {
+                self.record_failure(&addr.ip().to_string());
+            }
         }
     }
 
-    pub async fn start(&self, addr: SocketAddr) -> Result<(), Box<dyn Error>> {
-        let listener = TcpListener::bind(addr).await?;
-        println!("Network listening on {}", addr);
+    // Register a peer if a slot is free. An inbound peer may take the slot
+    // of the worst inbound peer, preferring to keep longer-lived connections.
+    // A peer reconnecting under an id that is still registered replaces the
+    // old connection, which is dropped.
+    async fn register_peer(&self, id: &str, handle: PeerHandle) -> Result<(), Box<dyn Error>> {
+        let mut peers = self.peers.write().await;
+        // Checked under the lock so shutdown can't miss a late registration
+        if self.is_shutting_down() {
+            return Err("Network is shutting down".into());
+        }
+        if peers.remove(id).is_some() {
+            println!("Replacing the previous connection to {}", id);
+        }
+        let outbound = peers.values().filter(|peer| peer.outbound).count();
+        let inbound = peers.len() - outbound;
 
-        while let Ok((stream, addr)) = listener.accept().await {
-            println!("New connection from {}", addr);
-            let message_tx = self.message_tx.clone();
-            let peers = self.peers.clone();
-            
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, addr, message_tx, peers).await {
-                    eprintln!("Error handling connection: {}", e);
+        if handle.outbound {
+            let full = outbound >= self.config.max_outbound || peers.len() >= self.config.max_peers;
+            if full && !self.is_static(id) {
+                return Err("No outbound slots available".into());
+            }
+        } else if inbound >= self.config.max_inbound || peers.len() >= self.config.max_peers {
+            let worst = peers.iter()
+                .filter(|(_, peer)| !peer.outbound)
+                .min_by_key(|(_, peer)| (peer.info.score, std::cmp::Reverse(peer.connected_at)))
+                .map(|(id, _)| id.clone())
+                .ok_or("No inbound slots available")?;
+            println!("Evicting peer {} to make room for {}", worst, id);
+            peers.remove(&worst);
+        }
+
+        peers.insert(id.to_string(), handle);
+        Ok(())
+    }
+
+    // Remove a peer's registration, unless it has since been replaced by a
+    // newer connection under the same id. `traffic` identifies the
+    // connection, as each one gets its own counters. Returns false if a
+    // newer connection holds the id.
+    async fn unregister_peer(&self, id: &str, traffic: &Arc<std::sync::Mutex<PeerTraffic>>) -> bool {
+        let mut peers = self.peers.write().await;
+        match peers.get(id) {
+            Some(handle) if !Arc::ptr_eq(&handle.traffic, traffic) => false,
+            _ => {
+                peers.remove(id);
+                true
+            }
+        }
+    }
+
+    // Send `message` to `peer` and wait for the matching response
+    pub async fn request(&self, peer: &str, message: NetworkMessage) -> Result<NetworkMessage, Box<dyn Error>> {
+        self.request_with_timeout(peer, message, REQUEST_TIMEOUT).await
+    }
+
+    pub async fn request_with_timeout(
+        &self,
+        peer: &str,
+        message: NetworkMessage,
+        timeout: Duration,
+    ) -> Result<NetworkMessage, Box<dyn Error>> {
+        let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, response) = oneshot::channel();
+        self.pending_requests.lock().await.insert(id, PendingRequest { peer: peer.to_string(), reply });
+
+        // A String error keeps the future Send while the entry is removed
+        let sent = self.send_to(peer, NetworkMessage::Request { id, message: Box::new(message) }).await;
+        let result = match sent.map_err(|e| e.to_string()) {
+            Ok(()) => match tokio::time::timeout(timeout, response).await {
+                Ok(Ok(message)) => Ok(message),
+                Ok(Err(_)) => Err(format!("Peer {} disconnected", peer)),
+                Err(_) => Err(format!("Request {} to {} timed out", id, peer)),
+            },
+            Err(e) => Err(e),
+        };
+        self.pending_requests.lock().await.remove(&id);
+        result.map_err(Into::into)
+    }
+
+    async fn complete_request(&self, from: &str, id: u64, message: NetworkMessage) {
+        let mut pending = self.pending_requests.lock().await;
+        match pending.get(&id) {
+            Some(request) if request.peer == from => {
+                let request = pending.remove(&id).unwrap();
+                let _ = request.reply.send(message);
+            }
+            _ => eprintln!("Ignoring unsolicited response {} from {}", id, from),
+        }
+    }
+
+    // Answer a query, correlating the reply if the query carried an id
+    async fn reply(&self, to: &str, request_id: Option<u64>, message: NetworkMessage) {
+        let message = match request_id {
+            Some(id) => NetworkMessage::Response { id, message: Box::new(message) },
+            None => message,
+        };
+        let _ = self.send_to(to, message).await;
+    }
+
+    // Dropping the handle closes the peer's queue, which ends its writer task
+    pub async fn disconnect(&self, peer: &str) {
+        if let Some(handle) = self.peers.write().await.remove(peer) {
+            if handle.outbound {
+                self.suppressed_reconnects.lock().await.insert(peer.to_string());
+            }
+        }
+    }
+
+    pub async fn peer_count(&self) -> usize {
+        self.peers.read().await.len()
+    }
+
+    pub async fn peer_info(&self, peer: &str) -> Option<PeerInfo> {
+        self.peers.read().await.get(peer).map(|handle| handle.info.clone())
+    }
+
+    pub fn blockchain(&self) -> &Blockchain {
+        &self.blockchain
+    }
+
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.read().await.values().map(|handle| handle.info.clone()).collect()
+    }
+
+    // Let the WebSocket layer refuse oversized messages before buffering them
+    fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.config.limits.max_message_size),
+            max_frame_size: Some(self.config.limits.max_message_size),
+            ..WebSocketConfig::default()
+        }
+    }
+
+    pub fn node_id(&self) -> String {
+        self.identity.node_id()
+    }
+
+    pub fn chain_id(&self) -> &str {
+        &self.config.chain_id
+    }
+
+    pub async fn metrics(&self) -> NetworkMetrics {
+        let peers: Vec<PeerMetrics> = self.peers.read().await.iter()
+            .map(|(id, handle)| PeerMetrics {
+                peer: id.clone(),
+                outbound: handle.outbound,
+                score: handle.info.score,
+                connected_seconds: handle.connected_at.elapsed().as_secs(),
+                traffic: handle.traffic.lock().unwrap().clone(),
+            })
+            .collect();
+        NetworkMetrics {
+            peer_count: peers.len(),
+            known_addresses: self.known_addresses().await,
+            peers,
+            compression: self.compression_metrics(),
+        }
+    }
+
+    pub fn compression_metrics(&self) -> CompressionMetrics {
+        self.compression_stats.metrics()
+    }
+
+    async fn mark_seen(&self, addresses: &[String]) {
+        let mut address_book = self.address_book.write().await;
+        for address in addresses {
+            address_book.mark_seen(address, chrono::Utc::now());
+        }
+    }
+
+    pub async fn known_addresses(&self) -> usize {
+        self.address_book.read().await.len()
+    }
+
+    // Load addresses saved by a previous run so discovery can reconnect to
+    // known good peers before falling back to bootstrap nodes
+    pub async fn restore_addresses(&self, database: Arc<Database>) -> Result<usize, Box<dyn Error>> {
+        let addresses = tokio::task::spawn_blocking(move || {
+            database.get_peer_addresses().map_err(|e| e.to_string())
+        }).await??;
+        let count = addresses.len();
+        self.address_book.write().await.restore(addresses);
+        Ok(count)
+    }
+
+    pub async fn save_addresses(&self, database: Arc<Database>) -> Result<(), Box<dyn Error>> {
+        let addresses = self.address_book.read().await.persistable();
+        tokio::task::spawn_blocking(move || {
+            database.save_peer_addresses(&addresses).map_err(|e| e.to_string())
+        }).await??;
+        Ok(())
+    }
+
+    // Reinstate lockouts from a previous run that have not yet run out
+    pub async fn restore_bans(&self, database: Arc<Database>) -> Result<usize, Box<dyn Error>> {
+        let bans = tokio::task::spawn_blocking(move || {
+            database.get_peer_bans().map_err(|e| e.to_string())
+        }).await??;
+        for ban in &bans {
+            self.intrusion_detection.lock_out_until(&ban.address, ban.until);
+        }
+        Ok(bans.len())
+    }
+
+    pub async fn save_bans(&self, database: Arc<Database>) -> Result<(), Box<dyn Error>> {
+        let bans: Vec<PeerBan> = self.intrusion_detection.lockouts().into_iter()
+            .map(|(address, until)| PeerBan { address, until })
+            .collect();
+        tokio::task::spawn_blocking(move || {
+            database.save_peer_bans(&bans).map_err(|e| e.to_string())
+        }).await??;
+        Ok(())
+    }
+
+    pub fn start_address_persistence(&self, database: Arc<Database>) -> tokio::task::JoinHandle<()> {
+        let network = self.clone();
+        self.track(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ADDRESS_SAVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if let Err(e) = network.save_addresses(database.clone()).await {
+                    eprintln!("Failed to save peer addresses: {}", e);
                 }
-            });
+                if let Err(e) = network.save_bans(database.clone()).await {
+                    eprintln!("Failed to save peer bans: {}", e);
+                }
+            }
+        }))
+    }
+
+    // Keep `target_outbound` connections open, dialling the best-scored known
+    // addresses (bootstrap nodes first) and asking a random peer for more
+    pub fn start_discovery(&self) -> tokio::task::JoinHandle<()> {
+        let network = self.clone();
+        self.track(tokio::spawn(async move {
+            network.query_dns_seeds().await;
+            let mut interval = tokio::time::interval(DISCOVERY_INTERVAL);
+            loop {
+                interval.tick().await;
+                network.discover().await;
+            }
+        }))
+    }
+
+    async fn discover(&self) {
+        let configured: Vec<String> = self.config.bootstrap_nodes.iter()
+            .chain(&self.config.static_peers)
+            .cloned()
+            .collect();
+        let expired = self.address_book.write().await.expire(self.config.address_expiry, &configured);
+        if expired > 0 {
+            println!("Forgot {} stale peer addresses", expired);
+        }
+
+        let (connected, outbound): (Vec<String>, usize) = {
+            let peers = self.peers.read().await;
+            (
+                peers.keys().cloned().collect(),
+                peers.values().filter(|peer| peer.outbound).count(),
+            )
+        };
+
+        // Static peers that aren't connected or already waiting to be redialled
+        let waiting: Vec<String> = self.reconnects.lock().await.keys().cloned().collect();
+        for addr in &self.config.static_peers {
+            if !connected.contains(addr) && !waiting.contains(addr) {
+                if let Err(e) = self.connect_to_peer(addr.clone()).await.map_err(|e| e.to_string()) {
+                    eprintln!("Failed to connect to static peer {}: {}", addr, e);
+                    self.schedule_reconnect(addr).await;
+                }
+            }
+        }
+
+        let wanted = self.config.target_outbound.saturating_sub(outbound);
+        if wanted > 0 {
+            // Static peers were handled above; peers in backoff are left to
+            // the reconnect scheduler
+            let mut exclude = connected.clone();
+            exclude.extend(waiting);
+            exclude.extend(self.config.static_peers.iter().cloned());
+            let candidates = self.address_book.read().await.candidates(&exclude, wanted);
+            for address in candidates {
+                if let Err(e) = self.connect_to_peer(address.clone()).await {
+                    eprintln!("Failed to connect to {}: {}", address, e);
+                }
+            }
+        }
+
+        let peer = connected.choose(&mut rand::thread_rng()).cloned();
+        if let Some(peer) = peer {
+            let _ = self.send_to(&peer, NetworkMessage::GetPeers).await;
+        }
+    }
+
+    // Serve chain data to peers that are syncing from us
+    async fn handle_sync_request(&self, from: &str, request_id: Option<u64>, message: &NetworkMessage) {
+        let reply = match message {
+            NetworkMessage::GetHeaders { locator, limit } => {
+                let limit = (*limit as usize).min(MAX_HEADERS_PER_REQUEST);
+                NetworkMessage::Headers(self.blockchain.headers_after(locator, limit).await)
+            }
+            NetworkMessage::GetBlocks(hashes) => {
+                let hashes: Vec<String> = hashes.iter().take(MAX_BLOCKS_PER_REQUEST).cloned().collect();
+                NetworkMessage::Blocks(self.blockchain.get_blocks(&hashes).await)
+            }
+            _ => return,
+        };
+        self.reply(from, request_id, reply).await;
+    }
+
+    // Peer exchange is answered here; everything else goes to subscribers
+    async fn handle_discovery_message(&self, from: &str, request_id: Option<u64>, message: &NetworkMessage) {
+        match message {
+            NetworkMessage::GetPeers => {
+                let addresses = self.address_book.read().await.good_addresses(MAX_PEERS_PER_REPLY, self.config.address_expiry);
+                let peers = addresses.into_iter()
+                    .filter_map(|entry| Some(PeerInfo {
+                        address: entry.address,
+                        node_id: String::new(),
+                        version: String::new(),
+                        last_seen: entry.last_seen?,
+                        protocol_version: 0,
+                        best_height: 0,
+                        capabilities: Capabilities::NONE,
+                        score: 0,
+                        compression: Compression::None,
+                    }))
+                    .collect();
+                self.reply(from, request_id, NetworkMessage::Peers(peers)).await;
+            }
+            NetworkMessage::Peers(peers) => {
+                let mut address_book = self.address_book.write().await;
+                for peer in peers.iter().take(MAX_PEERS_PER_REPLY) {
+                    // Stale addresses are neither kept nor passed on
+                    if is_stale(Some(peer.last_seen), self.config.address_expiry) {
+                        continue;
+                    }
+                    if let Ok(addr) = peer.address.parse::<SocketAddr>() {
+                        if self.is_allowed(addr.ip()) {
+                            address_book.mark_seen(&peer.address, peer.last_seen);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub async fn local_handshake(&self) -> Handshake {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            chain_id: self.config.chain_id.clone(),
+            genesis_hash: self.blockchain.genesis_hash().await,
+            best_height: self.blockchain.height().await,
+            user_agent: format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            listen_port: self.listen_addrs.read().await.first().map(|addr| addr.port()),
+            listen_addresses: self.advertised_addresses().await,
+            external_address: self.external_address.read().await.map(|addr| addr.to_string()),
+            observed_address: None,
+            capabilities: CAPABILITIES,
+            compression: SUPPORTED_COMPRESSION.to_vec(),
         }
+    }
 
+    // Reject peers on another network or speaking an unsupported protocol
+    async fn check_handshake(&self, handshake: &Handshake) -> Result<(), Box<dyn Error>> {
+        if handshake.protocol_version < MIN_PROTOCOL_VERSION {
+            return Err(format!("Unsupported protocol version {}", handshake.protocol_version).into());
+        }
+        if handshake.chain_id != self.config.chain_id {
+            return Err(format!("Peer is on chain {}", handshake.chain_id).into());
+        }
+        if handshake.genesis_hash != self.blockchain.genesis_hash().await {
+            return Err("Peer has a different genesis block".into());
+        }
         Ok(())
     }
 
-    pub async fn connect_to_peer(&self, addr: String) -> Result<(), Box<dyn Error>> {
-        let stream = TcpStream::connect(&addr).await?;
-        let ws_stream = accept_async(stream).await?;
+    // Handshake, register the peer, then pump its socket until either side
+    // hangs up. Inbound connections hold a pending-connection permit until
+    // they are registered.
+    async fn run_peer<S>(
+        &self,
+        ws_stream: WebSocketStream<S>,
+        id: PeerId,
+        outbound: bool,
+        pending: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), Box<dyn Error>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let _connection = ConnectionGuard::new(self.active_connections.clone());
+        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+        // Authenticate and encrypt the connection before anything else; the
+        // dialling side initiates
+        let noise = secure::handshake(&mut ws_sender, &mut ws_receiver, &self.identity, outbound);
+        let (mut encryptor, mut decryptor, node_id) = match tokio::time::timeout(HANDSHAKE_TIMEOUT, noise).await {
+            Ok(result) => result.map_err(|e| format!("Secure handshake with {} failed: {}", id, e))?,
+            Err(_) => return Err(format!("Secure handshake with {} timed out", id).into()),
+        };
+
+        let mut local = self.local_handshake().await;
+        local.observed_address = Some(id.clone());
+        let frame = encode_frame(&NetworkMessage::Handshake(local.clone()), Compression::None, &self.compression_stats)?;
+        let sealed = encryptor.encrypt(&frame)?;
+        ws_sender.send(Message::Binary(sealed)).await?;
+
+        // Handshakes are always sent uncompressed
+        let remote = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_message(&mut ws_receiver, &mut decryptor, Compression::None, &self.compression_stats)).await {
+            Ok(Some((NetworkMessage::Handshake(handshake), _))) => handshake,
+            Ok(_) => return Err(format!("Peer {} did not send a handshake", id).into()),
+            Err(_) => return Err(format!("Handshake with {} timed out", id).into()),
+        };
+        if let Err(reason) = self.check_handshake(&remote).await.map_err(|e| e.to_string()) {
+            let _ = ws_sender.close().await;
+            return Err(format!("Rejected peer {}: {}", id, reason).into());
+        }
+
+        // Inbound peers connect from an ephemeral port; remember where they
+        // listen. Those are the addresses kept fresh while they're connected.
+        let reachable_at: Vec<String> = if outbound {
+            vec![id.clone()]
+        } else {
+            remote_listen_addresses(&remote, &id).iter().map(|addr| addr.to_string()).collect()
+        };
+        self.mark_seen(&reachable_at).await;
+        if let Some(observed) = &remote.observed_address {
+            self.record_observed_address(&id, observed).await;
+        }
+
+        let compression = Compression::negotiate(&local.compression, &remote.compression);
+
+        // Add peer to peers list
+        let (sender, mut receiver) = mpsc::channel::<NetworkMessage>(PEER_QUEUE_SIZE);
+        let traffic = Arc::new(std::sync::Mutex::new(PeerTraffic::default()));
+        let handle = PeerHandle {
+            info: PeerInfo {
+                address: id.clone(),
+                node_id,
+                version: remote.user_agent,
+                last_seen: chrono::Utc::now(),
+                protocol_version: remote.protocol_version.min(PROTOCOL_VERSION),
+                best_height: remote.best_height,
+                capabilities: remote.capabilities.intersection(local.capabilities),
+                score: 0,
+                compression,
+            },
+            outbound,
+            connected_at: Instant::now(),
+            traffic: traffic.clone(),
+            sender,
+        };
+        if let Err(reason) = self.register_peer(&id, handle).await.map_err(|e| e.to_string()) {
+            let _ = ws_sender.close().await;
+            return Err(format!("Rejected peer {}: {}", id, reason).into());
+        }
+        if outbound {
+            self.reconnects.lock().await.remove(&id);
+        }
+        self.emit(NetworkEvent::PeerConnected { peer: id.clone(), outbound });
+        if let Some(permit) = pending {
+            drop(permit);
+            if let Ok(addr) = id.parse::<SocketAddr>() {
+                self.intrusion_detection.reset_failed_attempts(&addr.ip().to_string());
+            }
+        }
+
+        // Drain the outbound queue into the socket
+        let stats = self.compression_stats.clone();
+        let sent_traffic = traffic.clone();
+        let mut writer = tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                let frame = match encode_frame(&message, compression, &stats) {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                let frame = match encryptor.encrypt(&frame) {
+                    Ok(frame) => frame,
+                    Err(_) => break,
+                };
+                sent_traffic.lock().unwrap().record_sent(message.type_name(), frame.len());
+                if ws_sender.send(Message::Binary(frame)).await.is_err() {
+                    break;
+                }
+            }
+            let _ = ws_sender.close().await;
+        });
+
+        // Handle incoming messages until the peer hangs up or the writer
+        // fails, so a dead socket never leaves a peer registered whose
+        // queue nobody drains
+        let mut limiter = RateLimiter::new(self.config.limits.clone());
+        let mut last_seen_update = Instant::now();
+        loop {
+            let (message, size) = tokio::select! {
+                next = read_message(&mut ws_receiver, &mut decryptor, compression, &self.compression_stats) => match next {
+                    Some(next) => next,
+                    None => break,
+                },
+                _ = &mut writer => break,
+            };
+            traffic.lock().unwrap().record_received(message.type_name(), size);
+            if self.event_tx.receiver_count() > 0 {
+                self.emit(NetworkEvent::MessageReceived {
+                    peer: id.clone(),
+                    message_type: message.type_name().to_string(),
+                    size,
+                });
+            }
+            if let Err(e) = limiter.check(size) {
+                eprintln!("Disconnecting peer {}: {}", id, e);
+                break;
+            }
+            if let Some(peer) = self.peers.write().await.get_mut(&id) {
+                peer.info.last_seen = chrono::Utc::now();
+            }
+            if last_seen_update.elapsed() >= LAST_SEEN_UPDATE_INTERVAL {
+                last_seen_update = Instant::now();
+                self.mark_seen(&reachable_at).await;
+            }
+            if let NetworkMessage::Disconnect { reason } = &message {
+                println!("Peer {} disconnected: {}", id, reason);
+                break;
+            }
+            self.handle_peer_message(&id, message).await;
+        }
         
-        let message_tx = self.message_tx.clone();
-        let peers = self.peers.clone();
+        // Remove peer when disconnected, unless a reconnect already took its place
+        let current = self.unregister_peer(&id, &traffic).await;
+        self.mark_seen(&reachable_at).await;
+        writer.abort();
+        if current {
+            self.emit(NetworkEvent::PeerDisconnected { peer: id.clone() });
+        }
         
+        Ok(())
+    }
+
+    // Carry peer traffic over `transport` instead of sockets, so gossip,
+    // sync and discovery run unchanged over libp2p or in a simulated
+    // network. The transport's current peers are registered right away,
+    // later ones with their first message. There is no handshake, so every
+    // peer is taken to support everything this node does.
+    pub async fn attach_transport(&self, transport: Arc<dyn NetworkTransport>) -> Result<(), Box<dyn Error>> {
+        let mut messages = transport.subscribe();
+        for peer in transport.peers().await {
+            self.attach_peer(&transport, peer.node_id).await?;
+        }
+
+        let network = self.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, addr.parse().unwrap(), message_tx, peers).await {
-                eprintln!("Error handling connection: {}", e);
+            loop {
+                let (from, message) = match messages.recv().await {
+                    Ok(next) => next,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let known = network.peers.read().await.contains_key(&from);
+                if !known && network.attach_peer(&transport, from.clone()).await.is_err() {
+                    continue;
+                }
+                network.handle_peer_message(&from, message).await;
             }
         });
+        Ok(())
+    }
 
+    async fn attach_peer(&self, transport: &Arc<dyn NetworkTransport>, id: PeerId) -> Result<(), Box<dyn Error>> {
+        let (sender, mut receiver) = mpsc::channel::<NetworkMessage>(PEER_QUEUE_SIZE);
+        let handle = PeerHandle {
+            info: PeerInfo {
+                address: id.clone(),
+                node_id: id.clone(),
+                version: String::new(),
+                last_seen: chrono::Utc::now(),
+                protocol_version: PROTOCOL_VERSION,
+                best_height: 0,
+                capabilities: CAPABILITIES,
+                score: 0,
+                compression: Compression::None,
+            },
+            outbound: true,
+            connected_at: Instant::now(),
+            traffic: Arc::new(std::sync::Mutex::new(PeerTraffic::default())),
+            sender,
+        };
+        self.register_peer(&id, handle).await?;
+        self.emit(NetworkEvent::PeerConnected { peer: id.clone(), outbound: true });
+
+        // The transport decides whether and when each message arrives
+        let transport = transport.clone();
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                let _ = transport.send_to(&id, message).await;
+            }
+        });
         Ok(())
     }
+}
 
-    pub async fn broadcast_message(&self, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
-        let message = serde_json::to_string(&message)?;
-        let peers = self.peers.clone();
-        
-        for (_, peer) in peers.iter() {
-            if let Err(e) = peer.send(message.clone().into()).await {
-                eprintln!("Error broadcasting message: {}", e);
+#[async_trait]
+impl NetworkTransport for Network {
+    async fn listen(&self, addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+        self.start(addr).await
+    }
+
+    async fn connect(&self, addr: String) -> Result<(), Box<dyn Error>> {
+        self.connect_to_peer(addr).await
+    }
+
+    async fn broadcast(&self, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        self.broadcast_message(message).await
+    }
+
+    async fn send_to(&self, peer: &str, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        Network::send_to(self, peer, message).await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<(PeerId, NetworkMessage)> {
+        Network::subscribe(self)
+    }
+
+    async fn peers(&self) -> Vec<PeerInfo> {
+        Network::peers(self).await
+    }
+}
+
+// Next decodable message from the socket; None once the connection closes.
+// Frames that are not valid messages are skipped.
+async fn read_message<S>(
+    receiver: &mut futures_util::stream::SplitStream<WebSocketStream<S>>,
+    decryptor: &mut SecureReceiver,
+    compression: Compression,
+    stats: &CompressionStats,
+) -> Option<(NetworkMessage, usize)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(msg) = receiver.next().await {
+        match msg.ok()? {
+            Message::Close(_) => return None,
+            Message::Binary(data) => {
+                // A frame that fails authentication means the stream has been
+                // tampered with or the nonces are out of step; drop the peer
+                let frame = decryptor.decrypt(&data).ok()?;
+                // Newer peers may send message types added since this
+                // release; skip them rather than disconnect
+                match frame_type(&frame) {
+                    Some(message_type) if !NetworkMessage::is_known_type(message_type) => continue,
+                    _ => {}
+                }
+                return match decode_frame(&frame, compression, stats) {
+                    Ok(message) => Some((message, data.len())),
+                    Err(e) => {
+                        eprintln!("Malformed message from peer: {}", e);
+                        None
+                    }
+                };
             }
+            _ => continue,
         }
+    }
+    None
+}
 
-        Ok(())
+// How long a client has to send its subscription after connecting
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+const CLIENT_PING_INTERVAL: Duration = Duration::from_secs(30);
+// Clients silent for this long (no pong or other frame) are dropped
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+// Notifications queued per client before it is treated as too slow
+const CLIENT_QUEUE_SIZE: usize = 256;
+
+// First message a client sends: the notification types it wants, or an
+// empty list for everything. It may be resent later to change topics.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    pub subscribe: Vec<String>,
+}
+
+struct NotificationClient {
+    sender: mpsc::Sender<Message>,
+    topics: HashSet<String>,
+}
+
+impl NotificationClient {
+    fn wants(&self, topic: Option<&str>) -> bool {
+        self.topics.is_empty() || topic.map_or(false, |topic| self.topics.contains(topic))
     }
 }
 
-async fn handle_connection(
-    stream: TcpStream,
-    addr: SocketAddr,
-    message_tx: broadcast::Sender<NetworkMessage>,
-    peers: Arc<HashMap<String, WebSocketStream<TcpStream>>>,
-) -> Result<(), Box<dyn Error>> {
-    let ws_stream = accept_async(stream).await?;
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-    
-    // Add peer to peers list
-    peers.insert(addr.to_string(), ws_stream);
-    
-    // Handle incoming messages
-    while let Some(msg) = ws_receiver.next().await {
-        if let Ok(msg) = msg {
-            if let Ok(text) = msg.to_text() {
-                if let Ok(message) = serde_json::from_str::<NetworkMessage>(text) {
-                    // Broadcast message to other peers
-                    message_tx.send(message.clone())?;
-                }
+// Bind a listener. IPv6 sockets are made IPv6-only so that [::]:port and
+// 0.0.0.0:port can be bound side by side.
+fn bind_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Where a peer that connected from `id` accepts connections, from its
+// handshake. Wildcard addresses take the IP it connected from; peers that
+// only send a port get the same treatment.
+fn remote_listen_addresses(remote: &Handshake, id: &str) -> Vec<SocketAddr> {
+    let source_ip = id.parse::<SocketAddr>().ok().map(|addr| addr.ip());
+    let mut addresses: Vec<SocketAddr> = remote.external_address.iter()
+        .chain(&remote.listen_addresses)
+        .filter_map(|addr| addr.parse::<SocketAddr>().ok())
+        .filter_map(|addr| {
+            if addr.ip().is_unspecified() {
+                let ip = source_ip.filter(|ip| ip.is_ipv4() == addr.is_ipv4())?;
+                Some(SocketAddr::new(ip, addr.port()))
+            } else {
+                Some(addr)
             }
+        })
+        .collect();
+    if addresses.is_empty() {
+        if let (Some(ip), Some(port)) = (source_ip, remote.listen_port) {
+            addresses.push(SocketAddr::new(ip, port));
         }
     }
-    
-    // Remove peer when disconnected
-    peers.remove(&addr.to_string());
-    
-    Ok(())
+    addresses.dedup();
+    addresses
 }
 
 // WebSocket Server for real-time notifications
 pub struct WebSocketServer {
-    clients: Arc<HashMap<String, WebSocketStream<TcpStream>>>,
+    clients: Arc<RwLock<HashMap<String, NotificationClient>>>,
 }
 
 impl WebSocketServer {
     pub fn new() -> Self {
         WebSocketServer {
-            clients: Arc::new(HashMap::new()),
+            clients: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -136,45 +2726,219 @@ impl WebSocketServer {
         let listener = TcpListener::bind(addr).await?;
         println!("WebSocket server listening on {}", addr);
 
-        while let Ok((stream, _)) = listener.accept().await {
+        while let Ok((stream, client_addr)) = listener.accept().await {
             let clients = self.clients.clone();
-            
+
             tokio::spawn(async move {
-                if let Err(e) = handle_websocket_connection(stream, clients).await {
+                let client_addr = client_addr.to_string();
+                if let Err(e) = handle_websocket_connection(stream, client_addr.clone(), clients.clone()).await {
                     eprintln!("Error handling WebSocket connection: {}", e);
                 }
+                clients.write().await.remove(&client_addr);
             });
         }
 
         Ok(())
     }
 
+    pub async fn client_count(&self) -> usize {
+        self.clients.read().await.len()
+    }
+
+    // Queue `notification` for every client subscribed to its "type". Clients
+    // whose queue is full are dropped rather than allowed to stall the rest.
     pub async fn broadcast_notification(&self, notification: serde_json::Value) -> Result<(), Box<dyn Error>> {
         let message = serde_json::to_string(&notification)?;
-        let clients = self.clients.clone();
-        
-        for (_, client) in clients.iter() {
-            if let Err(e) = client.send(message.clone().into()).await {
-                eprintln!("Error broadcasting notification: {}", e);
+        let topic = notification.get("type").and_then(|t| t.as_str());
+
+        let mut slow = vec![];
+        for (addr, client) in self.clients.read().await.iter() {
+            if !client.wants(topic) {
+                continue;
+            }
+            if client.sender.try_send(Message::Text(message.clone())).is_err() {
+                slow.push(addr.clone());
             }
         }
 
+        if !slow.is_empty() {
+            let mut clients = self.clients.write().await;
+            for addr in slow {
+                eprintln!("Dropping notification client {}: not keeping up", addr);
+                clients.remove(&addr);
+            }
+        }
         Ok(())
     }
 }
 
+fn subscribed_reply(topics: &[String]) -> Message {
+    Message::Text(serde_json::json!({ "subscribed": topics }).to_string())
+}
+
 async fn handle_websocket_connection(
     stream: TcpStream,
-    clients: Arc<HashMap<String, WebSocketStream<TcpStream>>>,
+    addr: String,
+    clients: Arc<RwLock<HashMap<String, NotificationClient>>>,
 ) -> Result<(), Box<dyn Error>> {
     let ws_stream = accept_async(stream).await?;
-    let addr = ws_stream.peer_addr()?.to_string();
-    
-    // Add client to clients list
-    clients.insert(addr.clone(), ws_stream);
-    
-    // Remove client when disconnected
-    clients.remove(&addr);
-    
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+    // Nothing is delivered until the client says what it wants
+    let request = tokio::time::timeout(SUBSCRIBE_TIMEOUT, read_subscription(&mut ws_receiver))
+        .await
+        .map_err(|_| "Client did not subscribe in time")??;
+    ws_sender.send(subscribed_reply(&request.subscribe)).await?;
+
+    // The map holds the only sender, so dropping a slow client from it ends
+    // the writer, which closes the socket
+    let (sender, mut receiver) = mpsc::channel(CLIENT_QUEUE_SIZE);
+    clients.write().await.insert(addr.clone(), NotificationClient {
+        sender,
+        topics: request.subscribe.into_iter().collect(),
+    });
+
+    // Drain queued notifications and keep the connection alive with pings
+    let mut writer = tokio::spawn(async move {
+        let mut ping = tokio::time::interval(CLIENT_PING_INTERVAL);
+        loop {
+            let message = tokio::select! {
+                message = receiver.recv() => match message {
+                    Some(message) => message,
+                    None => break,
+                },
+                _ = ping.tick() => Message::Ping(vec![]),
+            };
+            if ws_sender.send(message).await.is_err() {
+                break;
+            }
+        }
+        let _ = ws_sender.close().await;
+    });
+
+    loop {
+        let next = tokio::select! {
+            next = tokio::time::timeout(CLIENT_IDLE_TIMEOUT, ws_receiver.next()) => next,
+            _ = &mut writer => break,
+        };
+        let message = match next {
+            Ok(Some(Ok(message))) => message,
+            Ok(Some(Err(_))) | Ok(None) => break,
+            Err(_) => {
+                eprintln!("Notification client {} timed out", addr);
+                break;
+            }
+        };
+        match message {
+            Message::Text(text) => {
+                let mut clients = clients.write().await;
+                let client = match clients.get_mut(&addr) {
+                    Some(client) => client,
+                    None => break,
+                };
+                let reply = match serde_json::from_str::<SubscribeRequest>(&text) {
+                    Ok(request) => {
+                        client.topics = request.subscribe.iter().cloned().collect();
+                        subscribed_reply(&request.subscribe)
+                    }
+                    Err(_) => {
+                        let error = serde_json::json!({ "error": "Expected {\"subscribe\": [...]}" });
+                        Message::Text(error.to_string())
+                    }
+                };
+                let _ = client.sender.try_send(reply);
+            }
+            Message::Close(_) => break,
+            // Pongs and anything else just count as activity
+            _ => {}
+        }
+    }
+
+    clients.write().await.remove(&addr);
+    writer.abort();
     Ok(())
-} 
\ No newline at end of file
+}
+
+async fn read_subscription(
+    receiver: &mut futures_util::stream::SplitStream<WebSocketStream<TcpStream>>,
+) -> Result<SubscribeRequest, Box<dyn Error>> {
+    loop {
+        match receiver.next().await {
+            Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text)?),
+            Some(Ok(Message::Close(_))) | None => return Err("Client closed before subscribing".into()),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::transfer;
+
+    fn peer_handle(id: &str) -> (PeerHandle, Arc<std::sync::Mutex<PeerTraffic>>, mpsc::Receiver<NetworkMessage>) {
+        let (sender, receiver) = mpsc::channel(PEER_QUEUE_SIZE);
+        let traffic = Arc::new(std::sync::Mutex::new(PeerTraffic::default()));
+        let handle = PeerHandle {
+            info: PeerInfo {
+                address: id.to_string(),
+                node_id: id.to_string(),
+                version: "test".to_string(),
+                last_seen: chrono::Utc::now(),
+                protocol_version: PROTOCOL_VERSION,
+                best_height: 0,
+                capabilities: CAPABILITIES,
+                score: 0,
+                compression: Compression::None,
+            },
+            outbound: true,
+            connected_at: Instant::now(),
+            traffic: traffic.clone(),
+            sender,
+        };
+        (handle, traffic, receiver)
+    }
+
+    #[tokio::test]
+    async fn reconnect_under_the_same_id_survives_the_old_cleanup() {
+        let network = Network::new(Blockchain::new());
+        let (first, first_traffic, mut first_queue) = peer_handle("peer");
+        network.register_peer("peer", first).await.unwrap();
+
+        let (second, second_traffic, _second_queue) = peer_handle("peer");
+        network.register_peer("peer", second).await.unwrap();
+        // The old connection's queue is closed, which ends its writer
+        assert!(first_queue.recv().await.is_none());
+
+        assert!(!network.unregister_peer("peer", &first_traffic).await);
+        assert_eq!(network.peer_count().await, 1);
+        assert!(network.send_to("peer", NetworkMessage::Headers(vec![])).await.is_ok());
+
+        assert!(network.unregister_peer("peer", &second_traffic).await);
+        assert_eq!(network.peer_count().await, 0);
+    }
+
+    async fn score(network: &Network, peer: &str) -> i32 {
+        network.peers.read().await[peer].info.score
+    }
+
+    #[tokio::test]
+    async fn only_gossip_that_is_invalid_on_its_own_costs_score() {
+        let (blockchain, signer) = crate::blockchain::tests::funded_chain(100.0);
+        let network = Network::new(blockchain.clone());
+        let (handle, _, _queue) = peer_handle("peer");
+        network.register_peer("peer", handle).await.unwrap();
+
+        // Lost a race for the sender's balance, which honest relays do
+        blockchain.add_transaction(transfer(&signer, 60.0).await).await.unwrap();
+        let stale = transfer(&signer, 60.0).await;
+        network.handle_gossip("peer", NetworkMessage::NewTransaction(stale)).await;
+        assert_eq!(score(&network, "peer").await, 0);
+
+        let mut forged = transfer(&signer, 1.0).await;
+        forged.amount = 2.0;
+        network.handle_gossip("peer", NetworkMessage::NewTransaction(forged)).await;
+        assert_eq!(score(&network, "peer").await, -INVALID_ITEM_PENALTY);
+    }
+}
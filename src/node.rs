@@ -0,0 +1,291 @@
+// Full node startup: storage, the P2P network, chain sync and the REST API,
+// configured from the environment
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::admin::{AdminConfig, AdminServer, MIN_ADMIN_TOKEN_LEN};
+use crate::api::{ApiRateLimits, ApiServer, CorsConfig, TlsConfig};
+use crate::blockchain::Blockchain;
+use crate::database::{env_var, parse_env_var, Database, DatabaseConfig};
+use crate::governance::Governance;
+use crate::market::Market;
+use crate::network::{build_transport, Network, NetworkConfig, NetworkMessage, TransportKind};
+use crate::security::Security;
+use crate::sync::SyncManager;
+use crate::webhook::WebhookRegistry;
+
+const DEFAULT_API_PORT: u16 = 8080;
+
+// Replay the stored best chain, or start a new chain and store its genesis
+// block so stored heights match the chain's
+async fn load_chain(database: Arc<Database>) -> Result<Blockchain, Box<dyn Error>> {
+    let stored = database.clone();
+    let blocks = tokio::task::spawn_blocking(move || {
+        crate::storage::best_chain(&**stored).map_err(|e| e.to_string())
+    }).await??;
+
+    if blocks.is_empty() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.latest_block().await;
+        tokio::task::spawn_blocking(move || database.save_block(&genesis).map_err(|e| e.to_string())).await??;
+        return Ok(blockchain);
+    }
+    let height = blocks.len() - 1;
+    let blockchain = tokio::task::spawn_blocking(move || {
+        Blockchain::from_blocks(blocks).map_err(|e| e.to_string())
+    }).await??;
+    println!("Loaded chain at height {} from storage", height);
+    Ok(blockchain)
+}
+
+// NETWORK_TRANSPORT, NETWORK_PORT, MAX_PEERS and BOOTSTRAP_NODES over the
+// network defaults
+fn network_config() -> Result<NetworkConfig, Box<dyn Error>> {
+    let mut config = NetworkConfig::default();
+    if let Some(transport) = env_var("NETWORK_TRANSPORT") {
+        config.transport = match transport.to_lowercase().as_str() {
+            "websocket" => TransportKind::WebSocket,
+            "libp2p" => TransportKind::Libp2p,
+            other => return Err(format!("Invalid NETWORK_TRANSPORT: {} (expected websocket or libp2p)", other).into()),
+        };
+    }
+    if let Some(port) = parse_env_var("NETWORK_PORT")? {
+        config.default_port = port;
+    }
+    if let Some(max_peers) = parse_env_var("MAX_PEERS")? {
+        config.max_peers = max_peers;
+    }
+    if let Some(nodes) = env_var("BOOTSTRAP_NODES") {
+        config.bootstrap_nodes = nodes.split(',').map(|node| node.trim().to_string()).collect();
+    }
+    Ok(config)
+}
+
+fn security() -> Result<Security, Box<dyn Error>> {
+    let secret = env_var("JWT_SECRET").ok_or("JWT_SECRET is not set")?;
+    // The first 32 bytes double as the encryption key
+    if secret.len() < 32 {
+        return Err("JWT_SECRET must be at least 32 bytes".into());
+    }
+    Security::new(secret.into_bytes())
+}
+
+// CORS_ALLOWED_ORIGINS limits which browser origins may call the API; any
+// origin may when it is unset
+fn cors() -> CorsConfig {
+    match env_var("CORS_ALLOWED_ORIGINS") {
+        Some(origins) => CorsConfig {
+            allowed_origins: origins.split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            ..CorsConfig::default()
+        },
+        None => CorsConfig::permissive(),
+    }
+}
+
+// API_TLS_CERT and API_TLS_KEY, both PEM files, serve the API over HTTPS.
+// Setting only one of them is an error rather than a silent fall back to HTTP.
+fn tls() -> Result<Option<TlsConfig>, Box<dyn Error>> {
+    match (env_var("API_TLS_CERT"), env_var("API_TLS_KEY")) {
+        (Some(cert), Some(key)) => Ok(Some(TlsConfig { cert_path: PathBuf::from(cert), key_path: PathBuf::from(key) })),
+        (None, None) => Ok(None),
+        (Some(_), None) => Err("API_TLS_CERT is set but API_TLS_KEY is not".into()),
+        (None, Some(_)) => Err("API_TLS_KEY is set but API_TLS_CERT is not".into()),
+    }
+}
+
+// ADMIN_TOKEN turns the admin API on, listening on ADMIN_LISTEN or loopback.
+// Backups go to BACKUP_PATH when it is set.
+fn admin_config() -> Result<Option<AdminConfig>, Box<dyn Error>> {
+    let listen = parse_env_var::<SocketAddr>("ADMIN_LISTEN")?;
+    let token = match (env_var("ADMIN_TOKEN"), listen) {
+        (Some(token), _) => token,
+        (None, Some(_)) => return Err("ADMIN_LISTEN is set but ADMIN_TOKEN is not".into()),
+        (None, None) => return Ok(None),
+    };
+    if token.len() < MIN_ADMIN_TOKEN_LEN {
+        return Err(format!("ADMIN_TOKEN must be at least {} characters", MIN_ADMIN_TOKEN_LEN).into());
+    }
+    let mut config = AdminConfig::new(token);
+    if let Some(listen) = listen {
+        config.listen = listen;
+    }
+    if let Some(path) = env_var("BACKUP_PATH") {
+        config.backup_dir = PathBuf::from(path);
+    }
+    Ok(Some(config))
+}
+
+// API_KEYS lists the keys that get their own rate limit bucket
+fn rate_limits() -> ApiRateLimits {
+    let api_keys = env_var("API_KEYS")
+        .map(|keys| keys.split(',').map(|key| key.trim().to_string()).filter(|key| !key.is_empty()).collect())
+        .unwrap_or_default();
+    ApiRateLimits { api_keys, ..ApiRateLimits::default() }
+}
+
+// Mine the mempool into a block every `interval`, storing and announcing
+// each one. Nodes without BLOCK_INTERVAL only follow the network.
+fn start_block_producer(
+    interval: Duration,
+    blockchain: Blockchain,
+    network: Network,
+    database: Arc<Database>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        ticks.tick().await;
+        loop {
+            ticks.tick().await;
+            if blockchain.pending_transactions().await.is_empty() {
+                continue;
+            }
+            let block = match blockchain.mine_block().await {
+                Ok(block) => block,
+                Err(e) => {
+                    eprintln!("Failed to mine block: {}", e);
+                    continue;
+                }
+            };
+            let stored = block.clone();
+            let database = database.clone();
+            let saved = tokio::task::spawn_blocking(move || database.save_block(&stored).map_err(|e| e.to_string()))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|result| result);
+            if let Err(e) = saved {
+                eprintln!("Failed to store block {}: {}", block.hash, e);
+            }
+            if let Err(e) = network.broadcast_message(NetworkMessage::NewBlock(block)).await {
+                eprintln!("Failed to announce block: {}", e);
+            }
+        }
+    })
+}
+
+// Run the node until ctrl-c, then shut the API and network down cleanly
+pub async fn run() -> Result<(), Box<dyn Error>> {
+    let database = Arc::new(Database::new(DatabaseConfig::from_env()?)?);
+    let api_port = parse_env_var("API_PORT")?.unwrap_or(DEFAULT_API_PORT);
+    let security = Arc::new(security()?);
+    let admin_config = admin_config()?;
+    let tls = tls()?;
+    let config = network_config()?;
+    let listen = SocketAddr::from(([0, 0, 0, 0], config.default_port));
+
+    let blockchain = load_chain(database.clone()).await?;
+    let transport = config.transport;
+    let network = Network::with_config(config.clone(), blockchain.clone()).with_database(database.clone());
+    // Known peers and lockouts from the last run, before anything is dialled
+    let addresses = network.restore_addresses(database.clone()).await?;
+    let bans = network.restore_bans(database.clone()).await?;
+    println!("Restored {} peer addresses and {} bans", addresses, bans);
+    network.start_address_persistence(database.clone());
+    match transport {
+        TransportKind::WebSocket => {
+            network.start_reconnect_scheduler();
+            network.start_discovery();
+            let listener = network.clone();
+            tokio::spawn(async move {
+                if let Err(e) = listener.start(listen).await {
+                    eprintln!("Network stopped: {}", e);
+                }
+            });
+        }
+        // The transport finds and dials peers itself; the network on top
+        // still validates, relays and syncs over it
+        TransportKind::Libp2p => {
+            let transport = build_transport(config, blockchain.clone())?;
+            transport.listen(listen).await?;
+            network.attach_transport(transport).await?;
+        }
+    }
+
+    // Blocks are stored as sync applies them
+    let sync = SyncManager::new(network.clone()).with_database(database.clone());
+    let progress = sync.progress();
+    let syncing = sync.start();
+
+    let producing = match parse_env_var::<u64>("BLOCK_INTERVAL")? {
+        Some(0) => return Err("BLOCK_INTERVAL must be at least one second".into()),
+        Some(seconds) => Some(start_block_producer(
+            Duration::from_secs(seconds),
+            blockchain.clone(),
+            network.clone(),
+            database.clone(),
+        )),
+        None => None,
+    };
+
+    let market = Arc::new(Market::new().with_database(database.clone()));
+    let orders = market.restore().await?;
+    println!("Restored {} open orders", orders);
+    let governance = Governance::new().with_database(database.clone());
+
+    // Hooks deliver chain, market and governance events from here on
+    let webhooks = Arc::new(WebhookRegistry::new().with_database(database.clone()));
+    let hooks = webhooks.restore().await?;
+    println!("Restored {} webhooks", hooks);
+    webhooks.start(&blockchain, &market, &governance);
+
+    let blockchain = Arc::new(blockchain);
+    let shared_network = Arc::new(network.clone());
+    let admin = admin_config.map(|config| {
+        let admin = AdminServer::new(
+            config,
+            blockchain.clone(),
+            market.clone(),
+            shared_network.clone(),
+            database.clone(),
+            security.clone(),
+            webhooks,
+        );
+        tokio::spawn(async move {
+            if let Err(e) = admin.start().await {
+                eprintln!("Admin API stopped: {}", e);
+            }
+        })
+    });
+
+    let mut api = ApiServer::new(
+        blockchain,
+        market,
+        Arc::new(governance),
+        shared_network,
+        database.clone(),
+        security,
+    )
+    .with_rate_limits(rate_limits())
+    .with_cors(cors())
+    .with_sync_progress(progress);
+    if let Some(tls) = tls {
+        api = api.with_tls(tls);
+    }
+
+    let serving = api.start(api_port);
+    tokio::pin!(serving);
+    tokio::select! {
+        result = &mut serving => result?,
+        _ = tokio::signal::ctrl_c() => {
+            api.shutdown();
+            serving.await?;
+        }
+    }
+    syncing.abort();
+    if let Some(admin) = admin {
+        admin.abort();
+    }
+    if let Some(producing) = producing {
+        producing.abort();
+    }
+    network.shutdown().await;
+    // Persistence runs on an interval, so save what changed since
+    network.save_addresses(database.clone()).await?;
+    network.save_bans(database).await?;
+    Ok(())
+}
@@ -0,0 +1,55 @@
+// Client for a running node's REST API, used by the CLI and the interactive
+// menu to submit signed transactions and read balances
+use std::error::Error;
+use serde_json::{json, Value};
+
+use crate::blockchain::Transaction;
+
+const DEFAULT_NODE_URL: &str = "http://127.0.0.1:8080";
+
+pub struct NodeClient {
+    api_url: String,
+    http: reqwest::Client,
+}
+
+impl NodeClient {
+    pub fn new(api_url: String) -> Self {
+        NodeClient {
+            api_url,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    // NODE_URL, or a node on this machine's default API port
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("NODE_URL").unwrap_or_else(|_| DEFAULT_NODE_URL.to_string()))
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/api/{}", self.api_url.trim_end_matches('/'), path)
+    }
+
+    // Send a request and unwrap the response envelope's data
+    async fn call(&self, request: reqwest::RequestBuilder) -> Result<Value, Box<dyn Error>> {
+        let response = request.send().await
+            .map_err(|e| format!("Could not reach the node at {}: {}", self.api_url, e))?;
+        let status = response.status();
+        let body: Value = response.json().await?;
+        if !status.is_success() || body["success"] != json!(true) {
+            return Err(body["error"].as_str().unwrap_or("Request failed").into());
+        }
+        Ok(body["data"].clone())
+    }
+
+    // Submit a signed transaction to the mempool; returns its id
+    pub async fn submit(&self, transaction: &Transaction) -> Result<String, Box<dyn Error>> {
+        let body = json!({ "raw": transaction.to_raw()? });
+        let data = self.call(self.http.post(self.url("tx/raw")).json(&body)).await?;
+        Ok(data["id"].as_str().ok_or("Response has no transaction id")?.to_string())
+    }
+
+    pub async fn balance(&self, address: &str) -> Result<f64, Box<dyn Error>> {
+        let data = self.call(self.http.get(self.url(&format!("wallet/balance/{}", address)))).await?;
+        Ok(data["balance"].as_f64().ok_or("Response has no balance")?)
+    }
+}
@@ -0,0 +1,239 @@
+use std::error::Error;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use libp2p::{
+    gossipsub, identity, kad, mdns, noise, request_response, tcp, yamux,
+    multiaddr::Protocol, swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    Multiaddr, StreamProtocol, Swarm,
+};
+use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+
+use crate::blockchain::Blockchain;
+use crate::network::{Capabilities, NetworkConfig, NetworkMessage, NetworkTransport, PeerId, PeerInfo};
+use crate::wire::Compression;
+
+// Re-run Kademlia bootstrap this often to keep the routing table fresh
+const KADEMLIA_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const COMMAND_QUEUE_SIZE: usize = 256;
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: gossipsub::Behaviour,
+    kademlia: kad::Behaviour<kad::store::MemoryStore>,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    // Point-to-point messages; the response is only an acknowledgement
+    direct: request_response::json::Behaviour<NetworkMessage, bool>,
+}
+
+// Requests from the transport handle to the task that owns the swarm
+enum Command {
+    Listen(Multiaddr, oneshot::Sender<Result<(), String>>),
+    Dial(Multiaddr, oneshot::Sender<Result<(), String>>),
+    Publish(NetworkMessage),
+    Send(libp2p::PeerId, NetworkMessage),
+}
+
+// libp2p backend: blocks and transactions travel over gossipsub topics
+// scoped to the chain id, peers are found through Kademlia, and other
+// messages go directly to a peer over request-response
+pub struct Libp2pNetwork {
+    commands: mpsc::Sender<Command>,
+    message_tx: broadcast::Sender<(PeerId, NetworkMessage)>,
+    peers: Arc<RwLock<HashMap<PeerId, PeerInfo>>>,
+}
+
+impl Libp2pNetwork {
+    pub fn new(config: NetworkConfig, _blockchain: Blockchain) -> Result<Self, Box<dyn Error>> {
+        let keypair = identity::Keypair::generate_ed25519();
+        let topic = gossipsub::IdentTopic::new(format!("{}/gossip", config.chain_id));
+        let protocol = StreamProtocol::try_from_owned(format!("/rbn/{}/direct/1", config.chain_id))?;
+
+        let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+            .with_tokio()
+            .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)?
+            .with_behaviour(|key| {
+                let gossipsub = gossipsub::Behaviour::new(
+                    gossipsub::MessageAuthenticity::Signed(key.clone()),
+                    gossipsub::Config::default(),
+                )?;
+                let peer_id = key.public().to_peer_id();
+                let kademlia = kad::Behaviour::new(peer_id, kad::store::MemoryStore::new(peer_id));
+                let direct = request_response::json::Behaviour::new(
+                    [(protocol, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default(),
+                );
+                let mdns = if config.mdns {
+                    Some(mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)?)
+                } else {
+                    None
+                };
+                Ok(Behaviour { gossipsub, kademlia, mdns: Toggle::from(mdns), direct })
+            })?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+
+        for address in &config.bootstrap_nodes {
+            match address.parse::<SocketAddr>() {
+                Ok(addr) => {
+                    let _ = swarm.dial(socket_multiaddr(addr));
+                }
+                Err(_) => eprintln!("Ignoring bootstrap node {}: not a socket address", address),
+            }
+        }
+
+        let (commands, command_rx) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let (message_tx, _) = broadcast::channel(100);
+        let peers = Arc::new(RwLock::new(HashMap::new()));
+
+        tokio::spawn(run_swarm(swarm, topic, command_rx, message_tx.clone(), peers.clone()));
+
+        Ok(Libp2pNetwork { commands, message_tx, peers })
+    }
+
+    async fn command(&self, command: Command) -> Result<(), Box<dyn Error>> {
+        self.commands.send(command).await.map_err(|_| "libp2p swarm has stopped")?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl NetworkTransport for Libp2pNetwork {
+    async fn listen(&self, addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+        let (tx, rx) = oneshot::channel();
+        self.command(Command::Listen(socket_multiaddr(addr), tx)).await?;
+        rx.await?.map_err(|e| e.into())
+    }
+
+    async fn connect(&self, addr: String) -> Result<(), Box<dyn Error>> {
+        let multiaddr = match addr.parse::<SocketAddr>() {
+            Ok(addr) => socket_multiaddr(addr),
+            Err(_) => addr.parse::<Multiaddr>()?,
+        };
+        let (tx, rx) = oneshot::channel();
+        self.command(Command::Dial(multiaddr, tx)).await?;
+        rx.await?.map_err(|e| e.into())
+    }
+
+    async fn broadcast(&self, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        self.command(Command::Publish(message)).await
+    }
+
+    async fn send_to(&self, peer: &str, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        let peer_id = peer.parse::<libp2p::PeerId>()?;
+        self.command(Command::Send(peer_id, message)).await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<(PeerId, NetworkMessage)> {
+        self.message_tx.subscribe()
+    }
+
+    async fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.read().await.values().cloned().collect()
+    }
+}
+
+// Owns the swarm: executes commands and turns behaviour events into
+// messages for subscribers
+async fn run_swarm(
+    mut swarm: Swarm<Behaviour>,
+    topic: gossipsub::IdentTopic,
+    mut commands: mpsc::Receiver<Command>,
+    message_tx: broadcast::Sender<(PeerId, NetworkMessage)>,
+    peers: Arc<RwLock<HashMap<PeerId, PeerInfo>>>,
+) {
+    let mut bootstrap = tokio::time::interval(KADEMLIA_BOOTSTRAP_INTERVAL);
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Listen(addr, reply)) => {
+                    let _ = reply.send(swarm.listen_on(addr).map(|_| ()).map_err(|e| e.to_string()));
+                }
+                Some(Command::Dial(addr, reply)) => {
+                    let _ = reply.send(swarm.dial(addr).map_err(|e| e.to_string()));
+                }
+                Some(Command::Publish(message)) => {
+                    match serde_json::to_vec(&message) {
+                        Ok(data) => {
+                            if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+                                eprintln!("Error publishing message: {}", e);
+                            }
+                        }
+                        Err(e) => eprintln!("Error encoding message: {}", e),
+                    }
+                }
+                Some(Command::Send(peer, message)) => {
+                    swarm.behaviour_mut().direct.send_request(&peer, message);
+                }
+                None => break,
+            },
+            _ = bootstrap.tick() => {
+                let _ = swarm.behaviour_mut().kademlia.bootstrap();
+            }
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    swarm.behaviour_mut().kademlia.add_address(&peer_id, endpoint.get_remote_address().clone());
+                    peers.write().await.insert(peer_id.to_string(), PeerInfo {
+                        address: endpoint.get_remote_address().to_string(),
+                        node_id: peer_id.to_string(),
+                        version: String::new(),
+                        last_seen: chrono::Utc::now(),
+                        protocol_version: 0,
+                        best_height: 0,
+                        capabilities: Capabilities::NONE,
+                        score: 0,
+                        compression: Compression::None,
+                    });
+                }
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    peers.write().await.remove(&peer_id.to_string());
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                    propagation_source,
+                    message,
+                    ..
+                })) => {
+                    if let Ok(decoded) = serde_json::from_slice::<NetworkMessage>(&message.data) {
+                        let source = message.source.unwrap_or(propagation_source);
+                        let _ = message_tx.send((source.to_string(), decoded));
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Direct(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                })) => {
+                    let _ = swarm.behaviour_mut().direct.send_response(channel, true);
+                    let _ = message_tx.send((peer.to_string(), request));
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(found))) => {
+                    for (peer, address) in found {
+                        swarm.behaviour_mut().kademlia.add_address(&peer, address);
+                        if !swarm.is_connected(&peer) {
+                            let _ = swarm.dial(peer);
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::RoutingUpdated { peer, .. })) => {
+                    // Connect to newly discovered peers so gossip reaches them
+                    if !swarm.is_connected(&peer) {
+                        let _ = swarm.dial(peer);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn socket_multiaddr(addr: SocketAddr) -> Multiaddr {
+    let ip = match addr.ip() {
+        std::net::IpAddr::V4(ip) => Protocol::Ip4(ip),
+        std::net::IpAddr::V6(ip) => Protocol::Ip6(ip),
+    };
+    Multiaddr::empty().with(ip).with(Protocol::Tcp(addr.port()))
+}
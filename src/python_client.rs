@@ -0,0 +1,136 @@
+// Python bindings for the REST API. Kept free of node internals so the
+// library target can build it as an extension module on its own.
+use pyo3::create_exception;
+use pyo3::exceptions::{PyConnectionError, PyException};
+use pyo3::prelude::*;
+use serde_json::{json, Value};
+
+create_exception!(blockchain_client, ApiError, PyException);
+create_exception!(blockchain_client, AuthError, ApiError);
+create_exception!(blockchain_client, ValidationError, ApiError);
+create_exception!(blockchain_client, RateLimitError, ApiError);
+
+#[pyclass]
+pub struct BlockchainClient {
+    api_url: String,
+    // JWT from `login`, sent on authenticated requests
+    token: Option<String>,
+    http: reqwest::blocking::Client,
+}
+
+impl BlockchainClient {
+    fn url(&self, path: &str) -> String {
+        format!("{}/api/{}", self.api_url.trim_end_matches('/'), path)
+    }
+
+    // Send a request and unwrap the response envelope's data, mapping
+    // failures onto the Python exception hierarchy
+    fn call(&self, request: reqwest::blocking::RequestBuilder) -> PyResult<Value> {
+        let request = match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        };
+        let response = request.send().map_err(|e| PyConnectionError::new_err(e.to_string()))?;
+        let status = response.status();
+        let retry_after = response.headers().get("retry-after")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let body: Value = response.json().map_err(|e| ApiError::new_err(format!("Invalid response: {}", e)))?;
+        let error = body["error"].as_str().unwrap_or("Request failed").to_string();
+
+        match status.as_u16() {
+            401 => Err(AuthError::new_err(error)),
+            400 => {
+                let fields: Vec<String> = body["fields"].as_array().into_iter().flatten()
+                    .map(|field| format!("{}: {}", field["field"].as_str().unwrap_or("?"), field["message"].as_str().unwrap_or("")))
+                    .collect();
+                Err(ValidationError::new_err(format!("{} ({})", error, fields.join("; "))))
+            }
+            429 => Err(RateLimitError::new_err(format!("{}; retry after {}s", error, retry_after.unwrap_or_default()))),
+            _ if !status.is_success() || body["success"] != json!(true) => Err(ApiError::new_err(error)),
+            _ => Ok(body["data"].clone()),
+        }
+    }
+}
+
+// JSON values become the equivalent Python objects
+fn to_python(py: Python, value: &Value) -> PyResult<PyObject> {
+    let json = py.import("json")?;
+    Ok(json.call_method1("loads", (value.to_string(),))?.into())
+}
+
+#[pymethods]
+impl BlockchainClient {
+    #[new]
+    #[pyo3(signature = (api_url, token=None))]
+    pub fn new(api_url: String, token: Option<String>) -> Self {
+        BlockchainClient {
+            api_url,
+            token,
+            http: reqwest::blocking::Client::new(),
+        }
+    }
+
+    // Returns {"id", "address", "mnemonic"}; the mnemonic is only shown once
+    #[pyo3(signature = (email, pin, key_scheme=None))]
+    pub fn create_wallet(&self, py: Python, email: String, pin: String, key_scheme: Option<String>) -> PyResult<PyObject> {
+        let mut body = json!({ "email": email, "pin": pin });
+        if let Some(key_scheme) = key_scheme {
+            body["key_scheme"] = json!(key_scheme);
+        }
+        let data = self.call(self.http.post(self.url("wallet")).json(&body))?;
+        to_python(py, &data)
+    }
+
+    // Exchange wallet credentials for a token used by later calls
+    pub fn login(&mut self, email: String, pin: String) -> PyResult<String> {
+        let data = self.call(self.http.post(self.url("auth/token")).json(&json!({ "email": email, "pin": pin })))?;
+        let token = data["token"].as_str().ok_or_else(|| ApiError::new_err("Response has no token"))?.to_string();
+        self.token = Some(token.clone());
+        Ok(token)
+    }
+
+    pub fn get_balance(&self, address: String) -> PyResult<f64> {
+        let data = self.call(self.http.get(self.url(&format!("wallet/balance/{}", address))))?;
+        data["balance"].as_f64().ok_or_else(|| ApiError::new_err("Response has no balance"))
+    }
+
+    // Returns {"id", "hash", "fee"}; the fee is estimated when omitted
+    #[pyo3(signature = (sender, to, amount, email, pin, fee=None))]
+    pub fn transfer(
+        &self,
+        py: Python,
+        sender: String,
+        to: String,
+        amount: f64,
+        email: String,
+        pin: String,
+        fee: Option<f64>,
+    ) -> PyResult<PyObject> {
+        let body = json!({ "from": sender, "to": to, "amount": amount, "email": email, "pin": pin, "fee": fee });
+        let data = self.call(self.http.post(self.url("transaction")).json(&body))?;
+        to_python(py, &data)
+    }
+
+    // One page of transactions involving `address`, newest first
+    #[pyo3(signature = (address, page=1, limit=20))]
+    pub fn history(&self, py: Python, address: String, page: usize, limit: usize) -> PyResult<PyObject> {
+        let request = self.http.get(self.url("transactions")).query(&[
+            ("address", address),
+            ("page", page.to_string()),
+            ("limit", limit.to_string()),
+        ]);
+        let data = self.call(request)?;
+        to_python(py, &data)
+    }
+}
+
+#[pymodule]
+fn blockchain_client(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<BlockchainClient>()?;
+    m.add("ApiError", py.get_type::<ApiError>())?;
+    m.add("AuthError", py.get_type::<AuthError>())?;
+    m.add("ValidationError", py.get_type::<ValidationError>())?;
+    m.add("RateLimitError", py.get_type::<RateLimitError>())?;
+    Ok(())
+}
@@ -0,0 +1,1043 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::Path;
+use std::sync::Mutex;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch, DB};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::blockchain::{Block, Transaction, TransactionKind};
+use crate::governance::Proposal;
+use crate::market::{Order, Token, Trade, UNITS_PER_COIN};
+use crate::migrations::{self, AppliedMigration, Migration, ROCKSDB_MIGRATIONS};
+use crate::network::{KnownAddress, PeerBan};
+use crate::security::ColumnKeys;
+use crate::storage::{
+    balance_deltas, block_heights, search_terms, BlockPage, ChainState, MemoMatch, PageRequest, ProposalMatch, Storage, TradePage,
+    TransactionPage,
+};
+use crate::wallet::{AddressBook, Metadata, ReceiveAddress, Wallet};
+use crate::webhook::Webhook;
+
+// Wallet JSON by wallet id, with receive addresses and annotations inline
+const WALLETS: &str = "wallets";
+// Wallet id by email
+const WALLET_EMAILS: &str = "wallet_emails";
+// Contact address by wallet id + label
+const ADDRESS_BOOK: &str = "address_book";
+const PEER_ADDRESSES: &str = "peer_addresses";
+// Ban JSON by IP
+const PEER_BANS: &str = "peer_bans";
+// Block JSON without transactions, by hash
+const BLOCKS: &str = "blocks";
+// Transaction JSON by id
+const TRANSACTIONS: &str = "transactions";
+// Empty values keyed by address + timestamp + transaction id
+const ADDRESS_TRANSACTIONS: &str = "address_transactions";
+// Big-endian position in the block keyed by block hash + transaction id,
+// for reverting and reassembling blocks. Entries written before positions
+// were recorded are empty.
+const BLOCK_TRANSACTIONS: &str = "block_transactions";
+// Empty values keyed by big-endian height + block hash, so blocks scan
+// parents first
+const BLOCK_ORDER: &str = "block_order";
+// Big-endian height by block hash
+const BLOCK_HEIGHTS: &str = "block_heights";
+// Native balance JSON by address
+const ADDRESS_BALANCES: &str = "address_balances";
+// Token JSON by symbol
+const TOKENS: &str = "tokens";
+// Order JSON by id
+const ORDERS: &str = "orders";
+// Empty values keyed by user id + timestamp + order id, for pending orders only
+const OPEN_ORDERS: &str = "open_orders";
+// Trade JSON keyed by token symbol + timestamp + trade id
+const TRADES: &str = "trades";
+// Proposal JSON without votes, by id
+const PROPOSALS: &str = "proposals";
+// Webhook JSON with the secret encrypted by the column keys, by id
+const WEBHOOKS: &str = "webhooks";
+const METADATA: &str = "metadata";
+
+const COLUMN_FAMILIES: [&str; 19] = [
+    WALLETS, WALLET_EMAILS, ADDRESS_BOOK, PEER_ADDRESSES, PEER_BANS, BLOCKS, TRANSACTIONS, ADDRESS_TRANSACTIONS,
+    BLOCK_TRANSACTIONS, BLOCK_ORDER, BLOCK_HEIGHTS, ADDRESS_BALANCES, TOKENS, ORDERS, OPEN_ORDERS, TRADES, PROPOSALS,
+    WEBHOOKS, METADATA,
+];
+
+// Chain state JSON. Before migration 6 only the latest block's hash was
+// kept, under LEGACY_LATEST_BLOCK_KEY.
+const CHAIN_STATE_KEY: &[u8] = b"chain_state";
+const LEGACY_LATEST_BLOCK_KEY: &[u8] = b"latest_block";
+// Applied migrations, keyed by big-endian version so they scan in order
+const SCHEMA_VERSION_PREFIX: &[u8] = b"schema_version";
+
+// Embedded key-value storage; values are JSON so records read back the
+// same way the rest of the node serializes them
+pub struct RocksDbStorage {
+    db: DB,
+    // Serializes read-modify-write updates of wallet records
+    wallet_lock: Mutex<()>,
+    // Serializes block writes, which read balances and the latest block
+    // before updating them
+    block_lock: Mutex<()>,
+    // Serializes order updates, which check the stored status first
+    order_lock: Mutex<()>,
+    column_keys: ColumnKeys,
+}
+
+impl RocksDbStorage {
+    pub fn open(path: &Path, column_keys: ColumnKeys) -> Result<Self, Box<dyn Error>> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let families = COLUMN_FAMILIES.iter().map(|name| ColumnFamilyDescriptor::new(*name, Options::default()));
+        let db = DB::open_cf_descriptors(&options, path, families)?;
+        Ok(RocksDbStorage {
+            db,
+            wallet_lock: Mutex::new(()),
+            block_lock: Mutex::new(()),
+            order_lock: Mutex::new(()),
+            column_keys,
+        })
+    }
+
+    fn cf(&self, name: &str) -> Result<&ColumnFamily, Box<dyn Error>> {
+        self.db.cf_handle(name).ok_or_else(|| format!("Missing column family {}", name).into())
+    }
+
+    fn get_json<T: DeserializeOwned>(&self, family: &str, key: &[u8]) -> Result<Option<T>, Box<dyn Error>> {
+        match self.db.get_cf(self.cf(family)?, key)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn put_json<T: Serialize>(&self, family: &str, key: &[u8], value: &T) -> Result<(), Box<dyn Error>> {
+        self.db.put_cf(self.cf(family)?, key, serde_json::to_vec(value)?)?;
+        Ok(())
+    }
+
+    // Keys and values of every entry whose key starts with `prefix`
+    fn scan_prefix(&self, family: &str, prefix: &[u8]) -> Result<Vec<(Box<[u8]>, Box<[u8]>)>, Box<dyn Error>> {
+        let mut entries = vec![];
+        for entry in self.db.prefix_iterator_cf(self.cf(family)?, prefix) {
+            let (key, value) = entry?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            entries.push((key, value));
+        }
+        Ok(entries)
+    }
+
+    fn block_height(&self, hash: &str) -> Result<Option<u64>, Box<dyn Error>> {
+        match self.db.get_cf(self.cf(BLOCK_HEIGHTS)?, hash.as_bytes())? {
+            Some(value) => Ok(Some(u64::from_be_bytes(value.as_slice().try_into()?))),
+            None => Ok(None),
+        }
+    }
+
+    // The block with its transactions, for a hash found in block_order
+    fn indexed_block(&self, hash: &[u8]) -> Result<Block, Box<dyn Error>> {
+        let mut block: Block = self.get_json(BLOCKS, hash)?
+            .ok_or_else(|| format!("Block {} is indexed but not stored", String::from_utf8_lossy(hash)))?;
+        block.transactions = self.block_transactions(&block.hash)?;
+        Ok(block)
+    }
+
+    // A stored block's transactions in block order
+    fn block_transactions(&self, hash: &str) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        let mut transactions = vec![];
+        for (key, position) in self.scan_prefix(BLOCK_TRANSACTIONS, &composite_key(&[hash.as_bytes(), b""]))? {
+            let id = &key[hash.len() + 1..];
+            if let Some(transaction) = self.get_json::<Transaction>(TRANSACTIONS, id)? {
+                // Entries without a position sort after the rest
+                let position = <[u8; 4]>::try_from(&position[..]).map_or(u32::MAX, u32::from_be_bytes);
+                transactions.push((position, transaction));
+            }
+        }
+        transactions.sort_by(|(a, x), (b, y)| (a, x.timestamp, &x.id).cmp(&(b, y.timestamp, &y.id)));
+        Ok(transactions.into_iter().map(|(_, transaction)| transaction).collect())
+    }
+
+    // Wallet JSON with the keystore encrypted by the column keys
+    fn encode_wallet(&self, wallet: &Wallet) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut value = serde_json::to_value(wallet)?;
+        let keystore = self.column_keys.encrypt(&serde_json::to_string(&wallet.encrypted_key)?)?;
+        value["encrypted_key"] = serde_json::Value::String(keystore);
+        Ok(serde_json::to_vec(&value)?)
+    }
+
+    fn decode_wallet(&self, bytes: &[u8]) -> Result<Wallet, Box<dyn Error>> {
+        let mut value: serde_json::Value = serde_json::from_slice(bytes)?;
+        // Wallets written before encryption hold the keystore as an object
+        if let Some(keystore) = value["encrypted_key"].as_str() {
+            value["encrypted_key"] = serde_json::from_str(&self.column_keys.decrypt(keystore)?)?;
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    fn get_wallet_by_id(&self, wallet_id: &[u8]) -> Result<Option<Wallet>, Box<dyn Error>> {
+        match self.db.get_cf(self.cf(WALLETS)?, wallet_id)? {
+            Some(bytes) => Ok(Some(self.decode_wallet(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn chain_state(&self) -> Result<ChainState, Box<dyn Error>> {
+        Ok(self.get_json(METADATA, CHAIN_STATE_KEY)?.unwrap_or_default())
+    }
+
+    // Apply `update` to a stored wallet and write it back
+    fn update_wallet(&self, wallet_id: &str, update: impl FnOnce(&mut Wallet)) -> Result<(), Box<dyn Error>> {
+        let _guard = self.wallet_lock.lock().unwrap();
+        let mut wallet = self.get_wallet_by_id(wallet_id.as_bytes())?
+            .ok_or_else(|| format!("Unknown wallet {}", wallet_id))?;
+        update(&mut wallet);
+        self.db.put_cf(self.cf(WALLETS)?, wallet_id.as_bytes(), self.encode_wallet(&wallet)?)?;
+        Ok(())
+    }
+
+    // Queue the balance changes of `transactions`, times `sign`, in `batch`
+    fn adjust_balances(&self, batch: &mut WriteBatch, transactions: &[Transaction], sign: f64) -> Result<(), Box<dyn Error>> {
+        let balances = self.cf(ADDRESS_BALANCES)?;
+        for (address, delta) in balance_deltas(transactions) {
+            let balance = self.get_json::<f64>(ADDRESS_BALANCES, address.as_bytes())?.unwrap_or(0.0);
+            batch.put_cf(balances, address.as_bytes(), serde_json::to_vec(&(balance + delta * sign))?);
+        }
+        Ok(())
+    }
+
+    // Queue an order's new fill and status in `batch`, refusing to change
+    // one that's final. Callers hold `order_lock`.
+    fn update_order_status(&self, batch: &mut WriteBatch, order: &Order) -> Result<(), Box<dyn Error>> {
+        let stored: Order = self.get_json(ORDERS, order.id.as_bytes())?
+            .ok_or_else(|| format!("Unknown order {}", order.id))?;
+        if !stored.is_open() {
+            return Err(format!("Order {} is not pending", order.id).into());
+        }
+        let updated = Order { filled: order.filled, status: order.status.clone(), ..stored };
+        batch.put_cf(self.cf(ORDERS)?, updated.id.as_bytes(), serde_json::to_vec(&updated)?);
+        if !updated.is_open() {
+            batch.delete_cf(self.cf(OPEN_ORDERS)?, open_order_key(&updated));
+        }
+        Ok(())
+    }
+}
+
+fn open_order_key(order: &Order) -> Vec<u8> {
+    composite_key(&[order.user_id.as_bytes(), &order.timestamp.timestamp_nanos().to_be_bytes(), order.id.as_bytes()])
+}
+
+fn block_order_key(height: u64, hash: &str) -> Vec<u8> {
+    composite_key(&[&height.to_be_bytes(), hash.as_bytes()])
+}
+
+// Height of every block given each one's parent, counting up from genesis,
+// which names itself as its parent, and from blocks whose parent is missing
+fn stored_heights(parents: &HashMap<String, String>) -> HashMap<String, u64> {
+    let mut heights = HashMap::new();
+    for hash in parents.keys() {
+        // Walk up to a block whose height is known or to a root, then
+        // number the path on the way back down
+        let mut path = vec![];
+        let mut current = hash;
+        let base = loop {
+            if let Some(height) = heights.get(current) {
+                break Some(*height);
+            }
+            path.push(current);
+            match parents.get(current) {
+                Some(parent) if parent != current && parents.contains_key(parent) => current = parent,
+                _ => break None,
+            }
+        };
+        let mut height = base.map_or(0, |height| height + 1);
+        for hash in path.into_iter().rev() {
+            heights.insert(hash.clone(), height);
+            height += 1;
+        }
+    }
+    heights
+}
+
+// Composite keys are joined with a NUL byte, which addresses, labels and
+// ids never contain
+fn composite_key(parts: &[&[u8]]) -> Vec<u8> {
+    parts.join(&0u8)
+}
+
+// Every address a transaction should be listed under
+fn indexed_addresses(transaction: &Transaction) -> Vec<&str> {
+    let mut addresses = vec![transaction.from.as_str(), transaction.to.as_str()];
+    if let TransactionKind::Batch { outputs } = &transaction.kind {
+        addresses.extend(outputs.iter().map(|output| output.address.as_str()));
+    }
+    addresses.sort_unstable();
+    addresses.dedup();
+    addresses
+}
+
+impl Storage for RocksDbStorage {
+    fn migrations(&self) -> &'static [Migration] {
+        ROCKSDB_MIGRATIONS
+    }
+
+    fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, Box<dyn Error>> {
+        let mut applied = vec![];
+        for (_, value) in self.scan_prefix(METADATA, SCHEMA_VERSION_PREFIX)? {
+            applied.push(serde_json::from_slice::<AppliedMigration>(&value)?);
+        }
+        Ok(applied)
+    }
+
+    fn apply_migration(&self, migration: &Migration) -> Result<(), Box<dyn Error>> {
+        match migration.version {
+            // Column families are created when the database is opened
+            1 => {}
+            2 => {
+                let balances = balance_deltas(&self.get_all_transactions()?).into_iter().collect();
+                self.replace_address_balances(&balances)?;
+            }
+            // block_order is filled in by height in 9
+            3 | 4 | 5 | 7 | 8 | 10 => {}
+            9 => {
+                let mut parents = HashMap::new();
+                for entry in self.db.iterator_cf(self.cf(BLOCKS)?, IteratorMode::Start) {
+                    let (_, value) = entry?;
+                    let block: Block = serde_json::from_slice(&value)?;
+                    parents.insert(block.hash, block.previous_hash);
+                }
+                let order = self.cf(BLOCK_ORDER)?;
+                let heights = self.cf(BLOCK_HEIGHTS)?;
+                let mut batch = WriteBatch::default();
+                for entry in self.db.iterator_cf(order, IteratorMode::Start) {
+                    let (key, _) = entry?;
+                    batch.delete_cf(order, key);
+                }
+                for (hash, height) in stored_heights(&parents) {
+                    batch.put_cf(order, block_order_key(height, &hash), b"");
+                    batch.put_cf(heights, hash.as_bytes(), height.to_be_bytes());
+                }
+                self.db.write(batch)?;
+            }
+            6 => {
+                let metadata = self.cf(METADATA)?;
+                let mut state = ChainState::default();
+                if let Some(hash) = self.db.get_cf(metadata, LEGACY_LATEST_BLOCK_KEY)? {
+                    // Walk back to genesis to find the height
+                    let mut block: Block = self.get_json(BLOCKS, &hash)?.ok_or("Latest block is missing")?;
+                    let mut height = 0;
+                    while let Some(parent) = self.get_json::<Block>(BLOCKS, block.previous_hash.as_bytes())? {
+                        block = parent;
+                        height += 1;
+                    }
+                    state.best_hash = Some(String::from_utf8(hash)?);
+                    state.best_height = Some(height);
+                    state.genesis_hash = Some(block.hash);
+                }
+                let mut batch = WriteBatch::default();
+                batch.put_cf(metadata, CHAIN_STATE_KEY, serde_json::to_vec(&state)?);
+                batch.delete_cf(metadata, LEGACY_LATEST_BLOCK_KEY);
+                self.db.write(batch)?;
+            }
+            11 => {
+                let mut batch = WriteBatch::default();
+                for (family, fields) in [(ORDERS, &["amount", "price", "filled"][..]), (TRADES, &["amount", "price"][..])] {
+                    let family = self.cf(family)?;
+                    for entry in self.db.iterator_cf(family, IteratorMode::Start) {
+                        let (key, value) = entry?;
+                        let mut record: serde_json::Value = serde_json::from_slice(&value)?;
+                        for field in fields {
+                            if let Some(coins) = record[*field].as_f64().filter(|_| record[*field].is_f64()) {
+                                record[*field] = serde_json::Value::from((coins * UNITS_PER_COIN as f64).round() as u64);
+                            }
+                        }
+                        batch.put_cf(family, key, serde_json::to_vec(&record)?);
+                    }
+                }
+                self.db.write(batch)?;
+            }
+            version => return Err(format!("No RocksDB steps for migration {}", version).into()),
+        }
+        let key = composite_key(&[SCHEMA_VERSION_PREFIX, &migration.version.to_be_bytes()]);
+        self.put_json(METADATA, &key, &AppliedMigration::new(migration))
+    }
+
+    fn save_wallet(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>> {
+        let _guard = self.wallet_lock.lock().unwrap();
+        let emails = self.cf(WALLET_EMAILS)?;
+        if self.db.get_cf(emails, wallet.email.as_bytes())?.is_some() {
+            return Err(format!("A wallet for {} already exists", wallet.email).into());
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.cf(WALLETS)?, wallet.id.as_bytes(), self.encode_wallet(wallet)?);
+        batch.put_cf(emails, wallet.email.as_bytes(), wallet.id.as_bytes());
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get_wallet_emails(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut emails = vec![];
+        for (email, _) in self.scan_prefix(WALLET_EMAILS, b"")? {
+            emails.push(String::from_utf8(email.into_vec())?);
+        }
+        Ok(emails)
+    }
+
+    fn get_wallet(&self, email: &str) -> Result<Option<Wallet>, Box<dyn Error>> {
+        match self.db.get_cf(self.cf(WALLET_EMAILS)?, email.as_bytes())? {
+            Some(id) => self.get_wallet_by_id(&id),
+            None => Ok(None),
+        }
+    }
+
+    fn reencrypt_wallets(&self, limit: usize) -> Result<usize, Box<dyn Error>> {
+        if self.column_keys.active.is_none() {
+            return Ok(0);
+        }
+        let _guard = self.wallet_lock.lock().unwrap();
+        let wallets = self.cf(WALLETS)?;
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+        for (key, bytes) in self.scan_prefix(WALLETS, b"")? {
+            if count == limit {
+                break;
+            }
+            let value: serde_json::Value = serde_json::from_slice(&bytes)?;
+            let current = value["encrypted_key"].as_str()
+                .map_or(false, |keystore| !self.column_keys.needs_reencrypt(keystore));
+            if !current {
+                batch.put_cf(wallets, &key, self.encode_wallet(&self.decode_wallet(&bytes)?)?);
+                count += 1;
+            }
+        }
+        self.db.write(batch)?;
+        Ok(count)
+    }
+
+    fn save_wallet_metadata(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>> {
+        let metadata = wallet.metadata.clone();
+        self.update_wallet(&wallet.id, |stored| stored.metadata = metadata)
+    }
+
+    fn save_transaction_metadata(
+        &self,
+        wallet_id: &str,
+        transaction_id: &str,
+        metadata: &Metadata,
+    ) -> Result<(), Box<dyn Error>> {
+        self.update_wallet(wallet_id, |stored| {
+            stored.transaction_metadata.insert(transaction_id.to_string(), metadata.clone());
+        })
+    }
+
+    fn update_cached_balance(&self, wallet_id: &str, balance: f64) -> Result<(), Box<dyn Error>> {
+        self.update_wallet(wallet_id, |stored| stored.balance = balance)
+    }
+
+    fn update_hardware_binding(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>> {
+        self.update_wallet(&wallet.id, |stored| {
+            stored.hardware_id = wallet.hardware_id.clone();
+            stored.portable = wallet.portable;
+        })
+    }
+
+    fn save_receive_address(&self, wallet_id: &str, receive_address: &ReceiveAddress) -> Result<(), Box<dyn Error>> {
+        let mut duplicate = false;
+        self.update_wallet(wallet_id, |stored| {
+            duplicate = stored.receive_addresses.iter().any(|existing| existing.index == receive_address.index);
+            if !duplicate {
+                stored.receive_addresses.push(receive_address.clone());
+                stored.receive_addresses.sort_by_key(|address| address.index);
+            }
+        })?;
+        if duplicate {
+            return Err(format!("Receive address {} already exists", receive_address.index).into());
+        }
+        Ok(())
+    }
+
+    fn save_contact(&self, wallet_id: &str, label: &str, address: &str) -> Result<(), Box<dyn Error>> {
+        let key = composite_key(&[wallet_id.as_bytes(), label.as_bytes()]);
+        let family = self.cf(ADDRESS_BOOK)?;
+        if self.db.get_cf(family, &key)?.is_some() {
+            return Err(format!("Contact {} already exists", label).into());
+        }
+        self.db.put_cf(family, key, address.as_bytes())?;
+        Ok(())
+    }
+
+    fn delete_contact(&self, wallet_id: &str, label: &str) -> Result<(), Box<dyn Error>> {
+        let key = composite_key(&[wallet_id.as_bytes(), label.as_bytes()]);
+        self.db.delete_cf(self.cf(ADDRESS_BOOK)?, key)?;
+        Ok(())
+    }
+
+    fn get_address_book(&self, wallet_id: &str) -> Result<AddressBook, Box<dyn Error>> {
+        let prefix = composite_key(&[wallet_id.as_bytes(), b""]);
+        let mut contacts = std::collections::HashMap::new();
+        for (key, value) in self.scan_prefix(ADDRESS_BOOK, &prefix)? {
+            let label = String::from_utf8(key[prefix.len()..].to_vec())?;
+            contacts.insert(label, String::from_utf8(value.to_vec())?);
+        }
+
+        Ok(AddressBook {
+            wallet_id: wallet_id.to_string(),
+            contacts,
+        })
+    }
+
+    fn save_peer_addresses(&self, addresses: &[KnownAddress]) -> Result<(), Box<dyn Error>> {
+        let family = self.cf(PEER_ADDRESSES)?;
+        let mut batch = WriteBatch::default();
+        for entry in addresses {
+            batch.put_cf(family, entry.address.as_bytes(), serde_json::to_vec(entry)?);
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get_peer_addresses(&self) -> Result<Vec<KnownAddress>, Box<dyn Error>> {
+        let mut addresses = vec![];
+        for (_, value) in self.scan_prefix(PEER_ADDRESSES, b"")? {
+            addresses.push(serde_json::from_slice::<KnownAddress>(&value)?);
+        }
+        addresses.sort_by(|a, b| b.score.cmp(&a.score));
+        Ok(addresses)
+    }
+
+    fn save_peer_bans(&self, bans: &[PeerBan]) -> Result<(), Box<dyn Error>> {
+        let family = self.cf(PEER_BANS)?;
+        let now = chrono::Utc::now();
+        let mut batch = WriteBatch::default();
+        for (key, value) in self.scan_prefix(PEER_BANS, b"")? {
+            if serde_json::from_slice::<PeerBan>(&value)?.until < now {
+                batch.delete_cf(family, key);
+            }
+        }
+        for ban in bans {
+            batch.put_cf(family, ban.address.as_bytes(), serde_json::to_vec(ban)?);
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get_peer_bans(&self) -> Result<Vec<PeerBan>, Box<dyn Error>> {
+        let now = chrono::Utc::now();
+        let mut bans = vec![];
+        for (_, value) in self.scan_prefix(PEER_BANS, b"")? {
+            let ban = serde_json::from_slice::<PeerBan>(&value)?;
+            if ban.until >= now {
+                bans.push(ban);
+            }
+        }
+        Ok(bans)
+    }
+
+    fn save_block(&self, block: &Block) -> Result<(), Box<dyn Error>> {
+        self.save_blocks(std::slice::from_ref(block))
+    }
+
+    fn save_blocks(&self, blocks: &[Block]) -> Result<(), Box<dyn Error>> {
+        let _guard = self.block_lock.lock().unwrap();
+        let blocks_cf = self.cf(BLOCKS)?;
+        let mut hashes = HashSet::new();
+        for block in blocks {
+            if !hashes.insert(block.hash.as_str()) || self.db.get_cf(blocks_cf, block.hash.as_bytes())?.is_some() {
+                return Err(format!("Block {} already exists", block.hash).into());
+            }
+        }
+
+        // Everything for the blocks goes in one atomic write
+        let mut batch = WriteBatch::default();
+        let transactions = self.cf(TRANSACTIONS)?;
+        let index = self.cf(ADDRESS_TRANSACTIONS)?;
+        let block_index = self.cf(BLOCK_TRANSACTIONS)?;
+        let order = self.cf(BLOCK_ORDER)?;
+        let heights_cf = self.cf(BLOCK_HEIGHTS)?;
+        let heights = block_heights(blocks, |hash| self.block_height(hash))?;
+        for (block, height) in blocks.iter().zip(heights) {
+            let header = Block { transactions: vec![], ..block.clone() };
+            batch.put_cf(blocks_cf, block.hash.as_bytes(), serde_json::to_vec(&header)?);
+            batch.put_cf(order, block_order_key(height, &block.hash), b"");
+            batch.put_cf(heights_cf, block.hash.as_bytes(), height.to_be_bytes());
+
+            for (position, transaction) in block.transactions.iter().enumerate() {
+                batch.put_cf(transactions, transaction.id.as_bytes(), serde_json::to_vec(transaction)?);
+                let position = (position as u32).to_be_bytes();
+                batch.put_cf(block_index, composite_key(&[block.hash.as_bytes(), transaction.id.as_bytes()]), position);
+                // Big-endian nanoseconds sort the index oldest first
+                let timestamp = transaction.timestamp.timestamp_nanos().to_be_bytes();
+                for address in indexed_addresses(transaction) {
+                    batch.put_cf(index, composite_key(&[address.as_bytes(), &timestamp, transaction.id.as_bytes()]), b"");
+                }
+            }
+        }
+        let mut state = self.chain_state()?;
+        state.advance(blocks);
+        batch.put_cf(self.cf(METADATA)?, CHAIN_STATE_KEY, serde_json::to_vec(&state)?);
+        let all_transactions: Vec<Transaction> = blocks.iter()
+            .flat_map(|block| block.transactions.iter().cloned())
+            .collect();
+        self.adjust_balances(&mut batch, &all_transactions, 1.0)?;
+
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn revert_block(&self, hash: &str) -> Result<(), Box<dyn Error>> {
+        let _guard = self.block_lock.lock().unwrap();
+        let block: Block = self.get_json(BLOCKS, hash.as_bytes())?.ok_or_else(|| format!("Unknown block {}", hash))?;
+        // Only the best block is known to have nothing built on it
+        let mut state = self.chain_state()?;
+        if state.best_hash.as_deref() != Some(hash) {
+            return Err(format!("Block {} has descendants; revert them first", hash).into());
+        }
+
+        let mut batch = WriteBatch::default();
+        let transactions_cf = self.cf(TRANSACTIONS)?;
+        let index = self.cf(ADDRESS_TRANSACTIONS)?;
+        let block_index = self.cf(BLOCK_TRANSACTIONS)?;
+        let mut transactions = vec![];
+        for (key, _) in self.scan_prefix(BLOCK_TRANSACTIONS, &composite_key(&[hash.as_bytes(), b""]))? {
+            let id = &key[hash.len() + 1..];
+            if let Some(transaction) = self.get_json::<Transaction>(TRANSACTIONS, id)? {
+                let timestamp = transaction.timestamp.timestamp_nanos().to_be_bytes();
+                for address in indexed_addresses(&transaction) {
+                    batch.delete_cf(index, composite_key(&[address.as_bytes(), &timestamp, id]));
+                }
+                transactions.push(transaction);
+            }
+            batch.delete_cf(transactions_cf, id);
+            batch.delete_cf(block_index, &key);
+        }
+        batch.delete_cf(self.cf(BLOCKS)?, hash.as_bytes());
+        let height = self.block_height(hash)?.ok_or_else(|| format!("Block {} has no stored height", hash))?;
+        batch.delete_cf(self.cf(BLOCK_ORDER)?, block_order_key(height, hash));
+        batch.delete_cf(self.cf(BLOCK_HEIGHTS)?, hash.as_bytes());
+
+        state.rewind(hash, &block.previous_hash);
+        batch.put_cf(self.cf(METADATA)?, CHAIN_STATE_KEY, serde_json::to_vec(&state)?);
+        self.adjust_balances(&mut batch, &transactions, -1.0)?;
+
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get_all_transactions(&self) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        let mut transactions = vec![];
+        for entry in self.db.iterator_cf(self.cf(TRANSACTIONS)?, IteratorMode::Start) {
+            let (_, value) = entry?;
+            transactions.push(serde_json::from_slice::<Transaction>(&value)?);
+        }
+        transactions.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+        Ok(transactions)
+    }
+
+    fn get_all_blocks(&self) -> Result<Vec<Block>, Box<dyn Error>> {
+        let mut blocks = vec![];
+        for entry in self.db.iterator_cf(self.cf(BLOCK_ORDER)?, IteratorMode::Start) {
+            let (key, _) = entry?;
+            blocks.push(self.indexed_block(&key[9..])?);
+        }
+        Ok(blocks)
+    }
+
+    fn get_blocks(&self, page: &PageRequest) -> Result<BlockPage, Box<dyn Error>> {
+        let limit = page.limit();
+        let order = self.cf(BLOCK_ORDER)?;
+        let start = match page.block_cursor()? {
+            Some(cursor) => block_order_key(cursor.height, &cursor.hash),
+            None => vec![],
+        };
+
+        let mut blocks = vec![];
+        for entry in self.db.iterator_cf(order, IteratorMode::From(&start, Direction::Forward)) {
+            let (key, _) = entry?;
+            // The cursor names the last block of the previous page
+            if *key == *start {
+                continue;
+            }
+            let height = u64::from_be_bytes(key[..8].try_into()?);
+            blocks.push((height, self.indexed_block(&key[9..])?));
+            if blocks.len() > limit {
+                break;
+            }
+        }
+        Ok(BlockPage::from_rows(blocks, limit))
+    }
+
+    fn get_address_balance(&self, address: &str) -> Result<f64, Box<dyn Error>> {
+        Ok(self.get_json(ADDRESS_BALANCES, address.as_bytes())?.unwrap_or(0.0))
+    }
+
+    fn get_address_balances(&self) -> Result<HashMap<String, f64>, Box<dyn Error>> {
+        let mut balances = HashMap::new();
+        for entry in self.db.iterator_cf(self.cf(ADDRESS_BALANCES)?, IteratorMode::Start) {
+            let (key, value) = entry?;
+            balances.insert(String::from_utf8(key.into_vec())?, serde_json::from_slice(&value)?);
+        }
+        Ok(balances)
+    }
+
+    fn replace_address_balances(&self, balances: &HashMap<String, f64>) -> Result<(), Box<dyn Error>> {
+        let _guard = self.block_lock.lock().unwrap();
+        let family = self.cf(ADDRESS_BALANCES)?;
+
+        let mut batch = WriteBatch::default();
+        for entry in self.db.iterator_cf(family, IteratorMode::Start) {
+            let (key, _) = entry?;
+            batch.delete_cf(family, key);
+        }
+        for (address, balance) in balances {
+            batch.put_cf(family, address.as_bytes(), serde_json::to_vec(balance)?);
+        }
+
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get_transactions_for(&self, addresses: &[String]) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        let mut ids = std::collections::HashSet::new();
+        let mut transactions = vec![];
+        for address in addresses {
+            let prefix = composite_key(&[address.as_bytes(), b""]);
+            for (key, _) in self.scan_prefix(ADDRESS_TRANSACTIONS, &prefix)? {
+                // The id follows the 8-byte timestamp and its separator
+                let id = &key[prefix.len() + 9..];
+                if ids.insert(id.to_vec()) {
+                    if let Some(transaction) = self.get_json::<Transaction>(TRANSACTIONS, id)? {
+                        transactions.push(transaction);
+                    }
+                }
+            }
+        }
+        transactions.sort_by_key(|transaction| transaction.timestamp);
+        Ok(transactions)
+    }
+
+    fn get_transactions_for_address(&self, address: &str, page: &PageRequest) -> Result<TransactionPage, Box<dyn Error>> {
+        let limit = page.limit();
+        let prefix = composite_key(&[address.as_bytes(), b""]);
+        // Walk the index backwards from just before the cursor, or from the
+        // end of the address's keys
+        let start = match page.cursor()? {
+            Some(cursor) => {
+                composite_key(&[address.as_bytes(), &cursor.timestamp.timestamp_nanos().to_be_bytes(), cursor.id.as_bytes()])
+            }
+            None => composite_key(&[address.as_bytes(), &[0xff; 9]]),
+        };
+
+        let mut transactions = vec![];
+        let entries = self.db.iterator_cf(self.cf(ADDRESS_TRANSACTIONS)?, IteratorMode::From(&start, Direction::Reverse));
+        for entry in entries {
+            let (key, _) = entry?;
+            if !key.starts_with(&prefix) || transactions.len() > limit {
+                break;
+            }
+            if *key == *start {
+                continue;
+            }
+            let id = &key[prefix.len() + 9..];
+            if let Some(transaction) = self.get_json::<Transaction>(TRANSACTIONS, id)? {
+                transactions.push(transaction);
+            }
+        }
+
+        Ok(TransactionPage::from_rows(transactions, limit))
+    }
+
+    fn get_latest_block(&self) -> Result<Option<Block>, Box<dyn Error>> {
+        match self.chain_state()?.best_hash {
+            Some(hash) => self.get_json(BLOCKS, hash.as_bytes()),
+            None => Ok(None),
+        }
+    }
+
+    fn get_chain_state(&self) -> Result<ChainState, Box<dyn Error>> {
+        self.chain_state()
+    }
+
+    fn set_last_snapshot_height(&self, height: u64) -> Result<(), Box<dyn Error>> {
+        // Block writes replace the whole state, so they must not interleave
+        let _guard = self.block_lock.lock().unwrap();
+        let state = ChainState { last_snapshot_height: Some(height), ..self.chain_state()? };
+        self.put_json(METADATA, CHAIN_STATE_KEY, &state)
+    }
+
+    fn save_token(&self, token: &Token) -> Result<(), Box<dyn Error>> {
+        self.put_json(TOKENS, token.symbol.as_bytes(), token)
+    }
+
+    fn get_tokens(&self) -> Result<Vec<Token>, Box<dyn Error>> {
+        let mut tokens = vec![];
+        for entry in self.db.iterator_cf(self.cf(TOKENS)?, IteratorMode::Start) {
+            let (_, value) = entry?;
+            tokens.push(serde_json::from_slice(&value)?);
+        }
+        Ok(tokens)
+    }
+
+    fn save_order(&self, order: &Order) -> Result<(), Box<dyn Error>> {
+        let _guard = self.order_lock.lock().unwrap();
+        if self.db.get_cf(self.cf(ORDERS)?, order.id.as_bytes())?.is_some() {
+            return Err(format!("Order {} already exists", order.id).into());
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.put_cf(self.cf(ORDERS)?, order.id.as_bytes(), serde_json::to_vec(order)?);
+        if order.is_open() {
+            batch.put_cf(self.cf(OPEN_ORDERS)?, open_order_key(order), b"");
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn update_order(&self, order: &Order) -> Result<(), Box<dyn Error>> {
+        let _guard = self.order_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        self.update_order_status(&mut batch, order)?;
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get_open_orders(&self, user_id: Option<&str>) -> Result<Vec<Order>, Box<dyn Error>> {
+        let entries = match user_id {
+            Some(user_id) => self.scan_prefix(OPEN_ORDERS, &composite_key(&[user_id.as_bytes(), b""]))?,
+            None => self.scan_prefix(OPEN_ORDERS, b"")?,
+        };
+        let mut orders = vec![];
+        for (key, _) in entries {
+            // The id follows the user id, the 8-byte timestamp and their separators
+            let user_len = key.iter().position(|byte| *byte == 0).unwrap_or(key.len());
+            let id = &key[(user_len + 10).min(key.len())..];
+            if let Some(order) = self.get_json::<Order>(ORDERS, id)? {
+                orders.push(order);
+            }
+        }
+        orders.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+        Ok(orders)
+    }
+
+    fn get_orders(&self) -> Result<Vec<Order>, Box<dyn Error>> {
+        let mut orders = vec![];
+        for entry in self.db.iterator_cf(self.cf(ORDERS)?, IteratorMode::Start) {
+            let (_, value) = entry?;
+            orders.push(serde_json::from_slice::<Order>(&value)?);
+        }
+        orders.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+        Ok(orders)
+    }
+
+    fn save_trades(&self, trades: &[Trade], orders: &[Order]) -> Result<(), Box<dyn Error>> {
+        let _guard = self.order_lock.lock().unwrap();
+        let mut batch = WriteBatch::default();
+        for order in orders {
+            self.update_order_status(&mut batch, order)?;
+        }
+        let family = self.cf(TRADES)?;
+        for trade in trades {
+            let timestamp = trade.timestamp.timestamp_nanos().to_be_bytes();
+            let key = composite_key(&[trade.token_symbol.as_bytes(), &timestamp, trade.id.as_bytes()]);
+            batch.put_cf(family, key, serde_json::to_vec(trade)?);
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get_trades(&self, token_symbol: &str, page: &PageRequest) -> Result<TradePage, Box<dyn Error>> {
+        let limit = page.limit();
+        let prefix = composite_key(&[token_symbol.as_bytes(), b""]);
+        // Walked backwards from just before the cursor, like address history
+        let start = match page.cursor()? {
+            Some(cursor) => {
+                composite_key(&[token_symbol.as_bytes(), &cursor.timestamp.timestamp_nanos().to_be_bytes(), cursor.id.as_bytes()])
+            }
+            None => composite_key(&[token_symbol.as_bytes(), &[0xff; 9]]),
+        };
+
+        let mut trades = vec![];
+        for entry in self.db.iterator_cf(self.cf(TRADES)?, IteratorMode::From(&start, Direction::Reverse)) {
+            let (key, value) = entry?;
+            if !key.starts_with(&prefix) || trades.len() > limit {
+                break;
+            }
+            if *key == *start {
+                continue;
+            }
+            trades.push(serde_json::from_slice::<Trade>(&value)?);
+        }
+
+        Ok(TradePage::from_rows(trades, limit))
+    }
+
+    // Trades are keyed by token, so finding a user's means reading them all
+    fn get_user_trades(&self, user_id: &str) -> Result<Vec<Trade>, Box<dyn Error>> {
+        let mut trades = vec![];
+        for entry in self.db.iterator_cf(self.cf(TRADES)?, IteratorMode::Start) {
+            let (_, value) = entry?;
+            let trade = serde_json::from_slice::<Trade>(&value)?;
+            if trade.buyer == user_id || trade.seller == user_id {
+                trades.push(trade);
+            }
+        }
+        trades.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+        Ok(trades)
+    }
+
+    // A checkpoint hard-links the current SST files, so it is consistent
+    // and cheap however large the database is
+    fn save_proposal(&self, proposal: &Proposal) -> Result<(), Box<dyn Error>> {
+        let stored = ProposalMatch {
+            id: proposal.id.clone(),
+            title: proposal.title.clone(),
+            description: proposal.description.clone(),
+            creator: proposal.creator.clone(),
+            status: proposal.status.as_str().to_string(),
+            budget_amount: proposal.budget_amount,
+            created_at: proposal.created_at,
+            voting_end: proposal.voting_end,
+        };
+        self.put_json(PROPOSALS, proposal.id.as_bytes(), &stored)
+    }
+
+    fn get_proposals(&self) -> Result<Vec<Proposal>, Box<dyn Error>> {
+        let mut proposals = vec![];
+        for entry in self.db.iterator_cf(self.cf(PROPOSALS)?, IteratorMode::Start) {
+            let (_, value) = entry?;
+            proposals.push(serde_json::from_slice::<ProposalMatch>(&value)?);
+        }
+        proposals.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+        proposals.into_iter().map(ProposalMatch::into_proposal).collect()
+    }
+
+    // There is no text index here, so every proposal is scanned and
+    // matches come newest first
+    fn search_proposals(&self, query: &str, limit: usize) -> Result<Vec<ProposalMatch>, Box<dyn Error>> {
+        let terms = search_terms(query);
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut matches = vec![];
+        for entry in self.db.iterator_cf(self.cf(PROPOSALS)?, IteratorMode::Start) {
+            let (_, value) = entry?;
+            let proposal: ProposalMatch = serde_json::from_slice(&value)?;
+            let text = format!("{} {}", proposal.title, proposal.description).to_lowercase();
+            if terms.iter().all(|term| text.contains(term.as_str())) {
+                matches.push(proposal);
+            }
+        }
+        matches.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        matches.truncate(limit);
+        Ok(matches)
+    }
+
+    fn save_webhook(&self, hook: &Webhook) -> Result<(), Box<dyn Error>> {
+        let mut value = serde_json::to_value(hook)?;
+        value["secret"] = serde_json::Value::String(self.column_keys.encrypt(&hook.secret)?);
+        self.put_json(WEBHOOKS, hook.id.as_bytes(), &value)
+    }
+
+    fn delete_webhook(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        self.db.delete_cf(self.cf(WEBHOOKS)?, id.as_bytes())?;
+        Ok(())
+    }
+
+    fn get_webhooks(&self) -> Result<Vec<Webhook>, Box<dyn Error>> {
+        let mut hooks = vec![];
+        for entry in self.db.iterator_cf(self.cf(WEBHOOKS)?, IteratorMode::Start) {
+            let (_, value) = entry?;
+            let mut hook: Webhook = serde_json::from_slice(&value)?;
+            hook.secret = self.column_keys.decrypt(&hook.secret)?;
+            hooks.push(hook);
+        }
+        Ok(hooks)
+    }
+
+    fn search_memos(&self, address: &str, query: &str, limit: usize) -> Result<Vec<MemoMatch>, Box<dyn Error>> {
+        let terms = search_terms(query);
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        // Read as plain JSON so the keystore is never decrypted
+        let mut annotations: HashMap<String, Metadata> = HashMap::new();
+        for entry in self.db.iterator_cf(self.cf(WALLETS)?, IteratorMode::Start) {
+            let (_, value) = entry?;
+            let mut wallet: serde_json::Value = serde_json::from_slice(&value)?;
+            if wallet["address"].as_str() == Some(address) {
+                annotations = serde_json::from_value(wallet["transaction_metadata"].take())?;
+                break;
+            }
+        }
+
+        let mut memos: Vec<MemoMatch> = annotations.into_iter()
+            .filter(|(_, metadata)| {
+                let text = format!(
+                    "{} {}",
+                    metadata.label.as_deref().unwrap_or(""),
+                    metadata.notes.as_deref().unwrap_or("")
+                ).to_lowercase();
+                terms.iter().all(|term| text.contains(term.as_str()))
+            })
+            .map(|(transaction_id, metadata)| MemoMatch {
+                transaction_id,
+                label: metadata.label,
+                category: metadata.category,
+                notes: metadata.notes,
+            })
+            .collect();
+        memos.sort_by(|a, b| a.transaction_id.cmp(&b.transaction_id));
+        memos.truncate(limit);
+        Ok(memos)
+    }
+
+    fn backup(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    // The open database can't be swapped for the checkpoint, so its entries
+    // are replaced with the checkpoint's in one atomic write
+    fn restore(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let _wallets = self.wallet_lock.lock().unwrap();
+        let _blocks = self.block_lock.lock().unwrap();
+        let _orders = self.order_lock.lock().unwrap();
+        let families = DB::list_cf(&Options::default(), path)?;
+        let backup = DB::open_cf_for_read_only(&Options::default(), path, &families, false)?;
+
+        let mut backup_versions = vec![];
+        if let Some(metadata) = backup.cf_handle(METADATA) {
+            for entry in backup.prefix_iterator_cf(metadata, SCHEMA_VERSION_PREFIX) {
+                let (key, value) = entry?;
+                if !key.starts_with(SCHEMA_VERSION_PREFIX) {
+                    break;
+                }
+                backup_versions.push(serde_json::from_slice::<AppliedMigration>(&value)?.version);
+            }
+        }
+        let current: Vec<u32> = self.applied_migrations()?.iter().map(|record| record.version).collect();
+        migrations::check_backup_schema(&backup_versions, &current)?;
+
+        let mut batch = WriteBatch::default();
+        for name in COLUMN_FAMILIES {
+            let family = self.cf(name)?;
+            for entry in self.db.iterator_cf(family, IteratorMode::Start) {
+                let (key, _) = entry?;
+                batch.delete_cf(family, key);
+            }
+            // Column families added after the backup was taken stay empty
+            if let Some(source) = backup.cf_handle(name) {
+                for entry in backup.iterator_cf(source, IteratorMode::Start) {
+                    let (key, value) = entry?;
+                    batch.put_cf(family, key, value);
+                }
+            }
+        }
+
+        self.db.write(batch)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Serialize, Deserialize};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use zeroize::Zeroizing;
+
+// XX: both sides prove their static key, neither needs to know it in advance
+const NOISE_PATTERN: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const NOISE_MAX_MESSAGE: usize = 65535;
+const NOISE_TAG_LEN: usize = 16;
+
+// The node's long-term X25519 key, used to authenticate it to peers
+pub struct NodeIdentity {
+    private_key: Zeroizing<Vec<u8>>,
+    pub public_key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentityFile {
+    private_key: String,
+    public_key: String,
+}
+
+impl NodeIdentity {
+    pub fn generate() -> Result<Self, Box<dyn Error>> {
+        let keypair = Builder::new(NOISE_PATTERN.parse()?).generate_keypair()?;
+        Ok(NodeIdentity {
+            private_key: Zeroizing::new(keypair.private),
+            public_key: keypair.public,
+        })
+    }
+
+    // Reuse the key stored at `path`, creating it on first start so the
+    // node keeps the same id across restarts
+    pub fn load_or_generate(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if path.exists() {
+            check_owner_only(path)?;
+            let file: IdentityFile = serde_json::from_str(&Zeroizing::new(std::fs::read_to_string(path)?))?;
+            return Ok(NodeIdentity {
+                private_key: Zeroizing::new(hex::decode(&file.private_key)?),
+                public_key: hex::decode(&file.public_key)?,
+            });
+        }
+
+        let identity = Self::generate()?;
+        let file = IdentityFile {
+            private_key: hex::encode(&*identity.private_key),
+            public_key: hex::encode(&identity.public_key),
+        };
+        let contents = Zeroizing::new(serde_json::to_string_pretty(&file)?);
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)?.write_all(contents.as_bytes())?;
+        Ok(identity)
+    }
+
+    pub fn node_id(&self) -> String {
+        hex::encode(&self.public_key)
+    }
+}
+
+// Refuse a key file other users could read, as ssh does
+#[cfg(unix)]
+fn check_owner_only(path: &Path) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(format!("{} is accessible to other users (mode {:o}); run chmod 600 on it", path.display(), mode).into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_owner_only(_path: &Path) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+// Run the Noise handshake over a fresh WebSocket connection. Returns the
+// two halves of the encrypted channel and the peer's authenticated node id.
+pub async fn handshake<S>(
+    sender: &mut SplitSink<WebSocketStream<S>, Message>,
+    receiver: &mut SplitStream<WebSocketStream<S>>,
+    identity: &NodeIdentity,
+    initiator: bool,
+) -> Result<(SecureSender, SecureReceiver, String), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let builder = Builder::new(NOISE_PATTERN.parse()?).local_private_key(&identity.private_key);
+    let mut state = if initiator {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+
+    // XX is three messages: initiator, responder, initiator
+    for step in 0..3 {
+        if (step % 2 == 0) == initiator {
+            write_handshake(&mut state, sender).await?;
+        } else {
+            read_handshake(&mut state, receiver).await?;
+        }
+    }
+
+    let remote_key = state.get_remote_static()
+        .ok_or("Peer did not send a static key")?
+        .to_vec();
+    if remote_key == identity.public_key {
+        return Err("Connected to ourselves".into());
+    }
+
+    let transport = Arc::new(state.into_stateless_transport_mode()?);
+    Ok((
+        SecureSender { transport: transport.clone(), nonce: 0 },
+        SecureReceiver { transport, nonce: 0 },
+        hex::encode(remote_key),
+    ))
+}
+
+async fn write_handshake<S>(state: &mut HandshakeState, sender: &mut SplitSink<WebSocketStream<S>, Message>) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+    let len = state.write_message(&[], &mut buf)?;
+    buf.truncate(len);
+    sender.send(Message::Binary(buf)).await?;
+    Ok(())
+}
+
+async fn read_handshake<S>(state: &mut HandshakeState, receiver: &mut SplitStream<WebSocketStream<S>>) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        match receiver.next().await {
+            Some(Ok(Message::Binary(data))) => {
+                let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+                state.read_message(&data, &mut buf)?;
+                return Ok(());
+            }
+            Some(Ok(Message::Close(_))) | None => return Err("Connection closed during handshake".into()),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+// Encrypting half of a secure channel. Noise messages are capped at 64 KiB,
+// so larger frames are sealed in chunks and the ciphertexts concatenated.
+pub struct SecureSender {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl SecureSender {
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut ciphertext = Vec::with_capacity(plaintext.len() + NOISE_TAG_LEN);
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+        // An empty frame still produces one chunk so the nonces stay in step
+        let mut chunks: Vec<&[u8]> = plaintext.chunks(NOISE_MAX_MESSAGE - NOISE_TAG_LEN).collect();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+        for chunk in chunks {
+            let len = self.transport.write_message(self.nonce, chunk, &mut buf)?;
+            self.nonce += 1;
+            ciphertext.extend_from_slice(&buf[..len]);
+        }
+        Ok(ciphertext)
+    }
+}
+
+pub struct SecureReceiver {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl SecureReceiver {
+    pub fn decrypt(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut plaintext = Vec::with_capacity(ciphertext.len());
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE];
+        for chunk in ciphertext.chunks(NOISE_MAX_MESSAGE) {
+            let len = self.transport.read_message(self.nonce, chunk, &mut buf)?;
+            self.nonce += 1;
+            plaintext.extend_from_slice(&buf[..len]);
+        }
+        Ok(plaintext)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn identity_file_is_private_to_its_owner() {
+        let path = std::env::temp_dir().join(format!("identity-test-{}", uuid::Uuid::new_v4()));
+        let identity = NodeIdentity::load_or_generate(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(NodeIdentity::load_or_generate(&path).unwrap().node_id(), identity.node_id());
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        assert!(NodeIdentity::load_or_generate(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
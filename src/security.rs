@@ -14,6 +14,158 @@ use aes_gcm::{
 use hmac::{Hmac, Mac};
 use sha1::Sha1;
 
+// Argon2id parameters stored alongside anything encrypted with a derived key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: String, // hex
+}
+
+impl KdfParams {
+    pub fn generate() -> Self {
+        KdfParams {
+            m_cost: 19 * 1024,
+            t_cost: 2,
+            p_cost: 1,
+            salt: hex::encode(rand::random::<[u8; 16]>()),
+        }
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String, Box<dyn Error>> {
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = Argon2::default();
+    let password_hash = argon2.hash_password(password.as_bytes(), &salt)?;
+    Ok(password_hash.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> Result<bool, Box<dyn Error>> {
+    let parsed_hash = PasswordHash::new(hash)?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok())
+}
+
+// Derive a 256-bit encryption key from a password or PIN
+pub fn derive_key(password: &[u8], params: &KdfParams) -> Result<[u8; 32], Box<dyn Error>> {
+    let salt = hex::decode(&params.salt)?;
+    let argon2_params = argon2::Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| e.to_string())?;
+    let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, argon2_params);
+
+    let mut key = [0u8; 32];
+    argon2.hash_password_into(password, &salt, &mut key)
+        .map_err(|e| e.to_string())?;
+    Ok(key)
+}
+
+// AES-256-GCM with a fresh random nonce; returns (nonce, ciphertext)
+pub fn seal(key: &[u8; 32], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    let cipher = Aes256Gcm::new(key.into());
+    let nonce = rand::random::<[u8; 12]>();
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| "Encryption failed")?;
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+pub fn open(key: &[u8; 32], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    // Nonce::from_slice panics on any other length
+    if nonce.len() != 12 {
+        return Err("Decryption failed: nonce must be 12 bytes".into());
+    }
+    let cipher = Aes256Gcm::new(key.into());
+    let plaintext = cipher.decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Decryption failed: wrong password or corrupted data")?;
+    Ok(plaintext)
+}
+
+// Compare without short-circuiting, so timing doesn't reveal the contents
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Marks a column value sealed by `ColumnKeys`
+const SEALED_PREFIX: &str = "enc:";
+
+// Keys for encrypting sensitive database columns at rest. Each sealed value
+// names the key that sealed it, so after a new key is made active the old
+// ones only need to stay listed until every value has been rewritten.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ColumnKeys {
+    // Id of the key new values are sealed with; values are stored in the
+    // clear while this is unset
+    pub active: Option<String>,
+    // Hex-encoded 256-bit keys by id
+    pub keys: std::collections::HashMap<String, String>,
+}
+
+impl ColumnKeys {
+    fn key(&self, id: &str) -> Result<[u8; 32], Box<dyn Error>> {
+        let key = self.keys.get(id).ok_or_else(|| format!("Unknown column key {}", id))?;
+        hex::decode(key)?
+            .try_into()
+            .map_err(|_| format!("Column key {} is not 32 bytes", id).into())
+    }
+
+    // The active key must be listed, and every listed key must decode
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        for id in self.keys.keys() {
+            self.key(id)?;
+        }
+        match &self.active {
+            Some(id) if id.contains(':') => Err(format!("Column key id {} contains ':'", id).into()),
+            Some(id) if !self.keys.contains_key(id) => Err(format!("Active column key {} is not listed", id).into()),
+            _ => Ok(()),
+        }
+    }
+
+    // What every value sealed with the active key starts with
+    pub fn active_prefix(&self) -> Option<String> {
+        self.active.as_ref().map(|id| format!("{}{}:", SEALED_PREFIX, id))
+    }
+
+    // "enc:<key id>:<nonce>:<ciphertext>" under the active key, or the
+    // plaintext itself when no key is active
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, Box<dyn Error>> {
+        let id = match &self.active {
+            Some(id) if id.contains(':') => return Err(format!("Column key id {} contains ':'", id).into()),
+            Some(id) => id,
+            None => return Ok(plaintext.to_string()),
+        };
+        let (nonce, ciphertext) = seal(&self.key(id)?, plaintext.as_bytes())?;
+        Ok(format!("{}{}:{}:{}", SEALED_PREFIX, id, hex::encode(nonce), hex::encode(ciphertext)))
+    }
+
+    // Values without the prefix were stored before encryption was enabled
+    // and are returned as they are
+    pub fn decrypt(&self, value: &str) -> Result<String, Box<dyn Error>> {
+        let sealed = match value.strip_prefix(SEALED_PREFIX) {
+            Some(sealed) => sealed,
+            None => return Ok(value.to_string()),
+        };
+        let mut parts = sealed.splitn(3, ':');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(id), Some(nonce), Some(ciphertext)) => {
+                let plaintext = open(&self.key(id)?, &hex::decode(nonce)?, &hex::decode(ciphertext)?)?;
+                Ok(String::from_utf8(plaintext)?)
+            }
+            _ => Err("Malformed encrypted column value".into()),
+        }
+    }
+
+    // Whether `value` would be written differently now: sealed with an
+    // older key, or not sealed at all while a key is active
+    pub fn needs_reencrypt(&self, value: &str) -> bool {
+        match self.active_prefix() {
+            Some(prefix) => !value.starts_with(&prefix),
+            None => false,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JwtClaims {
     pub sub: String,
@@ -22,7 +174,8 @@ pub struct JwtClaims {
 }
 
 pub struct Security {
-    jwt_secret: Vec<u8>,
+    // Swapped out by `rotate_jwt_secret`
+    jwt_secret: std::sync::RwLock<Vec<u8>>,
     encryption_key: Aes256Gcm,
 }
 
@@ -30,24 +183,18 @@ impl Security {
     pub fn new(jwt_secret: Vec<u8>) -> Result<Self, Box<dyn Error>> {
         let encryption_key = Aes256Gcm::new(&jwt_secret[..32].into());
         Ok(Security {
-            jwt_secret,
+            jwt_secret: std::sync::RwLock::new(jwt_secret),
             encryption_key,
         })
     }
 
     // Password hashing using Argon2
     pub fn hash_password(&self, password: &str) -> Result<String, Box<dyn Error>> {
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2.hash_password(password.as_bytes(), &salt)?;
-        Ok(password_hash.to_string())
+        hash_password(password)
     }
 
     pub fn verify_password(&self, password: &str, hash: &str) -> Result<bool, Box<dyn Error>> {
-        let parsed_hash = PasswordHash::new(hash)?;
-        Ok(Argon2::default()
-            .verify_password(password.as_bytes(), &parsed_hash)
-            .is_ok())
+        verify_password(password, hash)
     }
 
     // JWT token generation and verification
@@ -61,7 +208,7 @@ impl Security {
         let token = encode(
             &Header::default(),
             &claims,
-            &EncodingKey::from_secret(&self.jwt_secret),
+            &EncodingKey::from_secret(&self.jwt_secret.read().unwrap()),
         )?;
 
         Ok(token)
@@ -71,13 +218,22 @@ impl Security {
         let validation = Validation::default();
         let token_data = decode::<JwtClaims>(
             token,
-            &DecodingKey::from_secret(&self.jwt_secret),
+            &DecodingKey::from_secret(&self.jwt_secret.read().unwrap()),
             &validation,
         )?;
 
         Ok(token_data.claims)
     }
 
+    // Replace the JWT secret with a random one, invalidating every token
+    // issued so far. The encryption key is left alone so existing
+    // ciphertexts stay readable.
+    pub fn rotate_jwt_secret(&self) {
+        let mut secret = vec![0u8; 64];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut secret);
+        *self.jwt_secret.write().unwrap() = secret;
+    }
+
     // Digital signature generation and verification
     pub fn generate_keypair(&self) -> (PublicKey, SecretKey) {
         let mut csprng = OsRng{};
@@ -115,14 +271,14 @@ impl Security {
     // HMAC for message integrity
     pub fn generate_hmac(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
         type HmacSha1 = Hmac<Sha1>;
-        let mut mac = HmacSha1::new_from_slice(&self.jwt_secret)?;
+        let mut mac = HmacSha1::new_from_slice(&self.jwt_secret.read().unwrap())?;
         mac.update(message);
         Ok(mac.finalize().into_bytes().to_vec())
     }
 
     pub fn verify_hmac(&self, message: &[u8], hmac: &[u8]) -> Result<bool, Box<dyn Error>> {
         type HmacSha1 = Hmac<Sha1>;
-        let mut mac = HmacSha1::new_from_slice(&self.jwt_secret)?;
+        let mut mac = HmacSha1::new_from_slice(&self.jwt_secret.read().unwrap())?;
         mac.update(message);
         Ok(mac.verify_slice(hmac).is_ok())
     }
@@ -149,9 +305,19 @@ impl TwoFactorAuth {
     }
 }
 
+// Failure history of one source, as reported to operators
+#[derive(Debug, Clone, Serialize)]
+pub struct IntrusionRecord {
+    pub source: String,
+    pub failures: u32,
+    pub last_failure: chrono::DateTime<chrono::Utc>,
+    pub locked_out: bool,
+}
+
 // Intrusion Detection System
 pub struct IntrusionDetection {
-    failed_attempts: std::sync::Mutex<std::collections::HashMap<String, u32>>,
+    // Failure count and time of the latest failure for each source
+    failed_attempts: std::sync::Mutex<std::collections::HashMap<String, (u32, chrono::DateTime<chrono::Utc>)>>,
     lockout_duration: chrono::Duration,
 }
 
@@ -165,13 +331,63 @@ impl IntrusionDetection {
 
     pub fn record_failed_attempt(&self, ip: &str) -> bool {
         let mut attempts = self.failed_attempts.lock().unwrap();
-        let count = attempts.entry(ip.to_string()).or_insert(0);
-        *count += 1;
-        *count <= 5 // Allow up to 5 failed attempts
+        let now = chrono::Utc::now();
+        let entry = attempts.entry(ip.to_string()).or_insert((0, now));
+        // Failures older than the lockout window no longer count
+        if now - entry.1 > self.lockout_duration {
+            entry.0 = 0;
+        }
+        entry.0 += 1;
+        entry.1 = now;
+        entry.0 <= 5 // Allow up to 5 failed attempts
+    }
+
+    // True while a source that used up its attempts is still locked out
+    pub fn is_locked_out(&self, ip: &str) -> bool {
+        let attempts = self.failed_attempts.lock().unwrap();
+        match attempts.get(ip) {
+            Some((count, last)) => *count > 5 && chrono::Utc::now() - *last <= self.lockout_duration,
+            None => false,
+        }
     }
 
     pub fn reset_failed_attempts(&self, ip: &str) {
         let mut attempts = self.failed_attempts.lock().unwrap();
         attempts.remove(ip);
     }
-} 
\ No newline at end of file
+
+    // Lock a source out immediately, as if it had used up its attempts
+    pub fn lock_out(&self, ip: &str) {
+        let mut attempts = self.failed_attempts.lock().unwrap();
+        attempts.insert(ip.to_string(), (6, chrono::Utc::now()));
+    }
+
+    // Lock a source out until `until`, e.g. to carry a lockout over a restart
+    pub fn lock_out_until(&self, ip: &str, until: chrono::DateTime<chrono::Utc>) {
+        let mut attempts = self.failed_attempts.lock().unwrap();
+        attempts.insert(ip.to_string(), (6, until - self.lockout_duration));
+    }
+
+    // Sources currently locked out, with the time each lockout ends
+    pub fn lockouts(&self) -> Vec<(String, chrono::DateTime<chrono::Utc>)> {
+        let attempts = self.failed_attempts.lock().unwrap();
+        let now = chrono::Utc::now();
+        attempts.iter()
+            .filter(|(_, (failures, last))| *failures > 5 && now - *last <= self.lockout_duration)
+            .map(|(source, (_, last))| (source.clone(), *last + self.lockout_duration))
+            .collect()
+    }
+
+    pub fn records(&self) -> Vec<IntrusionRecord> {
+        let attempts = self.failed_attempts.lock().unwrap();
+        let now = chrono::Utc::now();
+        attempts.iter()
+            .map(|(source, (failures, last))| IntrusionRecord {
+                source: source.clone(),
+                failures: *failures,
+                last_failure: *last,
+                locked_out: *failures > 5 && now - *last <= self.lockout_duration,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,191 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use rand::{rngs::OsRng, RngCore};
+use zeroize::Zeroizing;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer as _, Verifier};
+use k256::ecdsa::{
+    signature::{Signer as _, Verifier as _},
+    Signature as EcdsaSignature, SigningKey as EcdsaSigningKey, VerifyingKey as EcdsaVerifyingKey,
+};
+
+// Signature algorithm behind a key. Both schemes use 32-byte secret keys;
+// secp256k1 public keys are SEC1-compressed (33 bytes) and signatures are
+// ECDSA over SHA-256 in compact form, as used by most other chains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum KeyScheme {
+    Ed25519,
+    Secp256k1,
+}
+
+impl Default for KeyScheme {
+    fn default() -> Self {
+        KeyScheme::Ed25519
+    }
+}
+
+impl KeyScheme {
+    pub const ALL: [KeyScheme; 2] = [KeyScheme::Ed25519, KeyScheme::Secp256k1];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyScheme::Ed25519 => "ed25519",
+            KeyScheme::Secp256k1 => "secp256k1",
+        }
+    }
+
+    pub fn generate_secret(&self) -> Zeroizing<Vec<u8>> {
+        loop {
+            let mut secret = Zeroizing::new(vec![0u8; 32]);
+            OsRng.fill_bytes(&mut secret);
+            // Not every 32-byte string is a valid secp256k1 scalar
+            if self.public_key(&secret).is_ok() {
+                return secret;
+            }
+        }
+    }
+
+    pub fn public_key(&self, secret_key: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            KeyScheme::Ed25519 => {
+                let secret = SecretKey::from_bytes(secret_key)?;
+                let public: PublicKey = (&secret).into();
+                Ok(public.to_bytes().to_vec())
+            }
+            KeyScheme::Secp256k1 => {
+                let signing_key = EcdsaSigningKey::from_slice(secret_key)?;
+                Ok(signing_key.verifying_key().to_sec1_bytes().to_vec())
+            }
+        }
+    }
+
+    pub fn is_valid_public_key(&self, public_key: &[u8]) -> bool {
+        match self {
+            KeyScheme::Ed25519 => PublicKey::from_bytes(public_key).is_ok(),
+            KeyScheme::Secp256k1 => EcdsaVerifyingKey::from_sec1_bytes(public_key).is_ok(),
+        }
+    }
+
+    pub fn sign(&self, secret_key: &[u8], message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            KeyScheme::Ed25519 => {
+                let secret = SecretKey::from_bytes(secret_key)?;
+                let public: PublicKey = (&secret).into();
+                let keypair = Keypair { secret, public };
+                Ok(keypair.sign(message).to_bytes().to_vec())
+            }
+            KeyScheme::Secp256k1 => {
+                let signing_key = EcdsaSigningKey::from_slice(secret_key)?;
+                let signature: EcdsaSignature = signing_key.sign(message);
+                Ok(signature.to_bytes().to_vec())
+            }
+        }
+    }
+
+    pub fn verify(&self, message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+        match self {
+            KeyScheme::Ed25519 => {
+                let public_key = match PublicKey::from_bytes(public_key) {
+                    Ok(key) => key,
+                    Err(_) => return false,
+                };
+                let signature = match Signature::from_bytes(signature) {
+                    Ok(signature) => signature,
+                    Err(_) => return false,
+                };
+                public_key.verify(message, &signature).is_ok()
+            }
+            KeyScheme::Secp256k1 => {
+                let public_key = match EcdsaVerifyingKey::from_sec1_bytes(public_key) {
+                    Ok(key) => key,
+                    Err(_) => return false,
+                };
+                let signature = match EcdsaSignature::from_slice(signature) {
+                    Ok(signature) => signature,
+                    Err(_) => return false,
+                };
+                public_key.verify(message, &signature).is_ok()
+            }
+        }
+    }
+}
+
+impl fmt::Display for KeyScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for KeyScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KeyScheme::ALL.iter()
+            .copied()
+            .find(|scheme| scheme.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown key scheme: {}", s))
+    }
+}
+
+// Anything that can produce signatures for a public key. Implementations
+// backed by a Ledger or HSM keep the secret key outside this process.
+#[async_trait]
+pub trait Signer: Send + Sync {
+    fn public_key(&self) -> Vec<u8>;
+
+    async fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+
+    fn scheme(&self) -> KeyScheme {
+        KeyScheme::Ed25519
+    }
+
+    fn address(&self) -> String {
+        crate::wallet::encode_address(&self.public_key())
+    }
+}
+
+// Signer over a secret key held in memory
+pub struct KeypairSigner {
+    scheme: KeyScheme,
+    secret_key: Zeroizing<Vec<u8>>,
+    public_key: Vec<u8>,
+}
+
+impl KeypairSigner {
+    pub fn new(keypair: Keypair) -> Self {
+        KeypairSigner {
+            scheme: KeyScheme::Ed25519,
+            secret_key: Zeroizing::new(keypair.secret.to_bytes().to_vec()),
+            public_key: keypair.public.to_bytes().to_vec(),
+        }
+    }
+
+    pub fn from_secret(scheme: KeyScheme, secret_key: &[u8]) -> Result<Self, Box<dyn Error>> {
+        Ok(KeypairSigner {
+            scheme,
+            public_key: scheme.public_key(secret_key)?,
+            secret_key: Zeroizing::new(secret_key.to_vec()),
+        })
+    }
+}
+
+#[async_trait]
+impl Signer for KeypairSigner {
+    fn public_key(&self) -> Vec<u8> {
+        self.public_key.clone()
+    }
+
+    async fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.scheme.sign(&self.secret_key, message)
+    }
+
+    fn scheme(&self) -> KeyScheme {
+        self.scheme
+    }
+}
+
+pub fn verify_signature(scheme: KeyScheme, message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+    scheme.verify(message, public_key, signature)
+}
@@ -0,0 +1,426 @@
+use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+
+use crate::network::{NetworkMessage, NetworkTransport, PeerId, PeerInfo, CAPABILITIES};
+use crate::wire::Compression;
+
+// In-process stand-in for the P2P network, for exercising multi-node
+// scenarios without sockets. Every random choice (message loss, latency
+// jitter) comes from one seeded RNG, and delivery uses tokio's clock, so a
+// scenario run under a paused runtime replays identically for a given seed.
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    world: Arc<Mutex<World>>,
+}
+
+struct World {
+    nodes: HashMap<PeerId, broadcast::Sender<(PeerId, NetworkMessage)>>,
+    // Undirected links, stored with the smaller id first
+    links: HashSet<(PeerId, PeerId)>,
+    // Nodes in different groups can't reach each other; empty means no partition
+    partition: Vec<HashSet<PeerId>>,
+    rng: StdRng,
+    loss_rate: f64,
+    latency: Duration,
+    jitter: Duration,
+    // Messages on a link arrive in the order they were sent
+    link_clock: HashMap<(PeerId, PeerId), Instant>,
+    stats: SimulationStats,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SimulationStats {
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub blocked: u64,
+}
+
+impl SimulatedNetwork {
+    pub fn new(seed: u64) -> Self {
+        SimulatedNetwork {
+            world: Arc::new(Mutex::new(World {
+                nodes: HashMap::new(),
+                links: HashSet::new(),
+                partition: vec![],
+                rng: StdRng::seed_from_u64(seed),
+                loss_rate: 0.0,
+                latency: Duration::ZERO,
+                jitter: Duration::ZERO,
+                link_clock: HashMap::new(),
+                stats: SimulationStats::default(),
+            })),
+        }
+    }
+
+    pub fn add_node(&self, id: &str) -> SimulatedTransport {
+        let (message_tx, _) = broadcast::channel(1000);
+        self.world.lock().unwrap().nodes.insert(id.to_string(), message_tx.clone());
+        SimulatedTransport {
+            id: id.to_string(),
+            network: self.clone(),
+            message_tx,
+        }
+    }
+
+    pub fn link(&self, a: &str, b: &str) {
+        self.world.lock().unwrap().links.insert(link_key(a, b));
+    }
+
+    pub fn unlink(&self, a: &str, b: &str) {
+        self.world.lock().unwrap().links.remove(&link_key(a, b));
+    }
+
+    // Split the nodes into groups that can only talk among themselves
+    pub fn partition(&self, groups: Vec<Vec<&str>>) {
+        self.world.lock().unwrap().partition = groups.into_iter()
+            .map(|group| group.into_iter().map(|id| id.to_string()).collect())
+            .collect();
+    }
+
+    pub fn heal(&self) {
+        self.world.lock().unwrap().partition.clear();
+    }
+
+    // Fraction of messages silently dropped, from 0.0 to 1.0
+    pub fn set_loss_rate(&self, rate: f64) {
+        self.world.lock().unwrap().loss_rate = rate.clamp(0.0, 1.0);
+    }
+
+    // Each message takes `latency` plus up to `jitter` to arrive
+    pub fn set_latency(&self, latency: Duration, jitter: Duration) {
+        let mut world = self.world.lock().unwrap();
+        world.latency = latency;
+        world.jitter = jitter;
+    }
+
+    pub fn stats(&self) -> SimulationStats {
+        self.world.lock().unwrap().stats.clone()
+    }
+
+    fn deliver(&self, from: &str, to: &str, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        let mut guard = self.world.lock().unwrap();
+        let world = &mut *guard;
+        let key = link_key(from, to);
+        if !world.links.contains(&key) {
+            return Err(format!("{} is not connected to {}", from, to).into());
+        }
+        let recipient = world.nodes.get(to).cloned().ok_or_else(|| format!("Unknown node {}", to))?;
+        world.stats.sent += 1;
+
+        if !world.reachable(from, to) {
+            world.stats.blocked += 1;
+            return Ok(());
+        }
+        let loss_rate = world.loss_rate;
+        if loss_rate > 0.0 && world.rng.gen_bool(loss_rate) {
+            world.stats.lost += 1;
+            return Ok(());
+        }
+
+        let jitter = if world.jitter.is_zero() {
+            Duration::ZERO
+        } else {
+            world.jitter.mul_f64(world.rng.gen_range(0.0..1.0))
+        };
+        let arrival = Instant::now() + world.latency + jitter;
+        let clock = world.link_clock.entry((from.to_string(), to.to_string())).or_insert(arrival);
+        let arrival = arrival.max(*clock);
+        *clock = arrival;
+        world.stats.delivered += 1;
+
+        let from = from.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep_until(arrival).await;
+            let _ = recipient.send((from, message));
+        });
+        Ok(())
+    }
+
+    fn neighbours(&self, id: &str) -> Vec<PeerId> {
+        let world = self.world.lock().unwrap();
+        let mut neighbours: Vec<PeerId> = world.links.iter()
+            .filter_map(|(a, b)| {
+                if a == id {
+                    Some(b.clone())
+                } else if b == id {
+                    Some(a.clone())
+                } else {
+                    None
+                }
+            })
+            .collect();
+        // HashSet order varies between runs; keep delivery order stable
+        neighbours.sort();
+        neighbours
+    }
+}
+
+impl World {
+    fn reachable(&self, a: &str, b: &str) -> bool {
+        self.partition.is_empty()
+            || self.partition.iter().any(|group| group.contains(a) && group.contains(b))
+    }
+}
+
+fn link_key(a: &str, b: &str) -> (PeerId, PeerId) {
+    if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+// One node's view of a SimulatedNetwork
+pub struct SimulatedTransport {
+    id: PeerId,
+    network: SimulatedNetwork,
+    message_tx: broadcast::Sender<(PeerId, NetworkMessage)>,
+}
+
+impl SimulatedTransport {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[async_trait]
+impl NetworkTransport for SimulatedTransport {
+    async fn listen(&self, _addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    // `addr` is the id of another node in the same simulation
+    async fn connect(&self, addr: String) -> Result<(), Box<dyn Error>> {
+        if !self.network.world.lock().unwrap().nodes.contains_key(&addr) {
+            return Err(format!("Unknown node {}", addr).into());
+        }
+        self.network.link(&self.id, &addr);
+        Ok(())
+    }
+
+    async fn broadcast(&self, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        for peer in self.network.neighbours(&self.id) {
+            self.network.deliver(&self.id, &peer, message.clone())?;
+        }
+        Ok(())
+    }
+
+    async fn send_to(&self, peer: &str, message: NetworkMessage) -> Result<(), Box<dyn Error>> {
+        self.network.deliver(&self.id, peer, message)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<(PeerId, NetworkMessage)> {
+        self.message_tx.subscribe()
+    }
+
+    async fn peers(&self) -> Vec<PeerInfo> {
+        self.network.neighbours(&self.id).into_iter()
+            .map(|peer| PeerInfo {
+                address: peer.clone(),
+                node_id: peer,
+                version: "simulation".to_string(),
+                last_seen: chrono::Utc::now(),
+                protocol_version: crate::network::PROTOCOL_VERSION,
+                best_height: 0,
+                capabilities: CAPABILITIES,
+                score: 0,
+                compression: Compression::None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::tests::{new_signer, transfer};
+    use crate::blockchain::Blockchain;
+    use crate::network::{Network, NetworkConfig};
+    use crate::signer::{KeypairSigner, Signer};
+    use crate::sync::SyncManager;
+
+    // Nodes running the real Network over simulated links, every pair
+    // linked. Runtimes are paused, so waits below take no real time.
+    async fn nodes(simulation: &SimulatedNetwork, ids: &[&str]) -> Vec<Network> {
+        let mut links = vec![];
+        for (i, a) in ids.iter().enumerate() {
+            for b in &ids[i + 1..] {
+                links.push((*a, *b));
+            }
+        }
+        linked_nodes(simulation, ids, &links, HashMap::new()).await
+    }
+
+    // Nodes linked only as listed, all starting from a genesis with `allocations`
+    async fn linked_nodes(
+        simulation: &SimulatedNetwork,
+        ids: &[&str],
+        links: &[(&str, &str)],
+        allocations: HashMap<String, f64>,
+    ) -> Vec<Network> {
+        for (a, b) in links {
+            simulation.link(a, b);
+        }
+        let mut nodes = vec![];
+        for id in ids {
+            let blockchain = Blockchain::with_allocations(allocations.clone());
+            let node = Network::with_config(NetworkConfig::default(), blockchain);
+            node.attach_transport(Arc::new(simulation.add_node(id))).await.unwrap();
+            nodes.push(node);
+        }
+        nodes
+    }
+
+    // A line a - b - c whose genesis funds the returned key, so transfers
+    // from it are valid on every node
+    async fn funded_line(simulation: &SimulatedNetwork) -> (Vec<Network>, KeypairSigner) {
+        let signer = new_signer();
+        let allocations = HashMap::from([(signer.address(), 100.0)]);
+        let nodes = linked_nodes(simulation, &["a", "b", "c"], &[("a", "b"), ("b", "c")], allocations).await;
+        (nodes, signer)
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    async fn received(receiver: &mut broadcast::Receiver<(PeerId, NetworkMessage)>) -> Vec<PeerId> {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let mut senders = vec![];
+        while let Ok((from, _)) = receiver.try_recv() {
+            senders.push(from);
+        }
+        senders
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partition_blocks_messages_until_healed() {
+        let simulation = SimulatedNetwork::new(1);
+        let nodes = nodes(&simulation, &["a", "b", "c"]).await;
+        let mut at_b = nodes[1].subscribe();
+        let mut at_c = nodes[2].subscribe();
+
+        simulation.partition(vec![vec!["a"], vec!["b", "c"]]);
+        nodes[0].broadcast_message(NetworkMessage::Headers(vec![])).await.unwrap();
+        nodes[1].send_to("c", NetworkMessage::Headers(vec![])).await.unwrap();
+        assert!(received(&mut at_b).await.is_empty());
+        assert_eq!(received(&mut at_c).await, vec!["b".to_string()]);
+        assert_eq!(simulation.stats().blocked, 2);
+
+        simulation.heal();
+        nodes[0].broadcast_message(NetworkMessage::Headers(vec![])).await.unwrap();
+        assert_eq!(received(&mut at_b).await, vec!["a".to_string()]);
+        assert_eq!(received(&mut at_c).await, vec!["a".to_string()]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gossip_crosses_a_healed_partition() {
+        let simulation = SimulatedNetwork::new(11);
+        let (nodes, signer) = funded_line(&simulation).await;
+        let (a, b, c) = (nodes[0].blockchain(), nodes[1].blockchain(), nodes[2].blockchain());
+
+        simulation.partition(vec![vec!["a"], vec!["b", "c"]]);
+        let cut_off = transfer(&signer, 1.0).await;
+        a.add_transaction(cut_off.clone()).await.unwrap();
+        nodes[0].broadcast_message(NetworkMessage::NewTransaction(cut_off.clone())).await.unwrap();
+        settle().await;
+        assert!(b.get_pending_transaction(&cut_off.hash()).await.is_none());
+        assert!(c.get_pending_transaction(&cut_off.hash()).await.is_none());
+
+        // c has no link to a, so it only hears of this through b's relay
+        simulation.heal();
+        let relayed = transfer(&signer, 2.0).await;
+        a.add_transaction(relayed.clone()).await.unwrap();
+        nodes[0].broadcast_message(NetworkMessage::NewTransaction(relayed.clone())).await.unwrap();
+        settle().await;
+        assert!(b.get_pending_transaction(&relayed.hash()).await.is_some());
+        assert!(c.get_pending_transaction(&relayed.hash()).await.is_some());
+
+        // The block carries the transaction the partition swallowed
+        let block = a.mine_block().await.unwrap();
+        nodes[0].broadcast_message(NetworkMessage::NewBlock(block.clone())).await.unwrap();
+        settle().await;
+        for chain in [b, c] {
+            assert_eq!(chain.latest_block().await.hash, block.hash);
+            assert!(chain.get_pending_transaction(&relayed.hash()).await.is_none());
+        }
+        assert_eq!(
+            c.read().await.balances[&signer.address()],
+            a.read().await.balances[&signer.address()],
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lagging_node_syncs_to_a_peer_ahead_of_it() {
+        let simulation = SimulatedNetwork::new(13);
+        let (nodes, signer) = funded_line(&simulation).await;
+        let (ahead, behind) = (nodes[1].blockchain(), nodes[2].blockchain());
+
+        // Both share the first block, then only b keeps mining
+        ahead.add_transaction(transfer(&signer, 1.0).await).await.unwrap();
+        let first = ahead.mine_block().await.unwrap();
+        behind.add_block(first).await.unwrap();
+        for _ in 0..4 {
+            ahead.add_transaction(transfer(&signer, 1.0).await).await.unwrap();
+            ahead.mine_block().await.unwrap();
+        }
+        assert_eq!((ahead.height().await, behind.height().await), (5, 1));
+
+        SyncManager::new(nodes[2].clone()).sync_with("b").await.unwrap();
+        assert_eq!(behind.height().await, 5);
+        assert_eq!(behind.latest_block().await.hash, ahead.latest_block().await.hash);
+        assert_eq!(
+            behind.read().await.balances[&signer.address()],
+            ahead.read().await.balances[&signer.address()],
+        );
+    }
+
+    async fn run_lossy(seed: u64) -> (SimulationStats, usize) {
+        let simulation = SimulatedNetwork::new(seed);
+        simulation.set_loss_rate(0.5);
+        let nodes = nodes(&simulation, &["a", "b"]).await;
+        let mut at_b = nodes[1].subscribe();
+        for _ in 0..50 {
+            nodes[0].send_to("b", NetworkMessage::Headers(vec![])).await.unwrap();
+        }
+        let arrived = received(&mut at_b).await.len();
+        (simulation.stats(), arrived)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loss_is_reproducible_for_a_seed() {
+        let (stats, arrived) = run_lossy(7).await;
+        assert_eq!(stats.sent, 50);
+        assert!(stats.lost > 0 && stats.delivered > 0);
+        assert_eq!(stats.sent, stats.delivered + stats.lost);
+        assert_eq!(arrived as u64, stats.delivered);
+
+        let (again, arrived_again) = run_lossy(7).await;
+        assert_eq!((again.delivered, again.lost), (stats.delivered, stats.lost));
+        assert_eq!(arrived_again, arrived);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn messages_arrive_after_the_link_latency() {
+        let simulation = SimulatedNetwork::new(3);
+        simulation.set_latency(Duration::from_millis(200), Duration::from_millis(50));
+        let nodes = nodes(&simulation, &["a", "b"]).await;
+        let mut at_b = nodes[1].subscribe();
+
+        let sent = Instant::now();
+        nodes[0].send_to("b", NetworkMessage::Headers(vec![])).await.unwrap();
+        let (from, _) = at_b.recv().await.unwrap();
+        let elapsed = sent.elapsed();
+        assert_eq!(from, "a");
+        assert!(elapsed >= Duration::from_millis(200) && elapsed <= Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,1156 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use chrono::Utc;
+use rusqlite::{params, Connection, DatabaseName, OpenFlags, OptionalExtension, Row};
+
+use crate::blockchain::{Block, Transaction};
+use crate::governance::Proposal;
+use crate::market::{Order, OrderStatus, Token, Trade};
+use crate::migrations::{self, AppliedMigration, Migration, SQLITE_MIGRATIONS};
+use crate::network::{KnownAddress, PeerBan};
+use crate::security::ColumnKeys;
+use crate::storage::{
+    balance_deltas, block_heights, check_id, check_signature, hash_from_column, hash_to_column, join_lock_time,
+    split_lock_time, BlockPage, ChainState, MemoMatch, PageRequest, ProposalMatch, Storage, TradePage, TransactionPage,
+};
+use crate::wallet::{AddressBook, Metadata, ReceiveAddress, Wallet};
+use crate::webhook::Webhook;
+
+const TRANSACTION_COLUMNS: &str = "id, version, from_address, to_address, amount, fee, timestamp, lock_height, \
+    lock_timestamp, kind, signature, public_key, key_scheme, multisig";
+const BLOCK_COLUMNS: &str = "hash, version, previous_hash, timestamp, poh_hash, poh_count";
+const ORDER_COLUMNS: &str = "id, user_id, token_symbol, order_type, amount, price, filled, status, timestamp";
+const TRADE_COLUMNS: &str = "id, token_symbol, buy_order_id, sell_order_id, buyer, seller, amount, price, timestamp";
+
+// Single-file embedded storage for development and small nodes
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    // Backups read through a connection of their own
+    path: PathBuf,
+    column_keys: ColumnKeys,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path, column_keys: ColumnKeys) -> Result<Self, Box<dyn Error>> {
+        let conn = Connection::open(path)?;
+        // WAL lets readers carry on while a block is being written
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        Ok(SqliteStorage { conn: Mutex::new(conn), path: path.to_path_buf(), column_keys })
+    }
+
+    fn schema_versions(conn: &Connection) -> Result<Vec<u32>, Box<dyn Error>> {
+        let mut statement = conn.prepare("SELECT version FROM schema_version ORDER BY version")?;
+        let versions = statement.query_map([], |row| row.get(0))?.collect::<rusqlite::Result<_>>()?;
+        Ok(versions)
+    }
+
+    fn transaction_from_row(row: &Row) -> rusqlite::Result<Transaction> {
+        let kind: Option<String> = row.get("kind")?;
+        let multisig: Option<String> = row.get("multisig")?;
+        Ok(Transaction {
+            id: row.get("id")?,
+            version: row.get("version")?,
+            from: row.get("from_address")?,
+            to: row.get("to_address")?,
+            amount: row.get("amount")?,
+            fee: row.get("fee")?,
+            timestamp: row.get("timestamp")?,
+            lock_time: join_lock_time(row.get("lock_height")?, row.get("lock_timestamp")?),
+            // Rows written before the kind column existed are plain transfers
+            kind: match kind {
+                Some(kind) => serde_json::from_str(&kind).map_err(|e| conversion_failure(row, "kind", e))?,
+                None => Default::default(),
+            },
+            signature: row.get("signature")?,
+            public_key: row.get::<_, Option<Vec<u8>>>("public_key")?.unwrap_or_default(),
+            key_scheme: parse_column(row, "key_scheme", str::parse)?,
+            multisig: multisig
+                .map(|witness| serde_json::from_str(&witness))
+                .transpose()
+                .map_err(|e| conversion_failure(row, "multisig", e))?,
+        })
+    }
+
+    fn order_from_row(row: &Row) -> rusqlite::Result<Order> {
+        Ok(Order {
+            id: row.get("id")?,
+            user_id: row.get("user_id")?,
+            token_symbol: row.get("token_symbol")?,
+            order_type: parse_column(row, "order_type", str::parse)?,
+            amount: row.get("amount")?,
+            price: row.get("price")?,
+            timestamp: row.get("timestamp")?,
+            status: parse_column(row, "status", str::parse)?,
+            filled: row.get("filled")?,
+        })
+    }
+
+    fn proposal_match_from_row(row: &Row) -> rusqlite::Result<ProposalMatch> {
+        Ok(ProposalMatch {
+            id: row.get("id")?,
+            title: row.get("title")?,
+            description: row.get("description")?,
+            creator: row.get("creator")?,
+            status: row.get("status")?,
+            budget_amount: row.get("budget_amount")?,
+            created_at: row.get("created_at")?,
+            voting_end: row.get("voting_end")?,
+        })
+    }
+
+    // A block header; its transactions are left empty
+    fn block_from_row(row: &Row) -> rusqlite::Result<Block> {
+        Ok(Block {
+            version: row.get("version")?,
+            hash: hash_column(row, "hash")?,
+            previous_hash: hash_column(row, "previous_hash")?,
+            timestamp: row.get("timestamp")?,
+            transactions: vec![],
+            poh_hash: hash_column(row, "poh_hash")?,
+            poh_count: row.get("poh_count")?,
+        })
+    }
+
+    fn trade_from_row(row: &Row) -> rusqlite::Result<Trade> {
+        Ok(Trade {
+            id: row.get("id")?,
+            token_symbol: row.get("token_symbol")?,
+            buy_order_id: row.get("buy_order_id")?,
+            sell_order_id: row.get("sell_order_id")?,
+            buyer: row.get("buyer")?,
+            seller: row.get("seller")?,
+            amount: row.get("amount")?,
+            price: row.get("price")?,
+            timestamp: row.get("timestamp")?,
+        })
+    }
+}
+
+// An FTS5 query requiring every word of `query`. Each word is quoted so
+// user input is never read as query syntax.
+fn fts_query(query: &str) -> String {
+    query.split_whitespace()
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Read a 32-byte hash column back as hex inside a row mapper
+fn hash_column(row: &Row, column: &str) -> rusqlite::Result<String> {
+    let bytes: Vec<u8> = row.get(column)?;
+    hash_from_column(&bytes).map_err(|e| conversion_failure(row, column, e.to_string()))
+}
+
+// Decode a text column holding JSON or an enum name inside a row mapper
+fn parse_column<T, E>(row: &Row, column: &str, parse: impl FnOnce(&str) -> Result<T, E>) -> rusqlite::Result<T>
+where
+    E: Into<Box<dyn Error + Send + Sync>>,
+{
+    let text: String = row.get(column)?;
+    parse(&text).map_err(|e| conversion_failure(row, column, e))
+}
+
+fn conversion_failure(row: &Row, column: &str, e: impl Into<Box<dyn Error + Send + Sync>>) -> rusqlite::Error {
+    let index = row.as_ref().column_index(column).unwrap_or_default();
+    rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, e.into())
+}
+
+// Add each delta, times `sign`, to the address's stored balance
+fn adjust_balances(tx: &rusqlite::Transaction, deltas: BTreeMap<String, f64>, sign: f64) -> rusqlite::Result<()> {
+    let mut statement = tx.prepare(
+        r"INSERT INTO address_balances (address, balance) VALUES (?1, ?2)
+          ON CONFLICT (address) DO UPDATE SET balance = balance + excluded.balance",
+    )?;
+    for (address, delta) in deltas {
+        statement.execute(params![address, delta * sign])?;
+    }
+    Ok(())
+}
+
+fn read_chain_state(conn: &Connection) -> Result<ChainState, Box<dyn Error>> {
+    let entries = conn
+        .prepare("SELECT name, value FROM chain_metadata")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    ChainState::from_entries(entries)
+}
+
+fn write_chain_state(tx: &rusqlite::Transaction, state: &ChainState) -> rusqlite::Result<()> {
+    for (name, value) in state.entries() {
+        match value {
+            Some(value) => tx.execute(
+                r"INSERT INTO chain_metadata (name, value) VALUES (?1, ?2)
+                  ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+                params![name, value],
+            )?,
+            None => tx.execute("DELETE FROM chain_metadata WHERE name = ?1", params![name])?,
+        };
+    }
+    Ok(())
+}
+
+// Store an order's fill and status, refusing to change one that's final
+fn update_order_status(tx: &rusqlite::Transaction, order: &Order) -> Result<(), Box<dyn Error>> {
+    let updated = tx.execute(
+        "UPDATE orders SET filled = ?1, status = ?2 WHERE id = ?3 AND status = ?4",
+        params![order.filled, order.status.as_str(), order.id, OrderStatus::Pending.as_str()],
+    )?;
+    if updated == 0 {
+        return Err(format!("Order {} is not pending", order.id).into());
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn migrations(&self) -> &'static [Migration] {
+        SQLITE_MIGRATIONS
+    }
+
+    fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute_batch(
+            r"CREATE TABLE IF NOT EXISTS schema_version (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                checksum TEXT NOT NULL,
+                applied_at TEXT NOT NULL
+            )"
+        )?;
+
+        let mut statement = conn.prepare(
+            r"SELECT version, name, checksum, applied_at FROM schema_version ORDER BY version",
+        )?;
+        let applied = statement
+            .query_map([], |row| {
+                Ok(AppliedMigration {
+                    version: row.get(0)?,
+                    name: row.get(1)?,
+                    checksum: row.get(2)?,
+                    applied_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(applied)
+    }
+
+    // SQLite DDL is transactional, so a failed migration leaves no trace
+    fn apply_migration(&self, migration: &Migration) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        tx.execute_batch(migration.script)?;
+        let record = AppliedMigration::new(migration);
+        tx.execute(
+            r"INSERT INTO schema_version (version, name, checksum, applied_at) VALUES (?1, ?2, ?3, ?4)",
+            params![record.version, record.name, record.checksum, record.applied_at],
+        )?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn save_wallet(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            r"INSERT INTO wallets (id, email, address, public_key, key_scheme, encrypted_key, pin_hash, hardware_id, portable, balance, created_at)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            params![
+                check_id(&wallet.id)?,
+                wallet.email,
+                wallet.address,
+                wallet.public_key,
+                wallet.key_scheme.as_str(),
+                self.column_keys.encrypt(&serde_json::to_string(&wallet.encrypted_key)?)?,
+                wallet.pin_hash,
+                wallet.hardware_id,
+                wallet.portable,
+                wallet.balance,
+                wallet.created_at,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn get_wallet_emails(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let emails = conn
+            .prepare("SELECT email FROM wallets ORDER BY created_at, id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(emails)
+    }
+
+    fn get_wallet(&self, email: &str) -> Result<Option<Wallet>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let row = conn.query_row(
+            r"SELECT id, email, address, public_key, key_scheme, encrypted_key, pin_hash, hardware_id, portable, balance,
+                     created_at, label, category, notes
+              FROM wallets WHERE email = ?1",
+            params![email],
+            |row| {
+                Ok(Wallet {
+                    id: row.get("id")?,
+                    email: row.get("email")?,
+                    address: row.get("address")?,
+                    public_key: row.get("public_key")?,
+                    key_scheme: parse_column(row, "key_scheme", str::parse)?,
+                    encrypted_key: parse_column(row, "encrypted_key", |text| {
+                        let json = self.column_keys.decrypt(text).map_err(|e| e.to_string())?;
+                        serde_json::from_str(&json).map_err(|e| e.to_string())
+                    })?,
+                    pin_hash: row.get("pin_hash")?,
+                    hardware_id: row.get("hardware_id")?,
+                    portable: row.get("portable")?,
+                    balance: row.get("balance")?,
+                    created_at: row.get("created_at")?,
+                    receive_addresses: vec![],
+                    metadata: Metadata {
+                        label: row.get("label")?,
+                        category: row.get("category")?,
+                        notes: row.get("notes")?,
+                    },
+                    transaction_metadata: HashMap::new(),
+                    session: Default::default(),
+                })
+            },
+        ).optional()?;
+        let mut wallet = match row {
+            Some(wallet) => wallet,
+            None => return Ok(None),
+        };
+
+        let mut statement = conn.prepare(
+            r"SELECT address_index, address, public_key, created_at
+              FROM wallet_addresses WHERE wallet_id = ?1 ORDER BY address_index",
+        )?;
+        wallet.receive_addresses = statement
+            .query_map(params![wallet.id], |row| {
+                Ok(ReceiveAddress {
+                    index: row.get(0)?,
+                    address: row.get(1)?,
+                    public_key: row.get(2)?,
+                    created_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut statement = conn.prepare(
+            r"SELECT transaction_id, label, category, notes
+              FROM transaction_metadata WHERE wallet_id = ?1",
+        )?;
+        wallet.transaction_metadata = statement
+            .query_map(params![wallet.id], |row| {
+                Ok((row.get(0)?, Metadata { label: row.get(1)?, category: row.get(2)?, notes: row.get(3)? }))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(Some(wallet))
+    }
+
+    fn reencrypt_wallets(&self, limit: usize) -> Result<usize, Box<dyn Error>> {
+        let prefix = match self.column_keys.active_prefix() {
+            Some(prefix) => prefix,
+            None => return Ok(0),
+        };
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let rows: Vec<(String, String)> = {
+            let mut statement = tx.prepare(
+                r"SELECT id, encrypted_key FROM wallets WHERE substr(encrypted_key, 1, ?1) <> ?2 LIMIT ?3",
+            )?;
+            let rows = statement
+                .query_map(params![prefix.len(), prefix, limit], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+            rows
+        };
+        for (id, encrypted_key) in &rows {
+            let resealed = self.column_keys.encrypt(&self.column_keys.decrypt(encrypted_key)?)?;
+            tx.execute(r"UPDATE wallets SET encrypted_key = ?1 WHERE id = ?2", params![resealed, id])?;
+        }
+        tx.commit()?;
+
+        Ok(rows.len())
+    }
+
+    fn save_wallet_metadata(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            r"UPDATE wallets SET label = ?1, category = ?2, notes = ?3 WHERE id = ?4",
+            params![wallet.metadata.label, wallet.metadata.category, wallet.metadata.notes, wallet.id],
+        )?;
+
+        Ok(())
+    }
+
+    fn save_transaction_metadata(
+        &self,
+        wallet_id: &str,
+        transaction_id: &str,
+        metadata: &Metadata,
+    ) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            r"INSERT INTO transaction_metadata (wallet_id, transaction_id, label, category, notes)
+              VALUES (?1, ?2, ?3, ?4, ?5)
+              ON CONFLICT (wallet_id, transaction_id) DO UPDATE
+              SET label = excluded.label, category = excluded.category, notes = excluded.notes",
+            params![wallet_id, transaction_id, metadata.label, metadata.category, metadata.notes],
+        )?;
+
+        Ok(())
+    }
+
+    fn update_cached_balance(&self, wallet_id: &str, balance: f64) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(r"UPDATE wallets SET balance = ?1 WHERE id = ?2", params![balance, wallet_id])?;
+        Ok(())
+    }
+
+    fn update_hardware_binding(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r"UPDATE wallets SET hardware_id = ?1, portable = ?2 WHERE id = ?3",
+            params![wallet.hardware_id, wallet.portable, wallet.id],
+        )?;
+        Ok(())
+    }
+
+    fn save_receive_address(&self, wallet_id: &str, receive_address: &ReceiveAddress) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            r"INSERT INTO wallet_addresses (wallet_id, address_index, address, public_key, created_at)
+              VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                wallet_id,
+                receive_address.index,
+                receive_address.address,
+                receive_address.public_key,
+                receive_address.created_at,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn save_contact(&self, wallet_id: &str, label: &str, address: &str) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r"INSERT INTO address_book (wallet_id, label, address) VALUES (?1, ?2, ?3)",
+            params![wallet_id, label, address],
+        )?;
+        Ok(())
+    }
+
+    fn delete_contact(&self, wallet_id: &str, label: &str) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r"DELETE FROM address_book WHERE wallet_id = ?1 AND label = ?2",
+            params![wallet_id, label],
+        )?;
+        Ok(())
+    }
+
+    fn get_address_book(&self, wallet_id: &str) -> Result<AddressBook, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut statement = conn.prepare(r"SELECT label, address FROM address_book WHERE wallet_id = ?1")?;
+        let contacts = statement
+            .query_map(params![wallet_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(AddressBook {
+            wallet_id: wallet_id.to_string(),
+            contacts,
+        })
+    }
+
+    fn save_peer_addresses(&self, addresses: &[KnownAddress]) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut statement = tx.prepare(
+                r"INSERT INTO peers (address, score, last_seen, last_attempt)
+                  VALUES (?1, ?2, ?3, ?4)
+                  ON CONFLICT (address) DO UPDATE
+                  SET score = excluded.score, last_seen = excluded.last_seen, last_attempt = excluded.last_attempt",
+            )?;
+            for entry in addresses {
+                statement.execute(params![entry.address, entry.score, entry.last_seen, entry.last_attempt])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_peer_addresses(&self) -> Result<Vec<KnownAddress>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut statement = conn.prepare(
+            r"SELECT address, score, last_seen, last_attempt FROM peers
+              WHERE banned_until IS NULL ORDER BY score DESC",
+        )?;
+        let addresses = statement
+            .query_map([], |row| {
+                Ok(KnownAddress {
+                    address: row.get(0)?,
+                    score: row.get(1)?,
+                    last_seen: row.get(2)?,
+                    last_attempt: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(addresses)
+    }
+
+    fn save_peer_bans(&self, bans: &[PeerBan]) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut statement = tx.prepare(
+                r"INSERT INTO peers (address, banned_until) VALUES (?1, ?2)
+                  ON CONFLICT (address) DO UPDATE SET banned_until = excluded.banned_until",
+            )?;
+            for ban in bans {
+                statement.execute(params![ban.address, ban.until])?;
+            }
+        }
+        tx.execute(r"DELETE FROM peers WHERE banned_until < ?1", params![Utc::now()])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_peer_bans(&self) -> Result<Vec<PeerBan>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut statement = conn.prepare(r"SELECT address, banned_until FROM peers WHERE banned_until >= ?1")?;
+        let bans = statement
+            .query_map(params![Utc::now()], |row| {
+                Ok(PeerBan {
+                    address: row.get(0)?,
+                    until: row.get(1)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(bans)
+    }
+
+    fn save_block(&self, block: &Block) -> Result<(), Box<dyn Error>> {
+        self.save_blocks(std::slice::from_ref(block))
+    }
+
+    fn save_blocks(&self, blocks: &[Block]) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        // The blocks and their transactions land together or not at all,
+        // and a single commit is what makes bulk imports fast
+        let tx = conn.transaction()?;
+
+        {
+            let heights = block_heights(blocks, |hash| {
+                Ok(tx.query_row("SELECT height FROM blocks WHERE hash = ?1", [hash_to_column(hash)?], |row| row.get(0))
+                    .optional()?)
+            })?;
+            let mut block_statement = tx.prepare(
+                r"INSERT INTO blocks (hash, version, previous_hash, timestamp, poh_hash, poh_count, height)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            let mut transaction_statement = tx.prepare(
+                r"INSERT INTO transactions (id, version, block_hash, from_address, to_address, amount, fee, timestamp,
+                                            lock_height, lock_timestamp, kind, signature, public_key, key_scheme,
+                                            multisig, position)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            )?;
+            for (block, height) in blocks.iter().zip(heights) {
+                let hash = hash_to_column(&block.hash)?;
+                block_statement.execute(params![
+                    hash,
+                    block.version,
+                    hash_to_column(&block.previous_hash)?,
+                    block.timestamp,
+                    hash_to_column(&block.poh_hash)?,
+                    block.poh_count,
+                    height,
+                ])?;
+                for (position, transaction) in block.transactions.iter().enumerate() {
+                    let (lock_height, lock_timestamp) = split_lock_time(&transaction.lock_time);
+                    transaction_statement.execute(params![
+                        check_id(&transaction.id)?,
+                        transaction.version,
+                        hash,
+                        transaction.from,
+                        transaction.to,
+                        transaction.amount,
+                        transaction.fee,
+                        transaction.timestamp,
+                        lock_height,
+                        lock_timestamp,
+                        serde_json::to_string(&transaction.kind)?,
+                        check_signature(&transaction.signature)?,
+                        transaction.public_key,
+                        transaction.key_scheme.as_str(),
+                        transaction.multisig.as_ref().map(serde_json::to_string).transpose()?,
+                        position as u32,
+                    ])?;
+                }
+            }
+        }
+
+        let transactions: Vec<Transaction> = blocks.iter()
+            .flat_map(|block| block.transactions.iter().cloned())
+            .collect();
+        adjust_balances(&tx, balance_deltas(&transactions), 1.0)?;
+        let mut state = read_chain_state(&tx)?;
+        state.advance(blocks);
+        write_chain_state(&tx, &state)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn revert_block(&self, hash: &str) -> Result<(), Box<dyn Error>> {
+        let column = hash_to_column(hash)?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let children: u64 = tx.query_row("SELECT COUNT(*) FROM blocks WHERE previous_hash = ?1", [&column], |row| row.get(0))?;
+        if children > 0 {
+            return Err(format!("Block {} has descendants; revert them first", hash).into());
+        }
+        let previous_hash = tx
+            .query_row("SELECT previous_hash FROM blocks WHERE hash = ?1", [&column], |row| hash_column(row, "previous_hash"))
+            .optional()?
+            .ok_or_else(|| format!("Unknown block {}", hash))?;
+        let transactions = tx
+            .prepare(&format!("SELECT {} FROM transactions WHERE block_hash = ?1", TRANSACTION_COLUMNS))?
+            .query_map([&column], Self::transaction_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        tx.execute("DELETE FROM transactions WHERE block_hash = ?1", [&column])?;
+        tx.execute("DELETE FROM blocks WHERE hash = ?1", [&column])?;
+        adjust_balances(&tx, balance_deltas(&transactions), -1.0)?;
+        let mut state = read_chain_state(&tx)?;
+        state.rewind(hash, &previous_hash);
+        write_chain_state(&tx, &state)?;
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_all_transactions(&self) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let transactions = conn
+            .prepare(&format!("SELECT {} FROM transactions ORDER BY timestamp, id", TRANSACTION_COLUMNS))?
+            .query_map([], Self::transaction_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(transactions)
+    }
+
+    fn get_all_blocks(&self) -> Result<Vec<Block>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let mut transactions: HashMap<String, Vec<Transaction>> = HashMap::new();
+        let mut statement = conn.prepare(&format!(
+            "SELECT block_hash, {} FROM transactions WHERE block_hash IS NOT NULL
+             ORDER BY block_hash, position, timestamp, id",
+            TRANSACTION_COLUMNS
+        ))?;
+        let rows = statement.query_map([], |row| Ok((hash_column(row, "block_hash")?, Self::transaction_from_row(row)?)))?;
+        for row in rows {
+            let (block_hash, transaction) = row?;
+            transactions.entry(block_hash).or_default().push(transaction);
+        }
+
+        let mut blocks = conn
+            .prepare(&format!("SELECT {} FROM blocks ORDER BY height, hash", BLOCK_COLUMNS))?
+            .query_map([], Self::block_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for block in &mut blocks {
+            block.transactions = transactions.remove(&block.hash).unwrap_or_default();
+        }
+        Ok(blocks)
+    }
+
+    fn get_blocks(&self, page: &PageRequest) -> Result<BlockPage, Box<dyn Error>> {
+        let limit = page.limit();
+        let cursor = page.block_cursor()?;
+        let conn = self.conn.lock().unwrap();
+
+        // A range scan on the blocks_by_height index
+        let fetch = (limit + 1) as i64;
+        let with_height = |row: &Row| Ok((row.get("height")?, Self::block_from_row(row)?));
+        let mut blocks = match &cursor {
+            Some(cursor) => conn
+                .prepare(&format!(
+                    "SELECT height, {} FROM blocks WHERE height > ?1 OR (height = ?1 AND hash > ?2)
+                     ORDER BY height, hash LIMIT ?3",
+                    BLOCK_COLUMNS
+                ))?
+                .query_map(params![cursor.height, hash_to_column(&cursor.hash)?, fetch], with_height)?
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+            None => conn
+                .prepare(&format!("SELECT height, {} FROM blocks ORDER BY height, hash LIMIT ?1", BLOCK_COLUMNS))?
+                .query_map(params![fetch], with_height)?
+                .collect::<rusqlite::Result<Vec<_>>>()?,
+        };
+        if blocks.is_empty() {
+            return Ok(BlockPage::from_rows(blocks, limit));
+        }
+
+        let hashes = blocks.iter().map(|(_, block)| hash_to_column(&block.hash)).collect::<Result<Vec<_>, _>>()?;
+        let mut statement = conn.prepare(&format!(
+            "SELECT block_hash, {} FROM transactions WHERE block_hash IN ({})
+             ORDER BY block_hash, position, timestamp, id",
+            TRANSACTION_COLUMNS,
+            vec!["?"; hashes.len()].join(", ")
+        ))?;
+        let rows = statement.query_map(rusqlite::params_from_iter(hashes), |row| {
+            Ok((hash_column(row, "block_hash")?, Self::transaction_from_row(row)?))
+        })?;
+        let mut transactions: HashMap<String, Vec<Transaction>> = HashMap::new();
+        for row in rows {
+            let (block_hash, transaction) = row?;
+            transactions.entry(block_hash).or_default().push(transaction);
+        }
+        for (_, block) in &mut blocks {
+            block.transactions = transactions.remove(&block.hash).unwrap_or_default();
+        }
+
+        Ok(BlockPage::from_rows(blocks, limit))
+    }
+
+    fn get_address_balance(&self, address: &str) -> Result<f64, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let balance = conn.query_row("SELECT balance FROM address_balances WHERE address = ?1", [address], |row| row.get(0))
+            .optional()?;
+        Ok(balance.unwrap_or(0.0))
+    }
+
+    fn get_address_balances(&self) -> Result<HashMap<String, f64>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let balances = conn
+            .prepare("SELECT address, balance FROM address_balances")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        Ok(balances)
+    }
+
+    fn replace_address_balances(&self, balances: &HashMap<String, f64>) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        tx.execute("DELETE FROM address_balances", [])?;
+        {
+            let mut statement = tx.prepare("INSERT INTO address_balances (address, balance) VALUES (?1, ?2)")?;
+            for (address, balance) in balances {
+                statement.execute(params![address, balance])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_transactions_for(&self, addresses: &[String]) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        if addresses.is_empty() {
+            return Ok(vec![]);
+        }
+        let conn = self.conn.lock().unwrap();
+
+        // Batch recipients live in the serialized kind, so batches are filtered below
+        let placeholders = vec!["?"; addresses.len()].join(", ");
+        let query = format!(
+            r"SELECT {}
+              FROM transactions
+              WHERE from_address IN ({1}) OR to_address IN ({1}) OR to_address = ?
+              ORDER BY timestamp",
+            TRANSACTION_COLUMNS, placeholders
+        );
+        let mut values: Vec<&str> = addresses.iter().chain(addresses.iter()).map(String::as_str).collect();
+        values.push(crate::blockchain::BATCH_ADDRESS);
+
+        let mut statement = conn.prepare(&query)?;
+        let transactions = statement
+            .query_map(rusqlite::params_from_iter(values), Self::transaction_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(transactions.into_iter()
+            .filter(|transaction| addresses.iter().any(|address| transaction.involves(address)))
+            .collect())
+    }
+
+    fn get_transactions_for_address(&self, address: &str, page: &PageRequest) -> Result<TransactionPage, Box<dyn Error>> {
+        let limit = page.limit();
+        let cursor = page.cursor()?;
+        let conn = self.conn.lock().unwrap();
+
+        // Each branch is a range scan on one address index
+        let after = if cursor.is_some() {
+            "AND (timestamp < ?3 OR (timestamp = ?3 AND id < ?4))"
+        } else {
+            ""
+        };
+        let query = format!(
+            r"SELECT * FROM (
+                SELECT * FROM (SELECT {0} FROM transactions WHERE from_address = ?1 {1}
+                               ORDER BY timestamp DESC, id DESC LIMIT ?2)
+                UNION
+                SELECT * FROM (SELECT {0} FROM transactions WHERE to_address = ?1 {1}
+                               ORDER BY timestamp DESC, id DESC LIMIT ?2)
+              )
+              ORDER BY timestamp DESC, id DESC LIMIT ?2",
+            TRANSACTION_COLUMNS, after
+        );
+
+        let mut statement = conn.prepare(&query)?;
+        let fetch = (limit + 1) as i64;
+        let rows = match &cursor {
+            Some(cursor) => statement.query_map(params![address, fetch, cursor.timestamp, cursor.id], Self::transaction_from_row)?,
+            None => statement.query_map(params![address, fetch], Self::transaction_from_row)?,
+        };
+        let transactions = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(TransactionPage::from_rows(transactions, limit))
+    }
+
+    fn get_latest_block(&self) -> Result<Option<Block>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        // Transactions are loaded separately
+        let block = conn.query_row(
+            &format!(
+                "SELECT {} FROM blocks WHERE hash = unhex((SELECT value FROM chain_metadata WHERE name = 'best_hash'))",
+                BLOCK_COLUMNS
+            ),
+            [],
+            Self::block_from_row,
+        ).optional()?;
+
+        Ok(block)
+    }
+
+    fn get_chain_state(&self) -> Result<ChainState, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        read_chain_state(&conn)
+    }
+
+    fn set_last_snapshot_height(&self, height: u64) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            r"INSERT INTO chain_metadata (name, value) VALUES ('last_snapshot_height', ?1)
+              ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+            params![height.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn save_token(&self, token: &Token) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            r"INSERT INTO tokens (symbol, name, total_supply, current_price, last_updated)
+              VALUES (?1, ?2, ?3, ?4, ?5)
+              ON CONFLICT (symbol) DO UPDATE SET name = excluded.name, total_supply = excluded.total_supply,
+                  current_price = excluded.current_price, last_updated = excluded.last_updated",
+            params![token.symbol, token.name, token.total_supply, token.current_price, token.last_updated],
+        )?;
+        Ok(())
+    }
+
+    fn get_tokens(&self) -> Result<Vec<Token>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let tokens = conn
+            .prepare("SELECT symbol, name, total_supply, current_price, last_updated FROM tokens ORDER BY symbol")?
+            .query_map([], |row| {
+                Ok(Token {
+                    symbol: row.get(0)?,
+                    name: row.get(1)?,
+                    total_supply: row.get(2)?,
+                    current_price: row.get(3)?,
+                    last_updated: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(tokens)
+    }
+
+    fn save_order(&self, order: &Order) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            r"INSERT INTO orders (id, user_id, token_symbol, order_type, amount, price, filled, status, timestamp)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                check_id(&order.id)?,
+                order.user_id,
+                order.token_symbol,
+                order.order_type.as_str(),
+                order.amount,
+                order.price,
+                order.filled,
+                order.status.as_str(),
+                order.timestamp,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_order(&self, order: &Order) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        update_order_status(&tx, order)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_open_orders(&self, user_id: Option<&str>) -> Result<Vec<Order>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        let query = format!(
+            "SELECT {} FROM orders WHERE status = ?1 AND (?2 IS NULL OR user_id = ?2) ORDER BY timestamp, id",
+            ORDER_COLUMNS
+        );
+        let orders = conn
+            .prepare(&query)?
+            .query_map(params![OrderStatus::Pending.as_str(), user_id], Self::order_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(orders)
+    }
+
+    fn get_orders(&self) -> Result<Vec<Order>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let orders = conn
+            .prepare(&format!("SELECT {} FROM orders ORDER BY timestamp, id", ORDER_COLUMNS))?
+            .query_map([], Self::order_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(orders)
+    }
+
+    fn save_trades(&self, trades: &[Trade], orders: &[Order]) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for order in orders {
+            update_order_status(&tx, order)?;
+        }
+        {
+            let mut statement = tx.prepare(
+                r"INSERT INTO trades (id, token_symbol, buy_order_id, sell_order_id, buyer, seller, amount, price, timestamp)
+                  VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )?;
+            for trade in trades {
+                statement.execute(params![
+                    trade.id,
+                    trade.token_symbol,
+                    trade.buy_order_id,
+                    trade.sell_order_id,
+                    trade.buyer,
+                    trade.seller,
+                    trade.amount,
+                    trade.price,
+                    trade.timestamp,
+                ])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_trades(&self, token_symbol: &str, page: &PageRequest) -> Result<TradePage, Box<dyn Error>> {
+        let limit = page.limit();
+        let cursor = page.cursor()?;
+        let conn = self.conn.lock().unwrap();
+
+        let after = if cursor.is_some() {
+            "AND (timestamp < ?3 OR (timestamp = ?3 AND id < ?4))"
+        } else {
+            ""
+        };
+        let query = format!(
+            "SELECT {} FROM trades WHERE token_symbol = ?1 {} ORDER BY timestamp DESC, id DESC LIMIT ?2",
+            TRADE_COLUMNS, after
+        );
+
+        let mut statement = conn.prepare(&query)?;
+        let fetch = (limit + 1) as i64;
+        let rows = match &cursor {
+            Some(cursor) => statement.query_map(params![token_symbol, fetch, cursor.timestamp, cursor.id], Self::trade_from_row)?,
+            None => statement.query_map(params![token_symbol, fetch], Self::trade_from_row)?,
+        };
+        let trades = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(TradePage::from_rows(trades, limit))
+    }
+
+    fn get_user_trades(&self, user_id: &str) -> Result<Vec<Trade>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let query = format!("SELECT {} FROM trades WHERE buyer = ?1 OR seller = ?1 ORDER BY timestamp, id", TRADE_COLUMNS);
+        let mut statement = conn.prepare(&query)?;
+        let trades = statement.query_map(params![user_id], Self::trade_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(trades)
+    }
+
+    fn save_proposal(&self, proposal: &Proposal) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            r"INSERT INTO proposals (id, title, description, creator, status, budget_amount, created_at, voting_end)
+              VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+              ON CONFLICT (id) DO UPDATE SET title = excluded.title, description = excluded.description,
+                  status = excluded.status, budget_amount = excluded.budget_amount, voting_end = excluded.voting_end",
+            params![
+                check_id(&proposal.id)?,
+                proposal.title,
+                proposal.description,
+                proposal.creator,
+                proposal.status.as_str(),
+                proposal.budget_amount,
+                proposal.created_at,
+                proposal.voting_end,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_proposals(&self) -> Result<Vec<Proposal>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let proposals = conn
+            .prepare(
+                r"SELECT id, title, description, creator, status, budget_amount, created_at, voting_end
+                  FROM proposals ORDER BY created_at, id",
+            )?
+            .query_map([], Self::proposal_match_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        proposals.into_iter().map(ProposalMatch::into_proposal).collect()
+    }
+
+    fn search_proposals(&self, query: &str, limit: usize) -> Result<Vec<ProposalMatch>, Box<dyn Error>> {
+        let terms = fts_query(query);
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        let conn = self.conn.lock().unwrap();
+
+        let mut statement = conn.prepare(
+            r"SELECT proposals.id, proposals.title, proposals.description, proposals.creator, proposals.status,
+                  proposals.budget_amount, proposals.created_at, proposals.voting_end
+              FROM proposals_search
+              JOIN proposals ON proposals.rowid = proposals_search.rowid
+              WHERE proposals_search MATCH ?1
+              ORDER BY proposals_search.rank, proposals.created_at DESC
+              LIMIT ?2",
+        )?;
+        let proposals = statement.query_map(params![terms, limit as i64], Self::proposal_match_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(proposals)
+    }
+
+    fn save_webhook(&self, hook: &Webhook) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+
+        conn.execute(
+            r"INSERT INTO webhooks (id, url, events, watch_addresses, secret) VALUES (?1, ?2, ?3, ?4, ?5)
+              ON CONFLICT (id) DO UPDATE SET url = excluded.url, events = excluded.events,
+                  watch_addresses = excluded.watch_addresses, secret = excluded.secret",
+            params![
+                check_id(&hook.id)?,
+                hook.url,
+                serde_json::to_string(&hook.events)?,
+                serde_json::to_string(&hook.watch_addresses)?,
+                self.column_keys.encrypt(&hook.secret)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_webhook(&self, id: &str) -> Result<(), Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(r"DELETE FROM webhooks WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    fn get_webhooks(&self) -> Result<Vec<Webhook>, Box<dyn Error>> {
+        let conn = self.conn.lock().unwrap();
+        let rows = conn
+            .prepare(r"SELECT id, url, events, watch_addresses, secret FROM webhooks ORDER BY id")?
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get::<_, String>(2)?, row.get::<_, String>(3)?, row.get::<_, String>(4)?))
+            })?
+            .collect::<rusqlite::Result<Vec<(String, String, String, String, String)>>>()?;
+        rows.into_iter()
+            .map(|(id, url, events, watch_addresses, secret)| Ok(Webhook {
+                id,
+                url,
+                events: serde_json::from_str(&events)?,
+                watch_addresses: serde_json::from_str(&watch_addresses)?,
+                secret: self.column_keys.decrypt(&secret)?,
+            }))
+            .collect()
+    }
+
+    fn search_memos(&self, address: &str, query: &str, limit: usize) -> Result<Vec<MemoMatch>, Box<dyn Error>> {
+        let terms = fts_query(query);
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        let conn = self.conn.lock().unwrap();
+
+        let mut statement = conn.prepare(
+            r"SELECT metadata.transaction_id, metadata.label, metadata.category, metadata.notes
+              FROM transaction_metadata_search
+              JOIN transaction_metadata metadata ON metadata.rowid = transaction_metadata_search.rowid
+              JOIN wallets ON wallets.id = metadata.wallet_id
+              WHERE transaction_metadata_search MATCH ?1 AND wallets.address = ?2
+              ORDER BY transaction_metadata_search.rank
+              LIMIT ?3",
+        )?;
+        let memos = statement
+            .query_map(params![terms, address, limit as i64], |row| {
+                Ok(MemoMatch {
+                    transaction_id: row.get(0)?,
+                    label: row.get(1)?,
+                    category: row.get(2)?,
+                    notes: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(memos)
+    }
+
+    // VACUUM INTO writes the database as of a single read transaction. It
+    // runs on a read-only connection of its own, so with WAL the node keeps
+    // writing blocks meanwhile.
+    fn backup(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let conn = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        conn.execute("VACUUM INTO ?1", params![path.to_str().ok_or("Backup path is not valid UTF-8")?])?;
+        Ok(())
+    }
+
+    fn restore(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let backup = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)?;
+        let backup_versions = Self::schema_versions(&backup)?;
+        drop(backup);
+
+        let mut conn = self.conn.lock().unwrap();
+        migrations::check_backup_schema(&backup_versions, &Self::schema_versions(&conn)?)?;
+        conn.restore(DatabaseName::Main, path, None::<fn(rusqlite::backup::Progress)>)?;
+        Ok(())
+    }
+}
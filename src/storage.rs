@@ -0,0 +1,995 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+
+use crate::blockchain::{Block, LockTime, Transaction};
+use crate::governance::{Proposal, DEFAULT_REQUIRED_VOTES};
+use crate::market::{Order, Token, Trade};
+use crate::migrations::{AppliedMigration, Migration};
+use crate::network::{KnownAddress, PeerBan};
+use crate::wallet::{AddressBook, Metadata, ReceiveAddress, Wallet};
+use crate::webhook::Webhook;
+
+// Where the node keeps its data. MySQL suits shared deployments; the
+// embedded backends need nothing but a local path.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(tag = "type", content = "path", rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Mysql,
+    Sqlite(PathBuf),
+    // Only available when built with the `rocksdb` feature
+    Rocksdb(PathBuf),
+}
+
+pub const DEFAULT_HISTORY_LIMIT: usize = 50;
+pub const MAX_HISTORY_LIMIT: usize = 500;
+
+// One page of an address's history. `cursor` is the `next_cursor` of the
+// previous page, or None for the newest transactions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PageRequest {
+    #[serde(default)]
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub cursor: Option<String>,
+}
+
+impl PageRequest {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT)
+    }
+
+    pub fn cursor(&self) -> Result<Option<HistoryCursor>, Box<dyn Error>> {
+        self.cursor.as_deref().map(HistoryCursor::decode).transpose()
+    }
+
+    pub fn block_cursor(&self) -> Result<Option<BlockCursor>, Box<dyn Error>> {
+        self.cursor.as_deref().map(BlockCursor::decode).transpose()
+    }
+}
+
+// Position just after the last transaction of a page. Pages are ordered
+// newest first, with the id breaking ties between equal timestamps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryCursor {
+    pub timestamp: DateTime<Utc>,
+    pub id: String,
+}
+
+impl HistoryCursor {
+    pub fn encode(&self) -> String {
+        format!("{}.{}", self.timestamp.timestamp_nanos(), self.id)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, Box<dyn Error>> {
+        let (nanos, id) = cursor.split_once('.').ok_or("Malformed cursor")?;
+        let nanos: i64 = nanos.parse().map_err(|_| "Malformed cursor")?;
+        let timestamp = timestamp_from_nanos(nanos).ok_or("Malformed cursor")?;
+        Ok(HistoryCursor { timestamp, id: id.to_string() })
+    }
+}
+
+// Position just after the last block of a page. Blocks are paged by
+// height, so a parent always comes before its children, with the hash
+// breaking ties between branches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockCursor {
+    pub height: u64,
+    pub hash: String,
+}
+
+impl BlockCursor {
+    pub fn encode(&self) -> String {
+        format!("{}.{}", self.height, self.hash)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, Box<dyn Error>> {
+        let (height, hash) = cursor.split_once('.').ok_or("Malformed cursor")?;
+        let height = height.parse().map_err(|_| "Malformed cursor")?;
+        Ok(BlockCursor { height, hash: hash.to_string() })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    // None on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl TransactionPage {
+    // Backends fetch one row more than `limit` to learn whether another
+    // page follows
+    pub(crate) fn from_rows(mut transactions: Vec<Transaction>, limit: usize) -> Self {
+        let next_cursor = truncate_page(&mut transactions, limit, |last| (last.timestamp, &last.id));
+        TransactionPage { transactions, next_cursor }
+    }
+}
+
+// One page of a token's trades, paged like transaction history
+#[derive(Debug, Clone, Serialize)]
+pub struct TradePage {
+    pub trades: Vec<Trade>,
+    // None on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl TradePage {
+    pub(crate) fn from_rows(mut trades: Vec<Trade>, limit: usize) -> Self {
+        let next_cursor = truncate_page(&mut trades, limit, |last| (last.timestamp, &last.id));
+        TradePage { trades, next_cursor }
+    }
+}
+
+// One page of stored blocks with their transactions, oldest first
+#[derive(Debug, Clone)]
+pub struct BlockPage {
+    pub blocks: Vec<Block>,
+    // None on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl BlockPage {
+    // `rows` pairs each block with its stored height
+    pub(crate) fn from_rows(mut rows: Vec<(u64, Block)>, limit: usize) -> Self {
+        let mut next_cursor = None;
+        if rows.len() > limit {
+            rows.truncate(limit);
+            next_cursor = rows.last().map(|(height, last)| BlockCursor { height: *height, hash: last.hash.clone() }.encode());
+        }
+        BlockPage { blocks: rows.into_iter().map(|(_, block)| block).collect(), next_cursor }
+    }
+}
+
+// Heights to store for `blocks`, which are saved together and list parents
+// before children. Genesis, which names itself as its parent, and blocks
+// whose parent isn't stored are at height 0. `stored` looks up the height
+// of a parent saved earlier.
+pub(crate) fn block_heights(
+    blocks: &[Block],
+    mut stored: impl FnMut(&str) -> Result<Option<u64>, Box<dyn Error>>,
+) -> Result<Vec<u64>, Box<dyn Error>> {
+    let mut batch: HashMap<&str, u64> = HashMap::new();
+    let mut heights = vec![];
+    for block in blocks {
+        let height = if block.previous_hash == block.hash {
+            0
+        } else {
+            match batch.get(block.previous_hash.as_str()) {
+                Some(parent) => parent + 1,
+                None => stored(&block.previous_hash)?.map_or(0, |parent| parent + 1),
+            }
+        };
+        batch.insert(block.hash.as_str(), height);
+        heights.push(height);
+    }
+    Ok(heights)
+}
+
+// Cut `rows` down to `limit` and return the cursor after the last one kept,
+// if any rows were cut
+fn truncate_page<T>(rows: &mut Vec<T>, limit: usize, position: impl Fn(&T) -> (DateTime<Utc>, &String)) -> Option<String> {
+    if rows.len() <= limit {
+        return None;
+    }
+    rows.truncate(limit);
+    rows.last().map(|last| {
+        let (timestamp, id) = position(last);
+        HistoryCursor { timestamp, id: id.clone() }.encode()
+    })
+}
+
+// Persistence for wallets, blocks, transactions and node metadata. Every
+// backend stores the same data; `Database` picks one from its config.
+pub trait Storage: Send + Sync {
+    // The migrations this backend's schema is built from, oldest first
+    fn migrations(&self) -> &'static [Migration];
+    // What the schema_version table records, oldest first
+    fn applied_migrations(&self) -> Result<Vec<AppliedMigration>, Box<dyn Error>>;
+    // Run one migration and record it in schema_version
+    fn apply_migration(&self, migration: &Migration) -> Result<(), Box<dyn Error>>;
+
+    fn save_wallet(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>>;
+    // Every wallet's email, for walking all wallets
+    fn get_wallet_emails(&self) -> Result<Vec<String>, Box<dyn Error>>;
+    // Looked up by email, with receive addresses and transaction metadata
+    fn get_wallet(&self, email: &str) -> Result<Option<Wallet>, Box<dyn Error>>;
+    // Rewrite up to `limit` wallet keystores not yet encrypted with the
+    // active column key and return how many were rewritten. After a key
+    // rotation, call until it returns 0; older keys stay usable meanwhile.
+    fn reencrypt_wallets(&self, limit: usize) -> Result<usize, Box<dyn Error>>;
+    fn save_wallet_metadata(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>>;
+    fn save_transaction_metadata(
+        &self,
+        wallet_id: &str,
+        transaction_id: &str,
+        metadata: &Metadata,
+    ) -> Result<(), Box<dyn Error>>;
+    // The balance column only caches chain state for listing and reporting
+    fn update_cached_balance(&self, wallet_id: &str, balance: f64) -> Result<(), Box<dyn Error>>;
+    fn update_hardware_binding(&self, wallet: &Wallet) -> Result<(), Box<dyn Error>>;
+    fn save_receive_address(&self, wallet_id: &str, receive_address: &ReceiveAddress) -> Result<(), Box<dyn Error>>;
+
+    fn save_contact(&self, wallet_id: &str, label: &str, address: &str) -> Result<(), Box<dyn Error>>;
+    fn delete_contact(&self, wallet_id: &str, label: &str) -> Result<(), Box<dyn Error>>;
+    fn get_address_book(&self, wallet_id: &str) -> Result<AddressBook, Box<dyn Error>>;
+
+    // Inserts new addresses and updates known ones
+    fn save_peer_addresses(&self, addresses: &[KnownAddress]) -> Result<(), Box<dyn Error>>;
+    // Best scored first
+    fn get_peer_addresses(&self) -> Result<Vec<KnownAddress>, Box<dyn Error>>;
+    // Records the given bans and forgets those that have run out
+    fn save_peer_bans(&self, bans: &[PeerBan]) -> Result<(), Box<dyn Error>>;
+    // Bans still in force
+    fn get_peer_bans(&self) -> Result<Vec<PeerBan>, Box<dyn Error>>;
+
+    // The block and all of its transactions
+    fn save_block(&self, block: &Block) -> Result<(), Box<dyn Error>>;
+    // Consecutive blocks, oldest first, stored together with batched
+    // inserts. Either all of them are saved or none are. Used when
+    // importing a long chain, where one write per row is too slow.
+    fn save_blocks(&self, blocks: &[Block]) -> Result<(), Box<dyn Error>>;
+    // Confirmed transactions sending to or from any of `addresses`, oldest first
+    fn get_transactions_for(&self, addresses: &[String]) -> Result<Vec<Transaction>, Box<dyn Error>>;
+    // Confirmed transactions sent from or to `address`, newest first, served
+    // from the address indexes. The SQL backends don't index batch payout
+    // recipients, so there a batch is listed under its sender only.
+    fn get_transactions_for_address(&self, address: &str, page: &PageRequest) -> Result<TransactionPage, Box<dyn Error>>;
+    // Header of the best block; transactions are loaded separately
+    fn get_latest_block(&self) -> Result<Option<Block>, Box<dyn Error>>;
+    fn get_chain_state(&self) -> Result<ChainState, Box<dyn Error>>;
+    fn set_last_snapshot_height(&self, height: u64) -> Result<(), Box<dyn Error>>;
+    // Remove a block that nothing builds on, with its transactions, and
+    // reverse its balance changes. A reorg reverts back to the fork point
+    // one block at a time, newest first.
+    fn revert_block(&self, hash: &str) -> Result<(), Box<dyn Error>>;
+    // Every stored transaction, oldest first, for recomputing balances
+    fn get_all_transactions(&self) -> Result<Vec<Transaction>, Box<dyn Error>>;
+    // Every stored block with its transactions in block order, by height
+    // and then hash, so parents come before their children. Transactions
+    // whose block isn't stored are left out.
+    fn get_all_blocks(&self) -> Result<Vec<Block>, Box<dyn Error>>;
+    // The same blocks in the same order, a cursor page at a time, for
+    // walking a long chain without holding all of it in memory
+    fn get_blocks(&self, page: &PageRequest) -> Result<BlockPage, Box<dyn Error>>;
+
+    // Maintained by save_block and revert_block in the same database
+    // transaction as the block itself
+    fn get_address_balance(&self, address: &str) -> Result<f64, Box<dyn Error>>;
+    fn get_address_balances(&self) -> Result<HashMap<String, f64>, Box<dyn Error>>;
+    // Overwrite every stored balance, to repair the table after a failed check
+    fn replace_address_balances(&self, balances: &HashMap<String, f64>) -> Result<(), Box<dyn Error>>;
+
+    // Inserts new tokens and updates known ones
+    fn save_token(&self, token: &Token) -> Result<(), Box<dyn Error>>;
+    fn get_tokens(&self) -> Result<Vec<Token>, Box<dyn Error>>;
+    fn save_order(&self, order: &Order) -> Result<(), Box<dyn Error>>;
+    // Stores an order's new fill and status. Fails unless the stored order
+    // is still pending, since the other states are final.
+    fn update_order(&self, order: &Order) -> Result<(), Box<dyn Error>>;
+    // Pending orders oldest first, for one user or for everyone
+    fn get_open_orders(&self, user_id: Option<&str>) -> Result<Vec<Order>, Box<dyn Error>>;
+    // Every order whatever its status, oldest first
+    fn get_orders(&self) -> Result<Vec<Order>, Box<dyn Error>>;
+    // New trades and the orders they filled, stored together
+    fn save_trades(&self, trades: &[Trade], orders: &[Order]) -> Result<(), Box<dyn Error>>;
+    // A token's trades, newest first
+    fn get_trades(&self, token_symbol: &str, page: &PageRequest) -> Result<TradePage, Box<dyn Error>>;
+    // Trades the user took either side of, oldest first
+    fn get_user_trades(&self, user_id: &str) -> Result<Vec<Trade>, Box<dyn Error>>;
+
+    // Inserts new proposals and updates known ones
+    fn save_proposal(&self, proposal: &Proposal) -> Result<(), Box<dyn Error>>;
+    // Every saved proposal, oldest first, without votes
+    fn get_proposals(&self) -> Result<Vec<Proposal>, Box<dyn Error>>;
+    // Proposals whose title or description match every word of `query`,
+    // best match first
+    fn search_proposals(&self, query: &str, limit: usize) -> Result<Vec<ProposalMatch>, Box<dyn Error>>;
+    // Annotations the wallet at `address` made on transactions whose label
+    // or notes match every word of `query`, best match first
+    fn search_memos(&self, address: &str, query: &str, limit: usize) -> Result<Vec<MemoMatch>, Box<dyn Error>>;
+
+    // Inserts new webhooks and replaces known ones, secrets included
+    fn save_webhook(&self, hook: &Webhook) -> Result<(), Box<dyn Error>>;
+    fn delete_webhook(&self, id: &str) -> Result<(), Box<dyn Error>>;
+    fn get_webhooks(&self) -> Result<Vec<Webhook>, Box<dyn Error>>;
+
+    // Write a consistent copy of everything stored to `path` while other
+    // callers keep reading and writing
+    fn backup(&self, path: &Path) -> Result<(), Box<dyn Error>>;
+    // Replace everything stored with a backup this backend wrote
+    fn restore(&self, path: &Path) -> Result<(), Box<dyn Error>>;
+}
+
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+pub const MAX_SEARCH_LIMIT: usize = 100;
+
+// A stored proposal found by search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProposalMatch {
+    pub id: String,
+    pub title: String,
+    pub description: String,
+    pub creator: String,
+    pub status: String,
+    pub budget_amount: f64,
+    pub created_at: DateTime<Utc>,
+    pub voting_end: DateTime<Utc>,
+}
+
+impl ProposalMatch {
+    // The proposal as saved. Votes and the vote threshold aren't stored,
+    // so it comes back without votes and with the default threshold.
+    pub(crate) fn into_proposal(self) -> Result<Proposal, Box<dyn Error>> {
+        Ok(Proposal {
+            status: self.status.parse()?,
+            id: self.id,
+            title: self.title,
+            description: self.description,
+            creator: self.creator,
+            created_at: self.created_at,
+            voting_start: self.created_at,
+            voting_end: self.voting_end,
+            votes: HashMap::new(),
+            required_votes: DEFAULT_REQUIRED_VOTES,
+            budget_amount: self.budget_amount,
+        })
+    }
+}
+
+// A transaction annotation found by search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemoMatch {
+    pub transaction_id: String,
+    pub label: Option<String>,
+    pub category: Option<String>,
+    pub notes: Option<String>,
+}
+
+// Lowercased words of a search query, for backends that match without an index
+pub(crate) fn search_terms(query: &str) -> Vec<String> {
+    query.split_whitespace().map(str::to_lowercase).collect()
+}
+
+// Where the stored chain stands, updated with every block saved or
+// reverted so the node resumes exactly where it stopped
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChainState {
+    pub best_hash: Option<String>,
+    pub best_height: Option<u64>,
+    pub genesis_hash: Option<String>,
+    pub last_snapshot_height: Option<u64>,
+}
+
+impl ChainState {
+    // Build from the SQL backends' name/value rows
+    pub(crate) fn from_entries(entries: Vec<(String, String)>) -> Result<Self, Box<dyn Error>> {
+        let mut state = ChainState::default();
+        for (name, value) in entries {
+            match name.as_str() {
+                "best_hash" => state.best_hash = Some(value),
+                "best_height" => state.best_height = Some(value.parse()?),
+                "genesis_hash" => state.genesis_hash = Some(value),
+                "last_snapshot_height" => state.last_snapshot_height = Some(value.parse()?),
+                _ => {}
+            }
+        }
+        Ok(state)
+    }
+
+    // Name/value rows to store; unset values are deleted
+    pub(crate) fn entries(&self) -> [(&'static str, Option<String>); 4] {
+        [
+            ("best_hash", self.best_hash.clone()),
+            ("best_height", self.best_height.map(|height| height.to_string())),
+            ("genesis_hash", self.genesis_hash.clone()),
+            ("last_snapshot_height", self.last_snapshot_height.map(|height| height.to_string())),
+        ]
+    }
+
+    // Account for `blocks` being stored, oldest first. A block extending
+    // the best block becomes the new best; the first block stored is
+    // genesis. Blocks off the best chain leave it unchanged.
+    pub(crate) fn advance(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            match (&self.best_hash, self.best_height) {
+                (None, _) => {
+                    self.genesis_hash = Some(block.hash.clone());
+                    self.best_hash = Some(block.hash.clone());
+                    self.best_height = Some(0);
+                }
+                (Some(best), Some(height)) if *best == block.previous_hash => {
+                    self.best_hash = Some(block.hash.clone());
+                    self.best_height = Some(height + 1);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Account for the block `hash`, child of `previous_hash`, being reverted
+    pub(crate) fn rewind(&mut self, hash: &str, previous_hash: &str) {
+        if self.best_hash.as_deref() != Some(hash) {
+            return;
+        }
+        match self.best_height {
+            Some(height) if height > 0 => {
+                self.best_hash = Some(previous_hash.to_string());
+                self.best_height = Some(height - 1);
+            }
+            _ => {
+                self.best_hash = None;
+                self.best_height = None;
+                self.genesis_hash = None;
+            }
+        }
+    }
+}
+
+// Differences smaller than the 8 decimal places balances are stored with
+const BALANCE_TOLERANCE: f64 = 1e-8;
+
+// Net change transactions make to native balances: senders pay the amount
+// and fee, recipients and batch outputs are credited. Genesis allocations
+// and vesting releases don't come from transactions, so they aren't part
+// of the stored balances. Sorted so backends lock rows in a stable order.
+pub(crate) fn balance_deltas(transactions: &[Transaction]) -> BTreeMap<String, f64> {
+    let mut deltas = BTreeMap::new();
+    for transaction in transactions {
+        *deltas.entry(transaction.from.clone()).or_insert(0.0) -= transaction.native_cost();
+        for (address, amount) in transaction.credits() {
+            *deltas.entry(address.to_string()).or_insert(0.0) += amount;
+        }
+    }
+    deltas.retain(|_, delta| *delta != 0.0);
+    deltas
+}
+
+// An address whose stored balance disagrees with its transactions
+#[derive(Debug, Clone, Serialize)]
+pub struct BalanceMismatch {
+    pub address: String,
+    pub stored: f64,
+    pub recomputed: f64,
+}
+
+// Recompute every balance from the stored transactions and compare it with
+// the balance table. With `repair`, the table is replaced by the
+// recomputed balances when they differ. Returns the mismatches found.
+pub fn check_balances(storage: &dyn Storage, repair: bool) -> Result<Vec<BalanceMismatch>, Box<dyn Error>> {
+    let recomputed: HashMap<String, f64> = balance_deltas(&storage.get_all_transactions()?).into_iter().collect();
+    let stored = storage.get_address_balances()?;
+
+    let mismatches: Vec<BalanceMismatch> = stored.keys().chain(recomputed.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter_map(|address| {
+            let stored = stored.get(address).copied().unwrap_or(0.0);
+            let recomputed = recomputed.get(address).copied().unwrap_or(0.0);
+            ((stored - recomputed).abs() > BALANCE_TOLERANCE)
+                .then(|| BalanceMismatch { address: address.clone(), stored, recomputed })
+        })
+        .collect();
+
+    if repair && !mismatches.is_empty() {
+        storage.replace_address_balances(&recomputed)?;
+    }
+    Ok(mismatches)
+}
+
+// Problems `verify` found in the stored chain
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IntegrityReport {
+    pub blocks: usize,
+    pub transactions: usize,
+    // Height of the latest block, counting back to the genesis block
+    pub height: Option<u64>,
+    // Blocks whose hash doesn't match one recomputed from their contents
+    pub hash_mismatches: Vec<String>,
+    // Blocks other than genesis whose parent isn't stored
+    pub missing_parents: Vec<String>,
+    // Blocks with more than one stored child
+    pub forks: Vec<String>,
+    // Blocks not on the path from the latest block back to genesis, which
+    // leaves a gap in the heights
+    pub unreachable: Vec<String>,
+    // Transactions stored without their block
+    pub orphaned_transactions: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.hash_mismatches.is_empty()
+            && self.missing_parents.is_empty()
+            && self.forks.is_empty()
+            && self.unreachable.is_empty()
+            && self.orphaned_transactions.is_empty()
+    }
+}
+
+// Check every stored block's hash against its contents, that blocks link
+// up into a single chain from the latest block back to genesis, and that
+// every transaction belongs to a stored block
+pub fn verify(storage: &dyn Storage) -> Result<IntegrityReport, Box<dyn Error>> {
+    let blocks = storage.get_all_blocks()?;
+    let transactions = storage.get_all_transactions()?;
+    let mut report = IntegrityReport {
+        blocks: blocks.len(),
+        transactions: transactions.len(),
+        ..Default::default()
+    };
+
+    // The node's genesis block has a fixed hash, not one computed from its
+    // contents, and names itself as its parent
+    let genesis = storage.get_chain_state()?.genesis_hash;
+    let is_genesis = |block: &Block| genesis.as_deref() == Some(block.hash.as_str());
+
+    let by_hash: HashMap<&str, &Block> = blocks.iter().map(|block| (block.hash.as_str(), block)).collect();
+    let mut children: HashMap<&str, usize> = HashMap::new();
+    for block in &blocks {
+        if is_genesis(block) {
+            continue;
+        }
+        if !block.verify_hash() {
+            report.hash_mismatches.push(block.hash.clone());
+        }
+        *children.entry(block.previous_hash.as_str()).or_insert(0) += 1;
+    }
+    report.forks = blocks.iter()
+        .filter(|block| children.get(block.hash.as_str()).copied().unwrap_or(0) > 1)
+        .map(|block| block.hash.clone())
+        .collect();
+
+    // Walk back from the latest block; the block we stop at is genesis
+    let mut chain = BTreeSet::new();
+    if let Some(latest) = storage.get_latest_block()? {
+        let mut current = by_hash.get(latest.hash.as_str()).copied();
+        while let Some(block) = current {
+            if !chain.insert(block.hash.as_str()) {
+                return Err(format!("Block {} is its own ancestor", block.hash).into());
+            }
+            if is_genesis(block) {
+                break;
+            }
+            current = by_hash.get(block.previous_hash.as_str()).copied();
+        }
+        report.height = (!chain.is_empty()).then(|| chain.len() as u64 - 1);
+    }
+    for block in &blocks {
+        if !chain.contains(block.hash.as_str()) {
+            report.unreachable.push(block.hash.clone());
+            if !by_hash.contains_key(block.previous_hash.as_str()) {
+                report.missing_parents.push(block.hash.clone());
+            }
+        }
+    }
+
+    let in_blocks: BTreeSet<&str> = blocks.iter()
+        .flat_map(|block| block.transactions.iter().map(|transaction| transaction.id.as_str()))
+        .collect();
+    report.orphaned_transactions = transactions.iter()
+        .filter(|transaction| !in_blocks.contains(transaction.id.as_str()))
+        .map(|transaction| transaction.id.clone())
+        .collect();
+
+    Ok(report)
+}
+
+// The stored best chain from genesis to the best block, empty when nothing
+// has been stored yet
+pub fn best_chain(storage: &dyn Storage) -> Result<Vec<Block>, Box<dyn Error>> {
+    let state = storage.get_chain_state()?;
+    let (best_hash, best_height, genesis_hash) = match (state.best_hash, state.best_height, state.genesis_hash) {
+        (Some(best_hash), Some(best_height), Some(genesis_hash)) => (best_hash, best_height, genesis_hash),
+        _ => return Ok(vec![]),
+    };
+
+    let mut by_hash: HashMap<String, Block> = storage.get_all_blocks()?.into_iter()
+        .map(|block| (block.hash.clone(), block))
+        .collect();
+    let mut chain = vec![];
+    let mut hash = best_hash;
+    loop {
+        let block = by_hash.remove(&hash).ok_or_else(|| format!("Stored chain is missing block {}", hash))?;
+        hash = block.previous_hash.clone();
+        let done = block.hash == genesis_hash;
+        chain.push(block);
+        if done {
+            break;
+        }
+    }
+    chain.reverse();
+
+    if chain.len() as u64 != best_height + 1 {
+        return Err(format!("Stored best height is {} but the chain has {} blocks", best_height, chain.len()).into());
+    }
+    Ok(chain)
+}
+
+// Blocks and trades written per call while copying between backends
+const COPY_BATCH: usize = 500;
+
+// What `copy` moved, and what differed between the two databases afterwards
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CopyReport {
+    pub wallets: usize,
+    pub peers: usize,
+    pub blocks: usize,
+    pub transactions: usize,
+    pub tokens: usize,
+    pub orders: usize,
+    pub trades: usize,
+    pub proposals: usize,
+    pub webhooks: usize,
+    // Checks that came out differently on the target, empty when it matches
+    pub mismatches: Vec<String>,
+}
+
+// Copy everything stored in `source` into `target`, which must be migrated
+// and empty, then compare the two. `progress` is called with a stage name
+// and how far into it the copy is.
+pub fn copy(
+    source: &dyn Storage,
+    target: &dyn Storage,
+    progress: &mut dyn FnMut(&str, usize, usize),
+) -> Result<CopyReport, Box<dyn Error>> {
+    if target.get_chain_state()?.best_hash.is_some()
+        || !target.get_wallet_emails()?.is_empty()
+        || !target.get_tokens()?.is_empty()
+    {
+        return Err("Target database already holds data".into());
+    }
+    let mut report = CopyReport::default();
+
+    // Wallets are saved bare and their addresses and annotations added
+    // after, as the backends store those separately
+    let emails = source.get_wallet_emails()?;
+    for (done, email) in emails.iter().enumerate() {
+        let mut wallet = source.get_wallet(email)?.ok_or_else(|| format!("Wallet {} disappeared", email))?;
+        let receive_addresses = std::mem::take(&mut wallet.receive_addresses);
+        let transaction_metadata = std::mem::take(&mut wallet.transaction_metadata);
+        let metadata = std::mem::take(&mut wallet.metadata);
+        target.save_wallet(&wallet)?;
+        for receive_address in &receive_addresses {
+            target.save_receive_address(&wallet.id, receive_address)?;
+        }
+        wallet.metadata = metadata;
+        target.save_wallet_metadata(&wallet)?;
+        for (transaction_id, metadata) in &transaction_metadata {
+            target.save_transaction_metadata(&wallet.id, transaction_id, metadata)?;
+        }
+        for (label, address) in &source.get_address_book(&wallet.id)?.contacts {
+            target.save_contact(&wallet.id, label, address)?;
+        }
+        progress("wallets", done + 1, emails.len());
+    }
+    report.wallets = emails.len();
+
+    let peers = source.get_peer_addresses()?;
+    target.save_peer_addresses(&peers)?;
+    target.save_peer_bans(&source.get_peer_bans()?)?;
+    report.peers = peers.len();
+
+    // Balances and the chain state follow from the blocks. Side branches
+    // are copied too, so the best chain's length only estimates the total
+    // until the last page.
+    let expected = source.get_chain_state()?.best_height.map_or(0, |height| height as usize + 1);
+    let mut page = PageRequest { limit: Some(COPY_BATCH), cursor: None };
+    loop {
+        let result = source.get_blocks(&page)?;
+        if result.blocks.is_empty() {
+            break;
+        }
+        target.save_blocks(&result.blocks)?;
+        report.blocks += result.blocks.len();
+        report.transactions += result.blocks.iter().map(|block| block.transactions.len()).sum::<usize>();
+        match result.next_cursor {
+            Some(cursor) => {
+                progress("blocks", report.blocks, expected.max(report.blocks + 1));
+                page.cursor = Some(cursor);
+            }
+            None => {
+                progress("blocks", report.blocks, report.blocks);
+                break;
+            }
+        }
+    }
+    if let Some(height) = source.get_chain_state()?.last_snapshot_height {
+        target.set_last_snapshot_height(height)?;
+    }
+
+    let tokens = source.get_tokens()?;
+    for token in &tokens {
+        target.save_token(token)?;
+    }
+    report.tokens = tokens.len();
+
+    let orders = source.get_orders()?;
+    for (done, order) in orders.iter().enumerate() {
+        target.save_order(order)?;
+        progress("orders", done + 1, orders.len());
+    }
+    report.orders = orders.len();
+
+    // Oldest first, as they were made
+    for (done, token) in tokens.iter().enumerate() {
+        let mut trades = all_trades(source, &token.symbol)?;
+        trades.reverse();
+        for chunk in trades.chunks(COPY_BATCH) {
+            target.save_trades(chunk, &[])?;
+        }
+        report.trades += trades.len();
+        progress("trades", done + 1, tokens.len());
+    }
+
+    let proposals = source.get_proposals()?;
+    for proposal in &proposals {
+        target.save_proposal(proposal)?;
+    }
+    report.proposals = proposals.len();
+
+    let webhooks = source.get_webhooks()?;
+    for hook in &webhooks {
+        target.save_webhook(hook)?;
+    }
+    report.webhooks = webhooks.len();
+
+    report.mismatches = compare(source, target)?;
+    Ok(report)
+}
+
+// A token's trades, newest first, gathered page by page
+fn all_trades(storage: &dyn Storage, token_symbol: &str) -> Result<Vec<Trade>, Box<dyn Error>> {
+    let mut trades = vec![];
+    let mut page = PageRequest { limit: Some(MAX_HISTORY_LIMIT), cursor: None };
+    loop {
+        let result = storage.get_trades(token_symbol, &page)?;
+        trades.extend(result.trades);
+        match result.next_cursor {
+            Some(cursor) => page.cursor = Some(cursor),
+            None => return Ok(trades),
+        }
+    }
+}
+
+// Differences between what two databases report, described for an operator
+fn compare(source: &dyn Storage, target: &dyn Storage) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut mismatches = vec![];
+
+    let (source_state, target_state) = (source.get_chain_state()?, target.get_chain_state()?);
+    if source_state != target_state {
+        mismatches.push(format!("chain state {:?} became {:?}", source_state, target_state));
+    }
+
+    let (source_balances, target_balances) = (source.get_address_balances()?, target.get_address_balances()?);
+    for address in source_balances.keys().chain(target_balances.keys()).collect::<BTreeSet<_>>() {
+        let before = source_balances.get(address).copied().unwrap_or(0.0);
+        let after = target_balances.get(address).copied().unwrap_or(0.0);
+        if (before - after).abs() > BALANCE_TOLERANCE {
+            mismatches.push(format!("balance of {} {:.8} became {:.8}", address, before, after));
+        }
+    }
+
+    let counts = [
+        ("wallets", source.get_wallet_emails()?.len(), target.get_wallet_emails()?.len()),
+        ("orders", source.get_orders()?.len(), target.get_orders()?.len()),
+        ("proposals", source.get_proposals()?.len(), target.get_proposals()?.len()),
+    ];
+    for (name, before, after) in counts {
+        if before != after {
+            mismatches.push(format!("{} {} became {}", before, name, after));
+        }
+    }
+
+    mismatches.extend(compare_blocks(source, target)?);
+
+    for token in source.get_tokens()? {
+        let (before, after) = (all_trades(source, &token.symbol)?.len(), all_trades(target, &token.symbol)?.len());
+        if before != after {
+            mismatches.push(format!("{} {} trades became {}", before, token.symbol, after));
+        }
+    }
+    Ok(mismatches)
+}
+
+// Both databases list their blocks in the same order, so they are walked
+// in step a page at a time. The walk stops at the first block that differs,
+// since every block after it would be reported too.
+fn compare_blocks(source: &dyn Storage, target: &dyn Storage) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut mismatches = vec![];
+    let (mut source_blocks, mut target_blocks) = (StoredBlocks::new(source), StoredBlocks::new(target));
+    loop {
+        let (before, after) = match (source_blocks.next().transpose()?, target_blocks.next().transpose()?) {
+            (None, None) => break,
+            (Some(before), Some(after)) if before.hash == after.hash => (before, after),
+            (Some(before), _) => {
+                mismatches.push(format!("block {} was not copied", before.hash));
+                break;
+            }
+            (None, Some(after)) => {
+                mismatches.push(format!("block {} is only in the target", after.hash));
+                break;
+            }
+        };
+
+        let ids = |block: &Block| block.transactions.iter().map(|transaction| transaction.id.clone()).collect::<Vec<_>>();
+        if ids(&before) != ids(&after) {
+            mismatches.push(format!("transactions of block {} were not copied", before.hash));
+            continue;
+        }
+        // Multisig spends only verify with their co-signer signatures
+        for (original, copied) in before.transactions.iter().zip(&after.transactions) {
+            if serde_json::to_value(&original.multisig)? != serde_json::to_value(&copied.multisig)? {
+                mismatches.push(format!("multisig witness of transaction {} was not copied", original.id));
+            }
+        }
+    }
+    Ok(mismatches)
+}
+
+// Stored blocks, oldest first, fetched a page at a time as they're needed
+struct StoredBlocks<'a> {
+    storage: &'a dyn Storage,
+    page: Option<PageRequest>,
+    buffered: std::vec::IntoIter<Block>,
+}
+
+impl<'a> StoredBlocks<'a> {
+    fn new(storage: &'a dyn Storage) -> Self {
+        StoredBlocks {
+            storage,
+            page: Some(PageRequest { limit: Some(MAX_HISTORY_LIMIT), cursor: None }),
+            buffered: vec![].into_iter(),
+        }
+    }
+}
+
+impl Iterator for StoredBlocks<'_> {
+    type Item = Result<Block, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(block) = self.buffered.next() {
+                return Some(Ok(block));
+            }
+            let mut page = self.page.take()?;
+            match self.storage.get_blocks(&page) {
+                Ok(result) => {
+                    self.buffered = result.blocks.into_iter();
+                    if let Some(cursor) = result.next_cursor {
+                        page.cursor = Some(cursor);
+                        self.page = Some(page);
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// Typed columns of the SQL backends: hashes are stored as their 32 raw
+// bytes, ids as hyphenated UUIDs and signatures as at most 64 bytes,
+// empty while a transaction is unsigned. These convert and check values
+// on the way in and out, so a malformed one fails with its name rather
+// than as a database error.
+pub(crate) const HASH_BYTES: usize = 32;
+pub(crate) const SIGNATURE_BYTES: usize = 64;
+
+pub(crate) fn hash_to_column(hash: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    match hex::decode(hash) {
+        Ok(bytes) if bytes.len() == HASH_BYTES => Ok(bytes),
+        _ => Err(format!("Hash {} is not {} hex-encoded bytes", hash, HASH_BYTES).into()),
+    }
+}
+
+pub(crate) fn hash_from_column(bytes: &[u8]) -> Result<String, Box<dyn Error>> {
+    if bytes.len() != HASH_BYTES {
+        return Err(format!("Stored hash is {} bytes, not {}", bytes.len(), HASH_BYTES).into());
+    }
+    Ok(hex::encode(bytes))
+}
+
+pub(crate) fn check_id(id: &str) -> Result<&str, Box<dyn Error>> {
+    match uuid::Uuid::parse_str(id) {
+        Ok(_) if id.len() == uuid::fmt::Hyphenated::LENGTH => Ok(id),
+        _ => Err(format!("Id {} is not a hyphenated UUID", id).into()),
+    }
+}
+
+pub(crate) fn check_signature(signature: &[u8]) -> Result<&[u8], Box<dyn Error>> {
+    if signature.len() > SIGNATURE_BYTES {
+        return Err(format!("Signature is {} bytes, more than {}", signature.len(), SIGNATURE_BYTES).into());
+    }
+    Ok(signature)
+}
+
+// Lock times are stored as two nullable columns
+// Inverse of `timestamp_nanos`, for backends that store times as integer
+// nanoseconds so hashed timestamps survive a round trip
+pub(crate) fn timestamp_from_nanos(nanos: i64) -> Option<DateTime<Utc>> {
+    let time = chrono::NaiveDateTime::from_timestamp_opt(
+        nanos.div_euclid(1_000_000_000),
+        nanos.rem_euclid(1_000_000_000) as u32,
+    )?;
+    Some(DateTime::<Utc>::from_utc(time, Utc))
+}
+
+pub(crate) fn split_lock_time(lock_time: &Option<LockTime>) -> (Option<u64>, Option<chrono::NaiveDateTime>) {
+    match lock_time {
+        Some(LockTime::Height(height)) => (Some(*height), None),
+        Some(LockTime::Timestamp(time)) => (None, Some(time.naive_utc())),
+        None => (None, None),
+    }
+}
+
+pub(crate) fn join_lock_time(height: Option<u64>, timestamp: Option<chrono::NaiveDateTime>) -> Option<LockTime> {
+    match (height, timestamp) {
+        (Some(height), _) => Some(LockTime::Height(height)),
+        (None, Some(time)) => Some(LockTime::Timestamp(chrono::DateTime::<chrono::Utc>::from_utc(time, chrono::Utc))),
+        (None, None) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::tests::{mined_blocks, test_databases};
+
+    // Linked blocks sharing one timestamp, with hashes falling as height
+    // rises, so ordering by timestamp and hash would list children first.
+    // Storage doesn't validate blocks, so only the links matter.
+    fn same_time_chain(length: usize) -> Vec<Block> {
+        let timestamp = Utc::now();
+        let mut blocks: Vec<Block> = vec![];
+        for height in 0..length {
+            let hash = format!("{:064x}", length - height);
+            blocks.push(Block {
+                version: crate::blockchain::BLOCK_VERSION,
+                previous_hash: blocks.last().map_or(hash.clone(), |parent| parent.hash.clone()),
+                hash,
+                timestamp,
+                transactions: vec![],
+                poh_hash: "0".repeat(64),
+                poh_count: height as u64,
+            });
+        }
+        blocks
+    }
+
+    #[test]
+    fn blocks_sharing_a_timestamp_page_parents_first() {
+        let blocks = same_time_chain(5);
+        let hashes: Vec<&str> = blocks.iter().map(|block| block.hash.as_str()).collect();
+        for (source, target) in test_databases().into_iter().zip(test_databases()) {
+            source.save_blocks(&blocks).unwrap();
+
+            // Pages of two, so the cursor is crossed on every page
+            let mut paged = StoredBlocks::new(&**source);
+            paged.page = Some(PageRequest { limit: Some(2), cursor: None });
+            let paged: Vec<String> = paged.map(|block| block.unwrap().hash).collect();
+            assert_eq!(paged, hashes);
+
+            let report = copy(&**source, &**target, &mut |_, _, _| {}).unwrap();
+            assert!(report.mismatches.is_empty(), "{:?}", report.mismatches);
+            let state = target.get_chain_state().unwrap();
+            assert_eq!(state.best_hash.as_deref(), hashes.last().copied());
+            assert_eq!(state.best_height, Some(4));
+        }
+    }
+
+    #[tokio::test]
+    async fn persisted_chain_verifies() {
+        let (blocks, _) = mined_blocks(3).await;
+        for database in test_databases() {
+            database.save_blocks(&blocks).unwrap();
+            let report = verify(&**database).unwrap();
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.blocks, 4);
+            assert_eq!(report.transactions, 6);
+            assert_eq!(report.height, Some(3));
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use std::error::Error;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Serialize, Deserialize};
+use tokio::sync::watch;
+
+use crate::blockchain::{Block, BlockHeader, BLOCK_VERSION};
+use crate::database::Database;
+use crate::network::{Capabilities, Network, NetworkEvent, NetworkMessage, PeerId, MAX_BLOCKS_PER_REQUEST, MAX_HEADERS_PER_REQUEST};
+
+// How often the manager looks for a peer ahead of us
+const SYNC_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+// How long to wait for a peer to answer a headers or blocks request
+const SYNC_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// What a peer must support for us to sync from it
+const SYNC_CAPABILITIES: Capabilities = Capabilities::HEADERS_SYNC.union(Capabilities::REQUEST_IDS);
+// Body requests kept in flight at once
+const PARALLEL_BLOCK_REQUESTS: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SyncState {
+    Idle,
+    DownloadingHeaders,
+    DownloadingBlocks,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncProgress {
+    pub state: SyncState,
+    pub peer: Option<PeerId>,
+    pub current_height: u64,
+    pub target_height: u64,
+    pub headers_downloaded: u64,
+    pub blocks_applied: u64,
+}
+
+// Keeps the local chain caught up with the best connected peer: headers are
+// downloaded and checked first, then bodies are fetched in parallel batches
+// and applied in order
+pub struct SyncManager {
+    network: Network,
+    progress: watch::Sender<SyncProgress>,
+    // Where applied blocks are stored, written in bulk per received batch
+    database: Option<Arc<Database>>,
+}
+
+impl SyncManager {
+    pub fn new(network: Network) -> Self {
+        let (progress, _) = watch::channel(SyncProgress {
+            state: SyncState::Idle,
+            peer: None,
+            current_height: 0,
+            target_height: 0,
+            headers_downloaded: 0,
+            blocks_applied: 0,
+        });
+        SyncManager { network, progress, database: None }
+    }
+
+    pub fn with_database(mut self, database: Arc<Database>) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    pub fn progress(&self) -> watch::Receiver<SyncProgress> {
+        self.progress.subscribe()
+    }
+
+    pub fn start(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SYNC_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Some(peer) = self.best_peer().await {
+                    if let Err(e) = self.sync_with(&peer).await {
+                        eprintln!("Sync with {} failed: {}", peer, e);
+                        self.progress.send_modify(|p| p.state = SyncState::Failed(e.to_string()));
+                        self.network.emit(NetworkEvent::SyncFailed { peer: peer.clone(), error: e.to_string() });
+                    }
+                }
+            }
+        })
+    }
+
+    // The connected peer with the most blocks, if it is ahead of us
+    async fn best_peer(&self) -> Option<PeerId> {
+        let height = self.network.blockchain().height().await;
+        self.network.peers().await.into_iter()
+            .filter(|peer| peer.best_height > height && peer.capabilities.contains(SYNC_CAPABILITIES))
+            .max_by_key(|peer| peer.best_height)
+            .map(|peer| peer.address)
+    }
+
+    pub async fn sync_with(&self, peer: &str) -> Result<(), Box<dyn Error>> {
+        let blockchain = self.network.blockchain().clone();
+        let info = self.network.peer_info(peer).await.ok_or("Peer disconnected")?;
+        let start_height = blockchain.height().await;
+        self.progress.send_modify(|p| {
+            p.state = SyncState::DownloadingHeaders;
+            p.peer = Some(peer.to_string());
+            p.current_height = start_height;
+            p.target_height = info.best_height;
+            p.headers_downloaded = 0;
+            p.blocks_applied = 0;
+        });
+        self.network.emit(NetworkEvent::SyncStarted { peer: peer.to_string(), target_height: info.best_height });
+
+        loop {
+            // Headers first: cheap to download and enough to check linkage
+            let tip = blockchain.latest_block().await;
+            let request = NetworkMessage::GetHeaders {
+                locator: blockchain.block_locator().await,
+                limit: MAX_HEADERS_PER_REQUEST as u32,
+            };
+            let headers = match self.network.request_with_timeout(peer, request, SYNC_REQUEST_TIMEOUT).await? {
+                NetworkMessage::Headers(headers) if headers.is_empty() => break,
+                NetworkMessage::Headers(headers) => headers,
+                _ => return Err("Peer answered GetHeaders with the wrong message".into()),
+            };
+            let hashes = validate_headers(&tip.hash, &tip.timestamp, &headers)?;
+            self.progress.send_modify(|p| {
+                p.headers_downloaded += hashes.len() as u64;
+                p.state = SyncState::DownloadingBlocks;
+            });
+
+            self.download_blocks(peer, &hashes).await?;
+            if headers.len() < MAX_HEADERS_PER_REQUEST {
+                break;
+            }
+        }
+
+        let height = blockchain.height().await;
+        self.progress.send_modify(|p| {
+            p.state = SyncState::Idle;
+            p.current_height = height;
+        });
+        self.network.emit(NetworkEvent::SyncFinished { peer: peer.to_string(), height });
+        Ok(())
+    }
+
+    // Fetch bodies for `hashes` with several requests in flight, applying
+    // each block as soon as everything before it has arrived
+    async fn download_blocks(&self, peer: &str, hashes: &[String]) -> Result<(), Box<dyn Error>> {
+        let blockchain = self.network.blockchain().clone();
+        let batches: Vec<&[String]> = hashes.chunks(MAX_BLOCKS_PER_REQUEST).collect();
+        let mut next_batch = 0;
+        let mut in_flight = FuturesUnordered::new();
+        let mut received: HashMap<String, Block> = HashMap::new();
+        let mut next_apply = 0;
+
+        while next_apply < hashes.len() {
+            while in_flight.len() < PARALLEL_BLOCK_REQUESTS && next_batch < batches.len() {
+                let request = NetworkMessage::GetBlocks(batches[next_batch].to_vec());
+                in_flight.push(self.network.request_with_timeout(peer, request, SYNC_REQUEST_TIMEOUT));
+                next_batch += 1;
+            }
+
+            let blocks = match in_flight.next().await {
+                Some(Ok(NetworkMessage::Blocks(blocks))) => blocks,
+                Some(Ok(_)) => return Err("Peer answered GetBlocks with the wrong message".into()),
+                Some(Err(e)) => return Err(e),
+                None => return Err(format!("Peer did not send block {}", hashes[next_apply]).into()),
+            };
+            if blocks.is_empty() {
+                return Err("Peer returned no blocks for a batch it advertised".into());
+            }
+            for block in blocks {
+                // Bodies must match the headers we already validated
+                if hashes.contains(&block.hash) && block.verify_hash() {
+                    received.insert(block.hash.clone(), block);
+                }
+            }
+
+            let mut applied = vec![];
+            while let Some(block) = hashes.get(next_apply).and_then(|hash| received.remove(hash)) {
+                if self.database.is_some() {
+                    applied.push(block.clone());
+                }
+                blockchain.add_block(block).await?;
+                next_apply += 1;
+                let height = blockchain.height().await;
+                self.progress.send_modify(|p| {
+                    p.blocks_applied += 1;
+                    p.current_height = height;
+                });
+            }
+            self.store_blocks(applied).await?;
+        }
+        Ok(())
+    }
+
+    // Write a run of applied blocks with one bulk insert rather than a
+    // write per block and transaction
+    async fn store_blocks(&self, blocks: Vec<Block>) -> Result<(), Box<dyn Error>> {
+        let database = match &self.database {
+            Some(database) if !blocks.is_empty() => database.clone(),
+            _ => return Ok(()),
+        };
+        tokio::task::spawn_blocking(move || database.save_blocks(&blocks).map_err(|e| e.to_string())).await??;
+        Ok(())
+    }
+}
+
+// Check that headers form a chain extending `tip` and return their hashes
+fn validate_headers(
+    tip_hash: &str,
+    tip_time: &chrono::DateTime<chrono::Utc>,
+    headers: &[BlockHeader],
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut previous_hash = tip_hash.to_string();
+    let mut previous_time = *tip_time;
+    let mut hashes = vec![];
+
+    for header in headers {
+        if header.previous_hash != previous_hash {
+            return Err("Headers do not extend our chain; fork resolution is not supported".into());
+        }
+        if header.version > BLOCK_VERSION {
+            return Err(format!("Unsupported block version {}", header.version).into());
+        }
+        if header.timestamp < previous_time {
+            return Err("Header timestamps go backwards".into());
+        }
+        previous_hash = header.hash();
+        previous_time = header.timestamp;
+        hashes.push(previous_hash.clone());
+    }
+    Ok(hashes)
+}
@@ -2,20 +2,301 @@ use std::error::Error;
 use serde::{Serialize, Deserialize};
 use sysinfo::{System, SystemExt};
 use sha2::{Sha256, Digest};
-use ed25519_dalek::{Keypair, PublicKey, SecretKey};
-use rand::rngs::OsRng;
 use uuid::Uuid;
+use qrcode::{QrCode, render::svg};
+use bip39::Mnemonic;
+use bech32::{FromBase32, ToBase32, Variant};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::blockchain::{
+    Blockchain, ChainEvent, Payout, Transaction, TransactionKind, BATCH_ADDRESS, MAX_BATCH_OUTPUTS, TRANSACTION_VERSION,
+    MIN_TRANSACTION_FEE,
+};
+use crate::security::{self, KdfParams};
+use crate::signer::{KeyScheme, KeypairSigner, Signer};
+use crate::database::Database;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Wallet {
     pub id: String,
     pub email: String,
+    pub address: String,
     pub public_key: Vec<u8>,
+    // Scheme of the primary key; receive addresses are derived with the same one
+    #[serde(default)]
+    pub key_scheme: KeyScheme,
+    pub pin_hash: String,
     pub hardware_id: String,
+    // Portable wallets skip the hardware check entirely
+    pub portable: bool,
+    // Last confirmed balance seen on sync; chain state is authoritative
     pub balance: f64,
     pub created_at: chrono::DateTime<chrono::Utc>,
+    pub receive_addresses: Vec<ReceiveAddress>,
+    pub metadata: Metadata,
+    // User annotations on individual transactions, by transaction id
+    pub transaction_metadata: HashMap<String, Metadata>,
+    pub encrypted_key: EncryptedKey,
+    // Decrypted secret key, only present while the wallet is unlocked
+    #[serde(skip)]
+    pub session: KeySession,
+}
+
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Holds the decrypted key between unlock and lock. The key is zeroized when
+// the wallet is locked, including automatically once it has been idle for
+// longer than `idle_timeout`.
+pub struct KeySession {
+    secret: Mutex<Option<Zeroizing<Vec<u8>>>>,
+    last_used: Mutex<Instant>,
+    idle_timeout: Duration,
+}
+
+impl Default for KeySession {
+    fn default() -> Self {
+        KeySession {
+            secret: Mutex::new(None),
+            last_used: Mutex::new(Instant::now()),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl std::fmt::Debug for KeySession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeySession")
+            .field("locked", &self.is_locked())
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+impl KeySession {
+    pub fn unlocked(secret: Zeroizing<Vec<u8>>) -> Self {
+        let session = KeySession::default();
+        session.unlock(secret);
+        session
+    }
+
+    pub fn unlock(&self, secret: Zeroizing<Vec<u8>>) {
+        *self.secret.lock().unwrap() = Some(secret);
+        *self.last_used.lock().unwrap() = Instant::now();
+    }
+
+    // Dropping the Zeroizing buffer wipes the key bytes
+    pub fn lock(&self) {
+        self.secret.lock().unwrap().take();
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.secret.lock().unwrap().is_none()
+    }
+
+    // Lock if the idle timeout has passed; returns whether the session is locked
+    pub fn lock_if_idle(&self) -> bool {
+        if self.last_used.lock().unwrap().elapsed() >= self.idle_timeout {
+            self.lock();
+        }
+        self.is_locked()
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    // Copy of the key for a single operation; refreshes the idle timer
+    pub fn secret(&self) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+        if self.lock_if_idle() {
+            return Err("Wallet is locked".into());
+        }
+        *self.last_used.lock().unwrap() = Instant::now();
+        let secret = self.secret.lock().unwrap();
+        secret.clone().ok_or_else(|| "Wallet is locked".into())
+    }
+}
+
+// Secret key encrypted at rest under a key derived from the wallet PIN
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedKey {
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub kdf_params: KdfParams,
+}
+
+impl EncryptedKey {
+    pub fn encrypt(secret_key: &[u8], pin: &str) -> Result<Self, Box<dyn Error>> {
+        let kdf_params = KdfParams::generate();
+        let key = security::derive_key(pin.as_bytes(), &kdf_params)?;
+        let (nonce, ciphertext) = security::seal(&key, secret_key)?;
+        Ok(EncryptedKey { nonce, ciphertext, kdf_params })
+    }
+
+    pub fn decrypt(&self, pin: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+        let key = security::derive_key(pin.as_bytes(), &self.kdf_params)?;
+        let secret = security::open(&key, &self.nonce, &self.ciphertext).map_err(|_| "Incorrect PIN")?;
+        Ok(Zeroizing::new(secret))
+    }
+}
+
+// User-editable bookkeeping fields for wallets and transactions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    pub label: Option<String>,
+    pub category: Option<String>,
+    pub notes: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub transaction: Transaction,
+    pub metadata: Metadata,
+}
+
+// Confirmed balance plus the effect of transactions still in the mempool
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WalletBalance {
+    pub confirmed: f64,
+    pub pending_incoming: f64,
+    pub pending_outgoing: f64,
+    pub available: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum WalletEvent {
+    IncomingTransfer(Transaction),
+    Confirmation { transaction_id: String, confirmations: u64 },
+    BalanceUpdated(WalletBalance),
+}
+
+// What a transfer would do if signed and submitted now
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionPreview {
+    pub transaction: Transaction,
+    pub fee: f64,
+    pub total_cost: f64,
+    pub balance_before: f64,
+    pub balance_after: f64,
+    pub replaces: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+// Confirmation events stop once a transaction is this deep
+const CONFIRMATION_TARGET: u64 = 6;
+
+// Additional address derived from the wallet key, handed out per payment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReceiveAddress {
+    pub index: u32,
+    pub address: String,
+    pub public_key: Vec<u8>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub const KEYSTORE_VERSION: u32 = 1;
+
+// Portable, password-encrypted wallet export
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Keystore {
+    pub version: u32,
+    pub id: String,
+    pub email: String,
+    pub address: String,
+    pub public_key: String,
+    #[serde(default)]
+    pub key_scheme: KeyScheme,
+    // Indexes of derived receive addresses, re-derived on import
+    #[serde(default)]
+    pub receive_indexes: Vec<u32>,
+    pub crypto: KeystoreCrypto,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeystoreCrypto {
+    pub cipher: String,
+    pub ciphertext: String,
+    pub nonce: String,
+    pub kdf: String,
+    pub kdfparams: KdfParams,
+}
+
+// Human-readable prefix identifying the network in bech32 addresses
+pub const ADDRESS_HRP: &str = "rbn";
+const ADDRESS_PAYLOAD_LEN: usize = 20;
+
+// URI scheme used in payment requests, e.g. `blockchain:<address>?amount=1.5`
+pub const PAYMENT_URI_SCHEME: &str = "blockchain";
+
+// Saved contacts for a wallet, keyed by a user-chosen label
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AddressBook {
+    pub wallet_id: String,
+    pub contacts: HashMap<String, String>,
+}
+
+// Proof that the holder of `address` signed `message`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedMessage {
+    pub address: String,
+    pub public_key: String,
+    #[serde(default)]
+    pub scheme: KeyScheme,
+    pub message: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum FeePriority {
+    Low,
+    Medium,
+    High,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeeEstimate {
+    pub fee: f64,
+    pub total_cost: f64,
+    pub priority: FeePriority,
+    pub mempool_size: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PayoutMode {
+    // One batch transaction that pays every recipient or none
+    Atomic,
+    // One transfer per recipient, each paying its own fee
+    Sequence,
+}
+
+// Unsigned transactions for a payout run plus a report for the operator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayoutPlan {
+    pub transactions: Vec<Transaction>,
+    pub summary: PayoutSummary,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayoutSummary {
+    pub mode: PayoutMode,
+    pub recipients: usize,
+    pub transactions: usize,
+    pub total_amount: f64,
+    pub total_fee: f64,
+    pub total_cost: f64,
+    pub available_balance: Option<f64>,
+    pub errors: Vec<String>,
+}
+
+// Mempool depth above which estimates start outbidding pending transactions
+const CONGESTION_THRESHOLD: usize = 1000;
+
+// Prefix keeps message signatures from ever being valid transaction signatures
+const SIGNED_MESSAGE_PREFIX: &str = "Blockchain Signed Message:\n";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct WalletCredentials {
     pub email: String,
@@ -24,32 +305,903 @@ pub struct WalletCredentials {
 
 impl Wallet {
     pub fn new(email: String, pin: String) -> Result<Self, Box<dyn Error>> {
+        Self::with_scheme(email, pin, KeyScheme::default())
+    }
+
+    pub fn with_scheme(email: String, pin: String, key_scheme: KeyScheme) -> Result<Self, Box<dyn Error>> {
+        let secret_bytes = key_scheme.generate_secret().to_vec();
+        Self::from_secret(email, pin, secret_bytes, key_scheme)
+    }
+
+    // Recreate a wallet from its recovery phrase on this machine
+    pub fn from_mnemonic(
+        email: String,
+        pin: String,
+        mnemonic: &str,
+        key_scheme: KeyScheme,
+    ) -> Result<Self, Box<dyn Error>> {
+        let secret_bytes = Mnemonic::parse(mnemonic)?.to_entropy();
+        Self::from_secret(email, pin, secret_bytes, key_scheme)
+    }
+
+    fn from_secret(
+        email: String,
+        pin: String,
+        secret_bytes: Vec<u8>,
+        key_scheme: KeyScheme,
+    ) -> Result<Self, Box<dyn Error>> {
         // Generate hardware ID based on system information
         let sys = System::new_all();
         let hardware_id = generate_hardware_id(&sys);
         
-        // Generate keypair
-        let mut csprng = OsRng{};
-        let keypair: Keypair = Keypair::generate(&mut csprng);
+        let public_key = key_scheme.public_key(&secret_bytes)?;
         
-        // Create wallet address (16 characters)
-        let address = generate_wallet_address(&keypair.public);
+        // Create wallet address
+        let address = encode_address(&public_key);
+
+        // Encrypt the secret key at rest under the PIN
+        let encrypted_key = EncryptedKey::encrypt(&secret_bytes, &pin)?;
+        let pin_hash = security::hash_password(&pin)?;
         
         Ok(Wallet {
             id: Uuid::new_v4().to_string(),
             email,
-            public_key: keypair.public.to_bytes().to_vec(),
+            address,
+            public_key,
+            key_scheme,
+            pin_hash,
             hardware_id,
+            portable: false,
             balance: 0.0,
             created_at: chrono::Utc::now(),
+            receive_addresses: vec![],
+            metadata: Metadata::default(),
+            transaction_metadata: HashMap::new(),
+            encrypted_key,
+            session: KeySession::unlocked(Zeroizing::new(secret_bytes)),
         })
     }
 
+    pub fn unlock(&mut self, pin: &str) -> Result<(), Box<dyn Error>> {
+        let secret_bytes = self.encrypted_key.decrypt(pin)?;
+        if self.key_scheme.public_key(&secret_bytes)? != self.public_key {
+            return Err("Decrypted key does not match wallet public key".into());
+        }
+
+        self.session.unlock(secret_bytes);
+        Ok(())
+    }
+
+    pub fn lock(&self) {
+        self.session.lock();
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.session.lock_if_idle()
+    }
+
+    // How long the key stays unlocked without being used
+    pub fn set_idle_timeout(&mut self, idle_timeout: Duration) {
+        self.session.set_idle_timeout(idle_timeout);
+    }
+
+    // Secret key for signing operations; fails until the wallet is unlocked
+    fn unlocked_secret(&self) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+        self.session.secret()
+    }
+
+    // Deterministically derive the secret key behind receive address `index`
+    pub fn derive_secret(&self, index: u32) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+        let secret_key = self.unlocked_secret()?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(&*secret_key);
+        hasher.update(b"receive");
+        hasher.update(index.to_be_bytes());
+        Ok(Zeroizing::new(hasher.finalize().to_vec()))
+    }
+
+    // Generate a fresh address for the next incoming payment
+    pub fn new_receive_address(&mut self) -> Result<ReceiveAddress, Box<dyn Error>> {
+        let index = self.receive_addresses.iter().map(|a| a.index).max().unwrap_or(0) + 1;
+        self.add_receive_address(index)
+    }
+
+    fn add_receive_address(&mut self, index: u32) -> Result<ReceiveAddress, Box<dyn Error>> {
+        let public_key = self.key_scheme.public_key(&self.derive_secret(index)?)?;
+
+        let receive_address = ReceiveAddress {
+            index,
+            address: encode_address(&public_key),
+            public_key,
+            created_at: chrono::Utc::now(),
+        };
+        self.receive_addresses.push(receive_address.clone());
+        Ok(receive_address)
+    }
+
+    // Signer for the primary key or the derived key behind a receive address
+    pub fn signer_for(&self, address: &str) -> Result<KeypairSigner, Box<dyn Error>> {
+        if address == self.address {
+            return KeypairSigner::from_secret(self.key_scheme, &self.unlocked_secret()?);
+        }
+        let receive_address = self.receive_addresses.iter()
+            .find(|a| a.address == address)
+            .ok_or_else(|| format!("{} does not belong to this wallet", address))?;
+        KeypairSigner::from_secret(self.key_scheme, &self.derive_secret(receive_address.index)?)
+    }
+
+    // Sign with whichever of the wallet's keys controls the sending address
+    pub async fn sign_transaction(&self, transaction: &mut Transaction) -> Result<(), Box<dyn Error>> {
+        let signer = self.signer_for(&transaction.from)?;
+        transaction.sign(&signer).await
+    }
+
+    // Primary address followed by every derived receive address
+    pub fn all_addresses(&self) -> Vec<String> {
+        let mut addresses = vec![self.address.clone()];
+        addresses.extend(self.receive_addresses.iter().map(|a| a.address.clone()));
+        addresses
+    }
+
+    // Balance across all of the wallet's addresses, read from chain state
+    pub async fn balance(&self, blockchain: &Blockchain) -> WalletBalance {
+        balance_of(blockchain, &self.all_addresses()).await
+    }
+
+    // Stream of payment, confirmation and balance events for this wallet's
+    // addresses, fed by the chain event bus
+    pub fn subscribe(&self, blockchain: &Blockchain) -> mpsc::Receiver<WalletEvent> {
+        let (tx, rx) = mpsc::channel(100);
+        let addresses = self.all_addresses();
+        let blockchain = blockchain.clone();
+        let mut chain_events = blockchain.subscribe();
+
+        tokio::spawn(async move {
+            // Wallet transactions still collecting confirmations, by id
+            let mut confirming: HashMap<String, u64> = HashMap::new();
+
+            loop {
+                let event = match chain_events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                let mut events = vec![];
+                match event {
+                    ChainEvent::TransactionAccepted(transaction) => {
+                        if addresses.iter().any(|a| a != &transaction.from && transaction.involves(a)) {
+                            events.push(WalletEvent::IncomingTransfer(transaction));
+                            events.push(WalletEvent::BalanceUpdated(balance_of(&blockchain, &addresses).await));
+                        }
+                    }
+                    ChainEvent::BlockAdded(block) => {
+                        for id in confirming.keys().cloned().collect::<Vec<_>>() {
+                            let confirmations = confirming.get_mut(&id).unwrap();
+                            *confirmations += 1;
+                            events.push(WalletEvent::Confirmation {
+                                transaction_id: id.clone(),
+                                confirmations: *confirmations,
+                            });
+                            if *confirmations >= CONFIRMATION_TARGET {
+                                confirming.remove(&id);
+                            }
+                        }
+
+                        let mut touched = false;
+                        for transaction in &block.transactions {
+                            if addresses.iter().any(|a| transaction.involves(a)) {
+                                touched = true;
+                                confirming.insert(transaction.id.clone(), 1);
+                                events.push(WalletEvent::Confirmation {
+                                    transaction_id: transaction.id.clone(),
+                                    confirmations: 1,
+                                });
+                            }
+                        }
+                        if touched {
+                            events.push(WalletEvent::BalanceUpdated(balance_of(&blockchain, &addresses).await));
+                        }
+                    }
+                }
+
+                for event in events {
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        rx
+    }
+
+    pub async fn token_balance(&self, blockchain: &Blockchain, symbol: &str) -> f64 {
+        let state = blockchain.read().await;
+        self.all_addresses().iter()
+            .map(|address| state.token_balance(address, symbol))
+            .sum()
+    }
+
+    // Every token held across the wallet's addresses
+    pub async fn token_balances(&self, blockchain: &Blockchain) -> HashMap<String, f64> {
+        let state = blockchain.read().await;
+        let mut totals = HashMap::new();
+        for address in self.all_addresses() {
+            for (symbol, amount) in state.token_balances(&address) {
+                *totals.entry(symbol).or_insert(0.0) += amount;
+            }
+        }
+        totals
+    }
+
+    pub async fn token_history(&self, blockchain: &Blockchain, symbol: &str) -> Vec<HistoryEntry> {
+        self.history(blockchain).await
+            .into_iter()
+            .filter(|entry| entry.transaction.token_symbol() == Some(symbol))
+            .collect()
+    }
+
+    // Refresh the cached balance from chain state
+    pub async fn sync_balance(&mut self, blockchain: &Blockchain) -> WalletBalance {
+        let balance = self.balance(blockchain).await;
+        self.balance = balance.confirmed;
+        balance
+    }
+
+    // Confirmed transactions with any user annotations attached
+    pub async fn history(&self, blockchain: &Blockchain) -> Vec<HistoryEntry> {
+        self.annotate(blockchain.transactions_for(&self.all_addresses()).await)
+    }
+
+    // Attach user annotations to transactions loaded from any source
+    pub fn annotate(&self, transactions: Vec<Transaction>) -> Vec<HistoryEntry> {
+        transactions.into_iter()
+            .map(|transaction| HistoryEntry {
+                metadata: self.transaction_metadata.get(&transaction.id).cloned().unwrap_or_default(),
+                transaction,
+            })
+            .collect()
+    }
+
+    pub fn annotate_transaction(&mut self, transaction_id: &str, metadata: Metadata) {
+        self.transaction_metadata.insert(transaction_id.to_string(), metadata);
+    }
+
+    // Encrypt the wallet's secret key into a keystore JSON document
+    pub fn export_keystore(&self, password: &str) -> Result<String, Box<dyn Error>> {
+        let secret_key = self.unlocked_secret()?;
+
+        let kdfparams = KdfParams::generate();
+        let key = security::derive_key(password.as_bytes(), &kdfparams)?;
+        let (nonce, ciphertext) = security::seal(&key, &secret_key)?;
+
+        let keystore = Keystore {
+            version: KEYSTORE_VERSION,
+            id: self.id.clone(),
+            email: self.email.clone(),
+            address: self.address.clone(),
+            public_key: hex::encode(&self.public_key),
+            key_scheme: self.key_scheme,
+            receive_indexes: self.receive_addresses.iter().map(|a| a.index).collect(),
+            crypto: KeystoreCrypto {
+                cipher: "aes-256-gcm".to_string(),
+                ciphertext: hex::encode(ciphertext),
+                nonce: hex::encode(nonce),
+                kdf: "argon2id".to_string(),
+                kdfparams,
+            },
+        };
+        Ok(serde_json::to_string_pretty(&keystore)?)
+    }
+
+    // Restore a wallet from a keystore, binding it to the current machine and
+    // re-encrypting the key under a local PIN
+    pub fn import_keystore(json: &str, password: &str, pin: &str) -> Result<Self, Box<dyn Error>> {
+        let keystore: Keystore = serde_json::from_str(json)?;
+        if keystore.version != KEYSTORE_VERSION {
+            return Err(format!("Unsupported keystore version {}", keystore.version).into());
+        }
+        if keystore.crypto.cipher != "aes-256-gcm" || keystore.crypto.kdf != "argon2id" {
+            return Err("Unsupported keystore cipher or KDF".into());
+        }
+
+        let key = security::derive_key(password.as_bytes(), &keystore.crypto.kdfparams)?;
+        let secret_bytes = security::open(
+            &key,
+            &hex::decode(&keystore.crypto.nonce)?,
+            &hex::decode(&keystore.crypto.ciphertext)?,
+        )?;
+
+        let public_key = keystore.key_scheme.public_key(&secret_bytes)?;
+        if hex::encode(&public_key) != keystore.public_key
+            || encode_address(&public_key) != keystore.address
+        {
+            return Err("Keystore key does not match its address".into());
+        }
+
+        let sys = System::new_all();
+        let mut wallet = Wallet {
+            id: keystore.id,
+            email: keystore.email,
+            address: keystore.address,
+            public_key,
+            key_scheme: keystore.key_scheme,
+            pin_hash: security::hash_password(pin)?,
+            hardware_id: generate_hardware_id(&sys),
+            portable: false,
+            balance: 0.0,
+            created_at: chrono::Utc::now(),
+            receive_addresses: vec![],
+            metadata: Metadata::default(),
+            transaction_metadata: HashMap::new(),
+            encrypted_key: EncryptedKey::encrypt(&secret_bytes, pin)?,
+            session: KeySession::unlocked(Zeroizing::new(secret_bytes)),
+        };
+        for index in keystore.receive_indexes {
+            wallet.add_receive_address(index)?;
+        }
+        Ok(wallet)
+    }
+
+    // Build an unsigned transfer; `recipient` may be an address or a contact label
+    pub fn build_transfer(
+        &self,
+        recipient: &str,
+        amount: f64,
+        fee: f64,
+        address_book: Option<&AddressBook>,
+    ) -> Result<Transaction, Box<dyn Error>> {
+        self.build_transfer_from(&self.address, recipient, amount, fee, address_book)
+    }
+
+    // As build_transfer, spending from one of the wallet's receive addresses
+    pub fn build_transfer_from(
+        &self,
+        from: &str,
+        recipient: &str,
+        amount: f64,
+        fee: f64,
+        address_book: Option<&AddressBook>,
+    ) -> Result<Transaction, Box<dyn Error>> {
+        if !self.all_addresses().iter().any(|address| address == from) {
+            return Err(format!("{} does not belong to this wallet", from).into());
+        }
+        let to = match address_book {
+            Some(book) => book.resolve(recipient)?,
+            None if validate_address(recipient) => recipient.to_string(),
+            None => return Err("Invalid recipient address".into()),
+        };
+
+        let mut transaction = self.unsigned_transaction(to, amount, fee, TransactionKind::Transfer);
+        transaction.from = from.to_string();
+        Ok(transaction)
+    }
+
+    fn unsigned_transaction(&self, to: String, amount: f64, fee: f64, kind: TransactionKind) -> Transaction {
+        Transaction {
+            version: TRANSACTION_VERSION,
+            id: Uuid::new_v4().to_string(),
+            from: self.address.clone(),
+            to,
+            amount,
+            fee,
+            timestamp: chrono::Utc::now(),
+            lock_time: None,
+            kind,
+            signature: vec![],
+            public_key: vec![],
+            key_scheme: self.key_scheme,
+            multisig: None,
+        }
+    }
+
+    pub fn payment_uri(&self, amount: Option<f64>, memo: Option<&str>) -> String {
+        let mut params = vec![];
+        if let Some(amount) = amount {
+            params.push(format!("amount={}", amount));
+        }
+        if let Some(memo) = memo {
+            params.push(format!("memo={}", percent_encode(memo)));
+        }
+
+        let mut uri = format!("{}:{}", PAYMENT_URI_SCHEME, self.address);
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+
+    // Render a payment request for this wallet's address as an SVG QR code
+    pub fn receive_qr(&self, amount: Option<f64>, memo: Option<&str>) -> Result<Vec<u8>, Box<dyn Error>> {
+        let code = QrCode::new(self.payment_uri(amount, memo).as_bytes())?;
+        let image = code.render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build();
+        Ok(image.into_bytes())
+    }
+
     pub fn verify_hardware(&self) -> bool {
+        if self.portable {
+            return true;
+        }
         let sys = System::new_all();
         let current_hardware_id = generate_hardware_id(&sys);
         self.hardware_id == current_hardware_id
     }
+
+    // Recovery phrase encoding the wallet's secret key
+    pub fn mnemonic(&self) -> Result<String, Box<dyn Error>> {
+        let mnemonic = Mnemonic::from_entropy(&self.unlocked_secret()?)?;
+        Ok(mnemonic.to_string())
+    }
+
+    // Suggest a fee from recent block fees, raised to outbid the mempool when congested
+    pub async fn estimate_fee(
+        &self,
+        blockchain: &Blockchain,
+        amount: f64,
+        priority: FeePriority,
+    ) -> FeeEstimate {
+        let stats = blockchain.fee_stats().await;
+        let quantile = match priority {
+            FeePriority::Low => 0.25,
+            FeePriority::Medium => 0.5,
+            FeePriority::High => 0.9,
+        };
+
+        let mut fee = percentile(&stats.recent_block_fees, quantile).unwrap_or(MIN_TRANSACTION_FEE);
+        if stats.mempool_size >= CONGESTION_THRESHOLD {
+            if let Some(mempool_fee) = percentile(&stats.mempool_fees, quantile) {
+                fee = fee.max(mempool_fee);
+            }
+        }
+        let fee = fee.max(MIN_TRANSACTION_FEE);
+
+        FeeEstimate {
+            fee,
+            total_cost: amount + fee,
+            priority,
+            mempool_size: stats.mempool_size,
+        }
+    }
+
+    // Build a transfer with an estimated fee and run it through mempool
+    // validation (minus the signature check) without broadcasting
+    pub async fn preview_transfer(
+        &self,
+        blockchain: &Blockchain,
+        to: &str,
+        amount: f64,
+    ) -> Result<TransactionPreview, Box<dyn Error>> {
+        let estimate = self.estimate_fee(blockchain, amount, FeePriority::Medium).await;
+        let transaction = self.build_transfer(to, amount, estimate.fee, None)?;
+        let balance_before = blockchain.available_balance(&self.address).await;
+
+        let (replaces, errors) = match blockchain.dry_run(&transaction, false).await {
+            Ok(replaces) => (replaces, vec![]),
+            Err(e) => (vec![], vec![e.to_string()]),
+        };
+
+        Ok(TransactionPreview {
+            fee: estimate.fee,
+            total_cost: transaction.native_cost(),
+            balance_after: balance_before - transaction.native_cost(),
+            balance_before,
+            replaces,
+            errors,
+            transaction,
+        })
+    }
+
+    // Build the unsigned transactions for a payout run. Repeat recipients are
+    // merged first so sequence mode sends as few transactions as possible.
+    pub fn build_payouts(
+        &self,
+        payouts: &[Payout],
+        mode: PayoutMode,
+        fee: f64,
+    ) -> Result<Vec<Transaction>, Box<dyn Error>> {
+        let outputs = merge_payouts(payouts)?;
+
+        match mode {
+            PayoutMode::Atomic => {
+                if outputs.len() > MAX_BATCH_OUTPUTS {
+                    return Err(format!("A batch can pay at most {} recipients", MAX_BATCH_OUTPUTS).into());
+                }
+                let amount = outputs.iter().map(|o| o.amount).sum();
+                Ok(vec![self.unsigned_transaction(
+                    BATCH_ADDRESS.to_string(),
+                    amount,
+                    fee,
+                    TransactionKind::Batch { outputs },
+                )])
+            }
+            PayoutMode::Sequence => outputs.iter()
+                .map(|output| self.build_transfer(&output.address, output.amount, fee, None))
+                .collect(),
+        }
+    }
+
+    // Price a payout run at the estimated fee and check it against the
+    // wallet's spendable balance without broadcasting anything
+    pub async fn plan_payouts(
+        &self,
+        blockchain: &Blockchain,
+        payouts: &[Payout],
+        mode: PayoutMode,
+        priority: FeePriority,
+    ) -> Result<PayoutPlan, Box<dyn Error>> {
+        let estimate = self.estimate_fee(blockchain, 0.0, priority).await;
+        let transactions = self.build_payouts(payouts, mode, estimate.fee)?;
+        let available = blockchain.available_balance(&self.address).await;
+
+        let mut summary = PayoutSummary::new(mode, &transactions);
+        summary.available_balance = Some(available);
+        if summary.total_cost > available {
+            summary.errors.push(format!(
+                "Payout needs {:.8} but only {:.8} is available",
+                summary.total_cost, available
+            ));
+        }
+        if let Some(first) = transactions.first() {
+            if let Err(e) = blockchain.dry_run(first, false).await {
+                summary.errors.push(e.to_string());
+            }
+        }
+
+        Ok(PayoutPlan { transactions, summary })
+    }
+
+    // Sign and submit every transaction in a plan, returning their ids
+    pub async fn execute_payouts(
+        &self,
+        blockchain: &Blockchain,
+        plan: PayoutPlan,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        if !plan.summary.errors.is_empty() {
+            return Err(plan.summary.errors.join("; ").into());
+        }
+
+        let mut ids = vec![];
+        for mut transaction in plan.transactions {
+            transaction.sign(self).await?;
+            ids.push(transaction.id.clone());
+            blockchain.add_transaction(transaction).await?;
+        }
+        Ok(ids)
+    }
+
+    // Sign a raw unsigned transaction exported from an online node
+    pub async fn sign_raw(&self, raw: &str) -> Result<String, Box<dyn Error>> {
+        let mut transaction = Transaction::from_raw(raw)?;
+        self.sign_transaction(&mut transaction).await?;
+        transaction.to_raw()
+    }
+
+    pub async fn sign_message(&self, text: &str) -> Result<SignedMessage, Box<dyn Error>> {
+        sign_message_with(self, text).await
+    }
+
+    // Render a printable SVG page with the recovery phrase, derivation path
+    // and primary address, each with a QR code. Nothing leaves the process.
+    pub fn paper_backup(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mnemonic = self.mnemonic()?;
+        let mut svg = String::new();
+
+        svg.push_str(r#"<svg xmlns="http://www.w3.org/2000/svg" width="794" height="1123" viewBox="0 0 794 1123" font-family="monospace">"#);
+        svg.push_str(r#"<rect width="794" height="1123" fill="white"/>"#);
+        svg.push_str(&svg_text(60, 80, 28, "Wallet Paper Backup"));
+        svg.push_str(&svg_text(60, 115, 14, &format!("Created {}", self.created_at.format("%Y-%m-%d"))));
+        svg.push_str(&svg_text(60, 160, 16, &format!("Address: {}", self.address)));
+        svg.push_str(&svg_text(60, 185, 16, &format!("Derivation path: {}", derivation_path(0))));
+
+        svg.push_str(&svg_text(60, 240, 18, "Recovery phrase"));
+        for (i, word) in mnemonic.split_whitespace().enumerate() {
+            let x = 60 + (i % 4) as u32 * 170;
+            let y = 275 + (i / 4) as u32 * 30;
+            svg.push_str(&svg_text(x, y, 16, &format!("{:>2}. {}", i + 1, word)));
+        }
+
+        svg.push_str(&svg_text(60, 520, 14, "Address"));
+        svg.push_str(&qr_svg_group(&self.payment_uri(None, None), 60, 535, 220)?);
+        svg.push_str(&svg_text(420, 520, 14, "Recovery phrase"));
+        svg.push_str(&qr_svg_group(&mnemonic, 420, 535, 220)?);
+
+        svg.push_str(&svg_text(60, 820, 14, "Anyone with this page can spend your funds. Store it offline."));
+        svg.push_str("</svg>");
+
+        Ok(svg.into_bytes())
+    }
+
+    // Turn hardware binding on or off; re-enabling binds to this machine
+    pub fn set_portable(&mut self, pin: &str, portable: bool) -> Result<(), Box<dyn Error>> {
+        self.encrypted_key.decrypt(pin)?;
+        self.portable = portable;
+        if !portable {
+            self.hardware_id = generate_hardware_id(&System::new_all());
+        }
+        Ok(())
+    }
+
+    // Move a bound wallet to this machine. Requires both the PIN and the
+    // recovery phrase so a stolen database row alone is not enough.
+    pub fn rebind_hardware(&mut self, pin: &str, mnemonic: &str) -> Result<(), Box<dyn Error>> {
+        let secret_bytes = self.encrypted_key.decrypt(pin)?;
+        let entropy = Zeroizing::new(Mnemonic::parse(mnemonic)?.to_entropy());
+        if !security::constant_time_eq(&entropy, &secret_bytes) {
+            return Err("Recovery phrase does not match this wallet".into());
+        }
+
+        self.hardware_id = generate_hardware_id(&System::new_all());
+        Ok(())
+    }
+}
+
+impl PayoutSummary {
+    pub fn new(mode: PayoutMode, transactions: &[Transaction]) -> Self {
+        let recipients = transactions.iter().map(|tx| tx.credits().len()).sum();
+        let total_amount: f64 = transactions.iter().map(|tx| tx.amount).sum();
+        let total_fee: f64 = transactions.iter().map(|tx| tx.fee).sum();
+
+        PayoutSummary {
+            mode,
+            recipients,
+            transactions: transactions.len(),
+            total_amount,
+            total_fee,
+            total_cost: total_amount + total_fee,
+            available_balance: None,
+            errors: vec![],
+        }
+    }
+}
+
+impl std::fmt::Display for PayoutSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Mode:          {:?}", self.mode)?;
+        writeln!(f, "Recipients:    {}", self.recipients)?;
+        writeln!(f, "Transactions:  {}", self.transactions)?;
+        writeln!(f, "Total amount:  {:.8}", self.total_amount)?;
+        writeln!(f, "Total fees:    {:.8}", self.total_fee)?;
+        write!(f, "Total cost:    {:.8}", self.total_cost)?;
+        if let Some(available) = self.available_balance {
+            write!(f, "\nAvailable:     {:.8}", available)?;
+        }
+        for error in &self.errors {
+            write!(f, "\nError: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl AddressBook {
+    pub fn new(wallet_id: String) -> Self {
+        AddressBook {
+            wallet_id,
+            contacts: HashMap::new(),
+        }
+    }
+
+    pub fn add_contact(&mut self, label: &str, address: &str) -> Result<(), Box<dyn Error>> {
+        let label = label.trim();
+        if label.is_empty() {
+            return Err("Contact label cannot be empty".into());
+        }
+        if !validate_address(address) {
+            return Err("Invalid wallet address".into());
+        }
+        if self.contacts.contains_key(label) {
+            return Err(format!("Contact '{}' already exists", label).into());
+        }
+
+        self.contacts.insert(label.to_string(), address.to_string());
+        Ok(())
+    }
+
+    pub fn remove_contact(&mut self, label: &str) -> Option<String> {
+        self.contacts.remove(label)
+    }
+
+    // Resolve a contact label to its address, or accept a literal address
+    pub fn resolve(&self, recipient: &str) -> Result<String, Box<dyn Error>> {
+        if let Some(address) = self.contacts.get(recipient) {
+            return Ok(address.clone());
+        }
+        if validate_address(recipient) {
+            return Ok(recipient.to_string());
+        }
+        Err(format!("'{}' is neither a contact nor a valid address", recipient).into())
+    }
+}
+
+pub async fn sign_message_with(signer: &dyn Signer, text: &str) -> Result<SignedMessage, Box<dyn Error>> {
+    let signature = signer.sign(&signed_message_digest(text)).await?;
+    Ok(SignedMessage {
+        address: signer.address(),
+        public_key: hex::encode(signer.public_key()),
+        scheme: signer.scheme(),
+        message: text.to_string(),
+        signature: hex::encode(signature),
+    })
+}
+
+// Check the signature and that the public key actually owns the claimed address
+pub fn verify_signed_message(signed: &SignedMessage) -> bool {
+    let (public_key, signature) = match (hex::decode(&signed.public_key), hex::decode(&signed.signature)) {
+        (Ok(public_key), Ok(signature)) => (public_key, signature),
+        _ => return false,
+    };
+    encode_address(&public_key) == signed.address
+        && crate::signer::verify_signature(signed.scheme, &signed_message_digest(&signed.message), &public_key, &signature)
+}
+
+fn signed_message_digest(text: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(SIGNED_MESSAGE_PREFIX.as_bytes());
+    hasher.update((text.len() as u64).to_be_bytes());
+    hasher.update(text.as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Human-readable path for a receive address derived by `derive_secret`;
+// index 0 is the wallet's primary key
+pub fn derivation_path(index: u32) -> String {
+    if index == 0 {
+        "m".to_string()
+    } else {
+        format!("m/receive/{}", index)
+    }
+}
+
+fn svg_text(x: u32, y: u32, size: u32, text: &str) -> String {
+    let escaped = text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    format!(r#"<text x="{}" y="{}" font-size="{}">{}</text>"#, x, y, size, escaped)
+}
+
+// QR code drawn as rects so it can be embedded inside a larger SVG
+fn qr_svg_group(data: &str, x: u32, y: u32, size: u32) -> Result<String, Box<dyn Error>> {
+    let code = QrCode::new(data.as_bytes())?;
+    let width = code.width();
+    let module = size as f64 / width as f64;
+
+    let mut group = format!(r#"<g transform="translate({},{})">"#, x, y);
+    for (i, color) in code.to_colors().iter().enumerate() {
+        if *color == qrcode::Color::Dark {
+            group.push_str(&format!(
+                r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}"/>"#,
+                (i % width) as f64 * module,
+                (i / width) as f64 * module,
+                module,
+                module
+            ));
+        }
+    }
+    group.push_str("</g>");
+    Ok(group)
+}
+
+async fn balance_of(blockchain: &Blockchain, addresses: &[String]) -> WalletBalance {
+    let state = blockchain.read().await;
+
+    let mut balance = WalletBalance::default();
+    for address in addresses {
+        balance.confirmed += state.get_balance(address);
+        balance.available += state.available_balance(address);
+    }
+    for tx in &state.pending_transactions {
+        for (address, amount) in tx.credits() {
+            if addresses.iter().any(|a| a == address) {
+                balance.pending_incoming += amount;
+            }
+        }
+        if addresses.contains(&tx.from) {
+            balance.pending_outgoing += tx.native_cost();
+        }
+    }
+    balance
+}
+
+// Parse `address,amount` lines. Blank lines, `#` comments and a header row
+// are skipped.
+pub fn parse_payouts_csv(text: &str) -> Result<Vec<Payout>, Box<dyn Error>> {
+    let mut payouts = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split(',').map(str::trim);
+        let (address, amount) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(address), Some(amount), None) => (address, amount),
+            _ => return Err(format!("Line {}: expected `address,amount`", number + 1).into()),
+        };
+        let amount: f64 = match amount.parse() {
+            Ok(amount) => amount,
+            Err(_) if payouts.is_empty() && !validate_address(address) => continue,
+            Err(_) => return Err(format!("Line {}: invalid amount '{}'", number + 1, amount).into()),
+        };
+        // "NaN" and "inf" parse as floats but are never payable
+        if !amount.is_finite() || amount <= 0.0 {
+            return Err(format!("Line {}: amount must be a positive number", number + 1).into());
+        }
+        payouts.push(Payout { address: address.to_string(), amount });
+    }
+    Ok(payouts)
+}
+
+// Validate payouts and combine repeat recipients, keeping first-seen order
+fn merge_payouts(payouts: &[Payout]) -> Result<Vec<Payout>, Box<dyn Error>> {
+    if payouts.is_empty() {
+        return Err("No payouts given".into());
+    }
+
+    let mut merged: Vec<Payout> = vec![];
+    for payout in payouts {
+        if !validate_address(&payout.address) {
+            return Err(format!("Invalid payout address {}", payout.address).into());
+        }
+        if !payout.amount.is_finite() || payout.amount <= 0.0 {
+            return Err(format!("Invalid payout amount {} for {}", payout.amount, payout.address).into());
+        }
+        match merged.iter_mut().find(|p| p.address == payout.address) {
+            Some(existing) => existing.amount += payout.amount,
+            None => merged.push(payout.clone()),
+        }
+    }
+    Ok(merged)
+}
+
+// Nearest-rank percentile of an ascending slice
+fn percentile(sorted: &[f64], quantile: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((sorted.len() - 1) as f64 * quantile).round() as usize;
+    Some(sorted[rank])
+}
+
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// Checks the checksum, network prefix and payload length of an address
+pub fn validate_address(address: &str) -> bool {
+    match bech32::decode(address) {
+        Ok((hrp, data, Variant::Bech32)) => {
+            hrp == ADDRESS_HRP
+                && Vec::<u8>::from_base32(&data).map_or(false, |payload| payload.len() == ADDRESS_PAYLOAD_LEN)
+        }
+        _ => false,
+    }
+}
+
+// The wallet signs with its primary key while unlocked
+#[async_trait::async_trait]
+impl Signer for Wallet {
+    fn public_key(&self) -> Vec<u8> {
+        self.public_key.clone()
+    }
+
+    async fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let signer = KeypairSigner::from_secret(self.key_scheme, &self.unlocked_secret()?)?;
+        signer.sign(message).await
+    }
+
+    fn scheme(&self) -> KeyScheme {
+        self.key_scheme
+    }
 }
 
 fn generate_hardware_id(sys: &System) -> String {
@@ -68,32 +1220,98 @@ fn generate_hardware_id(sys: &System) -> String {
     format!("{:x}", result)[..16].to_string()
 }
 
-fn generate_wallet_address(public_key: &PublicKey) -> String {
+// Derive an address from arbitrary key material (single key or multisig policy)
+pub fn encode_address(key_material: &[u8]) -> String {
     let mut hasher = Sha256::new();
-    hasher.update(public_key.to_bytes());
+    hasher.update(key_material);
     let result = hasher.finalize();
     
-    // Convert to base58 and take first 16 characters
-    let address = bs58::encode(result).into_string();
-    address[..16].to_string()
+    // Bech32 with the network prefix and the first 20 bytes of the hash
+    bech32::encode(ADDRESS_HRP, (&result[..ADDRESS_PAYLOAD_LEN]).to_base32(), Variant::Bech32)
+        .expect("address HRP is valid")
+}
+
+pub fn create_wallet(email: String, pin: String, key_scheme: KeyScheme) -> Result<Wallet, Box<dyn Error>> {
+    validate_credentials(&email, &pin)?;
+    Wallet::with_scheme(email, pin, key_scheme)
+}
+
+pub fn restore_wallet(
+    email: String,
+    pin: String,
+    mnemonic: &str,
+    key_scheme: KeyScheme,
+) -> Result<Wallet, Box<dyn Error>> {
+    validate_credentials(&email, &pin)?;
+    Wallet::from_mnemonic(email, pin, mnemonic, key_scheme)
 }
 
-pub fn create_wallet(email: String, pin: String) -> Result<Wallet, Box<dyn Error>> {
-    // Validate email format
-    if !email.contains('@') {
+fn validate_credentials(email: &str, pin: &str) -> Result<(), Box<dyn Error>> {
+    if !validate_email(email) {
         return Err("Invalid email format".into());
     }
-    
-    // Validate PIN format (7 digits)
-    if pin.len() != 7 || !pin.chars().all(|c| c.is_digit(10)) {
+    if !validate_pin(pin) {
         return Err("PIN must be 7 digits".into());
     }
-    
-    Wallet::new(email, pin)
+    Ok(())
+}
+
+// A single '@' between a non-empty local part and a dotted domain
+pub fn validate_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.split('.').count() > 1
+                && domain.split('.').all(|label| !label.is_empty())
+                && !email.chars().any(char::is_whitespace)
+        }
+        None => false,
+    }
 }
 
-pub fn access_wallet(email: String, pin: String) -> Result<Wallet, Box<dyn Error>> {
-    // TODO: Implement wallet access from database
-    // This will verify the credentials and hardware ID
-    unimplemented!()
+// PINs are exactly 7 digits
+pub fn validate_pin(pin: &str) -> bool {
+    pin.len() == 7 && pin.chars().all(|c| c.is_ascii_digit())
+}
+
+// Returned by `access_wallet` when a bound wallet is opened on another
+// machine; the caller can retry with the recovery phrase
+#[derive(Debug)]
+pub struct HardwareMismatch;
+
+impl std::fmt::Display for HardwareMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Wallet is bound to different hardware; recover it with the mnemonic")
+    }
+}
+
+impl Error for HardwareMismatch {}
+
+// Open a wallet from the database and unlock it. When the hardware check
+// fails, a matching recovery phrase rebinds the wallet to this machine.
+pub fn access_wallet(
+    db: &Database,
+    email: String,
+    pin: String,
+    mnemonic: Option<&str>,
+) -> Result<Wallet, Box<dyn Error>> {
+    let mut wallet = db.get_wallet(&email)?.ok_or("Invalid email or PIN")?;
+
+    if !security::verify_password(&pin, &wallet.pin_hash)? {
+        return Err("Invalid email or PIN".into());
+    }
+
+    if !wallet.verify_hardware() {
+        match mnemonic {
+            Some(mnemonic) => {
+                wallet.rebind_hardware(&pin, mnemonic)?;
+                db.update_hardware_binding(&wallet)?;
+            }
+            None => return Err(Box::new(HardwareMismatch)),
+        }
+    }
+
+    wallet.unlock(&pin)?;
+    Ok(wallet)
 } 
\ No newline at end of file
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use tokio::sync::{broadcast, RwLock};
+
+use crate::blockchain::{Blockchain, ChainEvent, Transaction};
+use crate::database::Database;
+use crate::governance::{Governance, GovernanceEvent};
+use crate::market::{Market, MarketEvent};
+
+// Signature of the request body: "sha256=" followed by the hex HMAC
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const EVENT_HEADER: &str = "x-webhook-event";
+
+const MAX_DELIVERY_ATTEMPTS: u32 = 6;
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    NewBlock,
+    // Only sent for transactions involving one of the hook's watched addresses
+    TransactionConfirmed,
+    OrderFilled,
+    ProposalStatus,
+}
+
+impl WebhookEventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEventKind::NewBlock => "new_block",
+            WebhookEventKind::TransactionConfirmed => "transaction_confirmed",
+            WebhookEventKind::OrderFilled => "order_filled",
+            WebhookEventKind::ProposalStatus => "proposal_status",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    // Every kind when empty
+    #[serde(default)]
+    pub events: Vec<WebhookEventKind>,
+    #[serde(default)]
+    pub watch_addresses: Vec<String>,
+    // Used to sign deliveries; never echoed back
+    #[serde(skip_serializing, default)]
+    pub secret: String,
+}
+
+impl Webhook {
+    fn wants(&self, kind: WebhookEventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+// The JSON body POSTed to a hook
+#[derive(Debug, Clone, Serialize)]
+pub struct WebhookEvent {
+    pub kind: WebhookEventKind,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub data: serde_json::Value,
+}
+
+impl WebhookEvent {
+    fn new(kind: WebhookEventKind, data: impl Serialize) -> Option<Self> {
+        Some(WebhookEvent {
+            kind,
+            timestamp: chrono::Utc::now(),
+            data: serde_json::to_value(data).ok()?,
+        })
+    }
+}
+
+// Registered hooks, fed from the chain, market and governance event buses
+pub struct WebhookRegistry {
+    hooks: Arc<RwLock<HashMap<String, Webhook>>>,
+    client: reqwest::Client,
+    database: Option<Arc<Database>>,
+}
+
+impl WebhookRegistry {
+    pub fn new() -> Self {
+        WebhookRegistry {
+            hooks: Arc::new(RwLock::new(HashMap::new())),
+            client: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()
+                .expect("Failed to build HTTP client"),
+            database: None,
+        }
+    }
+
+    // Keep registrations in `database` so they survive restarts
+    pub fn with_database(mut self, database: Arc<Database>) -> Self {
+        self.database = Some(database);
+        self
+    }
+
+    // Load the hooks saved in the registry's database. Returns how many
+    // were restored.
+    pub async fn restore(&self) -> Result<usize, Box<dyn Error>> {
+        let database = self.database.clone().ok_or("Webhook registry has no database to restore from")?;
+        let hooks = tokio::task::spawn_blocking(move || database.get_webhooks().map_err(|e| e.to_string())).await??;
+        let count = hooks.len();
+        self.hooks.write().await.extend(hooks.into_iter().map(|hook| (hook.id.clone(), hook)));
+        Ok(count)
+    }
+
+    // Run a storage write off the async workers; nothing to do without a database
+    async fn persist(
+        &self,
+        write: impl FnOnce(&Database) -> Result<(), Box<dyn Error>> + Send + 'static,
+    ) -> Result<(), Box<dyn Error>> {
+        let database = match &self.database {
+            Some(database) => database.clone(),
+            None => return Ok(()),
+        };
+        tokio::task::spawn_blocking(move || write(&database).map_err(|e| e.to_string())).await??;
+        Ok(())
+    }
+
+    pub async fn register(
+        &self,
+        url: String,
+        events: Vec<WebhookEventKind>,
+        watch_addresses: Vec<String>,
+        secret: String,
+    ) -> Result<Webhook, Box<dyn Error>> {
+        if !url.starts_with("https://") && !url.starts_with("http://") {
+            return Err("Webhook URL must be http or https".into());
+        }
+        if secret.is_empty() {
+            return Err("Webhook secret is required".into());
+        }
+
+        let hook = Webhook {
+            id: uuid::Uuid::new_v4().to_string(),
+            url,
+            events,
+            watch_addresses,
+            secret,
+        };
+        let saved = hook.clone();
+        self.persist(move |database| database.save_webhook(&saved)).await?;
+        self.hooks.write().await.insert(hook.id.clone(), hook.clone());
+        Ok(hook)
+    }
+
+    pub async fn remove(&self, id: &str) -> Result<bool, Box<dyn Error>> {
+        let mut hooks = self.hooks.write().await;
+        if !hooks.contains_key(id) {
+            return Ok(false);
+        }
+        let removed = id.to_string();
+        self.persist(move |database| database.delete_webhook(&removed)).await?;
+        hooks.remove(id);
+        Ok(true)
+    }
+
+    pub async fn list(&self) -> Vec<Webhook> {
+        self.hooks.read().await.values().cloned().collect()
+    }
+
+    // Deliver to every hook that wants the event; transaction events only go
+    // to hooks watching an address the transaction involves
+    async fn dispatch(&self, event: WebhookEvent, transaction: Option<&Transaction>) {
+        let body = match serde_json::to_vec(&event) {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        for hook in self.hooks.read().await.values() {
+            if !hook.wants(event.kind) {
+                continue;
+            }
+            if let Some(tx) = transaction {
+                if !hook.watch_addresses.iter().any(|watched| tx.involves(watched)) {
+                    continue;
+                }
+            }
+            tokio::spawn(deliver(self.client.clone(), hook.clone(), event.kind, body.clone()));
+        }
+    }
+
+    // Start forwarding events to the registered hooks
+    pub fn start(self: &Arc<Self>, blockchain: &Blockchain, market: &Market, governance: &Governance) {
+        let mut chain_events = blockchain.subscribe();
+        let registry = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = next_event(&mut chain_events).await {
+                if let ChainEvent::BlockAdded(block) = event {
+                    for tx in &block.transactions {
+                        let data = serde_json::json!({ "block_hash": block.hash, "transaction": tx });
+                        if let Some(event) = WebhookEvent::new(WebhookEventKind::TransactionConfirmed, data) {
+                            registry.dispatch(event, Some(tx)).await;
+                        }
+                    }
+                    if let Some(event) = WebhookEvent::new(WebhookEventKind::NewBlock, &block) {
+                        registry.dispatch(event, None).await;
+                    }
+                }
+            }
+        });
+
+        let mut market_events = market.subscribe();
+        let registry = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = next_event(&mut market_events).await {
+                if let MarketEvent::OrderFilled(order) = event {
+                    if let Some(event) = WebhookEvent::new(WebhookEventKind::OrderFilled, order) {
+                        registry.dispatch(event, None).await;
+                    }
+                }
+            }
+        });
+
+        let mut governance_events = governance.subscribe();
+        let registry = self.clone();
+        tokio::spawn(async move {
+            while let Some(GovernanceEvent::ProposalStatusChanged(proposal)) = next_event(&mut governance_events).await {
+                if let Some(event) = WebhookEvent::new(WebhookEventKind::ProposalStatus, proposal) {
+                    registry.dispatch(event, None).await;
+                }
+            }
+        });
+    }
+}
+
+// Next event from a bus, skipping over any we fell behind on
+async fn next_event<T: Clone>(events: &mut broadcast::Receiver<T>) -> Option<T> {
+    loop {
+        match events.recv().await {
+            Ok(event) => return Some(event),
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// POST the event, retrying failures and non-2xx replies with exponential
+// backoff until MAX_DELIVERY_ATTEMPTS is reached
+async fn deliver(client: reqwest::Client, hook: Webhook, kind: WebhookEventKind, body: Vec<u8>) {
+    let signature = sign(&hook.secret, &body);
+    for attempt in 0..MAX_DELIVERY_ATTEMPTS {
+        if attempt > 0 {
+            let delay = RETRY_BASE_DELAY.saturating_mul(1 << (attempt - 1)).min(RETRY_MAX_DELAY);
+            tokio::time::sleep(delay).await;
+        }
+        let result = client.post(&hook.url)
+            .header("content-type", "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header(EVENT_HEADER, kind.name())
+            .body(body.clone())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => eprintln!("Webhook {} returned {}", hook.url, response.status()),
+            Err(e) => eprintln!("Webhook {} failed: {}", hook.url, e),
+        }
+    }
+    eprintln!("Giving up on webhook {} after {} attempts", hook.url, MAX_DELIVERY_ATTEMPTS);
+}
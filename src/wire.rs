@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use bincode::Options;
+use serde::{Serialize, Deserialize};
+
+use crate::network::NetworkMessage;
+
+// Frame layout: format version (1 byte), message type (1 byte), payload
+// length (4 bytes, big endian), then the payload: the message encoded with
+// bincode and compressed with the algorithm negotiated for the connection
+pub const WIRE_FORMAT_VERSION: u8 = 1;
+const FRAME_HEADER_LEN: usize = 6;
+
+// Largest message accepted after decompression, so a small compressed
+// frame can't expand into an unbounded allocation
+pub const MAX_DECOMPRESSED_SIZE: usize = 32 * 1024 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Snappy,
+    Zstd,
+}
+
+// Algorithms this node supports, most preferred first
+pub const SUPPORTED_COMPRESSION: &[Compression] = &[Compression::Zstd, Compression::Snappy];
+
+impl Compression {
+    // Our most preferred algorithm that the peer also supports
+    pub fn negotiate(local: &[Compression], remote: &[Compression]) -> Compression {
+        local.iter()
+            .find(|c| remote.contains(c))
+            .copied()
+            .unwrap_or(Compression::None)
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Snappy => Ok(snap::raw::Encoder::new().compress_vec(data)?),
+            Compression::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Snappy => {
+                if snap::raw::decompress_len(data)? > MAX_DECOMPRESSED_SIZE {
+                    return Err("Decompressed message too large".into());
+                }
+                Ok(snap::raw::Decoder::new().decompress_vec(data)?)
+            }
+            Compression::Zstd => Ok(zstd::bulk::decompress(data, MAX_DECOMPRESSED_SIZE)?),
+        }
+    }
+}
+
+// Running totals of message bytes before and after compression
+#[derive(Debug, Default)]
+pub struct CompressionStats {
+    raw_sent: AtomicU64,
+    wire_sent: AtomicU64,
+    raw_received: AtomicU64,
+    wire_received: AtomicU64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompressionMetrics {
+    pub raw_bytes_sent: u64,
+    pub wire_bytes_sent: u64,
+    pub raw_bytes_received: u64,
+    pub wire_bytes_received: u64,
+    // Wire bytes divided by raw bytes; lower is better
+    pub send_ratio: f64,
+    pub receive_ratio: f64,
+}
+
+impl CompressionStats {
+    pub fn record_sent(&self, raw: usize, wire: usize) {
+        self.raw_sent.fetch_add(raw as u64, Ordering::Relaxed);
+        self.wire_sent.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_received(&self, raw: usize, wire: usize) {
+        self.raw_received.fetch_add(raw as u64, Ordering::Relaxed);
+        self.wire_received.fetch_add(wire as u64, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> CompressionMetrics {
+        let raw_bytes_sent = self.raw_sent.load(Ordering::Relaxed);
+        let wire_bytes_sent = self.wire_sent.load(Ordering::Relaxed);
+        let raw_bytes_received = self.raw_received.load(Ordering::Relaxed);
+        let wire_bytes_received = self.wire_received.load(Ordering::Relaxed);
+        CompressionMetrics {
+            raw_bytes_sent,
+            wire_bytes_sent,
+            raw_bytes_received,
+            wire_bytes_received,
+            send_ratio: ratio(wire_bytes_sent, raw_bytes_sent),
+            receive_ratio: ratio(wire_bytes_received, raw_bytes_received),
+        }
+    }
+}
+
+fn ratio(wire: u64, raw: u64) -> f64 {
+    if raw == 0 {
+        1.0
+    } else {
+        wire as f64 / raw as f64
+    }
+}
+
+pub fn encode_frame(message: &NetworkMessage, compression: Compression, stats: &CompressionStats) -> Result<Vec<u8>, Box<dyn Error>> {
+    let encoded = bincode::serialize(message)?;
+    let payload = compression.compress(&encoded)?;
+    stats.record_sent(encoded.len(), payload.len());
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.push(WIRE_FORMAT_VERSION);
+    frame.push(message.message_type());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+// Message type byte from a frame header
+pub fn frame_type(frame: &[u8]) -> Option<u8> {
+    if frame.len() < FRAME_HEADER_LEN {
+        return None;
+    }
+    Some(frame[1])
+}
+
+pub fn decode_frame(frame: &[u8], compression: Compression, stats: &CompressionStats) -> Result<NetworkMessage, Box<dyn Error>> {
+    if frame.len() < FRAME_HEADER_LEN {
+        return Err("Truncated frame header".into());
+    }
+    if frame[0] != WIRE_FORMAT_VERSION {
+        return Err(format!("Unsupported wire format version {}", frame[0]).into());
+    }
+    let length = u32::from_be_bytes([frame[2], frame[3], frame[4], frame[5]]) as usize;
+    let payload = &frame[FRAME_HEADER_LEN..];
+    if payload.len() != length {
+        return Err(format!("Frame length mismatch: header says {}, got {}", length, payload.len()).into());
+    }
+
+    let encoded = compression.decompress(payload)?;
+    stats.record_received(encoded.len(), payload.len());
+    let message: NetworkMessage = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_DECOMPRESSED_SIZE as u64)
+        .deserialize(&encoded)?;
+    if message.message_type() != frame[1] {
+        return Err("Frame type does not match its payload".into());
+    }
+    Ok(message)
+}
+
+// Serde helper for the fields a message gained after its first wire format.
+// Bincode has no field names, so a missing or unknown trailing field shifts
+// everything after it. Binary encodings therefore carry these fields as one
+// length-prefixed JSON object: an older peer skips the fields it doesn't know
+// and a newer one defaults those it doesn't get. Human-readable formats nest
+// the object as usual. New fields always go in the extensions, never in the
+// positional part of a message.
+pub mod extensions {
+    use serde::de::{DeserializeOwned, Error as _};
+    use serde::ser::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<T: Serialize, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return value.serialize(serializer);
+        }
+        let blob = serde_json::to_vec(value).map_err(S::Error::custom)?;
+        serializer.serialize_bytes(&blob)
+    }
+
+    pub fn deserialize<'de, T: DeserializeOwned, D: Deserializer<'de>>(deserializer: D) -> Result<T, D::Error> {
+        if deserializer.is_human_readable() {
+            return T::deserialize(deserializer);
+        }
+        let blob = Vec::<u8>::deserialize(deserializer)?;
+        serde_json::from_slice(&blob).map_err(D::Error::custom)
+    }
+}